@@ -0,0 +1,132 @@
+use std::{fs, hint::black_box, time::Duration};
+
+use cosmic_text::{Attrs, AttrsList, FontSystem};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use danmaku_renderer::{
+    danmaku::{Danmaku, DanmakuTime, DanmakuType},
+    layout::{DanmakuItem, DanmakuTrackState, LayoutMode, ScrollSpeedModel},
+    manager::{DanmakuTimeChunkProvider, LayoutedDanmakuItem},
+    shaper::CosmicTextShaper,
+    sources::{bilibili::parse_proto, DanmakuSource, VecDanmakuSource},
+    worker::{DanmakuParam, LineHeight, ShadowKernel, TallDanmaku, WideDanmaku},
+};
+
+fn create_param() -> DanmakuParam {
+    DanmakuParam {
+        screen_size: (1920, 1080),
+        scroll_lifetime: Duration::from_secs(8),
+        static_lifetime: Duration::from_secs(5),
+        chunk_duration: Duration::from_secs(8),
+        font_size: 28.0,
+        line_height: LineHeight::Fixed(32),
+        font_attrs: AttrsList::new(Attrs::new()),
+        layout_mode: LayoutMode::ShowAll,
+        scroll_speed: ScrollSpeedModel::ConstantDuration,
+        scroll_gap: 0,
+        scroll_speed_variation: 0.0,
+        wide_danmaku: WideDanmaku::Keep,
+        tall_danmaku: TallDanmaku::Overflow,
+        blocked_regions: Vec::new(),
+        static_limit: None,
+        shadow_size: 2,
+        shadow_weight: 1.5,
+        shadow_kernel: ShadowKernel::Outline,
+        scale_factor: 1.0,
+    }
+}
+
+fn load_danmaku() -> Vec<Danmaku> {
+    let content = fs::read("test/1176840.bin").unwrap();
+    parse_proto(&content).unwrap().into_all().collect()
+}
+
+fn shaping(c: &mut Criterion) {
+    let param = create_param();
+    let danmaku: Vec<_> = load_danmaku().into_iter().take(1000).collect();
+    let mut shaper = CosmicTextShaper::new(FontSystem::new());
+
+    let mut group = c.benchmark_group("shaping");
+    group.throughput(Throughput::Elements(danmaku.len() as u64));
+    group.bench_function("layouted_item", |b| {
+        b.iter(|| {
+            for danmaku in &danmaku {
+                black_box(LayoutedDanmakuItem::new(
+                    &mut shaper,
+                    &param.font_attrs,
+                    param.font_size,
+                    param.scale_factor,
+                    param.physical_line_height(),
+                    None,
+                    danmaku,
+                    param.wide_danmaku,
+                    param.max_line_width(),
+                ));
+            }
+        })
+    });
+    group.finish();
+}
+
+// Far more danmaku than tracks, so most insertions search every track and overlap
+fn track_insert(c: &mut Criterion) {
+    let param = create_param();
+    let state = DanmakuTrackState::new(
+        param.layout_mode,
+        param.screen_size,
+        param.physical_line_height(),
+        param.scroll_lifetime,
+        param.static_lifetime,
+        param.scroll_speed_model(),
+        param.physical_scroll_gap(),
+    );
+    let items: Vec<_> = (0..10000)
+        .map(|index| {
+            let r#type = match index % 10 {
+                0 => DanmakuType::Top,
+                1 => DanmakuType::Bottom,
+                _ => DanmakuType::Scroll,
+            };
+            let width = 100 + (index * 37 % 400) as u32;
+            DanmakuItem::new(width, DanmakuTime::from_millis(index), r#type)
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("track_state");
+    group.throughput(Throughput::Elements(items.len() as u64));
+    group.bench_function("insert_dense", |b| {
+        b.iter_batched(
+            || state.clone(),
+            |mut state| {
+                for item in &items {
+                    black_box(state.insert(item.clone()));
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn chunk_generation(c: &mut Criterion) {
+    let param = create_param();
+    let danmaku = load_danmaku();
+    let mut shaper = CosmicTextShaper::new(FontSystem::new());
+
+    c.bench_function("chunk_generation", |b| {
+        b.iter_batched(
+            || {
+                let source = VecDanmakuSource::new(danmaku.clone());
+                DanmakuTimeChunkProvider::new(param.clone(), Box::new(source))
+            },
+            |mut provider| {
+                for index in 0..8 {
+                    black_box(provider.get_chunk(&mut shaper, None, index).unwrap());
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, shaping, track_insert, chunk_generation);
+criterion_main!(benches);
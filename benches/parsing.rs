@@ -0,0 +1,27 @@
+use std::{fs, hint::black_box};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use danmaku_renderer::sources::bilibili::{parse_proto, parse_xml_from_reader};
+
+// The same video in both formats, about 3600 danmaku
+const XML_FIXTURE: &str = "test/1176840.xml";
+const PROTO_FIXTURE: &str = "test/1176840.bin";
+
+fn parse(c: &mut Criterion) {
+    let xml = fs::read(XML_FIXTURE).unwrap();
+    let proto = fs::read(PROTO_FIXTURE).unwrap();
+
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(xml.len() as u64));
+    group.bench_function("xml", |b| {
+        b.iter(|| parse_xml_from_reader(black_box(xml.as_slice())).unwrap())
+    });
+    group.throughput(Throughput::Bytes(proto.len() as u64));
+    group.bench_function("protobuf", |b| {
+        b.iter(|| parse_proto(black_box(&proto)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+use std::{fs, hint::black_box, time::Duration};
+
+use cosmic_text::{Attrs, AttrsList, FontSystem};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use danmaku_renderer::{
+    layout::{LayoutMode, ScrollSpeedModel},
+    manager::DanmakuTimeChunkProvider,
+    renderer::wgpu::{testing::request_device, WgpuRenderCache, WgpuVertexBuffer},
+    shaper::CosmicTextShaper,
+    sources::bilibili::parse_proto,
+    worker::{
+        ChunkBuffer, DanmakuParam, LineHeight, RenderCache, ShadowKernel, TallDanmaku, WideDanmaku,
+    },
+};
+
+fn create_param() -> DanmakuParam {
+    DanmakuParam {
+        screen_size: (1920, 1080),
+        scroll_lifetime: Duration::from_secs(8),
+        static_lifetime: Duration::from_secs(5),
+        chunk_duration: Duration::from_secs(8),
+        font_size: 28.0,
+        line_height: LineHeight::Fixed(32),
+        font_attrs: AttrsList::new(Attrs::new()),
+        layout_mode: LayoutMode::ShowAll,
+        scroll_speed: ScrollSpeedModel::ConstantDuration,
+        scroll_gap: 0,
+        scroll_speed_variation: 0.0,
+        wide_danmaku: WideDanmaku::Keep,
+        tall_danmaku: TallDanmaku::Overflow,
+        blocked_regions: Vec::new(),
+        static_limit: None,
+        shadow_size: 2,
+        shadow_weight: 1.5,
+        shadow_kernel: ShadowKernel::Outline,
+        scale_factor: 1.0,
+    }
+}
+
+// Builds the instance buffer of a chunk whose glyphs are already in the atlas
+fn vertex_generation(c: &mut Criterion) {
+    let Some((device, queue)) = request_device() else {
+        eprintln!("No wgpu adapter, skipping the vertex benchmark");
+        return;
+    };
+    let param = create_param();
+    let content = fs::read("test/1176840.bin").unwrap();
+    let source = parse_proto(&content).unwrap();
+    let mut provider = DanmakuTimeChunkProvider::new(param.clone(), Box::new(source));
+    let mut shaper = CosmicTextShaper::new(FontSystem::new());
+    let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+
+    let mut cache = WgpuRenderCache::new(device, queue, (2048, 2048), param);
+    cache.prepare(&mut shaper, &chunk);
+    cache.flush();
+
+    let mut group = c.benchmark_group("vertex");
+    group.throughput(Throughput::Elements(chunk.items.len() as u64));
+    group.bench_function("chunk_buffer", |b| {
+        b.iter(|| {
+            // The buffers are cached per chunk
+            cache.invalidate(0);
+            black_box(WgpuVertexBuffer::new(&chunk, &mut cache))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, vertex_generation);
+criterion_main!(benches);
@@ -0,0 +1,352 @@
+use alloc::string::String;
+use core::{
+    cmp::Ordering,
+    fmt::{self, Debug, Formatter},
+    ops::Sub,
+    time::Duration,
+};
+
+// Milliseconds since the start of the video, may be negative after applying an offset
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct DanmakuTime(i64);
+
+impl DanmakuTime {
+    pub const MIN: DanmakuTime = DanmakuTime(i64::MIN);
+
+    pub fn abs_diff(&self, other: &DanmakuTime) -> Duration {
+        let diff = self.0.abs_diff(other.0);
+        Duration::from_millis(diff)
+    }
+
+    pub fn from_millis(milis: i64) -> Self {
+        DanmakuTime(milis)
+    }
+
+    pub fn as_millis(&self) -> i64 {
+        self.0
+    }
+
+    pub fn seconds(&self) -> i64 {
+        self.0.div_euclid(1000)
+    }
+
+    pub fn millis(&self) -> u16 {
+        self.0.rem_euclid(1000) as u16
+    }
+
+    // None if rhs is later than self
+    pub fn checked_sub(&self, rhs: &DanmakuTime) -> Option<Duration> {
+        let milliseconds = self.0.checked_sub(rhs.0)?;
+        u64::try_from(milliseconds).ok().map(Duration::from_millis)
+    }
+
+    pub fn saturating_sub(&self, rhs: &DanmakuTime) -> Duration {
+        self.checked_sub(rhs).unwrap_or(Duration::ZERO)
+    }
+}
+
+impl Ord for DanmakuTime {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl PartialOrd for DanmakuTime {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Debug for DanmakuTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = DanmakuTime(self.0.saturating_abs());
+        let minutes = abs.seconds() / 60;
+        let seconds = abs.seconds() % 60;
+        write!(
+            f,
+            "{}{:02}:{:02}.{:03}",
+            sign,
+            minutes,
+            seconds,
+            abs.millis()
+        )
+    }
+}
+
+// Saturates to zero when rhs is later than self, use checked_sub to tell them apart
+impl Sub for DanmakuTime {
+    type Output = Duration;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.saturating_sub(&rhs)
+    }
+}
+
+impl Sub for &DanmakuTime {
+    type Output = Duration;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        *self - *rhs
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DanmakuColor(u32);
+
+impl DanmakuColor {
+    // Outline of bordered danmaku, like the ones sent by the user on Bilibili
+    pub const BORDER: DanmakuColor = DanmakuColor(0x66FF66);
+
+    pub fn from_code(rgb: u32) -> Self {
+        assert!(rgb >> 24 == 0);
+        DanmakuColor(rgb)
+    }
+
+    pub fn from_code_cast(rgb: u32) -> Self {
+        DanmakuColor(rgb & 0xFFFFFF)
+    }
+
+    pub fn from_rgb(r: u8, g: u8, b: u8) -> Self {
+        DanmakuColor((r as u32) << 16 | (g as u32) << 8 | (b as u32))
+    }
+
+    pub fn r(&self) -> u8 {
+        (self.0 >> 16) as u8
+    }
+
+    pub fn g(&self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+
+    pub fn b(&self) -> u8 {
+        self.0 as u8
+    }
+
+    pub fn code(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Debug for DanmakuColor {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "#{:02X}{:02X}{:02X}", self.r(), self.g(), self.b())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DanmakuType {
+    Scroll,
+    Top,
+    Bottom,
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DanmakuSize {
+    Small,
+    Regular,
+    Large,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DanmakuKeyframe {
+    // Milliseconds after the danmaku appears
+    pub time: u32,
+    // Multiplies the size around the center of the danmaku
+    pub scale: f32,
+    // Multiplies the opacity
+    pub alpha: f32,
+}
+
+impl DanmakuKeyframe {
+    pub const IDENTITY: DanmakuKeyframe = DanmakuKeyframe {
+        time: 0,
+        scale: 1.0,
+        alpha: 1.0,
+    };
+}
+
+// Scale and opacity interpolated linearly between up to MAX_KEYFRAMES keyframes, holding the
+// first and the last value outside of them. Looped animations start over after the last one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DanmakuAnimation {
+    keyframes: [DanmakuKeyframe; DanmakuAnimation::MAX_KEYFRAMES],
+    len: usize,
+    looped: bool,
+}
+
+impl DanmakuAnimation {
+    // Keyframes are per-instance attributes of the wgpu renderer, so there are only a few
+    pub const MAX_KEYFRAMES: usize = 4;
+
+    // None if there are no keyframes, too many, or they are out of time order
+    pub fn new(keyframes: &[DanmakuKeyframe], looped: bool) -> Option<Self> {
+        if keyframes.is_empty()
+            || keyframes.len() > Self::MAX_KEYFRAMES
+            || keyframes.windows(2).any(|pair| pair[0].time > pair[1].time)
+        {
+            return None;
+        }
+        let mut array = [*keyframes.last().unwrap(); Self::MAX_KEYFRAMES];
+        array[..keyframes.len()].copy_from_slice(keyframes);
+        Some(DanmakuAnimation {
+            keyframes: array,
+            len: keyframes.len(),
+            looped,
+        })
+    }
+
+    // Grows from nothing and fades in, e.g. for paid comments
+    pub fn pop_in(duration: u32) -> Self {
+        let start = DanmakuKeyframe {
+            time: 0,
+            scale: 0.0,
+            alpha: 0.0,
+        };
+        let overshoot = DanmakuKeyframe {
+            time: duration * 2 / 3,
+            scale: 1.2,
+            alpha: 1.0,
+        };
+        let end = DanmakuKeyframe {
+            time: duration,
+            ..DanmakuKeyframe::IDENTITY
+        };
+        Self::new(&[start, overshoot, end], false).unwrap()
+    }
+
+    // Swells to the scale and back in every period, as long as the danmaku is shown
+    pub fn pulse(period: u32, scale: f32) -> Self {
+        let peak = DanmakuKeyframe {
+            time: period / 2,
+            scale,
+            alpha: 1.0,
+        };
+        let end = DanmakuKeyframe {
+            time: period,
+            ..DanmakuKeyframe::IDENTITY
+        };
+        Self::new(&[DanmakuKeyframe::IDENTITY, peak, end], true).unwrap()
+    }
+
+    pub fn keyframes(&self) -> &[DanmakuKeyframe] {
+        &self.keyframes[..self.len]
+    }
+
+    pub fn looped(&self) -> bool {
+        self.looped
+    }
+
+    // Scale and alpha at the milliseconds after the danmaku appears
+    pub fn evaluate(&self, elapsed: f32) -> (f32, f32) {
+        let keyframes = self.keyframes();
+        let last = keyframes[keyframes.len() - 1];
+        let mut elapsed = elapsed.max(0.0);
+        if self.looped && last.time > 0 {
+            elapsed %= last.time as f32;
+        }
+        let mut previous = keyframes[0];
+        for keyframe in keyframes {
+            if elapsed < keyframe.time as f32 {
+                let span = (keyframe.time - previous.time) as f32;
+                let progress = if span > 0.0 {
+                    (elapsed - previous.time as f32) / span
+                } else {
+                    0.0
+                };
+                let lerp = |from: f32, to: f32| from + (to - from) * progress.max(0.0);
+                return (
+                    lerp(previous.scale, keyframe.scale),
+                    lerp(previous.alpha, keyframe.alpha),
+                );
+            }
+            previous = *keyframe;
+        }
+        (last.scale, last.alpha)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Danmaku {
+    pub time: DanmakuTime,
+    pub r#type: DanmakuType,
+    pub size: DanmakuSize,
+    pub color: DanmakuColor,
+    pub content: String,
+    pub bordered: bool,
+    // Solid block behind the text, like the "LED banner" danmaku of some platforms
+    pub background: Option<DanmakuColor>,
+    // Scale and opacity over time, like the pop-in of highlighted comments
+    pub animation: Option<DanmakuAnimation>,
+    // Fades horizontally from color on the left to this color on the right
+    pub gradient: Option<DanmakuColor>,
+    // Identifier given by the platform, e.g. the dmid of Bilibili
+    pub id: Option<u64>,
+    // Danmaku sharing the group in a chunk are laid out as one item, the later ones as lines under
+    // the first, e.g. a translation under the original comment
+    pub group_id: Option<u64>,
+}
+
+impl Danmaku {
+    // A regular white danmaku without any of the optional styles
+    pub fn new(time: DanmakuTime, r#type: DanmakuType, content: impl Into<String>) -> Self {
+        Danmaku {
+            time,
+            r#type,
+            size: DanmakuSize::Regular,
+            color: DanmakuColor::from_code(0xFFFFFF),
+            content: content.into(),
+            bordered: false,
+            background: None,
+            animation: None,
+            gradient: None,
+            id: None,
+            group_id: None,
+        }
+    }
+
+    // Orders danmaku of the same time by their id, so sorting doesn't depend on the input order
+    pub fn order_key(&self) -> (DanmakuTime, Option<u64>) {
+        (self.time, self.id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+    use core::time::Duration;
+
+    use super::{DanmakuAnimation, DanmakuKeyframe, DanmakuTime};
+
+    #[test]
+    fn test_time_sub() {
+        let early = DanmakuTime::from_millis(-1500);
+        let late = DanmakuTime::from_millis(2000);
+        assert_eq!(late.checked_sub(&early), Some(Duration::from_millis(3500)));
+        assert_eq!(early.checked_sub(&late), None);
+        assert_eq!(early - late, Duration::ZERO);
+        assert_eq!(format!("{:?}", early), "-00:01.500");
+    }
+
+    #[test]
+    fn test_animation() {
+        let pop_in = DanmakuAnimation::pop_in(300);
+        assert_eq!(pop_in.evaluate(-100.0), (0.0, 0.0));
+        assert_eq!(pop_in.evaluate(100.0), (0.6, 0.5));
+        assert_eq!(pop_in.evaluate(200.0), (1.2, 1.0));
+        assert_eq!(pop_in.evaluate(1000.0), (1.0, 1.0));
+
+        let pulse = DanmakuAnimation::pulse(1000, 1.5);
+        assert_eq!(pulse.evaluate(250.0), (1.25, 1.0));
+        assert_eq!(pulse.evaluate(2500.0), (1.5, 1.0));
+
+        let keyframe = |time| DanmakuKeyframe {
+            time,
+            ..DanmakuKeyframe::IDENTITY
+        };
+        assert!(DanmakuAnimation::new(&[], false).is_none());
+        assert!(DanmakuAnimation::new(&[keyframe(200), keyframe(100)], false).is_none());
+        assert!(DanmakuAnimation::new(&[keyframe(0); 5], false).is_none());
+    }
+}
@@ -1,3 +1,5 @@
+use alloc::{boxed::Box, vec::Vec};
+
 use super::DanmakuFilter;
 
 pub struct MergeFilter {
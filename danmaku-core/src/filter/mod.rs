@@ -0,0 +1,9 @@
+mod merge;
+mod simple;
+
+pub use merge::MergeFilter;
+pub use simple::SimpleFilter;
+
+pub trait DanmakuFilter {
+    fn is_filtered(&self, content: &str) -> bool;
+}
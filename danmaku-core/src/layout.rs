@@ -0,0 +1,697 @@
+use alloc::{collections::VecDeque, vec, vec::Vec};
+use core::time::Duration;
+
+use crate::danmaku::{DanmakuTime, DanmakuType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DanmakuPosition {
+    Scroll(usize),
+    Top(usize),
+    Bottom(usize),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LayoutMode {
+    NoOverlap(u32),
+    ShowAll,
+    // Like ShowAll, with another policy for choosing the track to overlap
+    ShowAllWith(TrackPolicy),
+}
+
+impl LayoutMode {
+    // How to choose the track when all of them are occupied, None if the danmaku is dropped
+    fn overlap_policy(&self) -> Option<TrackPolicy> {
+        match self {
+            LayoutMode::NoOverlap(_) => None,
+            LayoutMode::ShowAll => Some(TrackPolicy::RoundRobin),
+            LayoutMode::ShowAllWith(policy) => Some(*policy),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TrackPolicy {
+    // Causes visible banding when many danmaku arrive at once
+    #[default]
+    RoundRobin,
+    // Pseudo-random, seeded with the chunk index so generating a chunk again gives the same result
+    Random,
+    // The track whose latest danmaku is the oldest
+    LeastRecentlyUsed,
+}
+
+// SplitMix64
+#[derive(Clone, Debug)]
+struct TrackRng(u64);
+
+impl TrackRng {
+    fn next(&mut self, bound: usize) -> usize {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        (z % bound as u64) as usize
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ScrollSpeedModel {
+    // Every danmaku crosses the screen in the scroll lifetime, so wider ones move faster
+    #[default]
+    ConstantDuration,
+    // Every danmaku moves one screen width per scroll lifetime, so wider ones stay longer
+    ConstantSpeed,
+    // Like ConstantDuration, but danmaku wider than this many physical pixels move as fast as
+    // one of this width and stay longer
+    CappedDuration(u32),
+}
+
+impl ScrollSpeedModel {
+    // Pixels per millisecond
+    pub fn speed(&self, screen_width: u32, width: u32, lifetime: Duration) -> f64 {
+        let lifetime = lifetime.as_millis() as f64;
+        match self {
+            ScrollSpeedModel::ConstantDuration => (screen_width + width) as f64 / lifetime,
+            ScrollSpeedModel::ConstantSpeed => screen_width as f64 / lifetime,
+            ScrollSpeedModel::CappedDuration(max_width) => {
+                (screen_width + width.min(*max_width)) as f64 / lifetime
+            }
+        }
+    }
+
+    // Time from entering the screen until leaving it completely
+    pub fn duration(&self, screen_width: u32, width: u32, lifetime: Duration) -> Duration {
+        match self {
+            ScrollSpeedModel::ConstantDuration => lifetime,
+            ScrollSpeedModel::ConstantSpeed => {
+                let millis = lifetime.as_millis() as u64 * (screen_width + width) as u64
+                    / screen_width.max(1) as u64;
+                Duration::from_millis(millis)
+            }
+            ScrollSpeedModel::CappedDuration(max_width) if width > *max_width => {
+                let millis = lifetime.as_millis() as u64 * (screen_width + width) as u64
+                    / (screen_width + max_width).max(1) as u64;
+                Duration::from_millis(millis)
+            }
+            ScrollSpeedModel::CappedDuration(_) => lifetime,
+        }
+    }
+}
+
+// Deterministic multiplier in 1 ± variation for each scroll track, so dense walls of text don't
+// move in lockstep. The vertex shader computes the same value.
+pub fn track_speed_multiplier(track: usize, variation: f32) -> f64 {
+    if variation == 0.0 {
+        return 1.0;
+    }
+    // Golden ratio hash spreads neighbouring tracks over [0, 1)
+    let fraction = (track as u32).wrapping_mul(0x9E3779B9) as f64 / 4294967296.0;
+    1.0 + variation as f64 * (fraction * 2.0 - 1.0)
+}
+
+// Scroll lifetime of a track after applying its speed multiplier
+pub fn track_lifetime(lifetime: Duration, track: usize, variation: f32) -> Duration {
+    lifetime.div_f64(track_speed_multiplier(track, variation))
+}
+
+// Caps the share of the top or bottom tracks taken by the danmaku placed within the window, so
+// floods of static danmaku can't cover the screen. Danmaku beyond it are dropped.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StaticLimit {
+    // Fraction of the tracks, e.g. 0.5
+    pub max_ratio: f32,
+    pub window_millis: u32,
+}
+
+impl StaticLimit {
+    fn max_tracks(&self, tracks: usize) -> usize {
+        (tracks as f32 * self.max_ratio) as usize
+    }
+}
+
+// Area of the screen in physical pixels
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn overlaps_rows(&self, top: u32, bottom: u32) -> bool {
+        self.width > 0 && self.height > 0 && self.y < bottom && self.y + self.height > top
+    }
+}
+
+// Area kept free of static danmaku while it's shown, e.g. the subtitles found by OCR
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimedRegion {
+    pub start: DanmakuTime,
+    pub end: DanmakuTime,
+    pub rect: Rect,
+}
+
+impl TimedRegion {
+    // Whether the region is shown at any time from the start for the duration
+    pub fn overlaps_time(&self, start: DanmakuTime, duration: Duration) -> bool {
+        self.start.as_millis() < start.as_millis() + duration.as_millis() as i64 && self.end > start
+    }
+}
+
+// Tracks overlapping any of the regions, row_range gives the pixel rows of a track
+fn blocked_tracks(
+    tracks: usize,
+    regions: &[Rect],
+    row_range: impl Fn(usize) -> (u32, u32),
+) -> Vec<bool> {
+    (0..tracks)
+        .map(|track| {
+            let (top, bottom) = row_range(track);
+            regions
+                .iter()
+                .any(|region| region.overlaps_rows(top, bottom))
+        })
+        .collect()
+}
+
+fn top_rows(line_height: u32, track: usize) -> (u32, u32) {
+    let top = track as u32 * line_height;
+    (top, top + line_height)
+}
+
+// Bottom tracks count upwards from the bottom of the screen
+fn bottom_rows(screen_height: u32, line_height: u32, track: usize) -> (u32, u32) {
+    let bottom = screen_height.saturating_sub(track as u32 * line_height);
+    (bottom.saturating_sub(line_height), bottom)
+}
+
+#[derive(Clone, Debug)]
+pub struct DanmakuItem {
+    width: u32,
+    time: DanmakuTime,
+    r#type: DanmakuType,
+    lines: usize,
+}
+
+impl DanmakuItem {
+    pub fn new(width: u32, time: DanmakuTime, r#type: DanmakuType) -> Self {
+        DanmakuItem {
+            width,
+            time,
+            r#type,
+            lines: 1,
+        }
+    }
+
+    // Multi-line danmaku take this many adjacent tracks
+    pub fn with_lines(mut self, lines: usize) -> Self {
+        self.lines = lines.max(1);
+        self
+    }
+}
+
+// First tracks of the windows of adjacent tracks without blocked ones, empty if the item is
+// taller than all tracks
+fn window_starts(blocked: &[bool], lines: usize) -> impl Iterator<Item = usize> + '_ {
+    let last = blocked.len().checked_sub(lines);
+    last.into_iter()
+        .flat_map(|last| 0..=last)
+        .filter(move |start| !blocked[*start..*start + lines].contains(&true))
+}
+
+// Start of the window to overlap when every track is occupied, the item may be clamped to the
+// height of the screen. None if every window has a blocked track.
+fn overlap_window(
+    policy: TrackPolicy,
+    index: usize,
+    rng: &mut TrackRng,
+    times: &[Option<DanmakuTime>],
+    blocked: &[bool],
+    lines: usize,
+) -> Option<usize> {
+    let lines = lines.min(times.len());
+    let starts: Vec<_> = window_starts(blocked, lines).collect();
+    if starts.is_empty() {
+        return None;
+    }
+    let start = match policy {
+        TrackPolicy::RoundRobin => starts[index % starts.len()],
+        TrackPolicy::Random => starts[rng.next(starts.len())],
+        TrackPolicy::LeastRecentlyUsed => {
+            starts[least_recent(starts.iter().map(|start| {
+                times[*start..*start + lines]
+                    .iter()
+                    .max()
+                    .copied()
+                    .flatten()
+            }))]
+        }
+    };
+    Some(start)
+}
+
+#[derive(Clone, Debug)]
+struct StaticDanmakuTrackState {
+    tracks: Vec<Option<DanmakuItem>>,
+    // Tracks covered by the blocked regions, never used
+    blocked: Vec<bool>,
+    lifetime: Duration,
+    index: usize,
+    limit: Option<StaticLimit>,
+    // Time and number of tracks of the danmaku placed within the window of the limit
+    recent: VecDeque<(DanmakuTime, usize)>,
+}
+
+impl StaticDanmakuTrackState {
+    fn new(tracks: usize, lifetime: Duration) -> Self {
+        StaticDanmakuTrackState {
+            tracks: (0..tracks).map(|_| None).collect(),
+            blocked: vec![false; tracks],
+            lifetime,
+            index: 0,
+            limit: None,
+            recent: VecDeque::new(),
+        }
+    }
+
+    // Drops the placements older than the window, items arrive in time order
+    fn within_limit(&mut self, item: &DanmakuItem) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+        let window = Duration::from_millis(limit.window_millis as u64);
+        while let Some((time, _)) = self.recent.front() {
+            if item.time - *time < window {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        let taken: usize = self.recent.iter().map(|(_, lines)| lines).sum();
+        let lines = item.lines.min(self.tracks.len());
+        taken + lines <= limit.max_tracks(self.tracks.len())
+    }
+
+    fn clear_expired(&mut self, now_time: DanmakuTime) {
+        for track in self.tracks.iter_mut() {
+            if let Some(item) = track {
+                if now_time - item.time > self.lifetime {
+                    *track = None;
+                }
+            }
+        }
+    }
+
+    // The blocked tracks include the ones of the timed regions
+    fn find_track(
+        &self,
+        lines: usize,
+        mode: LayoutMode,
+        rng: &mut TrackRng,
+        blocked: &[bool],
+    ) -> Option<usize> {
+        if self.tracks.is_empty() {
+            return None;
+        }
+        let track = self.find_empty_track(lines, blocked);
+        let policy = mode.overlap_policy();
+        track.or_else(|| {
+            let times: Vec<_> = self
+                .tracks
+                .iter()
+                .map(|track| track.as_ref().map(|item| item.time))
+                .collect();
+            overlap_window(policy?, self.index, rng, &times, blocked, lines)
+        })
+    }
+
+    fn find_empty_track(&self, lines: usize, blocked: &[bool]) -> Option<usize> {
+        window_starts(blocked, lines).find(|start| {
+            self.tracks[*start..*start + lines]
+                .iter()
+                .all(Option::is_none)
+        })
+    }
+
+    // Returns the number of tracks taken
+    fn insert(&mut self, track: usize, item: DanmakuItem) -> usize {
+        let end = (track + item.lines).min(self.tracks.len());
+        for slot in &mut self.tracks[track..end] {
+            *slot = Some(item.clone());
+        }
+        if self.limit.is_some() {
+            self.recent.push_back((item.time, end - track));
+        }
+        self.index += 1;
+        end - track
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ScrollDanmakuTrack {
+    latest_danmaku_item: Option<DanmakuItem>,
+    // Scroll lifetime of the latest item, which moves with the speed of its first track
+    lifetime: Duration,
+}
+
+#[derive(Clone, Debug)]
+struct ScrollDanmakuTrackState {
+    tracks: Vec<ScrollDanmakuTrack>,
+    blocked: Vec<bool>,
+    lifetime: Duration,
+    screen_width: u32,
+    speed_model: ScrollSpeedModel,
+    // Minimum horizontal distance between danmaku in the same track
+    gap: u32,
+    speed_variation: f32,
+    index: usize,
+}
+
+impl ScrollDanmakuTrack {
+    fn new() -> Self {
+        ScrollDanmakuTrack {
+            latest_danmaku_item: None,
+            lifetime: Duration::ZERO,
+        }
+    }
+
+    fn clear_expired(
+        &mut self,
+        speed_model: ScrollSpeedModel,
+        screen_width: u32,
+        now_time: DanmakuTime,
+    ) {
+        if let Some(latest_danmaku_item) = &self.latest_danmaku_item {
+            let passed_time = now_time - latest_danmaku_item.time;
+            let duration =
+                speed_model.duration(screen_width, latest_danmaku_item.width, self.lifetime);
+            if passed_time > duration {
+                self.latest_danmaku_item = None
+            }
+        }
+    }
+
+    fn will_overlap(
+        &self,
+        state: &ScrollDanmakuTrackState,
+        lifetime: Duration,
+        item: &DanmakuItem,
+    ) -> bool {
+        if let Some(last_item) = &self.latest_danmaku_item {
+            let screen_width = state.screen_width;
+            let speed_last = state
+                .speed_model
+                .speed(screen_width, last_item.width, self.lifetime);
+            let speed_current = state.speed_model.speed(screen_width, item.width, lifetime);
+
+            let time_last = last_item.time.as_millis();
+            let time_current = item.time.as_millis();
+
+            let distance_last = speed_last * (time_current - time_last) as f64
+                - last_item.width as f64
+                - state.gap as f64;
+
+            if distance_last < 0.0 {
+                return true;
+            }
+            if speed_last >= speed_current {
+                return false;
+            }
+
+            let time_to_reach = distance_last / (speed_current - speed_last);
+            let time_current = screen_width as f64 / speed_current;
+            time_to_reach < time_current
+        } else {
+            false
+        }
+    }
+
+    fn insert(&mut self, item: DanmakuItem, lifetime: Duration) {
+        self.latest_danmaku_item = Some(item);
+        self.lifetime = lifetime;
+    }
+}
+
+impl ScrollDanmakuTrackState {
+    fn new(
+        tracks: usize,
+        screen_width: u32,
+        lifetime: Duration,
+        speed_model: ScrollSpeedModel,
+        gap: u32,
+    ) -> Self {
+        ScrollDanmakuTrackState {
+            tracks: (0..tracks).map(|_| ScrollDanmakuTrack::new()).collect(),
+            blocked: vec![false; tracks],
+            screen_width,
+            lifetime,
+            speed_model,
+            gap,
+            speed_variation: 0.0,
+            index: 0,
+        }
+    }
+
+    fn track_lifetime(&self, track: usize) -> Duration {
+        track_lifetime(self.lifetime, track, self.speed_variation)
+    }
+
+    fn clear_expired(&mut self, now_time: DanmakuTime) {
+        let (speed_model, screen_width) = (self.speed_model, self.screen_width);
+        self.tracks
+            .iter_mut()
+            .for_each(|track| track.clear_expired(speed_model, screen_width, now_time))
+    }
+
+    fn find_empty_track(&self, item: &DanmakuItem) -> Option<usize> {
+        window_starts(&self.blocked, item.lines).find(|start| {
+            let lifetime = self.track_lifetime(*start);
+            self.tracks[*start..*start + item.lines]
+                .iter()
+                .all(|track| !track.will_overlap(self, lifetime, item))
+        })
+    }
+
+    fn find_track(
+        &self,
+        item: &DanmakuItem,
+        mode: LayoutMode,
+        rng: &mut TrackRng,
+    ) -> Option<usize> {
+        if self.tracks.is_empty() {
+            return None;
+        }
+        let track = self.find_empty_track(item);
+        let policy = mode.overlap_policy();
+        track.or_else(|| {
+            let times: Vec<_> = self
+                .tracks
+                .iter()
+                .map(|track| track.latest_danmaku_item.as_ref().map(|item| item.time))
+                .collect();
+            overlap_window(policy?, self.index, rng, &times, &self.blocked, item.lines)
+        })
+    }
+
+    fn insert(&mut self, track: usize, item: DanmakuItem) {
+        self.index += 1;
+        let lifetime = self.track_lifetime(track);
+        let end = (track + item.lines).min(self.tracks.len());
+        for slot in &mut self.tracks[track..end] {
+            slot.insert(item.clone(), lifetime)
+        }
+    }
+}
+
+// Index of the track with the oldest latest danmaku, empty tracks come first
+fn least_recent(times: impl Iterator<Item = Option<DanmakuTime>>) -> usize {
+    times
+        .enumerate()
+        .min_by_key(|(_, time)| time.unwrap_or(DanmakuTime::MIN))
+        .map(|(index, _)| index)
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Debug)]
+pub struct DanmakuTrackState {
+    mode: LayoutMode,
+    rng: TrackRng,
+    screen_height: u32,
+    line_height: u32,
+    top: StaticDanmakuTrackState,
+    bottom: StaticDanmakuTrackState,
+    scroll: ScrollDanmakuTrackState,
+    timed_regions: Vec<TimedRegion>,
+}
+
+impl DanmakuTrackState {
+    pub fn new(
+        mode: LayoutMode,
+        screen_size: (u32, u32),
+        line_height: u32,
+        scroll_lifetime: Duration,
+        static_lifetime: Duration,
+        scroll_speed: ScrollSpeedModel,
+        scroll_gap: u32,
+    ) -> Self {
+        let total_tracks = (screen_size.1 / line_height) as usize;
+        let (scroll_tracks, static_tracks) = match mode {
+            LayoutMode::NoOverlap(percent) => {
+                let tracks = total_tracks * percent as usize / 100;
+                (tracks, tracks.min(total_tracks / 2))
+            }
+            LayoutMode::ShowAll | LayoutMode::ShowAllWith(_) => (total_tracks, total_tracks),
+        };
+        DanmakuTrackState {
+            mode,
+            rng: TrackRng(0),
+            screen_height: screen_size.1,
+            line_height,
+            top: StaticDanmakuTrackState::new(static_tracks, static_lifetime),
+            bottom: StaticDanmakuTrackState::new(static_tracks, static_lifetime),
+            scroll: ScrollDanmakuTrackState::new(
+                scroll_tracks,
+                screen_size.0,
+                scroll_lifetime,
+                scroll_speed,
+                scroll_gap,
+            ),
+            timed_regions: Vec::new(),
+        }
+    }
+
+    // Scroll tracks move at slightly different speeds, see track_speed_multiplier
+    pub fn with_scroll_speed_variation(mut self, variation: f32) -> Self {
+        self.scroll.speed_variation = variation;
+        self
+    }
+
+    pub fn with_static_limit(mut self, limit: Option<StaticLimit>) -> Self {
+        self.top.limit = limit;
+        self.bottom.limit = limit;
+        self
+    }
+
+    pub fn with_blocked_regions(mut self, regions: &[Rect]) -> Self {
+        self.set_blocked_regions(regions);
+        self
+    }
+
+    // Tracks overlapping the regions are left empty, danmaku already in them stay
+    pub fn set_blocked_regions(&mut self, regions: &[Rect]) {
+        let (screen_height, line_height) = (self.screen_height, self.line_height);
+        let top = |track| top_rows(line_height, track);
+        self.scroll.blocked = blocked_tracks(self.scroll.tracks.len(), regions, top);
+        self.top.blocked = blocked_tracks(self.top.tracks.len(), regions, top);
+        self.bottom.blocked = blocked_tracks(self.bottom.tracks.len(), regions, |track| {
+            bottom_rows(screen_height, line_height, track)
+        });
+    }
+
+    // Static danmaku shown while a region is aren't placed in the tracks it overlaps
+    pub fn set_timed_regions(&mut self, regions: &[TimedRegion]) {
+        self.timed_regions = regions.to_vec();
+    }
+
+    // Blocked static tracks for an item shown from the time for the lifetime
+    fn static_blocked(&self, r#type: DanmakuType, time: DanmakuTime) -> Vec<bool> {
+        let state = match r#type {
+            DanmakuType::Top => &self.top,
+            _ => &self.bottom,
+        };
+        let regions: Vec<_> = self
+            .timed_regions
+            .iter()
+            .filter(|region| region.overlaps_time(time, state.lifetime))
+            .map(|region| region.rect)
+            .collect();
+        if regions.is_empty() {
+            return state.blocked.clone();
+        }
+        let timed = blocked_tracks(state.tracks.len(), &regions, |track| match r#type {
+            DanmakuType::Top => top_rows(self.line_height, track),
+            _ => bottom_rows(self.screen_height, self.line_height, track),
+        });
+        state
+            .blocked
+            .iter()
+            .zip(timed)
+            .map(|(blocked, timed)| *blocked || timed)
+            .collect()
+    }
+
+    // Called before laying out each chunk, so the result doesn't depend on the earlier chunks
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = TrackRng(seed);
+    }
+
+    pub fn insert(&mut self, item: DanmakuItem) -> Option<DanmakuPosition> {
+        self.insert_with_mode(item, self.mode, false)
+    }
+
+    // Places the item even if it has to overlap others or exceeds the static limit, used for
+    // local echo
+    pub fn insert_priority(&mut self, item: DanmakuItem) -> Option<DanmakuPosition> {
+        self.insert_with_mode(item, LayoutMode::ShowAll, true)
+    }
+
+    fn insert_with_mode(
+        &mut self,
+        item: DanmakuItem,
+        mode: LayoutMode,
+        priority: bool,
+    ) -> Option<DanmakuPosition> {
+        match item.r#type {
+            DanmakuType::Scroll => {
+                self.scroll.clear_expired(item.time);
+                if let Some(track) = self.scroll.find_track(&item, mode, &mut self.rng) {
+                    self.scroll.insert(track, item);
+                    Some(DanmakuPosition::Scroll(track))
+                } else {
+                    None
+                }
+            }
+            DanmakuType::Top | DanmakuType::Bottom => {
+                let blocked = self.static_blocked(item.r#type, item.time);
+                let state = match item.r#type {
+                    DanmakuType::Top => &mut self.top,
+                    DanmakuType::Bottom => &mut self.bottom,
+                    _ => unreachable!(),
+                };
+                state.clear_expired(item.time);
+                if !state.within_limit(&item) && !priority {
+                    return None;
+                }
+                if let Some(track) = state.find_track(item.lines, mode, &mut self.rng, &blocked) {
+                    let r#type = item.r#type;
+                    let lines = state.insert(track, item);
+                    // Bottom tracks count upwards, the position is the track of the first line
+                    let result = match r#type {
+                        DanmakuType::Top => DanmakuPosition::Top(track),
+                        DanmakuType::Bottom => DanmakuPosition::Bottom(track + lines - 1),
+                        _ => unreachable!(),
+                    };
+                    Some(result)
+                } else {
+                    None
+                }
+            }
+            DanmakuType::Unknown => None,
+        }
+    }
+}
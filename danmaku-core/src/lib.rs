@@ -0,0 +1,9 @@
+// Data model, track layout and filters, without std so ports can bring their own renderer and
+// text shaper
+#![no_std]
+
+extern crate alloc;
+
+pub mod danmaku;
+pub mod filter;
+pub mod layout;
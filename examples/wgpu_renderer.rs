@@ -1,20 +1,19 @@
-use std::{
-    iter,
-    path::Path,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
-};
+use std::{iter, path::Path, sync::Arc, time::Duration};
 
-use cosmic_text::{Attrs, AttrsList, Family, FontSystem, ShapeBuffer, Weight};
 use danmaku_renderer::{
+    clock::PlaybackClock,
     danmaku::DanmakuTime,
-    layout::LayoutMode,
+    layout::{LayoutMode, ScrollSpeedModel},
+    pipeline::DanmakuPipelineBuilder,
     renderer::{
-        wgpu::{WgpuRenderCache, WgpuRenderer, WgpuWorkerBuffer, WgpuWorkerManager},
-        RendererParam,
+        winit::WinitOverlay, AnimationParam, BlendMode, ColorSpace, Orientation, RendererParam,
+        ShadowColor, TypeOpacity,
     },
     sources::bilibili::parse_xml_from_file,
-    worker::{DanmakuParam, WorkerBuffer, WorkerManager, WorkerState},
+    worker::{
+        DanmakuParam, LineHeight, ShadowKernel, TallDanmaku, WideDanmaku, WorkerStateBuilder,
+    },
+    Attrs, AttrsList, Family, Weight,
 };
 use fps_counter::FPSCounter;
 use futures::executor::block_on;
@@ -22,8 +21,8 @@ use log::info;
 use wgpu::{
     util::{backend_bits_from_env, initialize_adapter_from_env, power_preference_from_env},
     Backends, Device, DeviceDescriptor, Instance, InstanceDescriptor, PowerPreference, PresentMode,
-    Queue, RenderPass, RequestAdapterOptions, Surface, SurfaceConfiguration, SurfaceError,
-    TextureFormat, TextureUsages,
+    Queue, RequestAdapterOptions, Surface, SurfaceConfiguration, SurfaceError, TextureFormat,
+    TextureUsages,
 };
 use winit::{
     application::ApplicationHandler,
@@ -33,19 +32,31 @@ use winit::{
     window::{Window, WindowAttributes, WindowId},
 };
 
-fn create_param(screen_size: PhysicalSize<u32>) -> DanmakuParam {
+// The screen is taken from the window
+fn create_param() -> DanmakuParam {
     let attrs = Attrs::new();
     attrs.family(Family::SansSerif);
     attrs.weight(Weight::BOLD);
     DanmakuParam {
-        screen_size: (screen_size.width, screen_size.height),
-        lifetime: Duration::from_secs(8),
+        screen_size: (0, 0),
+        scroll_lifetime: Duration::from_secs(8),
+        static_lifetime: Duration::from_secs(5),
+        chunk_duration: Duration::from_secs(8),
         font_size: 28.0,
-        line_height: 32,
+        line_height: LineHeight::Fixed(32),
         font_attrs: AttrsList::new(attrs),
         layout_mode: LayoutMode::ShowAll,
+        scroll_speed: ScrollSpeedModel::ConstantDuration,
+        scroll_gap: 0,
+        scroll_speed_variation: 0.0,
+        wide_danmaku: WideDanmaku::Keep,
+        tall_danmaku: TallDanmaku::Overflow,
+        blocked_regions: Vec::new(),
+        static_limit: None,
         shadow_size: 3,
         shadow_weight: 1.5,
+        shadow_kernel: ShadowKernel::Outline,
+        scale_factor: 1.0,
     }
 }
 
@@ -114,86 +125,41 @@ impl<'a> AppSurface<'a> {
     }
 }
 
-struct DanmakuRenderer {
-    renderer: WgpuRenderer,
-    buffer: Arc<Mutex<WgpuWorkerBuffer>>,
-    worker: WgpuWorkerManager,
-    start_time: Instant,
-    param: DanmakuParam,
-}
-
-impl DanmakuRenderer {
-    fn new(surface: &AppSurface, param: DanmakuParam) -> Self {
-        let path = Path::new("test/1176840_history.xml");
-        let source = parse_xml_from_file(path).unwrap();
-        let font_system = FontSystem::new();
-        let shape_buffer = ShapeBuffer::default();
-        let cache = WgpuRenderCache::new(
-            surface.device.clone(),
-            surface.queue.clone(),
-            (256, 256),
-            param.clone(),
-        );
-        let renderer = WgpuRenderer::new(
-            &surface.config,
-            &surface.device,
-            param.clone(),
-            RendererParam { opacity: 1.0 },
-            &cache,
+fn create_overlay(window: &Window, surface: &AppSurface) -> WinitOverlay {
+    let path = Path::new("test/1176840_history.xml");
+    let source = parse_xml_from_file(path).unwrap();
+    let renderer_param = RendererParam {
+        opacity: 1.0,
+        color_space: ColorSpace::Auto,
+        premultiplied_alpha: false,
+        blend_mode: BlendMode::Normal,
+        orientation: Orientation::default(),
+        animation: AnimationParam::default(),
+        type_opacity: TypeOpacity::default(),
+        shadow_color: ShadowColor::default(),
+    };
+    let builder = DanmakuPipelineBuilder::new(Box::new(source), create_param(), renderer_param)
+        .state_builder(
+            WorkerStateBuilder::new()
+                .sans_serif_families(&["Noto Sans CJK SC", "Source Han Sans SC"]),
         );
-        let buffer = WorkerBuffer::new(cache);
-
-        let buffer = Arc::new(Mutex::new(buffer));
-        let state = WorkerState {
-            buffer: buffer.clone(),
-            font_system,
-            shape_buffer,
-            source: Box::new(source),
-        };
-        let mut worker = WorkerManager::new(param.clone(), state);
-        worker.request(None, 0).unwrap();
-
-        Self {
-            renderer,
-            buffer,
-            worker,
-            start_time: Instant::now(),
-            param,
-        }
-    }
-
-    fn update_param(&mut self, surface: &AppSurface, new_param: DanmakuParam) {
-        self.worker.change_param(new_param.clone()).unwrap();
-        self.renderer
-            .update_danmaku_param(&surface.device, &surface.queue, new_param.clone());
-        self.param = new_param;
-    }
-
-    fn render_buffer(&mut self, surface: &AppSurface) {
-        let timestamp = self.start_time.elapsed();
-        let timestamp = DanmakuTime::from_millis(timestamp.as_millis() as u32);
-        self.renderer.update(&surface.queue, timestamp);
-
-        let buffer = self.buffer.lock().unwrap();
-        let lifetime = self.param.lifetime.as_millis() as u32;
-        let index = timestamp.as_millis() / lifetime;
-        if buffer.should_request_worker(index) {
-            self.worker.request(None, index).unwrap();
-        }
-        self.renderer
-            .render_buffer(&surface.device, &surface.queue, &buffer);
-    }
-
-    fn render(&mut self, render_pass: &mut RenderPass) {
-        self.renderer.render(render_pass)
-    }
+    WinitOverlay::new(
+        window,
+        builder,
+        surface.device.clone(),
+        surface.queue.clone(),
+        &surface.config,
+    )
+    .unwrap()
 }
 
 struct State<'a> {
     surface: AppSurface<'a>,
     size: PhysicalSize<u32>,
     fps_counter: FPSCounter,
-    danmaku_renderer: DanmakuRenderer,
+    overlay: WinitOverlay,
+    // Stands in for the position callbacks of a video player
+    clock: PlaybackClock,
 
     window: Arc<Window>,
 }
@@ -203,16 +169,19 @@ impl<'a> State<'a> {
         let fps_counter = FPSCounter::new();
 
         let size = window.inner_size();
-        let param = create_param(size);
         let surface = AppSurface::new(window.clone()).await;
+        let overlay = create_overlay(&window, &surface);
 
-        let danmaku_renderer = DanmakuRenderer::new(&surface, param);
+        let mut clock = PlaybackClock::new();
+        clock.sync(DanmakuTime::from_millis(0));
+        clock.set_playing(true);
 
         State {
             surface,
             size,
             fps_counter,
-            danmaku_renderer,
+            overlay,
+            clock,
             window,
         }
     }
@@ -221,7 +190,10 @@ impl<'a> State<'a> {
         let fps = self.fps_counter.tick();
         self.window.set_title(&format!("FPS: {}", fps));
 
-        self.danmaku_renderer.render_buffer(&self.surface);
+        let now = self.clock.now();
+        let playback = self.overlay.pipeline().worker().playback();
+        playback.update(now, &self.clock);
+        self.overlay.tick(now).unwrap();
 
         let output = self.surface.surface.get_current_texture()?;
         let texture_view = output
@@ -253,7 +225,7 @@ impl<'a> State<'a> {
             occlusion_query_set: None,
             timestamp_writes: None,
         });
-        self.danmaku_renderer.render(&mut render_pass);
+        self.overlay.draw(&mut render_pass);
         drop(render_pass);
         self.surface.queue.submit(iter::once(encoder.finish()));
 
@@ -267,11 +239,9 @@ impl<'a> State<'a> {
 
     fn resize(&mut self, new_size: PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
-            let new_param = create_param(new_size);
-
             self.size = new_size;
             self.surface.resize(new_size);
-            self.danmaku_renderer.update_param(&self.surface, new_param);
+            self.overlay.resize(&self.window).unwrap();
         }
     }
 }
@@ -325,6 +295,11 @@ impl ApplicationHandler for App {
                     state.resize(physical_size);
                 }
             }
+            WindowEvent::ScaleFactorChanged { .. } => {
+                if let Some(state) = self.state.as_mut() {
+                    state.overlay.resize(&state.window).unwrap();
+                }
+            }
             _ => (),
         }
     }
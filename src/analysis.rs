@@ -0,0 +1,341 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    danmaku::{DanmakuColor, DanmakuTime, DanmakuType},
+    layout::{DanmakuItem, DanmakuTrackState},
+    sources::DanmakuSource,
+    worker::DanmakuParam,
+};
+
+pub struct DanmakuStatistics {
+    // Second of the first bucket in per_second
+    start_second: i64,
+    per_second: Vec<usize>,
+    types: HashMap<DanmakuType, usize>,
+    colors: HashMap<DanmakuColor, usize>,
+    keywords: HashMap<String, usize>,
+    total: usize,
+}
+
+// Runs of letters and digits, CJK text has no spaces so a whole run counts as one keyword
+fn keywords(content: &str) -> HashSet<String> {
+    content
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+impl DanmakuStatistics {
+    pub fn compute<S: DanmakuSource + ?Sized>(source: &mut S) -> Self {
+        let mut seconds: HashMap<i64, usize> = HashMap::new();
+        let mut types = HashMap::new();
+        let mut colors = HashMap::new();
+        let mut keyword_counts = HashMap::new();
+        let mut total = 0;
+        for item in source.get_all() {
+            total += 1;
+            *seconds.entry(item.time.seconds()).or_default() += 1;
+            *types.entry(item.r#type).or_default() += 1;
+            *colors.entry(item.color).or_default() += 1;
+            // Repeating a word in one danmaku doesn't make it more popular
+            for keyword in keywords(&item.content) {
+                *keyword_counts.entry(keyword).or_default() += 1;
+            }
+        }
+
+        let start_second = seconds.keys().min().copied().unwrap_or(0);
+        let end_second = seconds.keys().max().map_or(0, |second| second + 1);
+        let mut per_second = vec![0; (end_second - start_second) as usize];
+        for (second, count) in seconds {
+            per_second[(second - start_second) as usize] = count;
+        }
+
+        DanmakuStatistics {
+            start_second,
+            per_second,
+            types,
+            colors,
+            keywords: keyword_counts,
+            total,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn start_time(&self) -> DanmakuTime {
+        DanmakuTime::from_millis(self.start_second * 1000)
+    }
+
+    // Count of each second starting from start_time
+    pub fn per_second(&self) -> &[usize] {
+        &self.per_second
+    }
+
+    pub fn count_at(&self, time: DanmakuTime) -> usize {
+        usize::try_from(time.seconds() - self.start_second)
+            .ok()
+            .and_then(|index| self.per_second.get(index))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // Density of each bucket between 0.0 and 1.0, with the busiest bucket being 1.0. The buckets
+    // evenly cover 0..duration_seconds, pass the video length to line them up with the progress
+    // bar
+    pub fn heatmap(&self, buckets: usize, duration_seconds: i64) -> Vec<f32> {
+        let mut result = vec![0.0; buckets];
+        if buckets == 0 || duration_seconds <= 0 {
+            return result;
+        }
+        for (index, count) in self.per_second.iter().enumerate() {
+            let second = self.start_second + index as i64;
+            if !(0..duration_seconds).contains(&second) {
+                continue;
+            }
+            let bucket = (second as u128 * buckets as u128 / duration_seconds as u128) as usize;
+            result[bucket] += *count as f32;
+        }
+        let max = result.iter().copied().fold(0.0, f32::max);
+        if max > 0.0 {
+            result.iter_mut().for_each(|value| *value /= max);
+        }
+        result
+    }
+
+    pub fn type_count(&self, r#type: DanmakuType) -> usize {
+        self.types.get(&r#type).copied().unwrap_or(0)
+    }
+
+    // Most used colors first
+    pub fn top_colors(&self, limit: usize) -> Vec<(DanmakuColor, usize)> {
+        let mut colors: Vec<_> = self
+            .colors
+            .iter()
+            .map(|(color, count)| (*color, *count))
+            .collect();
+        colors.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.code().cmp(&b.0.code())));
+        colors.truncate(limit);
+        colors
+    }
+
+    // Keywords with the number of danmaku containing them, most used first
+    pub fn top_keywords(&self, limit: usize) -> Vec<(&str, usize)> {
+        let mut keywords: Vec<_> = self
+            .keywords
+            .iter()
+            .map(|(keyword, count)| (keyword.as_str(), *count))
+            .collect();
+        keywords.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        keywords.truncate(limit);
+        keywords
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DensitySample {
+    // Danmaku on the screen during the second
+    pub visible: usize,
+    // Danmaku of the second the layout had no room for
+    pub dropped: usize,
+}
+
+// Rough width of the content, full width characters take one em and the others half of it
+fn estimate_width(content: &str, font_size: f32) -> (u32, usize) {
+    let mut width: f32 = 0.0;
+    let mut lines = 0;
+    for line in content.trim_end_matches(['\r', '\n']).split('\n') {
+        let ems: f32 = line
+            .chars()
+            .filter(|c| !c.is_control())
+            .map(|c| if c as u32 >= 0x1100 { 1.0 } else { 0.5 })
+            .sum();
+        width = width.max(ems * font_size);
+        lines += 1;
+    }
+    (width.ceil() as u32, lines)
+}
+
+// Lays out the whole source apart from the chunk generation, to tell how crowded each second is
+// for a density bar along the seekbar. Widths are estimated instead of shaped, so the counts may
+// differ slightly from what is rendered. step can be called from idle callbacks, the seconds
+// before scanned_until are final right away.
+pub struct DensityScanner {
+    param: DanmakuParam,
+    // Time, type, estimated physical width and lines, sorted by time
+    items: Vec<(DanmakuTime, DanmakuType, u32, usize)>,
+    next: usize,
+    state: DanmakuTrackState,
+    chunk: Option<u32>,
+    start_second: i64,
+    samples: Vec<DensitySample>,
+}
+
+impl DensityScanner {
+    pub fn new<S: DanmakuSource + ?Sized>(source: &mut S, param: DanmakuParam) -> Self {
+        let font_size = param.font_size * param.scale_factor;
+        let mut items: Vec<_> = source
+            .get_all()
+            .map(|danmaku| {
+                let (width, lines) = estimate_width(&danmaku.content, font_size);
+                (danmaku.time, danmaku.r#type, width, lines)
+            })
+            .collect();
+        items.sort_by_key(|(time, _, _, _)| *time);
+        let state = DanmakuTrackState::new(
+            param.layout_mode,
+            param.screen_size,
+            param.physical_line_height(),
+            param.scroll_lifetime,
+            param.static_lifetime,
+            param.scroll_speed_model(),
+            param.physical_scroll_gap(),
+        )
+        .with_scroll_speed_variation(param.scroll_speed_variation)
+        .with_static_limit(param.static_limit)
+        .with_blocked_regions(&param.blocked_regions);
+        let start_second = items.first().map_or(0, |(time, _, _, _)| time.seconds());
+        DensityScanner {
+            param,
+            items,
+            next: 0,
+            state,
+            chunk: None,
+            start_second,
+            samples: Vec::new(),
+        }
+    }
+
+    // Lays out up to count danmaku, returns whether there are more
+    pub fn step(&mut self, count: usize) -> bool {
+        let end = (self.next + count).min(self.items.len());
+        for index in self.next..end {
+            let (time, r#type, width, lines) = self.items[index];
+            // The chunks reseed the layout, so the tracks are picked the same way
+            let chunk = self.param.chunk_index(time);
+            if self.chunk != Some(chunk) {
+                self.state.seed(chunk as u64);
+                self.chunk = Some(chunk);
+            }
+            let item = DanmakuItem::new(width, time, r#type).with_lines(lines);
+            match self.state.insert(item) {
+                Some(position) => {
+                    let lifetime = self.param.item_lifetime(position, width).as_millis() as i64;
+                    let last = time.as_millis() + lifetime.max(1) - 1;
+                    for second in time.seconds()..=last.div_euclid(1000) {
+                        self.sample_mut(second).visible += 1;
+                    }
+                }
+                None => self.sample_mut(time.seconds()).dropped += 1,
+            }
+        }
+        self.next = end;
+        !self.is_done()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next >= self.items.len()
+    }
+
+    // Samples of the seconds before this time won't change anymore
+    pub fn scanned_until(&self) -> DanmakuTime {
+        match self.items.get(self.next) {
+            Some((time, _, _, _)) => DanmakuTime::from_millis(time.seconds() * 1000),
+            None => DanmakuTime::from_millis(i64::MAX),
+        }
+    }
+
+    pub fn start_time(&self) -> DanmakuTime {
+        DanmakuTime::from_millis(self.start_second * 1000)
+    }
+
+    // Sample of each second starting from start_time
+    pub fn samples(&self) -> &[DensitySample] {
+        &self.samples
+    }
+
+    pub fn sample_at(&self, time: DanmakuTime) -> DensitySample {
+        usize::try_from(time.seconds() - self.start_second)
+            .ok()
+            .and_then(|index| self.samples.get(index))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn sample_mut(&mut self, second: i64) -> &mut DensitySample {
+        let index = (second - self.start_second) as usize;
+        if index >= self.samples.len() {
+            self.samples.resize(index + 1, DensitySample::default());
+        }
+        &mut self.samples[index]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        danmaku::{Danmaku, DanmakuTime, DanmakuType},
+        sources::VecDanmakuSource,
+        worker::test::test_param,
+    };
+
+    use super::{DanmakuStatistics, DensitySample, DensityScanner};
+
+    fn danmaku(millis: i64, r#type: DanmakuType, content: &str) -> Danmaku {
+        Danmaku::new(DanmakuTime::from_millis(millis), r#type, content)
+    }
+
+    #[test]
+    fn test_statistics() {
+        let mut source = VecDanmakuSource::new(vec![
+            danmaku(1200, DanmakuType::Scroll, "前方高能"),
+            danmaku(1800, DanmakuType::Top, "Hello hello world"),
+            danmaku(3500, DanmakuType::Scroll, "前方高能!!"),
+            danmaku(9000, DanmakuType::Bottom, "hello"),
+        ]);
+        let statistics = DanmakuStatistics::compute(&mut source);
+        assert_eq!(statistics.total(), 4);
+        assert_eq!(statistics.per_second(), &[2, 0, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(statistics.count_at(DanmakuTime::from_millis(3999)), 1);
+        assert_eq!(statistics.count_at(DanmakuTime::from_millis(-1000)), 0);
+        assert_eq!(statistics.type_count(DanmakuType::Scroll), 2);
+        assert_eq!(
+            statistics.top_keywords(2),
+            vec![("hello", 2), ("前方高能", 2)]
+        );
+        assert_eq!(statistics.top_colors(1)[0].1, 4);
+        assert_eq!(statistics.heatmap(2, 10), vec![1.0, 1.0 / 3.0]);
+    }
+
+    #[test]
+    fn test_density_scanner() {
+        // Two scroll tracks
+        let param = test_param();
+        let mut source = VecDanmakuSource::new(
+            (0..5)
+                .map(|_| danmaku(0, DanmakuType::Scroll, "前方高能"))
+                .chain([danmaku(20_000, DanmakuType::Top, "hello")])
+                .collect(),
+        );
+        let mut scanner = DensityScanner::new(&mut source, param);
+        assert!(scanner.step(2));
+        assert_eq!(scanner.scanned_until(), DanmakuTime::from_millis(0));
+        assert!(!scanner.step(10));
+        assert_eq!(
+            scanner.sample_at(DanmakuTime::from_millis(500)),
+            DensitySample {
+                visible: 2,
+                dropped: 3
+            }
+        );
+        assert_eq!(scanner.sample_at(DanmakuTime::from_millis(7999)).visible, 2);
+        assert_eq!(scanner.sample_at(DanmakuTime::from_millis(8000)).visible, 0);
+        assert_eq!(
+            scanner.sample_at(DanmakuTime::from_millis(24_000)).visible,
+            1
+        );
+        assert_eq!(scanner.samples().len(), 25);
+    }
+}
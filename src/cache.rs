@@ -0,0 +1,455 @@
+// Replay files keep the parsed danmaku of a video together with the positions the layout gave
+// them, so a video watched again doesn't need the source to be fetched and parsed, nor the tracks
+// to be allocated again, see DanmakuTimeChunkProvider::with_cached_layout. The danmaku are still
+// shaped, as the renderers need their glyphs. There is a layout for every param the video was
+// watched with.
+use std::{fs, io, path::Path};
+
+use crate::{
+    danmaku::{
+        Danmaku, DanmakuAnimation, DanmakuColor, DanmakuKeyframe, DanmakuSize, DanmakuTime,
+        DanmakuType,
+    },
+    layout::DanmakuPosition,
+    manager::{ChunkError, DanmakuTimeChunkProvider},
+    renderer::disk_cache::{fnv1a, Reader, FNV_OFFSET},
+    shaper::TextShaper,
+    sources::VecDanmakuSource,
+    worker::DanmakuParam,
+};
+
+const MAGIC: &[u8; 4] = b"DMKR";
+const VERSION: u32 = 4;
+
+// Identifies the params giving the same layout, which are all but the shadow. Unlike
+// DanmakuParam::layout_changed, the blocked regions count. The debug output of a param may change
+// with the version of the crate or of cosmic-text, which only makes the stored layout miss.
+pub fn layout_key(param: &DanmakuParam) -> u64 {
+    let param = DanmakuParam {
+        shadow_size: 0,
+        shadow_weight: 0.0,
+        shadow_kernel: Default::default(),
+        ..param.clone()
+    };
+    fnv1a(FNV_OFFSET, format!("{:?}", param).as_bytes())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CachedItem {
+    pub time: DanmakuTime,
+    pub position: DanmakuPosition,
+    // Physical width of the widest line
+    pub width: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedLayout {
+    pub key: u64,
+    // The danmaku placed by the layout in time order, the dropped ones are left out
+    pub items: Vec<CachedItem>,
+}
+
+impl CachedLayout {
+    // Position of the next recorded item of the time and width from the cursor on, None for the
+    // danmaku the layout dropped. Danmaku of the same time are matched in order.
+    pub(crate) fn position(
+        &self,
+        cursor: &mut usize,
+        time: DanmakuTime,
+        width: u32,
+    ) -> Option<DanmakuPosition> {
+        let start = (*cursor).max(self.items.partition_point(|item| item.time < time));
+        let offset = self.items[start..]
+            .iter()
+            .take_while(|item| item.time == time)
+            .position(|item| item.width == width)?;
+        *cursor = start + offset + 1;
+        Some(self.items[start + offset].position)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReplayFile {
+    danmaku: Vec<Danmaku>,
+    layouts: Vec<CachedLayout>,
+}
+
+impl ReplayFile {
+    pub fn new(mut danmaku: Vec<Danmaku>) -> Self {
+        danmaku.sort_by_key(Danmaku::order_key);
+        ReplayFile {
+            danmaku,
+            layouts: Vec::new(),
+        }
+    }
+
+    // Files written by other versions of the format are reported as invalid data
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        decode(&data).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Invalid danmaku replay file")
+        })
+    }
+
+    // Replaces the file at once, a crash while writing leaves the previous one
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, encode(self))?;
+        fs::rename(&temp_path, path)
+    }
+
+    pub fn danmaku(&self) -> &[Danmaku] {
+        &self.danmaku
+    }
+
+    pub fn into_source(self) -> VecDanmakuSource {
+        VecDanmakuSource::new(self.danmaku)
+    }
+
+    // For WorkerStateBuilder::cached_layouts, which picks the one of the param
+    pub fn layouts(&self) -> &[CachedLayout] {
+        &self.layouts
+    }
+
+    pub fn layout(&self, param: &DanmakuParam) -> Option<&CachedLayout> {
+        let key = layout_key(param);
+        self.layouts.iter().find(|layout| layout.key == key)
+    }
+
+    // Replaces the layout of the same param
+    pub fn insert_layout(&mut self, layout: CachedLayout) {
+        self.layouts.retain(|existing| existing.key != layout.key);
+        self.layouts.push(layout);
+    }
+
+    // Lays out all danmaku with the param, unless the file already has the layout
+    pub fn record_layout(
+        &mut self,
+        shaper: &mut dyn TextShaper,
+        param: &DanmakuParam,
+    ) -> Result<&CachedLayout, ChunkError> {
+        let key = layout_key(param);
+        if let Some(index) = self.layouts.iter().position(|layout| layout.key == key) {
+            return Ok(&self.layouts[index]);
+        }
+        let source = VecDanmakuSource::new(self.danmaku.clone());
+        let mut provider = DanmakuTimeChunkProvider::new(param.clone(), Box::new(source));
+        let mut items = Vec::new();
+        for index in 0..=provider.last_index() {
+            let chunk = provider.get_chunk(shaper, None, index)?;
+            items.extend(chunk.items.iter().map(|item| CachedItem {
+                time: item.item.time,
+                position: item.position,
+                width: item.item.width(),
+            }));
+        }
+        self.layouts.push(CachedLayout { key, items });
+        Ok(self.layouts.last().unwrap())
+    }
+}
+
+fn push_color(data: &mut Vec<u8>, color: Option<DanmakuColor>) {
+    match color {
+        Some(color) => {
+            data.push(1);
+            data.extend_from_slice(&color.code().to_le_bytes());
+        }
+        None => data.push(0),
+    }
+}
+
+fn encode_danmaku(data: &mut Vec<u8>, danmaku: &Danmaku) {
+    data.extend_from_slice(&danmaku.time.as_millis().to_le_bytes());
+    data.push(match danmaku.r#type {
+        DanmakuType::Scroll => 0,
+        DanmakuType::Top => 1,
+        DanmakuType::Bottom => 2,
+        DanmakuType::Unknown => 3,
+    });
+    data.push(match danmaku.size {
+        DanmakuSize::Small => 0,
+        DanmakuSize::Regular => 1,
+        DanmakuSize::Large => 2,
+    });
+    data.extend_from_slice(&danmaku.color.code().to_le_bytes());
+    data.extend_from_slice(&(danmaku.content.len() as u32).to_le_bytes());
+    data.extend_from_slice(danmaku.content.as_bytes());
+    data.push(danmaku.bordered as u8);
+    push_color(data, danmaku.background);
+    match &danmaku.animation {
+        Some(animation) => {
+            data.push(1);
+            data.push(animation.looped() as u8);
+            data.push(animation.keyframes().len() as u8);
+            for keyframe in animation.keyframes() {
+                data.extend_from_slice(&keyframe.time.to_le_bytes());
+                data.extend_from_slice(&keyframe.scale.to_le_bytes());
+                data.extend_from_slice(&keyframe.alpha.to_le_bytes());
+            }
+        }
+        None => data.push(0),
+    }
+    push_color(data, danmaku.gradient);
+    write_id(data, danmaku.id);
+    write_id(data, danmaku.group_id);
+}
+
+fn write_id(data: &mut Vec<u8>, id: Option<u64>) {
+    match id {
+        Some(id) => {
+            data.push(1);
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+        None => data.push(0),
+    }
+}
+
+fn encode_item(data: &mut Vec<u8>, item: &CachedItem) {
+    data.extend_from_slice(&item.time.as_millis().to_le_bytes());
+    let (kind, track) = match item.position {
+        DanmakuPosition::Scroll(track) => (0, track),
+        DanmakuPosition::Top(track) => (1, track),
+        DanmakuPosition::Bottom(track) => (2, track),
+    };
+    data.push(kind);
+    data.extend_from_slice(&(track as u32).to_le_bytes());
+    data.extend_from_slice(&item.width.to_le_bytes());
+}
+
+fn encode(file: &ReplayFile) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&(file.danmaku.len() as u32).to_le_bytes());
+    for danmaku in &file.danmaku {
+        encode_danmaku(&mut data, danmaku);
+    }
+    data.extend_from_slice(&(file.layouts.len() as u32).to_le_bytes());
+    for layout in &file.layouts {
+        data.extend_from_slice(&layout.key.to_le_bytes());
+        data.extend_from_slice(&(layout.items.len() as u32).to_le_bytes());
+        for item in &layout.items {
+            encode_item(&mut data, item);
+        }
+    }
+    data
+}
+
+fn read_color(reader: &mut Reader) -> Option<Option<DanmakuColor>> {
+    match reader.u8()? {
+        0 => Some(None),
+        1 => Some(Some(DanmakuColor::from_code_cast(reader.u32()?))),
+        _ => None,
+    }
+}
+
+fn read_id(reader: &mut Reader) -> Option<Option<u64>> {
+    match reader.u8()? {
+        0 => Some(None),
+        _ => Some(Some(reader.u64()?)),
+    }
+}
+
+fn decode_danmaku(reader: &mut Reader) -> Option<Danmaku> {
+    let time = DanmakuTime::from_millis(reader.i64()?);
+    let r#type = match reader.u8()? {
+        0 => DanmakuType::Scroll,
+        1 => DanmakuType::Top,
+        2 => DanmakuType::Bottom,
+        3 => DanmakuType::Unknown,
+        _ => return None,
+    };
+    let size = match reader.u8()? {
+        0 => DanmakuSize::Small,
+        1 => DanmakuSize::Regular,
+        2 => DanmakuSize::Large,
+        _ => return None,
+    };
+    let color = DanmakuColor::from_code_cast(reader.u32()?);
+    let len = reader.u32()? as usize;
+    let content = String::from_utf8(reader.bytes(len)?.to_vec()).ok()?;
+    let bordered = reader.u8()? != 0;
+    let background = read_color(reader)?;
+    let animation = match reader.u8()? {
+        0 => None,
+        _ => {
+            let looped = reader.u8()? != 0;
+            let count = reader.u8()?;
+            let mut keyframes = Vec::new();
+            for _ in 0..count {
+                keyframes.push(DanmakuKeyframe {
+                    time: reader.u32()?,
+                    scale: reader.f32()?,
+                    alpha: reader.f32()?,
+                });
+            }
+            Some(DanmakuAnimation::new(&keyframes, looped)?)
+        }
+    };
+    let gradient = read_color(reader)?;
+    let id = read_id(reader)?;
+    let group_id = read_id(reader)?;
+    Some(Danmaku {
+        time,
+        r#type,
+        size,
+        color,
+        content,
+        bordered,
+        background,
+        animation,
+        gradient,
+        id,
+        group_id,
+    })
+}
+
+fn decode_item(reader: &mut Reader) -> Option<CachedItem> {
+    let time = DanmakuTime::from_millis(reader.i64()?);
+    let kind = reader.u8()?;
+    let track = reader.u32()? as usize;
+    let position = match kind {
+        0 => DanmakuPosition::Scroll(track),
+        1 => DanmakuPosition::Top(track),
+        2 => DanmakuPosition::Bottom(track),
+        _ => return None,
+    };
+    Some(CachedItem {
+        time,
+        position,
+        width: reader.u32()?,
+    })
+}
+
+fn decode(data: &[u8]) -> Option<ReplayFile> {
+    let mut reader = Reader::new(data);
+    if reader.bytes(MAGIC.len())? != MAGIC || reader.u32()? != VERSION {
+        return None;
+    }
+    let count = reader.u32()?;
+    let mut danmaku = Vec::new();
+    for _ in 0..count {
+        danmaku.push(decode_danmaku(&mut reader)?);
+    }
+    let count = reader.u32()?;
+    let mut layouts = Vec::new();
+    for _ in 0..count {
+        let key = reader.u64()?;
+        let len = reader.u32()?;
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(decode_item(&mut reader)?);
+        }
+        layouts.push(CachedLayout { key, items });
+    }
+    if !reader.is_empty() {
+        return None;
+    }
+    Some(ReplayFile { danmaku, layouts })
+}
+
+#[cfg(test)]
+mod test {
+    use cosmic_text::FontSystem;
+
+    use crate::{
+        danmaku::{Danmaku, DanmakuAnimation, DanmakuColor, DanmakuTime, DanmakuType},
+        layout::{DanmakuPosition, LayoutMode, Rect},
+        manager::DanmakuTimeChunkProvider,
+        shaper::CosmicTextShaper,
+        sources::VecDanmakuSource,
+        worker::{test::test_param, DanmakuParam},
+    };
+
+    use super::{decode, encode, layout_key, ReplayFile};
+
+    fn test_danmaku(millis: i64, r#type: DanmakuType, content: &str) -> Danmaku {
+        Danmaku {
+            color: DanmakuColor::from_rgb(255, 255, 255),
+            ..Danmaku::new(DanmakuTime::from_millis(millis), r#type, content)
+        }
+    }
+
+    #[test]
+    fn test_replay_round_trip() {
+        let mut styled = test_danmaku(500, DanmakuType::Top, "弹幕");
+        styled.background = Some(DanmakuColor::from_rgb(0, 0, 0));
+        styled.animation = Some(DanmakuAnimation::pulse(1000, 1.5));
+        styled.gradient = Some(DanmakuColor::from_rgb(255, 0, 0));
+        styled.id = Some(42);
+        styled.group_id = Some(7);
+        let mut file = ReplayFile::new(vec![
+            test_danmaku(9000, DanmakuType::Scroll, "later"),
+            test_danmaku(0, DanmakuType::Scroll, "first"),
+            styled,
+        ]);
+        let param = DanmakuParam {
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let layout = file.record_layout(&mut shaper, &param).unwrap().clone();
+        assert_eq!(layout.items.len(), 3);
+        assert!(layout.items.iter().all(|item| item.width > 0));
+
+        let data = encode(&file);
+        let decoded = decode(&data).unwrap();
+        assert_eq!(
+            format!("{:?}", decoded.danmaku()),
+            format!("{:?}", file.danmaku())
+        );
+        assert_eq!(decoded.layout(&param), Some(&layout));
+
+        // The shadow is drawn over the layout, other params need their own one
+        let shadowed = DanmakuParam {
+            shadow_size: 2,
+            ..param.clone()
+        };
+        assert_eq!(layout_key(&shadowed), layout_key(&param));
+        let larger = DanmakuParam {
+            font_size: 32.0,
+            ..param
+        };
+        assert!(decoded.layout(&larger).is_none());
+
+        assert!(decode(&data[..data.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_cached_layout() {
+        let danmaku = (0..20)
+            .map(|index| test_danmaku(index * 200, DanmakuType::Scroll, "cached"))
+            .collect::<Vec<_>>();
+        let mut file = ReplayFile::new(danmaku.clone());
+        let param = DanmakuParam {
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let mut layout = file.record_layout(&mut shaper, &param).unwrap().clone();
+        assert_eq!(layout.items.len(), 20);
+        // Stands out from any allocated track
+        for item in &mut layout.items {
+            item.position = DanmakuPosition::Scroll(7);
+        }
+
+        let source = VecDanmakuSource::new(danmaku);
+        let mut provider = DanmakuTimeChunkProvider::new(param, Box::new(source))
+            .with_cached_layout(Some(layout.clone()));
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+        assert_eq!(chunk.items.len(), layout.items.len());
+        assert!(chunk
+            .items
+            .iter()
+            .all(|item| item.position == DanmakuPosition::Scroll(7)));
+
+        // Changes the recording doesn't know of allocate the tracks again
+        provider.set_blocked_regions(vec![Rect::new(0, 0, 1, 1)], 0);
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+        assert!(chunk
+            .items
+            .iter()
+            .any(|item| item.position != DanmakuPosition::Scroll(7)));
+    }
+}
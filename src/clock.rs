@@ -0,0 +1,263 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::danmaku::DanmakuTime;
+
+// Turns the occasional, jittery positions reported by a player into a smooth time for every
+// frame. Small errors are corrected by running slightly faster or slower, large ones (seeking)
+// are applied at once.
+#[derive(Clone, Debug)]
+pub struct PlaybackClock {
+    // Media time in milliseconds at the instant
+    anchor: Option<(Instant, f64)>,
+    rate: f64,
+    playing: bool,
+    // Error of the estimate still to be absorbed, and how fast to do it relative to the rate
+    pending_error: f64,
+    slew: f64,
+    // Output never goes backwards, except after a jump
+    last_output: f64,
+    jump_threshold: Duration,
+    correction_window: Duration,
+    max_correction: f64,
+}
+
+impl Default for PlaybackClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlaybackClock {
+    pub fn new() -> Self {
+        PlaybackClock {
+            anchor: None,
+            rate: 1.0,
+            playing: false,
+            pending_error: 0.0,
+            slew: 0.0,
+            last_output: f64::MIN,
+            jump_threshold: Duration::from_millis(500),
+            correction_window: Duration::from_secs(1),
+            max_correction: 0.1,
+        }
+    }
+
+    // Errors larger than this are treated as a seek
+    pub fn with_jump_threshold(mut self, threshold: Duration) -> Self {
+        self.jump_threshold = threshold;
+        self
+    }
+
+    // Smaller errors are spread over this duration, running at most max_correction (e.g. 0.1 for
+    // 10%) faster or slower
+    pub fn with_correction(mut self, window: Duration, max_correction: f64) -> Self {
+        self.correction_window = window;
+        self.max_correction = max_correction;
+        self
+    }
+
+    pub fn sync(&mut self, media_time: DanmakuTime) {
+        self.sync_at(media_time, Instant::now())
+    }
+
+    // An authoritative position from the player, observed at the instant
+    pub fn sync_at(&mut self, media_time: DanmakuTime, at: Instant) {
+        let reported = media_time.as_millis() as f64;
+        let estimate = match self.anchor {
+            Some(_) => self.estimate(at),
+            None => {
+                self.jump(at, reported);
+                return;
+            }
+        };
+        let error = reported - estimate;
+        if error.abs() > self.jump_threshold.as_millis() as f64 || !self.playing {
+            self.jump(at, reported);
+            return;
+        }
+        // Continue from the current estimate, so the output stays continuous
+        let window = self.correction_window.as_millis().max(1) as f64;
+        self.anchor = Some((at, estimate));
+        self.pending_error = error;
+        self.slew = (error.abs() / window).min(self.max_correction);
+    }
+
+    pub fn set_playing(&mut self, playing: bool) {
+        self.set_playing_at(playing, Instant::now())
+    }
+
+    pub fn set_playing_at(&mut self, playing: bool, at: Instant) {
+        if playing == self.playing {
+            return;
+        }
+        if self.anchor.is_some() {
+            self.anchor = Some((at, self.estimate(at)));
+        }
+        self.pending_error = 0.0;
+        self.playing = playing;
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.set_rate_at(rate, Instant::now())
+    }
+
+    pub fn set_rate_at(&mut self, rate: f64, at: Instant) {
+        if self.anchor.is_some() {
+            self.anchor = Some((at, self.estimate(at)));
+        }
+        self.pending_error = 0.0;
+        self.rate = rate;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn now(&mut self) -> DanmakuTime {
+        self.time_at(Instant::now())
+    }
+
+    // Time to render the frame presented at the instant
+    pub fn time_at(&mut self, at: Instant) -> DanmakuTime {
+        let time = match self.anchor {
+            Some(_) => self.estimate(at).max(self.last_output),
+            None => 0.0,
+        };
+        self.last_output = time;
+        DanmakuTime::from_millis(time.round() as i64)
+    }
+
+    fn jump(&mut self, at: Instant, media_millis: f64) {
+        self.anchor = Some((at, media_millis));
+        self.pending_error = 0.0;
+        self.last_output = f64::MIN;
+    }
+
+    fn estimate(&self, at: Instant) -> f64 {
+        let (anchor_instant, anchor_millis) = match self.anchor {
+            Some(anchor) => anchor,
+            None => return 0.0,
+        };
+        if !self.playing {
+            return anchor_millis;
+        }
+        // Instants before the anchor happen when the frame time is predicted
+        let elapsed = match at.checked_duration_since(anchor_instant) {
+            Some(elapsed) => elapsed.as_secs_f64() * 1000.0,
+            None => -(anchor_instant.duration_since(at).as_secs_f64() * 1000.0),
+        };
+        let advance = elapsed * self.rate;
+        let correction =
+            (elapsed.max(0.0) * self.rate.abs() * self.slew).min(self.pending_error.abs());
+        anchor_millis + advance + correction.copysign(self.pending_error)
+    }
+}
+
+// Playback state shared by the host and the worker thread, clones refer to the same state. The
+// host stores the time of each frame and the state of the player, the worker lays out the chunks
+// ahead in the direction of the playback while it has nothing else to do.
+#[derive(Clone, Debug)]
+pub struct SharedPlayback(Arc<PlaybackState>);
+
+#[derive(Debug)]
+struct PlaybackState {
+    // In milliseconds
+    time: AtomicI64,
+    // Bits of the f64 rate, negative when playing backwards
+    speed: AtomicU64,
+    paused: AtomicBool,
+}
+
+impl Default for SharedPlayback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SharedPlayback {
+    pub fn new() -> Self {
+        SharedPlayback(Arc::new(PlaybackState {
+            time: AtomicI64::new(0),
+            speed: AtomicU64::new(1.0f64.to_bits()),
+            paused: AtomicBool::new(false),
+        }))
+    }
+
+    pub fn time(&self) -> DanmakuTime {
+        DanmakuTime::from_millis(self.0.time.load(Ordering::Relaxed))
+    }
+
+    pub fn set_time(&self, time: DanmakuTime) {
+        self.0.time.store(time.as_millis(), Ordering::Relaxed);
+    }
+
+    pub fn speed(&self) -> f64 {
+        f64::from_bits(self.0.speed.load(Ordering::Relaxed))
+    }
+
+    pub fn set_speed(&self, speed: f64) {
+        self.0.speed.store(speed.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.0.paused.store(paused, Ordering::Relaxed);
+    }
+
+    // Stores the time of the frame and the state of the clock
+    pub fn update(&self, time: DanmakuTime, clock: &PlaybackClock) {
+        self.set_time(time);
+        self.set_speed(clock.rate());
+        self.set_paused(!clock.is_playing());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::danmaku::DanmakuTime;
+
+    use super::PlaybackClock;
+
+    #[test]
+    fn test_playback_clock() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut clock = PlaybackClock::new();
+        clock.sync_at(DanmakuTime::from_millis(10_000), at(0));
+        clock.set_playing_at(true, at(0));
+        assert_eq!(clock.time_at(at(100)).as_millis(), 10_100);
+
+        // A late report slows the clock down instead of going backwards
+        clock.sync_at(DanmakuTime::from_millis(10_150), at(250));
+        let mut last = clock.time_at(at(250)).as_millis();
+        assert_eq!(last, 10_250);
+        for millis in (266..=2_000).step_by(16) {
+            let time = clock.time_at(at(millis)).as_millis();
+            assert!(time >= last);
+            last = time;
+        }
+        assert!(last < 12_000);
+
+        // Seeking is applied at once
+        clock.sync_at(DanmakuTime::from_millis(60_000), at(2_000));
+        assert_eq!(clock.time_at(at(2_000)).as_millis(), 60_000);
+
+        clock.set_playing_at(false, at(2_500));
+        assert_eq!(clock.time_at(at(5_000)).as_millis(), 60_500);
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Formatter},
+    ops::Range,
+    sync::Arc,
+};
+
+pub struct EmoteImage {
+    width: u32,
+    height: u32,
+    // RGBA8, sRGB encoded, straight alpha
+    data: Vec<u8>,
+}
+
+impl EmoteImage {
+    pub fn new(width: u32, height: u32, data: Vec<u8>) -> Self {
+        assert!(width > 0 && height > 0);
+        assert_eq!(data.len(), (width * height * 4) as usize);
+        EmoteImage {
+            width,
+            height,
+            data,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Debug for EmoteImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "EmoteImage({}x{})", self.width, self.height)
+    }
+}
+
+// Maps a shortcode (the text between the brackets of "[doge]") to an image
+pub trait EmoteProvider: Send + Sync {
+    fn get(&self, shortcode: &str) -> Option<Arc<EmoteImage>>;
+}
+
+impl EmoteProvider for HashMap<String, Arc<EmoteImage>> {
+    fn get(&self, shortcode: &str) -> Option<Arc<EmoteImage>> {
+        HashMap::get(self, shortcode).cloned()
+    }
+}
+
+// Byte ranges of the known shortcodes in the content, including the brackets
+pub(crate) fn find_emotes(
+    content: &str,
+    provider: &dyn EmoteProvider,
+) -> Vec<(Range<usize>, String, Arc<EmoteImage>)> {
+    let mut emotes = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = content[offset..].find('[') {
+        let start = offset + start;
+        let end = match content[start + 1..].find(']') {
+            Some(end) => start + 1 + end,
+            None => break,
+        };
+        let shortcode = &content[start + 1..end];
+        // Nested bracket, retry from the inner one
+        if let Some(inner) = shortcode.rfind('[') {
+            offset = start + 1 + inner;
+            continue;
+        }
+        if let Some(image) = provider.get(shortcode) {
+            emotes.push((start..end + 1, shortcode.to_string(), image));
+        }
+        offset = end + 1;
+    }
+    emotes
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::{find_emotes, EmoteImage};
+
+    #[test]
+    fn test_find_emotes() {
+        let mut provider = HashMap::new();
+        let image = Arc::new(EmoteImage::new(1, 1, vec![0; 4]));
+        provider.insert("doge".to_string(), image.clone());
+        provider.insert("笑哭".to_string(), image);
+
+        let content = "a[doge][unknown][[笑哭]b[doge";
+        let emotes = find_emotes(content, &provider);
+        let ranges: Vec<_> = emotes
+            .iter()
+            .map(|(range, shortcode, _)| (&content[range.clone()], shortcode.as_str()))
+            .collect();
+        assert_eq!(ranges, vec![("[doge]", "doge"), ("[笑哭]", "笑哭")]);
+    }
+}
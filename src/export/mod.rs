@@ -0,0 +1,2 @@
+#[cfg(feature = "export-svg")]
+pub mod svg;
@@ -0,0 +1,216 @@
+// Writes the danmaku on the screen at a time as an SVG document, for documentation, debugging
+// and stills at any resolution. Glyphs are paths from the font outlines, glyphs without outlines
+// (bitmap emoji) and emotes are left out. Shadows and the orientation aren't applied.
+use std::fmt::Write;
+
+use cosmic_text::Command;
+
+use crate::{
+    danmaku::{DanmakuColor, DanmakuTime},
+    manager::DanmakuTimeChunk,
+    renderer::RendererParam,
+    shaper::TextShaper,
+    worker::DanmakuParam,
+};
+
+fn hex(color: DanmakuColor) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r(), color.g(), color.b())
+}
+
+// Outline points are relative to the glyph origin with y pointing up
+fn write_outline(path: &mut String, commands: &[Command], x: f32, y: f32) {
+    for command in commands {
+        match command {
+            Command::MoveTo(p) => write!(path, "M{} {}", x + p.x, y - p.y),
+            Command::LineTo(p) => write!(path, "L{} {}", x + p.x, y - p.y),
+            Command::CurveTo(a, b, p) => write!(
+                path,
+                "C{} {} {} {} {} {}",
+                x + a.x,
+                y - a.y,
+                x + b.x,
+                y - b.y,
+                x + p.x,
+                y - p.y
+            ),
+            Command::QuadTo(a, p) => {
+                write!(path, "Q{} {} {} {}", x + a.x, y - a.y, x + p.x, y - p.y)
+            }
+            Command::Close => write!(path, "Z"),
+        }
+        .unwrap();
+    }
+}
+
+pub fn export_svg<'a>(
+    chunks: impl IntoIterator<Item = &'a DanmakuTimeChunk>,
+    param: &DanmakuParam,
+    renderer_param: &RendererParam,
+    shaper: &mut dyn TextShaper,
+    now: DanmakuTime,
+) -> String {
+    let (width, height) = param.screen_size;
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    ).unwrap();
+    let mut gradients = 0;
+    for chunk in chunks {
+        for item in &chunk.items {
+            let easing = renderer_param.animation.scroll_easing;
+            let Some((x, y)) = item.eased_position_at(now, param, easing) else {
+                continue;
+            };
+            let danmaku = &item.item;
+            let (scale, alpha) = danmaku.animation_at(now);
+            if scale <= 0.0 || alpha <= 0.0 {
+                continue;
+            }
+            let lifetime = param.item_lifetime(item.position, danmaku.width());
+            let opacity = renderer_param.opacity
+                * renderer_param.animation.fade(now - danmaku.time, lifetime)
+                * danmaku.opacity
+                * renderer_param.type_opacity.get(item.position)
+                * alpha;
+
+            // The same transformations as the cairo renderer, from the top of the line
+            let descent = danmaku.max_descent();
+            let (bg_x, bg_y, bg_width, bg_height) = danmaku.background_rect();
+            let mut transform = format!("translate({} {})", x, y - descent);
+            if scale != 1.0 {
+                let center_x = bg_x as f32 + bg_width as f32 / 2.0;
+                let center_y = bg_y as f32 + bg_height as f32 / 2.0 + descent;
+                write!(
+                    transform,
+                    " translate({center_x} {center_y}) scale({scale}) translate({} {})",
+                    -center_x, -center_y
+                )
+                .unwrap();
+            }
+            writeln!(svg, r#"<g transform="{transform}" opacity="{opacity}">"#).unwrap();
+
+            if let Some(background) = danmaku.background {
+                writeln!(
+                    svg,
+                    r#"<rect x="{bg_x}" y="{}" width="{bg_width}" height="{bg_height}" fill="{}"/>"#,
+                    bg_y as f32 + descent,
+                    hex(background)
+                ).unwrap();
+            }
+            if danmaku.bordered {
+                let border = danmaku.border_width() as f32;
+                let top = bg_y as f32 + descent;
+                writeln!(
+                    svg,
+                    r#"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="{}" stroke-width="{border}"/>"#,
+                    -border * 1.5,
+                    top + border / 2.0,
+                    danmaku.width() as f32 + border * 3.0,
+                    bg_height as f32 - border,
+                    hex(DanmakuColor::BORDER)
+                ).unwrap();
+            }
+
+            let mut path = String::new();
+            for glyph in &danmaku.physical_glyphs {
+                if let Some(commands) = shaper.outline(glyph.cache_key) {
+                    write_outline(&mut path, &commands, glyph.x as f32, glyph.y as f32);
+                }
+            }
+            if path.is_empty() {
+                writeln!(svg, "</g>").unwrap();
+                continue;
+            }
+            let fill = match danmaku.gradient {
+                // Across the whole line
+                Some(gradient) => {
+                    gradients += 1;
+                    writeln!(
+                        svg,
+                        r#"<linearGradient id="gradient{gradients}" gradientUnits="userSpaceOnUse" x1="0" y1="0" x2="{}" y2="0"><stop offset="0" stop-color="{}"/><stop offset="1" stop-color="{}"/></linearGradient>"#,
+                        danmaku.width(),
+                        hex(danmaku.color),
+                        hex(gradient)
+                    ).unwrap();
+                    format!("url(#gradient{gradients})")
+                }
+                None => hex(danmaku.color),
+            };
+            writeln!(svg, r#"<path d="{path}" fill="{fill}"/>"#).unwrap();
+            writeln!(svg, "</g>").unwrap();
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod test {
+    use cosmic_text::FontSystem;
+
+    use crate::{
+        danmaku::{Danmaku, DanmakuColor, DanmakuTime, DanmakuType},
+        layout::LayoutMode,
+        manager::DanmakuTimeChunkProvider,
+        renderer::RendererParam,
+        shaper::CosmicTextShaper,
+        sources::VecDanmakuSource,
+        worker::{test::test_param, DanmakuParam},
+    };
+
+    use super::export_svg;
+
+    #[test]
+    fn test_export_svg() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let param = DanmakuParam {
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let danmaku = |time, r#type, gradient| Danmaku {
+            color: DanmakuColor::from_code(0xFF0000),
+            bordered: true,
+            background: Some(DanmakuColor::from_code(0x000000)),
+            gradient,
+            ..Danmaku::new(DanmakuTime::from_millis(time), r#type, "svg")
+        };
+        let source = VecDanmakuSource::new(vec![
+            danmaku(1000, DanmakuType::Top, None),
+            danmaku(
+                2000,
+                DanmakuType::Scroll,
+                Some(DanmakuColor::from_code(0x0000FF)),
+            ),
+            danmaku(7000, DanmakuType::Bottom, None),
+        ]);
+        let mut provider = DanmakuTimeChunkProvider::new(param.clone(), Box::new(source));
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+        let renderer_param = RendererParam {
+            opacity: 1.0,
+            color_space: Default::default(),
+            premultiplied_alpha: false,
+            blend_mode: Default::default(),
+            orientation: Default::default(),
+            animation: Default::default(),
+            type_opacity: Default::default(),
+            shadow_color: Default::default(),
+        };
+        let svg = export_svg(
+            [chunk.as_ref()],
+            &param,
+            &renderer_param,
+            &mut shaper,
+            DanmakuTime::from_millis(3000),
+        );
+
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.ends_with("</svg>\n"));
+        // The bottom danmaku isn't on the screen yet
+        assert_eq!(svg.matches("<g ").count(), 2);
+        assert_eq!(svg.matches("<rect").count(), 4);
+        assert_eq!(svg.matches("<path").count(), 2);
+        assert_eq!(svg.matches("<linearGradient").count(), 1);
+        assert!(svg.contains(r##"fill="#ff0000""##));
+    }
+}
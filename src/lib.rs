@@ -1,9 +1,22 @@
-pub mod danmaku;
+pub mod analysis;
+pub mod cache;
+pub mod clock;
+pub mod emote;
+pub mod export;
 pub mod filter;
-pub mod layout;
 pub mod manager;
+pub mod pacing;
+#[cfg(any(feature = "renderer-cairo", feature = "renderer-wgpu"))]
+pub mod pipeline;
+pub mod position;
+pub mod prelude;
+#[cfg(feature = "renderer-cairo")]
+pub mod preview;
 pub mod renderer;
+pub mod shaper;
 pub mod sources;
+pub mod style;
 pub mod worker;
 
-pub use cosmic_text;
+pub use cosmic_text::{self, Attrs, AttrsList, Family, FontSystem, Weight};
+pub use danmaku_core::{danmaku, layout};
@@ -1,76 +1,467 @@
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap},
     error::Error,
+    fmt::Display,
     sync::Arc,
     time::Duration,
 };
 
-use cosmic_text::{
-    AttrsList, CacheKey, FontSystem, LayoutLine, PhysicalGlyph, ShapeBuffer, ShapeLine, Shaping,
-    Wrap,
-};
+use cosmic_text::{AttrsList, CacheKey, LayoutLine, PhysicalGlyph, Placement};
 
 use crate::{
-    danmaku::{Danmaku, DanmakuColor, DanmakuSize, DanmakuTime, DanmakuType},
-    layout::{DanmakuPosition, DanmakuTrackState, LayoutMode},
+    cache::CachedLayout,
+    danmaku::{Danmaku, DanmakuAnimation, DanmakuColor, DanmakuSize, DanmakuTime, DanmakuType},
+    emote::{find_emotes, EmoteImage, EmoteProvider},
+    layout::{
+        track_lifetime, DanmakuItem, DanmakuPosition, DanmakuTrackState, LayoutMode, Rect,
+        ScrollSpeedModel, StaticLimit, TimedRegion,
+    },
+    position::{self, Easing},
+    shaper::TextShaper,
     sources::DanmakuSource,
+    style::{DanmakuStyle, DanmakuStyler},
+    worker::{DanmakuParam, TallDanmaku, WideDanmaku},
 };
 
+// Stands in for an emote while shaping
+const EMOTE_PLACEHOLDER: char = '\u{FFFC}';
+
+const ELLIPSIS: &str = "\u{2026}";
+
+#[derive(Debug)]
+pub struct LayoutedEmote {
+    pub shortcode: String,
+    pub image: Arc<EmoteImage>,
+    // Physical rectangle, x relative to the line start and y relative to the baseline
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
 #[derive(Debug)]
 pub struct LayoutedDanmakuItem {
-    pub layout_line: LayoutLine,
+    pub layout_lines: Vec<LayoutLine>,
+    // Physical distance between the baselines of the lines
+    pub line_height: u32,
     pub physical_glyphs: Vec<PhysicalGlyph>,
+    pub emotes: Vec<LayoutedEmote>,
+    // Adjacent tracks taken, at least one per line. The lines fill the bottom ones.
+    pub tracks: usize,
     pub time: DanmakuTime,
     pub color: DanmakuColor,
     pub r#type: DanmakuType,
     pub size: DanmakuSize,
+    pub bordered: bool,
+    pub background: Option<DanmakuColor>,
+    pub animation: Option<DanmakuAnimation>,
+    pub gradient: Option<DanmakuColor>,
+    // See Danmaku::id, e.g. for reporting or blocking the danmaku under the cursor
+    pub id: Option<u64>,
+    pub opacity: f32,
+    pub scale_factor: f32,
+}
+
+// Ascent + descent of the font in logical pixels, measured by shaping a sample text
+pub(crate) fn measure_line_height(
+    shaper: &mut dyn TextShaper,
+    attrs: &AttrsList,
+    font_size: f32,
+) -> f32 {
+    shaper
+        .shape_line("Hg弹幕", attrs, font_size)
+        .map(|line| line.max_ascent + line.max_descent)
+        .filter(|height| *height > 0.0)
+        .unwrap_or(font_size)
+}
+
+// Stands in for a line which failed to shape
+fn blank_line() -> LayoutLine {
+    LayoutLine {
+        w: 0.0,
+        max_ascent: 0.0,
+        max_descent: 0.0,
+        line_height_opt: None,
+        glyphs: Vec::new(),
+    }
+}
+
+// Shapes one line of text, with the emotes widened to their aspect ratio. Emote rectangles are
+// physical and relative to the baseline of the line.
+fn layout_text_line(
+    shaper: &mut dyn TextShaper,
+    attrs: &AttrsList,
+    font_size: f32,
+    scale_factor: f32,
+    emote_provider: Option<&dyn EmoteProvider>,
+    text: &str,
+) -> Option<(LayoutLine, Vec<LayoutedEmote>)> {
+    let found_emotes = emote_provider
+        .map(|provider| find_emotes(text, provider))
+        .unwrap_or_default();
+
+    // Replace every shortcode with a single placeholder, remembering where it starts
+    let mut content = String::with_capacity(text.len());
+    let mut placeholders = Vec::with_capacity(found_emotes.len());
+    let mut offset = 0;
+    for (range, shortcode, image) in found_emotes {
+        content.push_str(&text[offset..range.start]);
+        placeholders.push((content.len(), Some((shortcode, image))));
+        content.push(EMOTE_PLACEHOLDER);
+        offset = range.end;
+    }
+    content.push_str(&text[offset..]);
+
+    shaper
+        .shape_line(&content, attrs, font_size)
+        .map(|mut line| {
+            let mut emotes = Vec::new();
+            if !placeholders.is_empty() {
+                let emote_height = font_size * scale_factor;
+                let emote_y = (line.max_descent * scale_factor - emote_height).round() as i32;
+
+                // Glyphs are in visual order, so widening an emote pushes everything after it
+                let mut shift = 0.0;
+                let mut glyphs = Vec::with_capacity(line.glyphs.len());
+                for mut glyph in line.glyphs.drain(..) {
+                    let placeholder = placeholders
+                        .iter_mut()
+                        .find(|(start, _)| *start == glyph.start);
+                    let emote = match placeholder {
+                        Some((_, emote)) => emote.take(),
+                        None => {
+                            glyph.x += shift;
+                            glyphs.push(glyph);
+                            continue;
+                        }
+                    };
+                    // The placeholder may be shaped into several glyphs, keep the first one
+                    let (shortcode, image) = match emote {
+                        Some(emote) => emote,
+                        None => {
+                            shift -= glyph.w;
+                            continue;
+                        }
+                    };
+                    let width = font_size * image.width() as f32 / image.height() as f32;
+                    emotes.push(LayoutedEmote {
+                        shortcode,
+                        image,
+                        x: ((glyph.x + shift) * scale_factor).round() as i32,
+                        y: emote_y,
+                        width: (width * scale_factor).round() as u32,
+                        height: emote_height.round() as u32,
+                    });
+                    shift += width - glyph.w;
+                }
+                line.glyphs = glyphs;
+                line.w += shift;
+            }
+            (line, emotes)
+        })
+}
+
+// Length in bytes of the longest prefix of the text which fits into the logical width with the
+// suffix appended. At least one character is kept, so wrapping always makes progress.
+fn fitting_prefix(
+    shaper: &mut dyn TextShaper,
+    attrs: &AttrsList,
+    font_size: f32,
+    emote_provider: Option<&dyn EmoteProvider>,
+    text: &str,
+    suffix: &str,
+    max_width: f32,
+) -> usize {
+    let ends: Vec<_> = text
+        .char_indices()
+        .skip(1)
+        .map(|(index, _)| index)
+        .chain([text.len()])
+        .collect();
+    // Widths grow with the prefix, so the last fitting one is found by bisection
+    let (mut low, mut high) = (0, ends.len());
+    while high - low > 1 {
+        let middle = (low + high) / 2;
+        let prefix = format!("{}{}", &text[..ends[middle]], suffix);
+        let width = layout_text_line(shaper, attrs, font_size, 1.0, emote_provider, &prefix)
+            .map_or(0.0, |(line, _)| line.w);
+        if width <= max_width {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    ends.first().map_or(0, |_| ends[low])
+}
+
+// Applies WideDanmaku::Ellipsize and Wrap to a line of the content
+fn limit_line<'a>(
+    shaper: &mut dyn TextShaper,
+    attrs: &AttrsList,
+    font_size: f32,
+    emote_provider: Option<&dyn EmoteProvider>,
+    text: &'a str,
+    wide_danmaku: WideDanmaku,
+    max_width: f32,
+) -> Vec<Cow<'a, str>> {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    loop {
+        let width = layout_text_line(shaper, attrs, font_size, 1.0, emote_provider, rest)
+            .map_or(0.0, |(line, _)| line.w);
+        if width <= max_width {
+            lines.push(Cow::Borrowed(rest));
+            return lines;
+        }
+        match wide_danmaku {
+            WideDanmaku::Ellipsize(_) => {
+                let end = fitting_prefix(
+                    shaper,
+                    attrs,
+                    font_size,
+                    emote_provider,
+                    rest,
+                    ELLIPSIS,
+                    max_width,
+                );
+                lines.push(Cow::Owned(format!(
+                    "{}{}",
+                    rest[..end].trim_end(),
+                    ELLIPSIS
+                )));
+                return lines;
+            }
+            WideDanmaku::Wrap(_) => {
+                let end = fitting_prefix(
+                    shaper,
+                    attrs,
+                    font_size,
+                    emote_provider,
+                    rest,
+                    "",
+                    max_width,
+                );
+                lines.push(Cow::Borrowed(&rest[..end]));
+                rest = rest[end..].trim_start();
+            }
+            _ => {
+                lines.push(Cow::Borrowed(rest));
+                return lines;
+            }
+        }
+    }
 }
 
 impl LayoutedDanmakuItem {
-    fn new(
-        font_system: &mut FontSystem,
-        shape_buffer: &mut ShapeBuffer,
+    // Lines of the content are stacked line_height physical pixels apart, so that each of them
+    // fills one track. Lines wider than max_width are handled as wide_danmaku says.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        shaper: &mut dyn TextShaper,
         attrs: &AttrsList,
         font_size: f32,
+        scale_factor: f32,
+        line_height: u32,
+        emote_provider: Option<&dyn EmoteProvider>,
         danmaku: &Danmaku,
+        wide_danmaku: WideDanmaku,
+        max_width: Option<f32>,
     ) -> Option<LayoutedDanmakuItem> {
-        let shape_line = ShapeLine::new_in_buffer(
-            shape_buffer,
-            font_system,
-            &danmaku.content,
-            attrs,
-            Shaping::Advanced,
-            2,
-        );
-        let mut lines = Vec::new();
-        shape_line.layout_to_buffer(
-            shape_buffer,
-            font_size,
-            None,
-            Wrap::None,
-            None,
-            &mut lines,
-            None,
-        );
-        lines.into_iter().nth(0).map(|line| {
-            let physical_glyphs = line
-                .glyphs
-                .iter()
-                .map(|glyph| glyph.physical((0.0, 0.0), 1.0))
-                .collect();
-            LayoutedDanmakuItem {
-                layout_line: line,
-                physical_glyphs,
-                time: danmaku.time,
-                color: danmaku.color,
-                r#type: danmaku.r#type,
-                size: danmaku.size,
-            }
+        let mut layout_lines = Vec::new();
+        let mut physical_glyphs = Vec::new();
+        let mut emotes = Vec::new();
+        let content = danmaku.content.trim_end_matches(['\r', '\n']);
+        let texts: Vec<Cow<str>> = content
+            .split('\n')
+            .map(|text| text.strip_suffix('\r').unwrap_or(text))
+            .flat_map(|text| match max_width {
+                Some(max_width) => limit_line(
+                    shaper,
+                    attrs,
+                    font_size,
+                    emote_provider,
+                    text,
+                    wide_danmaku,
+                    max_width,
+                ),
+                None => vec![Cow::Borrowed(text)],
+            })
+            .collect();
+        let mut shaped = false;
+        for (index, text) in texts.iter().enumerate() {
+            let layouted =
+                layout_text_line(shaper, attrs, font_size, scale_factor, emote_provider, text);
+            let (line, line_emotes) = match layouted {
+                Some(layouted) => {
+                    shaped = true;
+                    layouted
+                }
+                // Left blank, so the other lines keep their tracks
+                None => (blank_line(), Vec::new()),
+            };
+            let offset_y = (line_height as usize * index) as i32;
+            physical_glyphs.extend(line.glyphs.iter().map(|glyph| {
+                let mut glyph = glyph.physical((0.0, 0.0), scale_factor);
+                glyph.y += offset_y;
+                glyph
+            }));
+            emotes.extend(line_emotes.into_iter().map(|mut emote| {
+                emote.y += offset_y;
+                emote
+            }));
+            layout_lines.push(line);
+        }
+        if !shaped {
+            return None;
+        }
+
+        Some(LayoutedDanmakuItem {
+            tracks: layout_lines.len(),
+            layout_lines,
+            line_height,
+            physical_glyphs,
+            emotes,
+            time: danmaku.time,
+            color: danmaku.color,
+            r#type: danmaku.r#type,
+            size: danmaku.size,
+            bordered: danmaku.bordered,
+            background: danmaku.background,
+            animation: danmaku.animation,
+            gradient: danmaku.gradient,
+            id: danmaku.id,
+            opacity: 1.0,
+            scale_factor,
         })
     }
 
+    pub fn lines(&self) -> usize {
+        self.layout_lines.len()
+    }
+
     pub fn width(&self) -> u32 {
-        self.layout_line.w.ceil() as u32
+        let width = self
+            .layout_lines
+            .iter()
+            .map(|line| line.w)
+            .fold(0.0, f32::max);
+        (width * self.scale_factor).ceil() as u32
+    }
+
+    pub fn max_descent(&self) -> f32 {
+        let descent = self
+            .layout_lines
+            .iter()
+            .map(|line| line.max_descent)
+            .fold(0.0, f32::max);
+        descent * self.scale_factor
+    }
+
+    // Height of the first line plus the tracks of the following lines
+    pub fn height(&self) -> u32 {
+        let ascent = self
+            .layout_lines
+            .iter()
+            .map(|line| line.max_ascent)
+            .fold(0.0, f32::max);
+        let line = ((ascent * self.scale_factor + self.max_descent()).ceil()) as u32;
+        line + self.line_height * (self.lines() as u32).saturating_sub(1)
+    }
+
+    // Takes as many tracks as the height covers, moving the lines down into the bottom ones, so
+    // that tall glyphs stay out of the track above
+    pub fn expand_tracks(&mut self) {
+        let tracks = self.height().div_ceil(self.line_height.max(1)) as usize;
+        if tracks <= self.tracks {
+            return;
+        }
+        let offset_y = (self.line_height as usize * (tracks - self.tracks)) as i32;
+        for glyph in &mut self.physical_glyphs {
+            glyph.y += offset_y;
+        }
+        for emote in &mut self.emotes {
+            emote.y += offset_y;
+        }
+        self.tracks = tracks;
+    }
+
+    // Scale and opacity multipliers of the animation at the given time
+    pub fn animation_at(&self, now: DanmakuTime) -> (f32, f32) {
+        match &self.animation {
+            Some(animation) => animation.evaluate((now.as_millis() - self.time.as_millis()) as f32),
+            None => (1.0, 1.0),
+        }
+    }
+
+    pub fn border_width(&self) -> u32 {
+        (self.scale_factor.round() as u32).max(1)
+    }
+
+    // Physical rectangle (x, y, width, height) of the background, relative to the bottom left
+    // corner of the first track. The border is drawn along its edges.
+    pub fn background_rect(&self) -> (i32, i32, u32, u32) {
+        let padding = self.border_width() * 2;
+        let height = self.height();
+        let below = self.line_height * (self.tracks as u32).saturating_sub(1);
+        (
+            -(padding as i32),
+            below as i32 - height as i32,
+            self.width() + padding * 2,
+            height,
+        )
+    }
+
+    // Physical rectangle of the bitmap of one of the physical_glyphs, relative to the same corner
+    // as background_rect. The placement is the one of the bitmap from TextShaper::rasterize,
+    // grown by any padding the backend keeps around it.
+    pub fn glyph_rect(&self, glyph: &PhysicalGlyph, placement: Placement) -> (i32, i32, u32, u32) {
+        let y = glyph.y - placement.top - self.max_descent() as i32;
+        (
+            glyph.x + placement.left,
+            y,
+            placement.width,
+            placement.height,
+        )
+    }
+
+    // Physical rectangle of one of the emotes, the image is scaled to fill it
+    pub fn emote_rect(&self, emote: &LayoutedEmote) -> (i32, i32, u32, u32) {
+        let y = emote.y - self.max_descent() as i32;
+        (emote.x, y, emote.width, emote.height)
+    }
+}
+
+type ChunkDanmaku<D> = (D, Option<DanmakuStyle>, bool);
+
+// The later danmaku of a group become lines under the first one, which keeps its time and style.
+// The merged item takes adjacent tracks and moves at one speed. Groups split across chunks are
+// only merged within each chunk.
+fn merge_groups<'a>(
+    danmakus: Vec<ChunkDanmaku<&'a Danmaku>>,
+) -> Vec<ChunkDanmaku<Cow<'a, Danmaku>>> {
+    let mut merged: Vec<ChunkDanmaku<Cow<Danmaku>>> = Vec::with_capacity(danmakus.len());
+    let mut groups: HashMap<u64, usize> = HashMap::new();
+    for (danmaku, style, local) in danmakus {
+        if let Some(group_id) = danmaku.group_id {
+            if let Some(index) = groups.get(&group_id) {
+                let primary = merged[*index].0.to_mut();
+                let content = primary.content.trim_end_matches(['\r', '\n']).len();
+                primary.content.truncate(content);
+                primary.content.push('\n');
+                primary.content.push_str(&danmaku.content);
+                continue;
+            }
+            groups.insert(group_id, merged.len());
+        }
+        merged.push((Cow::Borrowed(danmaku), style, local));
+    }
+    merged
+}
+
+impl From<&LayoutedDanmakuItem> for DanmakuItem {
+    fn from(value: &LayoutedDanmakuItem) -> Self {
+        DanmakuItem::new(value.width(), value.time, value.r#type).with_lines(value.tracks)
     }
 }
 
@@ -80,92 +471,477 @@ pub struct PositionedDanmakuItem {
     pub position: DanmakuPosition,
 }
 
+impl PositionedDanmakuItem {
+    // Physical position of the left end of the first baseline at the time, None when the item
+    // isn't on the screen, see position::position_at
+    pub fn position_at(&self, now: DanmakuTime, param: &DanmakuParam) -> Option<(f32, f32)> {
+        position::position_at(self.position, self.item.width(), self.item.time, now, param)
+    }
+
+    // Where the renderers draw the item, see position::eased_position_at
+    pub fn eased_position_at(
+        &self,
+        now: DanmakuTime,
+        param: &DanmakuParam,
+        easing: Easing,
+    ) -> Option<(f32, f32)> {
+        let (width, time) = (self.item.width(), self.item.time);
+        position::eased_position_at(self.position, width, time, now, param, easing)
+    }
+}
+
+// The danmaku laid out for one chunk duration, which the worker hands to RenderCache::prepare
+// and ChunkBuffer::new. Backends draw the items at their positions with the glyphs and emotes
+// listed here.
 #[derive(Debug)]
 pub struct DanmakuTimeChunk {
     pub base_state_index: u32,
     pub index: u32,
     pub items: Vec<PositionedDanmakuItem>,
     glyph_ids: BTreeSet<CacheKey>,
+    emotes: BTreeMap<String, Arc<EmoteImage>>,
+    // Time the last item of the chunk leaves the screen
+    end_time: DanmakuTime,
+    // First chunk with items still on the screen at the start of this one
+    first_visible: u32,
+    font_size: f32,
 }
 
 impl DanmakuTimeChunk {
+    pub fn end_time(&self) -> DanmakuTime {
+        self.end_time
+    }
+
+    pub fn first_visible(&self) -> u32 {
+        self.first_visible
+    }
+
+    // Physical font size the items were shaped with
+    pub fn font_size(&self) -> f32 {
+        self.font_size
+    }
+
+    // Every glyph of the items, once each, for rasterizing them before the chunk is drawn
     pub fn glyph_ids(&self) -> impl Iterator<Item = &CacheKey> {
         self.glyph_ids.iter()
     }
+
+    // Every emote of the items by shortcode, once each
+    pub fn emotes(&self) -> impl Iterator<Item = (&str, &Arc<EmoteImage>)> {
+        self.emotes
+            .iter()
+            .map(|(shortcode, image)| (shortcode.as_str(), image))
+    }
+
+    // Number of items on the screen at the time
+    pub fn visible_count(&self, now: DanmakuTime, param: &DanmakuParam) -> usize {
+        self.positions_at(now, param).count()
+    }
+
+    // Items on the screen at the time with their positions, see PositionedDanmakuItem::position_at
+    pub fn positions_at<'a>(
+        &'a self,
+        now: DanmakuTime,
+        param: &'a DanmakuParam,
+    ) -> impl Iterator<Item = (f32, f32, &'a LayoutedDanmakuItem)> + 'a {
+        self.items.iter().filter_map(move |item| {
+            item.position_at(now, param)
+                .map(|(x, y)| (x, y, &item.item))
+        })
+    }
+}
+
+// Times before zero belong to the first chunk
+#[derive(Debug)]
+pub enum ChunkError {
+    // The chunk ends after the largest DanmakuTime
+    TimeOverflow(u32),
+}
+
+impl Display for ChunkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkError::TimeOverflow(index) => write!(f, "Chunk #{} is out of time range", index),
+        }
+    }
+}
+
+impl Error for ChunkError {}
+
+pub fn chunk_index(time: DanmakuTime, chunk_duration: Duration) -> u32 {
+    let index = time.as_millis().max(0) / chunk_duration.as_millis() as i64;
+    index.try_into().unwrap_or(u32::MAX)
 }
 
 pub struct DanmakuTimeChunkProvider {
-    lifetime: Duration,
+    scroll_lifetime: Duration,
+    static_lifetime: Duration,
+    chunk_duration: Duration,
     font_size: f32,
+    scale_factor: f32,
     font_attrs: AttrsList,
     screen_size: (u32, u32),
     line_height: u32,
     layout_mode: LayoutMode,
+    scroll_speed: ScrollSpeedModel,
+    max_line_width: Option<f32>,
+    wide_danmaku: WideDanmaku,
+    tall_danmaku: TallDanmaku,
+    scroll_gap: u32,
+    scroll_speed_variation: f32,
+    blocked_regions: Vec<Rect>,
+    static_limit: Option<StaticLimit>,
+    timed_regions: Vec<TimedRegion>,
     source: Box<dyn DanmakuSource + Send>,
+    emote_provider: Option<Arc<dyn EmoteProvider>>,
+    styler: Option<Arc<dyn DanmakuStyler>>,
+    // Sorted by time
+    local_danmaku: Vec<Danmaku>,
+    // Positions recorded in a replay file, used instead of allocating tracks until a change
+    // invalidates the chunks
+    cached_layout: Option<CachedLayout>,
+    // Chunk of the playback and the chunks before it which may still be on the screen, see
+    // prune_states
+    current: u32,
+    lookback: u32,
     states: BTreeMap<u32, (u32, DanmakuTrackState)>,
     chunks: BTreeMap<u32, Arc<DanmakuTimeChunk>>,
 }
 
 impl DanmakuTimeChunkProvider {
-    pub fn new(
-        lifetime: Duration,
-        font_size: f32,
-        font_attrs: AttrsList,
-        screen_size: (u32, u32),
-        line_height: u32,
-        layout_mode: LayoutMode,
-        source: Box<dyn DanmakuSource + Send>,
-    ) -> Self {
+    pub fn new(param: DanmakuParam, source: Box<dyn DanmakuSource + Send>) -> Self {
+        let lookback = param.lookback_chunks();
         DanmakuTimeChunkProvider {
-            lifetime,
-            font_size,
-            font_attrs,
-            screen_size,
-            line_height,
-            layout_mode,
+            scroll_lifetime: param.scroll_lifetime,
+            static_lifetime: param.static_lifetime,
+            chunk_duration: param.chunk_duration,
+            font_size: param.font_size,
+            scale_factor: param.scale_factor,
+            line_height: param.physical_line_height(),
+            scroll_gap: param.physical_scroll_gap(),
+            scroll_speed: param.scroll_speed_model(),
+            max_line_width: param.max_line_width(),
+            wide_danmaku: param.wide_danmaku,
+            tall_danmaku: param.tall_danmaku,
+            font_attrs: param.font_attrs,
+            screen_size: param.screen_size,
+            layout_mode: param.layout_mode,
+            scroll_speed_variation: param.scroll_speed_variation,
+            blocked_regions: param.blocked_regions,
+            static_limit: param.static_limit,
+            timed_regions: Vec::new(),
             source,
+            emote_provider: None,
+            styler: None,
+            local_danmaku: Vec::new(),
+            cached_layout: None,
+            current: 0,
+            lookback,
             states: BTreeMap::new(),
             chunks: BTreeMap::new(),
         }
     }
 
+    pub fn with_emote_provider(mut self, emote_provider: Option<Arc<dyn EmoteProvider>>) -> Self {
+        self.emote_provider = emote_provider;
+        self
+    }
+
+    pub fn with_styler(mut self, styler: Option<Arc<dyn DanmakuStyler>>) -> Self {
+        self.styler = styler;
+        self
+    }
+
+    pub fn with_local_danmaku(mut self, mut local_danmaku: Vec<Danmaku>) -> Self {
+        local_danmaku.sort_by_key(|danmaku| danmaku.time);
+        self.local_danmaku = local_danmaku;
+        self
+    }
+
+    pub fn with_timed_regions(mut self, regions: Vec<TimedRegion>) -> Self {
+        self.timed_regions = regions;
+        self
+    }
+
+    // The layout has to be recorded with the same param, styler and emote provider. It isn't used
+    // with local danmaku or timed regions, which the recording doesn't know of.
+    pub fn with_cached_layout(mut self, layout: Option<CachedLayout>) -> Self {
+        self.cached_layout =
+            layout.filter(|_| self.local_danmaku.is_empty() && self.timed_regions.is_empty());
+        self
+    }
+
     pub fn source(self) -> Box<dyn DanmakuSource + Send> {
         self.source
     }
 
-    pub fn lifetime(&self) -> Duration {
-        self.lifetime
+    pub fn into_parts(self) -> (Box<dyn DanmakuSource + Send>, Vec<Danmaku>) {
+        (self.source, self.local_danmaku)
     }
 
+    // Chunk of the playback time, the insertions and region changes around it lay out the chunks
+    // again from the same state
+    pub fn set_current(&mut self, index: u32) {
+        self.current = index;
+        self.prune_states();
+    }
+
+    // The state after a chunk is kept while the next chunk isn't laid out yet, or while the chunk
+    // is near the playback, where insertions and region changes lay out the next one again.
+    // Chunks laid out again further away start from a warmed up state instead. Keeping all of
+    // them would hold one for every chunk of the video once it's indexed in the background.
+    fn prune_states(&mut self) {
+        let first = self.current.saturating_sub(self.lookback + 1);
+        let last = self.current.saturating_add(2);
+        let chunks = &self.chunks;
+        self.states.retain(|index, _| {
+            (first..=last).contains(index) || !chunks.contains_key(&index.saturating_add(1))
+        });
+    }
+
+    // Returns the index of the first chunk which needs to be generated again
+    pub fn insert_local(&mut self, mut danmaku: Danmaku) -> u32 {
+        danmaku.bordered = true;
+        let index = chunk_index(danmaku.time, self.chunk_duration);
+        let position = self
+            .local_danmaku
+            .partition_point(|item| item.time <= danmaku.time);
+        self.local_danmaku.insert(position, danmaku);
+        self.invalidate_from(index);
+        index
+    }
+
+    // Drops the chunks the source added danmaku to, returning the first of them
+    pub fn take_source_changes(&mut self) -> Option<u32> {
+        let time = self.source.take_changed()?;
+        let index = chunk_index(time, self.chunk_duration);
+        self.invalidate_from(index);
+        Some(index)
+    }
+
+    // Chunks from the index on are laid out with the new regions
+    pub fn set_blocked_regions(&mut self, regions: Vec<Rect>, index: u32) {
+        self.blocked_regions = regions;
+        self.invalidate_from(index);
+    }
+
+    // Static danmaku avoid the regions while they are shown. Chunks from the first one placing
+    // danmaku shown with a changed region are laid out again, but not before the current one, so
+    // the danmaku on the screen don't jump. Returns the first chunk laid out again.
+    pub fn set_timed_regions(&mut self, regions: Vec<TimedRegion>, current: u32) -> Option<u32> {
+        let first_changed = regions
+            .iter()
+            .filter(|region| !self.timed_regions.contains(region))
+            .chain(
+                self.timed_regions
+                    .iter()
+                    .filter(|region| !regions.contains(region)),
+            )
+            .map(|region| region.start)
+            .min()?;
+        let first_placed = DanmakuTime::from_millis(
+            first_changed.as_millis() - self.static_lifetime.as_millis() as i64,
+        );
+        let index = chunk_index(first_placed, self.chunk_duration).max(current);
+        self.timed_regions = regions;
+        self.invalidate_from(index);
+        Some(index)
+    }
+
+    fn invalidate_from(&mut self, index: u32) {
+        // Layout of the following chunks depends on the changed one
+        self.chunks.split_off(&index);
+        self.states.split_off(&index);
+        // The states of the cached chunks have no danmaku, the chunks laid out again warm up
+        if self.cached_layout.take().is_some() {
+            self.states.clear();
+        }
+    }
+
+    pub fn chunk_duration(&self) -> Duration {
+        self.chunk_duration
+    }
+
+    // Chunk of the latest danmaku of the source and the local ones
+    pub fn last_index(&mut self) -> u32 {
+        let last_time = self
+            .source
+            .get_all()
+            .chain(self.local_danmaku.last())
+            .map(|danmaku| danmaku.time)
+            .max()
+            .unwrap_or(DanmakuTime::from_millis(0));
+        chunk_index(last_time, self.chunk_duration)
+    }
+
+    // Same as DanmakuParam::item_lifetime
+    fn item_lifetime(&self, position: DanmakuPosition, width: u32) -> Duration {
+        match position {
+            DanmakuPosition::Scroll(track) => {
+                let lifetime =
+                    track_lifetime(self.scroll_lifetime, track, self.scroll_speed_variation);
+                self.scroll_speed
+                    .duration(self.screen_size.0, width, lifetime)
+            }
+            _ => self.static_lifetime,
+        }
+    }
+
+    // Wide scroll danmaku can outlive the chunks before this one
+    fn first_visible(&self, base_state_index: u32, index: u32, start_time: DanmakuTime) -> u32 {
+        if index == base_state_index {
+            return index;
+        }
+        let Some(previous) = self.chunks.get(&(index - 1)) else {
+            return index;
+        };
+        self.chunks
+            .range(previous.first_visible..index)
+            .find(|(_, chunk)| chunk.end_time > start_time)
+            .map(|(index, _)| *index)
+            .unwrap_or(index)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(index = index)))]
     fn generate_chunk(
         &mut self,
-        font_system: &mut FontSystem,
-        shape_buffer: &mut ShapeBuffer,
+        shaper: &mut dyn TextShaper,
         base_state_index: u32,
         base_state: &mut DanmakuTrackState,
         index: u32,
-    ) -> Arc<DanmakuTimeChunk> {
-        let lifetime = self.lifetime.as_millis() as u32;
-        let start_millis = lifetime * index;
-        let end_millis = start_millis + lifetime;
-        let start_time = DanmakuTime::from_millis(start_millis);
+    ) -> Result<Arc<DanmakuTimeChunk>, ChunkError> {
+        base_state.seed(index as u64);
+        let chunk_duration = self.chunk_duration.as_millis() as i64;
+        let end_millis = chunk_duration
+            .checked_mul(index as i64 + 1)
+            .ok_or(ChunkError::TimeOverflow(index))?;
+        let start_millis = end_millis - chunk_duration;
+        // Danmaku before the start of the video are shown in the first chunk
+        let start_time = if index == 0 {
+            DanmakuTime::MIN
+        } else {
+            DanmakuTime::from_millis(start_millis)
+        };
         let end_time = DanmakuTime::from_millis(end_millis);
 
-        let mut items = Vec::new();
+        let items = self.place_danmakus(shaper, base_state, start_time, end_time);
         let mut glyph_ids = BTreeSet::new();
-        for danmaku in self.source.get_range(start_time, end_time) {
-            if let Some(layouted) = LayoutedDanmakuItem::new(
-                font_system,
-                shape_buffer,
+        let mut emotes = BTreeMap::new();
+        for item in &items {
+            for glyph in &item.item.physical_glyphs {
+                glyph_ids.insert(glyph.cache_key);
+            }
+            for emote in &item.item.emotes {
+                emotes
+                    .entry(emote.shortcode.clone())
+                    .or_insert_with(|| emote.image.clone());
+            }
+        }
+
+        let end_time = items
+            .iter()
+            .map(|item| {
+                let lifetime = self.item_lifetime(item.position, item.item.width());
+                let millis = item
+                    .item
+                    .time
+                    .as_millis()
+                    .saturating_add(lifetime.as_millis() as i64);
+                DanmakuTime::from_millis(millis)
+            })
+            .max()
+            .unwrap_or(start_time);
+        let first_visible = self.first_visible(
+            base_state_index,
+            index,
+            DanmakuTime::from_millis(start_millis),
+        );
+        Ok(Arc::new(DanmakuTimeChunk {
+            base_state_index,
+            index,
+            items,
+            glyph_ids,
+            emotes,
+            end_time,
+            first_visible,
+            font_size: self.font_size * self.scale_factor,
+        }))
+    }
+
+    // Lays out the danmaku of the time range and inserts them into the state in time order,
+    // returning the ones which got a position
+    fn place_danmakus(
+        &mut self,
+        shaper: &mut dyn TextShaper,
+        base_state: &mut DanmakuTrackState,
+        start_time: DanmakuTime,
+        end_time: DanmakuTime,
+    ) -> Vec<PositionedDanmakuItem> {
+        let source_danmakus = self.source.get_range_styled(start_time, end_time);
+        let local_start = self
+            .local_danmaku
+            .partition_point(|danmaku| danmaku.time < start_time);
+        let local_end = self
+            .local_danmaku
+            .partition_point(|danmaku| danmaku.time < end_time);
+        let mut danmakus: Vec<(&Danmaku, Option<DanmakuStyle>, bool)> =
+            Vec::with_capacity(source_danmakus.size_hint().0 + (local_end - local_start));
+        danmakus.extend(source_danmakus.map(|(danmaku, style)| (danmaku, style, false)));
+        danmakus.extend(
+            self.local_danmaku[local_start..local_end]
+                .iter()
+                .map(|danmaku| (danmaku, None, true)),
+        );
+        // Sources are not required to return sorted ranges, and tracks must be filled in time
+        // order. Local danmaku stay after source danmaku of the same time.
+        let order_key =
+            |(danmaku, _, local): &(&Danmaku, _, bool)| (danmaku.time, *local, danmaku.id);
+        if !danmakus.is_sorted_by_key(order_key) {
+            danmakus.sort_by_key(order_key);
+        }
+
+        let danmakus = merge_groups(danmakus);
+
+        let cached_layout = self.cached_layout.as_ref();
+        let mut cached_cursor = 0;
+        let mut items = Vec::with_capacity(danmakus.len());
+        for (danmaku, source_style, local) in danmakus {
+            let danmaku = danmaku.as_ref();
+            let mut style = self
+                .styler
+                .as_ref()
+                .map(|styler| styler.style(danmaku))
+                .unwrap_or_default();
+            if let Some(source_style) = source_style {
+                style = style.combine(&source_style);
+            }
+            if let Some(mut layouted) = LayoutedDanmakuItem::new(
+                shaper,
                 &self.font_attrs,
-                self.font_size,
+                self.font_size * style.size_multiplier,
+                self.scale_factor,
+                self.line_height,
+                self.emote_provider.as_deref(),
                 danmaku,
+                self.wide_danmaku,
+                self.max_line_width,
             ) {
-                if let Some(position) = base_state.insert((&layouted).into()) {
-                    for glyph in &layouted.physical_glyphs {
-                        glyph_ids.insert(glyph.cache_key);
-                    }
+                if let Some(color) = style.color {
+                    layouted.color = color;
+                }
+                if self.tall_danmaku == TallDanmaku::Expand {
+                    layouted.expand_tracks();
+                }
+                layouted.opacity = style.opacity;
 
+                let position = if let Some(layout) = cached_layout {
+                    layout.position(&mut cached_cursor, layouted.time, layouted.width())
+                } else if local {
+                    base_state.insert_priority((&layouted).into())
+                } else {
+                    base_state.insert((&layouted).into())
+                };
+                if let Some(position) = position {
                     let item = PositionedDanmakuItem {
                         item: layouted,
                         position,
@@ -174,22 +950,39 @@ impl DanmakuTimeChunkProvider {
                 }
             }
         }
+        items
+    }
 
-        Arc::new(DanmakuTimeChunk {
-            base_state_index,
-            index,
-            items,
-            glyph_ids,
-        })
+    // A fresh state knows nothing of the danmaku of the chunks before, e.g. after seeking far
+    // ahead, and the first danmaku of the chunk would overlap the ones still on the screen.
+    // Places the danmaku of the longest lifetime before the chunk into it first. Wide danmaku
+    // which stay longer with ScrollSpeedModel::ConstantSpeed may still be missed.
+    fn warm_up(&mut self, shaper: &mut dyn TextShaper, state: &mut DanmakuTrackState, index: u32) {
+        let Some(start_millis) = (self.chunk_duration.as_millis() as i64).checked_mul(index as i64)
+        else {
+            return;
+        };
+        let window = self
+            .scroll_lifetime
+            .mul_f32(1.0 + self.scroll_speed_variation)
+            .max(self.static_lifetime);
+        let start_time =
+            DanmakuTime::from_millis(start_millis.saturating_sub(window.as_millis() as i64));
+        state.seed(index as u64);
+        self.place_danmakus(
+            shaper,
+            state,
+            start_time,
+            DanmakuTime::from_millis(start_millis),
+        );
     }
 
     pub fn get_chunk(
         &mut self,
-        font_system: &mut FontSystem,
-        shape_buffer: &mut ShapeBuffer,
+        shaper: &mut dyn TextShaper,
         base_state_index: Option<u32>,
         index: u32,
-    ) -> Result<Arc<DanmakuTimeChunk>, Box<dyn Error>> {
+    ) -> Result<Arc<DanmakuTimeChunk>, ChunkError> {
         if let Some(chunk) = self.chunks.get(&index) {
             if let Some(base_state_index) = base_state_index {
                 if chunk.base_state_index == base_state_index {
@@ -200,12 +993,15 @@ impl DanmakuTimeChunkProvider {
             }
         }
 
+        // The state stays around near the playback, so the chunk can be generated again after a
+        // local insertion
         let base_state = if index != 0 {
             let index = index - 1;
-            self.states.remove(&index)
+            self.states.get(&index).cloned()
         } else {
             None
         };
+        let fresh = base_state.is_none();
         let (base_state_index, mut base_state_item) = base_state.unwrap_or_else(|| {
             (
                 index,
@@ -213,22 +1009,28 @@ impl DanmakuTimeChunkProvider {
                     self.layout_mode,
                     self.screen_size,
                     self.line_height,
-                    self.lifetime,
-                ),
+                    self.scroll_lifetime,
+                    self.static_lifetime,
+                    self.scroll_speed,
+                    self.scroll_gap,
+                )
+                .with_scroll_speed_variation(self.scroll_speed_variation)
+                .with_static_limit(self.static_limit),
             )
         });
+        // The regions may have changed since the previous chunk
+        base_state_item.set_blocked_regions(&self.blocked_regions);
+        base_state_item.set_timed_regions(&self.timed_regions);
+        if fresh && index != 0 && self.cached_layout.is_none() {
+            self.warm_up(shaper, &mut base_state_item, index);
+        }
 
-        let chunk = self.generate_chunk(
-            font_system,
-            shape_buffer,
-            base_state_index,
-            &mut base_state_item,
-            index,
-        );
+        let chunk = self.generate_chunk(shaper, base_state_index, &mut base_state_item, index)?;
 
         self.states
             .insert(index, (base_state_index, base_state_item));
         self.chunks.insert(index, chunk.clone());
+        self.prune_states();
 
         Ok(chunk)
     }
@@ -236,18 +1038,47 @@ impl DanmakuTimeChunkProvider {
 
 #[cfg(test)]
 mod test {
-    use std::{fs::File, io::Read, time::Duration};
+    use std::{collections::BTreeSet, fs::File, io::Read, sync::Arc, time::Duration};
 
-    use cosmic_text::{Attrs, AttrsList, FontSystem, ShapeBuffer};
+    use cosmic_text::{Attrs, AttrsList, CacheKey, FontSystem, LayoutLine};
 
     use crate::{
-        layout::LayoutMode, manager::DanmakuTimeChunkProvider, sources::bilibili::parse_proto,
+        danmaku::{Danmaku, DanmakuColor, DanmakuTime, DanmakuType},
+        layout::{
+            DanmakuPosition, LayoutMode, Rect, ScrollSpeedModel, StaticLimit, TimedRegion,
+            TrackPolicy,
+        },
+        manager::{ChunkError, DanmakuTimeChunk, DanmakuTimeChunkProvider, LayoutedDanmakuItem},
+        shaper::{CosmicTextShaper, GlyphBitmap, TextShaper},
+        sources::{bilibili::parse_proto, DanmakuSource, VecDanmakuSource},
+        style::DanmakuStyle,
+        worker::{test::test_param, DanmakuParam, LineHeight, TallDanmaku, WideDanmaku},
     };
 
+    // Returns every range backwards
+    struct ReversedSource(VecDanmakuSource);
+
+    impl DanmakuSource for ReversedSource {
+        fn get_range<'a>(
+            &'a mut self,
+            start_included: DanmakuTime,
+            end_excluded: DanmakuTime,
+        ) -> Box<dyn Iterator<Item = &'a Danmaku> + 'a> {
+            Box::new(self.0.range(start_included, end_excluded).iter().rev())
+        }
+
+        fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+            self.0.get_all()
+        }
+
+        fn into_all(self) -> Box<dyn Iterator<Item = Danmaku>> {
+            self.0.into_all()
+        }
+    }
+
     #[test]
     fn test_chunk_generate() {
-        let mut font_system = FontSystem::new();
-        let mut shape_buffer = ShapeBuffer::default();
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
         let attr = Attrs::new();
         let attrs = AttrsList::new(attr);
 
@@ -256,27 +1087,631 @@ mod test {
         file.read_to_end(&mut content).unwrap();
         let source = parse_proto(&content).unwrap();
 
-        let mut provider = DanmakuTimeChunkProvider::new(
-            Duration::from_secs(8),
-            28.0,
-            attrs,
-            (1280, 720),
-            32,
-            LayoutMode::ShowAll,
-            Box::new(source),
-        );
+        let param = DanmakuParam {
+            font_attrs: attrs,
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let mut provider = DanmakuTimeChunkProvider::new(param, Box::new(source));
 
-        provider
-            .get_chunk(&mut font_system, &mut shape_buffer, None, 0)
-            .unwrap();
+        provider.get_chunk(&mut shaper, None, 0).unwrap();
         for i in 1..10 {
-            provider
-                .get_chunk(&mut font_system, &mut shape_buffer, Some(0), i)
-                .unwrap();
+            provider.get_chunk(&mut shaper, Some(0), i).unwrap();
         }
-        let chunk = provider
-            .get_chunk(&mut font_system, &mut shape_buffer, Some(0), 10)
-            .unwrap();
+        let chunk = provider.get_chunk(&mut shaper, Some(0), 10).unwrap();
         println!("{:?}", chunk);
     }
+
+    #[test]
+    fn test_insert_local() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+
+        let mut file = File::open("test/1176840.bin").unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        let source = parse_proto(&content).unwrap();
+
+        let param = test_param();
+        let mut provider = DanmakuTimeChunkProvider::new(param, Box::new(source));
+        for i in 0..4 {
+            provider.get_chunk(&mut shaper, Some(0), i).unwrap();
+        }
+
+        let time = DanmakuTime::from_millis(20_001);
+        let color = DanmakuColor::from_code(0x123456);
+        let index = provider.insert_local(Danmaku {
+            color,
+            ..Danmaku::new(time, DanmakuType::Scroll, "local")
+        });
+        assert_eq!(index, 2);
+
+        let chunk = provider.get_chunk(&mut shaper, Some(0), 2).unwrap();
+        assert_eq!(chunk.base_state_index, 0);
+        assert!(chunk
+            .items
+            .iter()
+            .any(|item| item.item.time == time && item.item.color == color && item.item.bordered));
+    }
+
+    #[test]
+    fn test_prune_states() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let danmaku = |millis| {
+            Danmaku::new(
+                DanmakuTime::from_millis(millis),
+                DanmakuType::Scroll,
+                "danmaku",
+            )
+        };
+        let danmakus = (0..10).map(|index| danmaku(index * 8000 + 100)).collect();
+
+        let mut provider =
+            DanmakuTimeChunkProvider::new(test_param(), Box::new(VecDanmakuSource::new(danmakus)));
+        provider.set_current(5);
+        for index in 0..10 {
+            provider.get_chunk(&mut shaper, None, index).unwrap();
+        }
+        // The states around the playback, and the one the next chunk is laid out from
+        let kept: Vec<_> = provider.states.keys().copied().collect();
+        assert_eq!(kept, vec![3, 4, 5, 6, 7, 9]);
+
+        // Laid out again from the same state
+        let index = provider.insert_local(danmaku(6 * 8000 + 200));
+        assert_eq!(index, 6);
+        let chunk = provider.get_chunk(&mut shaper, None, 6).unwrap();
+        assert_eq!(chunk.base_state_index, 0);
+        assert_eq!(chunk.items.len(), 2);
+
+        provider.set_current(8);
+        let kept: Vec<_> = provider.states.keys().copied().collect();
+        assert_eq!(kept, vec![6]);
+    }
+
+    #[test]
+    fn test_track_policies() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let danmaku = |millis, r#type, content: &str| {
+            Danmaku::new(DanmakuTime::from_millis(millis), r#type, content)
+        };
+        let tracks = |chunk: &DanmakuTimeChunk| {
+            chunk
+                .items
+                .iter()
+                .map(|item| match item.position {
+                    DanmakuPosition::Scroll(track) | DanmakuPosition::Top(track) => track,
+                    DanmakuPosition::Bottom(_) => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let provider = |policy, danmakus| {
+            let param = DanmakuParam {
+                layout_mode: LayoutMode::ShowAllWith(policy),
+                ..test_param()
+            };
+            DanmakuTimeChunkProvider::new(param, Box::new(VecDanmakuSource::new(danmakus)))
+        };
+
+        // 18 danmaku more than the 22 tracks
+        let flood: Vec<_> = (0..40)
+            .map(|_| danmaku(0, DanmakuType::Top, "flood"))
+            .collect();
+        let mut random = provider(TrackPolicy::Random, flood.clone());
+        let chunk = random.get_chunk(&mut shaper, None, 0).unwrap();
+        let positions = tracks(&chunk);
+        assert_eq!(positions[..22], (0..22).collect::<Vec<_>>());
+        let round_robin: Vec<_> = (22..40).map(|index| index % 22).collect();
+        assert_ne!(positions[22..], round_robin);
+        // Seeded with the chunk index, so laying it out again gives the same positions
+        let mut other = provider(TrackPolicy::Random, flood);
+        let chunk = other.get_chunk(&mut shaper, None, 0).unwrap();
+        assert_eq!(tracks(&chunk), positions);
+
+        // The short danmaku leave their tracks free after a second while the long one still
+        // takes the track 0, which then has the oldest latest danmaku
+        let long = "long danmaku ".repeat(5);
+        let mut danmakus = vec![danmaku(0, DanmakuType::Scroll, &long)];
+        danmakus.extend((0..21).map(|_| danmaku(0, DanmakuType::Scroll, "short")));
+        danmakus.extend((0..22).map(|_| danmaku(1000, DanmakuType::Scroll, "short")));
+        let mut least_recent = provider(TrackPolicy::LeastRecentlyUsed, danmakus);
+        let chunk = least_recent.get_chunk(&mut shaper, None, 0).unwrap();
+        let positions = tracks(&chunk);
+        assert_eq!(
+            positions[..43],
+            [(0..22).collect::<Vec<_>>(), (1..22).collect()].concat()
+        );
+        // Round robin would take the track 21
+        assert_eq!(positions[43], 0);
+    }
+
+    #[test]
+    fn test_unsorted_source() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+
+        let mut file = File::open("test/1176840.bin").unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        let danmaku: Vec<_> = parse_proto(&content).unwrap().into_all().collect();
+
+        let sorted = VecDanmakuSource::new(danmaku.clone());
+        let reversed = ReversedSource(VecDanmakuSource::new(danmaku));
+        let mut sorted = DanmakuTimeChunkProvider::new(test_param(), Box::new(sorted));
+        let mut reversed = DanmakuTimeChunkProvider::new(test_param(), Box::new(reversed));
+        for i in 0..4 {
+            let expected = sorted.get_chunk(&mut shaper, Some(0), i).unwrap();
+            let actual = reversed.get_chunk(&mut shaper, Some(0), i).unwrap();
+            let positions = |items: &[super::PositionedDanmakuItem]| {
+                items
+                    .iter()
+                    .map(|item| format!("{:?} {:?}", item.item.time, item.position))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(positions(&actual.items), positions(&expected.items));
+        }
+    }
+
+    #[test]
+    fn test_chunk_time_overflow() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let param = DanmakuParam {
+            chunk_duration: Duration::from_millis(i64::MAX as u64 / 2),
+            ..test_param()
+        };
+        let source = VecDanmakuSource::new(Vec::new());
+        let mut provider = DanmakuTimeChunkProvider::new(param, Box::new(source));
+        assert!(provider.get_chunk(&mut shaper, None, 1).is_ok());
+        assert!(matches!(
+            provider.get_chunk(&mut shaper, None, 2),
+            Err(ChunkError::TimeOverflow(2))
+        ));
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let danmaku = |millis, r#type, content: &str| {
+            Danmaku::new(DanmakuTime::from_millis(millis), r#type, content)
+        };
+        // Both still on the screen when the chunk #2 starts at 16s
+        let source = VecDanmakuSource::new(vec![
+            danmaku(15_900, DanmakuType::Scroll, "before"),
+            danmaku(15_000, DanmakuType::Top, "before"),
+            danmaku(16_000, DanmakuType::Scroll, "after"),
+            danmaku(17_000, DanmakuType::Top, "after"),
+        ]);
+        let mut provider = DanmakuTimeChunkProvider::new(test_param(), Box::new(source));
+        let chunk = provider.get_chunk(&mut shaper, None, 2).unwrap();
+        assert_eq!(chunk.base_state_index, 2);
+        let positions: Vec<_> = chunk
+            .items
+            .iter()
+            .map(|item| format!("{:?}", item.position))
+            .collect();
+        assert_eq!(positions, vec!["Scroll(1)", "Top(1)"]);
+    }
+
+    #[test]
+    fn test_multi_line() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let danmaku = |millis, r#type, content: &str| {
+            Danmaku::new(DanmakuTime::from_millis(millis), r#type, content)
+        };
+        let source = VecDanmakuSource::new(vec![
+            danmaku(0, DanmakuType::Top, "first\nsecond\r\nthird\n"),
+            danmaku(1, DanmakuType::Top, "next"),
+            danmaku(2, DanmakuType::Bottom, "first\nsecond"),
+            danmaku(3, DanmakuType::Scroll, "first\nsecond"),
+            danmaku(4, DanmakuType::Scroll, "next"),
+        ]);
+        let param = DanmakuParam {
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let mut provider = DanmakuTimeChunkProvider::new(param, Box::new(source));
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+        let positions: Vec<_> = chunk
+            .items
+            .iter()
+            .map(|item| format!("{} {:?}", item.item.lines(), item.position))
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                "3 Top(0)",
+                "1 Top(3)",
+                "2 Bottom(1)",
+                "2 Scroll(0)",
+                "1 Scroll(2)"
+            ]
+        );
+
+        // Each line sits one track below the previous one
+        let item = &chunk.items[0].item;
+        let first_y = item.physical_glyphs.first().unwrap().y;
+        let last_y = item.physical_glyphs.last().unwrap().y;
+        assert_eq!(last_y - first_y, 64);
+        assert!(item.height() > 64);
+    }
+
+    // Fails to shape the lines with the given text
+    struct FailingShaper(CosmicTextShaper, &'static str);
+
+    impl TextShaper for FailingShaper {
+        fn shape_line(
+            &mut self,
+            text: &str,
+            attrs: &AttrsList,
+            font_size: f32,
+        ) -> Option<LayoutLine> {
+            if text == self.1 {
+                return None;
+            }
+            self.0.shape_line(text, attrs, font_size)
+        }
+
+        fn rasterize(&mut self, glyph: CacheKey) -> Option<GlyphBitmap> {
+            self.0.rasterize(glyph)
+        }
+    }
+
+    #[test]
+    fn test_failed_line() {
+        let mut shaper = FailingShaper(CosmicTextShaper::new(FontSystem::new()), "broken");
+        let param = test_param();
+        let mut layout = |content: &str| {
+            LayoutedDanmakuItem::new(
+                &mut shaper,
+                &param.font_attrs,
+                param.font_size,
+                param.scale_factor,
+                param.physical_line_height(),
+                None,
+                &Danmaku::new(DanmakuTime::from_millis(0), DanmakuType::Top, content),
+                param.wide_danmaku,
+                param.max_line_width(),
+            )
+        };
+
+        // The other lines keep their tracks
+        let item = layout("first\nbroken\nthird").unwrap();
+        assert_eq!(item.lines(), 3);
+        assert_eq!(item.layout_lines[1].glyphs.len(), 0);
+        let first_y = item.physical_glyphs.first().unwrap().y;
+        let last_y = item.physical_glyphs.last().unwrap().y;
+        assert_eq!(last_y - first_y, 64);
+
+        assert!(layout("broken").is_none());
+    }
+
+    #[test]
+    fn test_grouped() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let danmaku = |millis, content: &str, group_id| Danmaku {
+            group_id,
+            ..Danmaku::new(
+                DanmakuTime::from_millis(millis),
+                DanmakuType::Scroll,
+                content,
+            )
+        };
+        let source = VecDanmakuSource::new(vec![
+            danmaku(0, "original", Some(1)),
+            danmaku(1, "other", None),
+            danmaku(2, "translation", Some(1)),
+            danmaku(3, "next", Some(2)),
+            danmaku(9000, "next chunk", Some(1)),
+        ]);
+        let param = DanmakuParam {
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let mut provider = DanmakuTimeChunkProvider::new(param, Box::new(source));
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+        let positions: Vec<_> = chunk
+            .items
+            .iter()
+            .map(|item| {
+                let time = item.item.time.as_millis();
+                format!("{} {} {:?}", time, item.item.lines(), item.position)
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            vec!["0 2 Scroll(0)", "1 1 Scroll(2)", "3 1 Scroll(3)"]
+        );
+        let chunk = provider.get_chunk(&mut shaper, Some(0), 1).unwrap();
+        assert_eq!(chunk.items.len(), 1);
+        assert_eq!(chunk.items[0].item.lines(), 1);
+    }
+
+    #[test]
+    fn test_tall_danmaku() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let danmaku = |millis, content: &str| {
+            Danmaku::new(
+                DanmakuTime::from_millis(millis),
+                DanmakuType::Scroll,
+                content,
+            )
+        };
+        let styler = |danmaku: &Danmaku| DanmakuStyle {
+            size_multiplier: if danmaku.content == "tall" { 2.5 } else { 1.0 },
+            ..Default::default()
+        };
+        let generate = |shaper: &mut CosmicTextShaper, tall_danmaku| {
+            let source = VecDanmakuSource::new(vec![danmaku(0, "tall"), danmaku(1, "short")]);
+            let param = DanmakuParam {
+                line_height: LineHeight::Fixed(48),
+                layout_mode: LayoutMode::ShowAll,
+                tall_danmaku,
+                ..test_param()
+            };
+            let mut provider = DanmakuTimeChunkProvider::new(param, Box::new(source))
+                .with_styler(Some(Arc::new(styler)));
+            provider.get_chunk(shaper, None, 0).unwrap()
+        };
+
+        let overflow = generate(&mut shaper, TallDanmaku::Overflow);
+        let positions: Vec<_> = overflow.items.iter().map(|item| item.position).collect();
+        assert_eq!(
+            positions,
+            vec![DanmakuPosition::Scroll(0), DanmakuPosition::Scroll(1)]
+        );
+
+        let expanded = generate(&mut shaper, TallDanmaku::Expand);
+        let positions: Vec<_> = expanded.items.iter().map(|item| item.position).collect();
+        assert_eq!(
+            positions,
+            vec![DanmakuPosition::Scroll(0), DanmakuPosition::Scroll(2)]
+        );
+        let tall = &expanded.items[0].item;
+        assert_eq!((tall.lines(), tall.tracks), (1, 2));
+        // Moved down into the second track, with the background inside of both
+        assert_eq!(
+            tall.physical_glyphs[0].y,
+            overflow.items[0].item.physical_glyphs[0].y + 48
+        );
+        let (_, y, _, height) = tall.background_rect();
+        assert!(y >= -48 && y + height as i32 <= 48);
+        assert_eq!(expanded.items[1].item.tracks, 1);
+    }
+
+    #[test]
+    fn test_positions_at() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let danmaku =
+            |millis, r#type| Danmaku::new(DanmakuTime::from_millis(millis), r#type, "test");
+        let source = VecDanmakuSource::new(vec![
+            danmaku(0, DanmakuType::Scroll),
+            danmaku(1000, DanmakuType::Top),
+            danmaku(2000, DanmakuType::Bottom),
+        ]);
+        let param = test_param();
+        let mut provider = DanmakuTimeChunkProvider::new(param.clone(), Box::new(source));
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+        let width = chunk.items[0].item.width() as f32;
+
+        let at = |millis| {
+            chunk
+                .positions_at(DanmakuTime::from_millis(millis), &param)
+                .map(|(x, y, _)| (x, y))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(at(0), vec![(1280.0, 32.0)]);
+        // Halfway through the 8 second lifetime
+        assert_eq!(
+            at(4000),
+            vec![
+                (1280.0 - (1280.0 + width) / 2.0, 32.0),
+                ((1280.0 - width) / 2.0, 32.0),
+                ((1280.0 - width) / 2.0, 720.0),
+            ]
+        );
+        // The top danmaku has expired after 5 seconds
+        assert_eq!(at(6500).len(), 2);
+        assert_eq!(
+            chunk.visible_count(DanmakuTime::from_millis(6500), &param),
+            2
+        );
+        assert!(at(9000).is_empty());
+    }
+
+    #[test]
+    fn test_wide_danmaku() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let content = "wide ".repeat(100);
+        let layout = |shaper: &mut CosmicTextShaper, wide_danmaku| {
+            let param = DanmakuParam {
+                wide_danmaku,
+                ..test_param()
+            };
+            LayoutedDanmakuItem::new(
+                shaper,
+                &param.font_attrs,
+                param.font_size,
+                param.scale_factor,
+                param.physical_line_height(),
+                None,
+                &Danmaku::new(
+                    DanmakuTime::from_millis(0),
+                    DanmakuType::Scroll,
+                    content.clone(),
+                ),
+                param.wide_danmaku,
+                param.max_line_width(),
+            )
+            .unwrap()
+        };
+
+        let kept = layout(&mut shaper, WideDanmaku::Keep);
+        assert!(kept.width() > 1280);
+        let ellipsized = layout(&mut shaper, WideDanmaku::Ellipsize(0.5));
+        assert_eq!(ellipsized.lines(), 1);
+        assert!(ellipsized.width() <= 640 && ellipsized.width() > 600);
+        let wrapped = layout(&mut shaper, WideDanmaku::Wrap(0.5));
+        assert!(wrapped.lines() > 1);
+        assert!(wrapped.width() <= 640);
+
+        // Wider danmaku scroll as fast as one of half the screen width
+        let param = DanmakuParam {
+            wide_danmaku: WideDanmaku::CapSpeed(0.5),
+            tall_danmaku: TallDanmaku::Overflow,
+            ..test_param()
+        };
+        let position = DanmakuPosition::Scroll(0);
+        assert_eq!(param.item_lifetime(position, 640), Duration::from_secs(8));
+        assert_eq!(param.item_lifetime(position, 2560), Duration::from_secs(16));
+    }
+
+    #[test]
+    fn test_first_visible() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let param = DanmakuParam {
+            chunk_duration: Duration::from_secs(2),
+            scroll_speed: ScrollSpeedModel::ConstantSpeed,
+            ..test_param()
+        };
+        let lookback = param.lookback_chunks();
+        let danmaku = |time, content: &str| {
+            Danmaku::new(DanmakuTime::from_millis(time), DanmakuType::Scroll, content)
+        };
+        let source = VecDanmakuSource::new(vec![
+            danmaku(0, &"wide ".repeat(100)),
+            danmaku(2000, "short"),
+        ]);
+        let mut provider = DanmakuTimeChunkProvider::new(param.clone(), Box::new(source));
+        let chunks: Vec<_> = (0..32)
+            .map(|index| provider.get_chunk(&mut shaper, Some(0), index).unwrap())
+            .collect();
+
+        // The wide danmaku stays on the screen longer than the lookback covers
+        let wide = &chunks[0].items[0];
+        let lifetime = param.item_lifetime(wide.position, wide.item.width());
+        assert_eq!(
+            chunks[0].end_time(),
+            DanmakuTime::from_millis(lifetime.as_millis() as i64)
+        );
+        let last_visible = chunks[0].end_time().as_millis() as u32 / 2000;
+        assert!(
+            last_visible as usize > lookback as usize && (last_visible as usize) + 1 < chunks.len()
+        );
+        assert_eq!(chunks[1].first_visible(), 0);
+        assert_eq!(chunks[last_visible as usize].first_visible(), 0);
+        assert_eq!(
+            chunks[last_visible as usize + 1].first_visible(),
+            last_visible + 1
+        );
+    }
+
+    #[test]
+    fn test_blocked_regions() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let danmaku = |r#type| Danmaku::new(DanmakuTime::from_millis(0), r#type, "blocked");
+        let danmakus = (0..40)
+            .map(|_| danmaku(DanmakuType::Scroll))
+            .chain((0..40).map(|_| danmaku(DanmakuType::Bottom)))
+            .collect();
+        // A banner over the first two tracks, and subtitles from 620 to the bottom
+        let param = DanmakuParam {
+            layout_mode: LayoutMode::ShowAll,
+            blocked_regions: vec![Rect::new(0, 0, 1280, 64), Rect::new(200, 620, 880, 100)],
+            ..test_param()
+        };
+        let mut provider =
+            DanmakuTimeChunkProvider::new(param, Box::new(VecDanmakuSource::new(danmakus)));
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+
+        let mut scroll = BTreeSet::new();
+        let mut bottom = BTreeSet::new();
+        for item in &chunk.items {
+            match item.position {
+                DanmakuPosition::Scroll(track) => scroll.insert(track),
+                DanmakuPosition::Bottom(track) => bottom.insert(track),
+                DanmakuPosition::Top(_) => unreachable!(),
+            };
+        }
+        assert_eq!(scroll, (2..19).collect());
+        assert_eq!(bottom, (4..20).collect());
+
+        // Removing the regions lays the chunk out again
+        provider.set_blocked_regions(Vec::new(), 0);
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+        assert!(chunk
+            .items
+            .iter()
+            .any(|item| matches!(item.position, DanmakuPosition::Scroll(0))));
+    }
+
+    #[test]
+    fn test_static_limit() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let danmaku =
+            |millis| Danmaku::new(DanmakuTime::from_millis(millis), DanmakuType::Top, "flood");
+        let danmakus = (0..40)
+            .map(|_| danmaku(0))
+            .chain((0..40).map(|_| danmaku(6000)))
+            .collect();
+        // Half of the 22 tracks within 5 seconds
+        let param = DanmakuParam {
+            layout_mode: LayoutMode::ShowAll,
+            static_limit: Some(StaticLimit {
+                max_ratio: 0.5,
+                window_millis: 5000,
+            }),
+            ..test_param()
+        };
+        let mut provider =
+            DanmakuTimeChunkProvider::new(param, Box::new(VecDanmakuSource::new(danmakus)));
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+        let count = |millis| {
+            chunk
+                .items
+                .iter()
+                .filter(|item| item.item.time == DanmakuTime::from_millis(millis))
+                .count()
+        };
+        assert_eq!(count(0), 11);
+        assert_eq!(count(6000), 11);
+    }
+
+    #[test]
+    fn test_timed_regions() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let danmaku = |millis| {
+            Danmaku::new(
+                DanmakuTime::from_millis(millis),
+                DanmakuType::Bottom,
+                "subtitle",
+            )
+        };
+        let danmakus = vec![danmaku(0), danmaku(6000)];
+        let param = DanmakuParam {
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let mut provider =
+            DanmakuTimeChunkProvider::new(param, Box::new(VecDanmakuSource::new(danmakus)));
+        // Subtitles from 620 to the bottom, appearing while the first danmaku is shown
+        let regions = vec![TimedRegion {
+            start: DanmakuTime::from_millis(3000),
+            end: DanmakuTime::from_millis(5500),
+            rect: Rect::new(200, 620, 880, 100),
+        }];
+        assert_eq!(provider.set_timed_regions(regions.clone(), 0), Some(0));
+        assert_eq!(provider.set_timed_regions(regions, 0), None);
+        let positions = |provider: &mut DanmakuTimeChunkProvider, shaper: &mut CosmicTextShaper| {
+            let chunk = provider.get_chunk(shaper, None, 0).unwrap();
+            chunk
+                .items
+                .iter()
+                .map(|item| match item.position {
+                    DanmakuPosition::Bottom(track) => track,
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(positions(&mut provider, &mut shaper), vec![4, 0]);
+
+        assert_eq!(provider.set_timed_regions(Vec::new(), 0), Some(0));
+        assert_eq!(positions(&mut provider, &mut shaper), vec![0, 0]);
+    }
 }
@@ -0,0 +1,163 @@
+use std::time::Duration;
+
+use crate::{
+    danmaku::DanmakuTime,
+    layout::DanmakuPosition,
+    manager::{DanmakuTimeChunk, PositionedDanmakuItem},
+    renderer::AnimationParam,
+    worker::DanmakuParam,
+};
+
+// Fades are drawn in steps of this many alpha levels
+const ALPHA_STEPS: u32 = 255;
+
+// Media time from now until the drawn danmaku change by at least a pixel or an alpha step, so
+// hosts can schedule the next redraw instead of drawing at a fixed rate. Zero while something
+// changes every frame, None if nothing in the chunks changes after now. Divide by the playback
+// rate for the wall clock time, and pass the next chunk too so its first danmaku are included.
+pub fn next_change<'a>(
+    chunks: impl IntoIterator<Item = &'a DanmakuTimeChunk>,
+    param: &DanmakuParam,
+    animation: &AnimationParam,
+    now: DanmakuTime,
+) -> Option<Duration> {
+    chunks
+        .into_iter()
+        .flat_map(|chunk| chunk.items.iter())
+        .filter_map(|item| item_next_change(item, param, animation, now))
+        .min()
+}
+
+fn item_next_change(
+    item: &PositionedDanmakuItem,
+    param: &DanmakuParam,
+    animation: &AnimationParam,
+    now: DanmakuTime,
+) -> Option<Duration> {
+    let time = item.item.time;
+    if now < time {
+        return Some(time - now);
+    }
+    let lifetime = param.item_lifetime(item.position, item.item.width());
+    let elapsed = now - time;
+    if elapsed >= lifetime {
+        return None;
+    }
+    let remaining = lifetime - elapsed;
+
+    // Disappears at the end of its lifetime at the latest
+    let mut next = remaining;
+    if let Some(keyframes) = &item.item.animation {
+        let last = keyframes
+            .keyframes()
+            .last()
+            .map_or(0, |keyframe| keyframe.time);
+        if keyframes.looped() || elapsed.as_millis() < last as u128 {
+            return Some(Duration::ZERO);
+        }
+    }
+
+    let fade_in = Duration::from_millis(animation.fade_in_millis as u64);
+    let fade_out = Duration::from_millis(animation.fade_out_millis as u64);
+    if elapsed < fade_in {
+        next = next.min(fade_in / ALPHA_STEPS);
+    }
+    if remaining <= fade_out {
+        next = next.min(fade_out / ALPHA_STEPS);
+    } else if !fade_out.is_zero() {
+        next = next.min(remaining - fade_out);
+    }
+
+    if let DanmakuPosition::Scroll(_) = item.position {
+        // Time to move one physical pixel at the fastest
+        let distance = (param.screen_size.0 + item.item.width()) as f64;
+        let max_speed = animation.scroll_easing.max_speed() as f64;
+        next = next.min(lifetime.div_f64(distance * max_speed));
+    }
+    Some(next)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use cosmic_text::FontSystem;
+
+    use crate::{
+        danmaku::{Danmaku, DanmakuTime, DanmakuType},
+        layout::LayoutMode,
+        manager::DanmakuTimeChunkProvider,
+        position::Easing,
+        renderer::AnimationParam,
+        shaper::CosmicTextShaper,
+        sources::VecDanmakuSource,
+        worker::{test::test_param, DanmakuParam},
+    };
+
+    use super::next_change;
+
+    #[test]
+    fn test_next_change() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let param = DanmakuParam {
+            chunk_duration: Duration::from_secs(30),
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let danmaku = |time, r#type| Danmaku::new(DanmakuTime::from_millis(time), r#type, "pacing");
+        let source = VecDanmakuSource::new(vec![
+            danmaku(1000, DanmakuType::Top),
+            danmaku(10000, DanmakuType::Scroll),
+        ]);
+        let mut provider = DanmakuTimeChunkProvider::new(param.clone(), Box::new(source));
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+        let animation = AnimationParam::default();
+        let at = |millis| {
+            next_change(
+                [chunk.as_ref()],
+                &param,
+                &animation,
+                DanmakuTime::from_millis(millis),
+            )
+        };
+
+        // Waits for the top danmaku to appear and to disappear
+        assert_eq!(at(0), Some(Duration::from_secs(1)));
+        assert_eq!(at(2000), Some(Duration::from_secs(4)));
+        // Scrolling 1280 pixels and the width in 8 seconds moves a pixel in less than 8ms
+        let scrolling = at(11000).unwrap();
+        assert!(scrolling > Duration::from_millis(5) && scrolling < Duration::from_millis(7));
+        assert_eq!(at(20000), None);
+
+        // Eased danmaku move twice as fast at the start
+        let eased = AnimationParam {
+            scroll_easing: Easing::EaseOut(1.0),
+            ..AnimationParam::default()
+        };
+        let eased = next_change(
+            [chunk.as_ref()],
+            &param,
+            &eased,
+            DanmakuTime::from_millis(11000),
+        );
+        assert_eq!(eased, Some(scrolling / 2));
+
+        // Fading changes in small steps
+        let animation = AnimationParam {
+            fade_in_millis: 0,
+            fade_out_millis: 510,
+            transition_millis: 0,
+            scroll_easing: Easing::Linear,
+        };
+        let fading = |millis| {
+            next_change(
+                [chunk.as_ref()],
+                &param,
+                &animation,
+                DanmakuTime::from_millis(millis),
+            )
+        };
+        assert_eq!(fading(2000), Some(Duration::from_millis(3490)));
+        assert_eq!(fading(5500), Some(Duration::from_millis(2)));
+    }
+}
@@ -0,0 +1,513 @@
+// Wires the source, worker, cache and renderer together, for hosts which don't need to
+// customize the individual parts
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use log::warn;
+
+use crate::{
+    danmaku::{Danmaku, DanmakuTime},
+    layout::TimedRegion,
+    renderer::{disk_cache::GlyphDiskCache, RendererParam},
+    sources::DanmakuSource,
+    worker::{DanmakuParam, WorkerBuffer, WorkerError, WorkerManager, WorkerStateBuilder},
+};
+
+#[cfg(feature = "renderer-wgpu")]
+use crate::renderer::wgpu::{
+    wgpu::{Device, Queue, RenderPass, SurfaceConfiguration, TextureView},
+    GpuMemoryUsage, Viewport, WgpuRenderCache, WgpuRenderer, WgpuWorkerBuffer, WgpuWorkerManager,
+};
+#[cfg(feature = "renderer-cairo")]
+use crate::{
+    manager::DanmakuTimeChunk,
+    pacing::next_change,
+    renderer::cairo::{CairoGlyphCache, CairoRenderer, MaskPath, StrideGlyphCache},
+};
+#[cfg(feature = "renderer-cairo")]
+use std::time::Duration;
+
+pub struct DanmakuPipelineBuilder {
+    source: Box<dyn DanmakuSource + Send>,
+    param: DanmakuParam,
+    renderer_param: RendererParam,
+    state_builder: Option<WorkerStateBuilder>,
+    glyph_cache_dir: Option<PathBuf>,
+    #[cfg(feature = "renderer-wgpu")]
+    cpu_atlas_mirror: bool,
+    #[cfg(feature = "renderer-wgpu")]
+    gpu_memory_budget: Option<u64>,
+    #[cfg(feature = "renderer-wgpu")]
+    low_latency: bool,
+}
+
+impl DanmakuPipelineBuilder {
+    pub fn new(
+        source: Box<dyn DanmakuSource + Send>,
+        param: DanmakuParam,
+        renderer_param: RendererParam,
+    ) -> Self {
+        DanmakuPipelineBuilder {
+            source,
+            param,
+            renderer_param,
+            state_builder: None,
+            glyph_cache_dir: None,
+            #[cfg(feature = "renderer-wgpu")]
+            cpu_atlas_mirror: false,
+            #[cfg(feature = "renderer-wgpu")]
+            gpu_memory_budget: None,
+            #[cfg(feature = "renderer-wgpu")]
+            low_latency: false,
+        }
+    }
+
+    // Replaces the screen of the param, e.g. with the size of the window
+    pub fn screen(mut self, screen_size: (u32, u32), scale_factor: f32) -> Self {
+        self.param.screen_size = screen_size;
+        self.param.scale_factor = scale_factor;
+        self
+    }
+
+    pub fn param(&self) -> &DanmakuParam {
+        &self.param
+    }
+
+    // Fonts, emotes and styler of the worker, the system fonts are used by default
+    pub fn state_builder(mut self, state_builder: WorkerStateBuilder) -> Self {
+        self.state_builder = Some(state_builder);
+        self
+    }
+
+    // Keeps the rasterized glyphs in the directory, so the next session starts faster
+    pub fn glyph_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.glyph_cache_dir = Some(dir.into());
+        self
+    }
+
+    // Keeps a copy of the wgpu glyph atlas in memory, so recreate doesn't rasterize the glyphs
+    // again
+    #[cfg(feature = "renderer-wgpu")]
+    pub fn cpu_atlas_mirror(mut self, enabled: bool) -> Self {
+        self.cpu_atlas_mirror = enabled;
+        self
+    }
+
+    // See WgpuRenderCache::set_memory_budget
+    #[cfg(feature = "renderer-wgpu")]
+    pub fn gpu_memory_budget(mut self, bytes: u64) -> Self {
+        self.gpu_memory_budget = Some(bytes);
+        self
+    }
+
+    // Draws the danmaku straight into the render pass of the host, see WgpuRenderer::new_direct
+    #[cfg(feature = "renderer-wgpu")]
+    pub fn low_latency(mut self, enabled: bool) -> Self {
+        self.low_latency = enabled;
+        self
+    }
+
+    fn disk_cache(&self) -> Option<GlyphDiskCache> {
+        let dir = self.glyph_cache_dir.as_ref()?;
+        match GlyphDiskCache::new(dir) {
+            Ok(disk_cache) => Some(disk_cache),
+            Err(err) => {
+                warn!("Failed to open glyph cache {}: {}", dir.display(), err);
+                None
+            }
+        }
+    }
+
+    #[cfg(feature = "renderer-wgpu")]
+    pub fn build_wgpu(
+        self,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        config: &SurfaceConfiguration,
+    ) -> Result<WgpuPipeline, WorkerError> {
+        self.param.validate()?;
+        let mut cache = WgpuRenderCache::new(
+            device.clone(),
+            queue.clone(),
+            (256, 256),
+            self.param.clone(),
+        );
+        if let Some(disk_cache) = self.disk_cache() {
+            cache = cache.with_disk_cache(disk_cache);
+        }
+        if self.cpu_atlas_mirror {
+            cache = cache.with_cpu_mirror();
+        }
+        cache.set_memory_budget(self.gpu_memory_budget);
+        let create = if self.low_latency {
+            WgpuRenderer::new_direct
+        } else {
+            WgpuRenderer::new
+        };
+        let mut renderer = create(
+            config,
+            &device,
+            self.param.clone(),
+            self.renderer_param,
+            &cache,
+        );
+        let buffer = Arc::new(Mutex::new(WorkerBuffer::new(cache)));
+        let state = self
+            .state_builder
+            .unwrap_or_default()
+            .build(buffer.clone(), self.source);
+        let worker = WorkerManager::new(self.param, state);
+        // The line height may only be known once the fonts are loaded
+        renderer.update_danmaku_param(&device, &queue, worker.param().clone());
+        Ok(WgpuPipeline {
+            device,
+            queue,
+            renderer,
+            surfaces: Vec::new(),
+            next_surface_id: 0,
+            buffer,
+            worker,
+        })
+    }
+
+    #[cfg(feature = "renderer-cairo")]
+    pub fn build_cairo(self) -> Result<CairoPipeline, WorkerError> {
+        self.param.validate()?;
+        let mut cache = StrideGlyphCache::new(self.param.clone());
+        if let Some(disk_cache) = self.disk_cache() {
+            cache = cache.with_disk_cache(disk_cache);
+        }
+        let buffer = Arc::new(Mutex::new(WorkerBuffer::new(cache)));
+        let state = self
+            .state_builder
+            .unwrap_or_default()
+            .build(buffer.clone(), self.source);
+        let worker = WorkerManager::new(self.param, state);
+        Ok(CairoPipeline {
+            renderer: CairoRenderer::new(self.renderer_param),
+            glyph_cache: CairoGlyphCache::default(),
+            buffer,
+            worker,
+            now: DanmakuTime::from_millis(0),
+        })
+    }
+}
+
+// An additional output of a WgpuPipeline, see WgpuPipeline::add_surface
+#[cfg(feature = "renderer-wgpu")]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct SurfaceId(u32);
+
+#[cfg(feature = "renderer-wgpu")]
+pub struct WgpuPipeline {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    renderer: WgpuRenderer,
+    // Renderers of the additional surfaces, drawing the chunks of the same buffer
+    surfaces: Vec<(SurfaceId, WgpuRenderer)>,
+    next_surface_id: u32,
+    buffer: Arc<Mutex<WgpuWorkerBuffer>>,
+    worker: WgpuWorkerManager,
+}
+
+#[cfg(feature = "renderer-wgpu")]
+impl WgpuPipeline {
+    // Requests the chunks around the time and renders them into the target texture
+    pub fn tick(&mut self, now: DanmakuTime) -> Result<(), WorkerError> {
+        self.worker.tick(now)?;
+        let buffer = self.buffer.lock().unwrap();
+        self.renderer.update(&self.queue, now);
+        self.renderer
+            .render_buffer(&self.device, &self.queue, &buffer);
+        for (_, renderer) in &mut self.surfaces {
+            renderer.update(&self.queue, now);
+            renderer.render_buffer(&self.device, &self.queue, &buffer);
+        }
+        Ok(())
+    }
+
+    // Composites the frame of the last tick into the render pass, or draws it in low latency
+    // mode
+    pub fn render(&self, render_pass: &mut RenderPass, viewport: Option<Viewport>) {
+        self.render_with(&self.renderer, render_pass, viewport)
+    }
+
+    fn render_with(
+        &self,
+        renderer: &WgpuRenderer,
+        render_pass: &mut RenderPass,
+        viewport: Option<Viewport>,
+    ) {
+        if renderer.is_direct() {
+            let buffer = self.buffer.lock().unwrap();
+            renderer.render_buffer_direct(render_pass, &buffer, viewport);
+        } else {
+            renderer.render(render_pass, viewport);
+        }
+    }
+
+    // Draws the same danmaku on another surface, e.g. a window on an external display. It has its
+    // own format and renderer param, and shares the glyph atlas and the vertex buffers with the
+    // main one. The danmaku are laid out for the screen of the param and scaled to the viewport.
+    pub fn add_surface(
+        &mut self,
+        config: &SurfaceConfiguration,
+        renderer_param: RendererParam,
+    ) -> SurfaceId {
+        let buffer = self.buffer.lock().unwrap();
+        let create = if self.renderer.is_direct() {
+            WgpuRenderer::new_direct
+        } else {
+            WgpuRenderer::new
+        };
+        let renderer = create(
+            config,
+            &self.device,
+            self.worker.param().clone(),
+            renderer_param,
+            &buffer.cache,
+        );
+        drop(buffer);
+        let id = SurfaceId(self.next_surface_id);
+        self.next_surface_id += 1;
+        self.surfaces.push((id, renderer));
+        id
+    }
+
+    pub fn remove_surface(&mut self, id: SurfaceId) {
+        self.surfaces.retain(|(surface, _)| *surface != id);
+    }
+
+    // Same as render for an added surface, nothing is drawn for removed ones
+    pub fn render_surface(
+        &self,
+        id: SurfaceId,
+        render_pass: &mut RenderPass,
+        viewport: Option<Viewport>,
+    ) {
+        if let Some((_, renderer)) = self.surfaces.iter().find(|(surface, _)| *surface == id) {
+            self.render_with(renderer, render_pass, viewport)
+        }
+    }
+
+    pub fn surface_renderer(&mut self, id: SurfaceId) -> Option<&mut WgpuRenderer> {
+        self.surfaces
+            .iter_mut()
+            .find(|(surface, _)| *surface == id)
+            .map(|(_, renderer)| renderer)
+    }
+
+    pub fn set_surface_renderer_param(&mut self, id: SurfaceId, renderer_param: RendererParam) {
+        if let Some((_, renderer)) = self.surfaces.iter_mut().find(|(surface, _)| *surface == id) {
+            renderer.update_renderer_param(&self.device, &self.queue, renderer_param);
+        }
+    }
+
+    pub fn set_param(&mut self, param: DanmakuParam) -> Result<(), WorkerError> {
+        self.worker.change_param(param)?;
+        let param = self.worker.param();
+        self.renderer
+            .update_danmaku_param(&self.device, &self.queue, param.clone());
+        for (_, renderer) in &mut self.surfaces {
+            renderer.update_danmaku_param(&self.device, &self.queue, param.clone());
+        }
+        Ok(())
+    }
+
+    pub fn set_renderer_param(&mut self, renderer_param: RendererParam) {
+        self.renderer
+            .update_renderer_param(&self.device, &self.queue, renderer_param);
+    }
+
+    // See WgpuRenderer::set_mask, for every surface
+    pub fn set_mask(&mut self, mask: Option<&TextureView>) {
+        self.renderer.set_mask(&self.device, mask);
+        for (_, renderer) in &mut self.surfaces {
+            renderer.set_mask(&self.device, mask);
+        }
+    }
+
+    // See WgpuRenderer::write_mask, for every surface
+    pub fn write_mask(&mut self, size: (u32, u32), data: &[u8]) {
+        self.renderer
+            .write_mask(&self.device, &self.queue, size, data);
+        for (_, renderer) in &mut self.surfaces {
+            renderer.write_mask(&self.device, &self.queue, size, data);
+        }
+    }
+
+    // See WgpuRenderer::set_video_frame, for the main surface only
+    pub fn set_video_frame(&mut self, video: Option<&TextureView>) {
+        self.renderer.set_video_frame(&self.device, video);
+    }
+
+    // Moves everything to a new device after the old one was lost, keeping the worker and its
+    // chunks
+    pub fn recreate(&mut self, device: Arc<Device>, queue: Arc<Queue>) -> Result<(), WorkerError> {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.cache.recreate(device.clone(), queue.clone());
+        buffer.clear_chunks();
+        self.renderer.recreate(&device, &buffer.cache);
+        for (_, renderer) in &mut self.surfaces {
+            renderer.recreate(&device, &buffer.cache);
+        }
+        drop(buffer);
+        self.device = device;
+        self.queue = queue;
+        self.worker.refresh()
+    }
+
+    pub fn insert_local(&mut self, danmaku: Danmaku) -> Result<(), WorkerError> {
+        self.worker.insert_local(danmaku)
+    }
+
+    // See WorkerManager::set_timed_regions
+    pub fn set_timed_regions(&mut self, regions: Vec<TimedRegion>) -> Result<(), WorkerError> {
+        self.worker.set_timed_regions(regions)
+    }
+
+    // See WorkerManager::start_indexing
+    pub fn start_indexing(&mut self) -> Result<(), WorkerError> {
+        self.worker.start_indexing()
+    }
+
+    pub fn cancel_indexing(&mut self) -> Result<(), WorkerError> {
+        self.worker.cancel_indexing()
+    }
+
+    // With the line height resolved
+    pub fn param(&self) -> &DanmakuParam {
+        self.worker.param()
+    }
+
+    pub fn renderer(&mut self) -> &mut WgpuRenderer {
+        &mut self.renderer
+    }
+
+    pub fn worker(&self) -> &WgpuWorkerManager {
+        &self.worker
+    }
+
+    // Of the glyph atlas and the cached chunks, for diagnostics
+    pub fn memory_usage(&self) -> GpuMemoryUsage {
+        self.buffer.lock().unwrap().cache.memory_usage()
+    }
+}
+
+#[cfg(feature = "renderer-cairo")]
+pub struct CairoPipeline {
+    renderer: CairoRenderer,
+    glyph_cache: CairoGlyphCache,
+    buffer: Arc<Mutex<WorkerBuffer<StrideGlyphCache, DanmakuTimeChunk>>>,
+    worker: WorkerManager<StrideGlyphCache, DanmakuTimeChunk>,
+    now: DanmakuTime,
+}
+
+#[cfg(feature = "renderer-cairo")]
+impl CairoPipeline {
+    // Requests the chunks around the time, which the next render draws
+    pub fn tick(&mut self, now: DanmakuTime) -> Result<(), WorkerError> {
+        self.worker.tick(now)?;
+        self.now = now;
+        Ok(())
+    }
+
+    pub fn render(&mut self, context: &cairo::Context) -> Result<(), cairo::Error> {
+        let param = self.worker.param();
+        let buffer = self.buffer.lock().unwrap();
+        let index = param.chunk_index(self.now);
+        let (first, second) = match buffer.acquire_index(index) {
+            Some(chunks) => chunks,
+            None => return Ok(()),
+        };
+        let chunks = buffer
+            .history
+            .iter()
+            .map(|chunk| chunk.as_ref())
+            .chain([first, second]);
+        for chunk in chunks {
+            self.renderer.draw_chunk(
+                param,
+                chunk,
+                &buffer.cache,
+                &mut self.glyph_cache,
+                context,
+                self.now,
+            )?;
+        }
+        Ok(())
+    }
+
+    // The frame of the last tick as a recording surface of the output size, for GTK snapshots or
+    // scene graphs which keep the danmaku layer and replay it until the next tick
+    pub fn record(&mut self) -> Result<cairo::RecordingSurface, cairo::Error> {
+        let surface = self.renderer.recording_surface(self.worker.param())?;
+        self.render(&cairo::Context::new(&surface)?)?;
+        Ok(surface)
+    }
+
+    // Media time from the last tick until the drawn danmaku change, see pacing::next_change.
+    // Zero while the chunk of the time isn't generated yet or the opacity is in transition.
+    pub fn next_change(&self) -> Option<Duration> {
+        if self.renderer.is_transitioning() {
+            return Some(Duration::ZERO);
+        }
+        let param = self.worker.param();
+        let buffer = self.buffer.lock().unwrap();
+        let index = param.chunk_index(self.now);
+        let (first, second) = match buffer.acquire_index(index) {
+            Some(chunks) => chunks,
+            None => return Some(Duration::ZERO),
+        };
+        let chunks = buffer
+            .history
+            .iter()
+            .map(|chunk| chunk.as_ref())
+            .chain([first, second])
+            .chain(buffer.next.as_deref());
+        let animation = &self.renderer.renderer_param().animation;
+        next_change(chunks, param, animation, self.now)
+    }
+
+    pub fn set_param(&mut self, param: DanmakuParam) -> Result<(), WorkerError> {
+        self.worker.change_param(param)
+    }
+
+    pub fn set_renderer_param(&mut self, renderer_param: RendererParam) {
+        self.renderer.update_renderer_param(renderer_param);
+    }
+
+    // See CairoRenderer::set_mask
+    pub fn set_mask(&mut self, mask: Vec<MaskPath>) {
+        self.renderer.set_mask(mask);
+    }
+
+    pub fn insert_local(&mut self, danmaku: Danmaku) -> Result<(), WorkerError> {
+        self.worker.insert_local(danmaku)
+    }
+
+    // See WorkerManager::set_timed_regions
+    pub fn set_timed_regions(&mut self, regions: Vec<TimedRegion>) -> Result<(), WorkerError> {
+        self.worker.set_timed_regions(regions)
+    }
+
+    // See WorkerManager::start_indexing
+    pub fn start_indexing(&mut self) -> Result<(), WorkerError> {
+        self.worker.start_indexing()
+    }
+
+    pub fn cancel_indexing(&mut self) -> Result<(), WorkerError> {
+        self.worker.cancel_indexing()
+    }
+
+    // With the line height resolved
+    pub fn param(&self) -> &DanmakuParam {
+        self.worker.param()
+    }
+
+    pub fn worker(&self) -> &WorkerManager<StrideGlyphCache, DanmakuTimeChunk> {
+        &self.worker
+    }
+}
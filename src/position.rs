@@ -0,0 +1,235 @@
+// Where the danmaku are on the screen over their lifetime. The cairo renderer and the svg exporter
+// call these directly, vs_main in vertex.wgsl computes the same in whole pixels, and the track
+// layout predicts scroll overlaps with the speed the scroll position moves at.
+use std::time::Duration;
+
+use crate::{danmaku::DanmakuTime, layout::DanmakuPosition, worker::DanmakuParam};
+
+// Share of the lifetime passed at the time, None when the item isn't on the screen
+pub fn progress(time: DanmakuTime, lifetime: Duration, now: DanmakuTime) -> Option<f32> {
+    if now < time || now - time >= lifetime {
+        return None;
+    }
+    Some((now.as_millis() - time.as_millis()) as f32 / lifetime.as_millis() as f32)
+}
+
+// Progress of the scroll danmaku across the screen as drawn. The layout keeps the danmaku apart at
+// linear progress, so eased danmaku of a track may come closer to each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Easing {
+    #[default]
+    Linear,
+    // Decelerates towards the end, mixed with linear by the strength from 0 to 1
+    EaseOut(f32),
+    // Accelerates at the start and decelerates towards the end, mixed like EaseOut
+    EaseInOut(f32),
+}
+
+impl Easing {
+    pub fn apply(&self, progress: f32) -> f32 {
+        let (curve, strength) = match *self {
+            Easing::Linear => return progress,
+            Easing::EaseOut(strength) => (1.0 - (1.0 - progress) * (1.0 - progress), strength),
+            Easing::EaseInOut(strength) => (progress * progress * (3.0 - 2.0 * progress), strength),
+        };
+        progress + (curve - progress) * strength.clamp(0.0, 1.0)
+    }
+
+    // Largest speed as a multiple of the linear one
+    pub fn max_speed(&self) -> f32 {
+        match *self {
+            Easing::Linear => 1.0,
+            Easing::EaseOut(strength) => 1.0 + strength.clamp(0.0, 1.0),
+            Easing::EaseInOut(strength) => 1.0 + strength.clamp(0.0, 1.0) / 2.0,
+        }
+    }
+}
+
+// Enters from the right edge and leaves past the left edge
+pub fn scroll_x(screen_width: u32, width: u32, progress: f32) -> f32 {
+    screen_width as f32 - (screen_width + width) as f32 * progress
+}
+
+pub fn centered_x(screen_width: u32, width: u32) -> f32 {
+    (screen_width as f32 - width as f32) / 2.0
+}
+
+// Baseline of the first line, counted from the top
+pub fn top_y(track: usize, line_height: u32) -> f32 {
+    (track as f32 + 1.0) * line_height as f32
+}
+
+// Baseline of the first line, counted from the bottom
+pub fn bottom_y(screen_height: u32, track: usize, line_height: u32) -> f32 {
+    screen_height as f32 - track as f32 * line_height as f32
+}
+
+// Physical position of the left end of the first baseline
+pub fn baseline(
+    position: DanmakuPosition,
+    width: u32,
+    progress: f32,
+    param: &DanmakuParam,
+) -> (f32, f32) {
+    let (screen_width, screen_height) = param.screen_size;
+    let line_height = param.physical_line_height();
+    match position {
+        DanmakuPosition::Scroll(track) => (
+            scroll_x(screen_width, width, progress),
+            top_y(track, line_height),
+        ),
+        DanmakuPosition::Top(track) => (centered_x(screen_width, width), top_y(track, line_height)),
+        DanmakuPosition::Bottom(track) => (
+            centered_x(screen_width, width),
+            bottom_y(screen_height, track, line_height),
+        ),
+    }
+}
+
+// Where the layout expects the item, at linear progress
+pub fn position_at(
+    position: DanmakuPosition,
+    width: u32,
+    time: DanmakuTime,
+    now: DanmakuTime,
+    param: &DanmakuParam,
+) -> Option<(f32, f32)> {
+    eased_position_at(position, width, time, now, param, Easing::Linear)
+}
+
+// Where the renderers draw the item with the easing of AnimationParam::scroll_easing
+pub fn eased_position_at(
+    position: DanmakuPosition,
+    width: u32,
+    time: DanmakuTime,
+    now: DanmakuTime,
+    param: &DanmakuParam,
+    easing: Easing,
+) -> Option<(f32, f32)> {
+    let lifetime = param.item_lifetime(position, width);
+    let progress = progress(time, lifetime, now)?;
+    Some(baseline(position, width, easing.apply(progress), param))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        danmaku::DanmakuTime,
+        layout::{DanmakuPosition, LayoutMode, ScrollSpeedModel},
+        worker::{test::test_param, DanmakuParam, WideDanmaku},
+    };
+
+    use super::{eased_position_at, position_at, progress, Easing};
+
+    fn scroll_param(scroll_speed: ScrollSpeedModel, wide_danmaku: WideDanmaku) -> DanmakuParam {
+        DanmakuParam {
+            layout_mode: LayoutMode::ShowAll,
+            scroll_speed,
+            wide_danmaku,
+            scale_factor: 1.5,
+            ..test_param()
+        }
+    }
+
+    #[test]
+    fn test_progress() {
+        let time = DanmakuTime::from_millis(1000);
+        let lifetime = Duration::from_secs(4);
+        let at = |millis| progress(time, lifetime, DanmakuTime::from_millis(millis));
+        assert_eq!(at(999), None);
+        assert_eq!(at(1000), Some(0.0));
+        assert_eq!(at(3000), Some(0.5));
+        assert_eq!(at(5000), None);
+    }
+
+    #[test]
+    fn test_static_positions() {
+        let param = scroll_param(ScrollSpeedModel::ConstantDuration, WideDanmaku::Keep);
+        let time = DanmakuTime::from_millis(0);
+        let now = DanmakuTime::from_millis(4000);
+        // Line height 32 at scale 1.5
+        assert_eq!(
+            position_at(DanmakuPosition::Top(2), 200, time, now, &param),
+            Some((540.0, 144.0))
+        );
+        assert_eq!(
+            position_at(DanmakuPosition::Bottom(0), 200, time, now, &param),
+            Some((540.0, 720.0))
+        );
+        assert_eq!(
+            position_at(DanmakuPosition::Bottom(2), 200, time, now, &param),
+            Some((540.0, 624.0))
+        );
+        let gone = DanmakuTime::from_millis(5000);
+        assert_eq!(
+            position_at(DanmakuPosition::Top(0), 200, time, gone, &param),
+            None
+        );
+    }
+
+    // The layout keeps scroll danmaku apart assuming they move at the speed of its model
+    #[test]
+    fn test_scroll_speed_matches_layout() {
+        let width = 400;
+        for (scroll_speed, wide_danmaku) in [
+            (ScrollSpeedModel::ConstantDuration, WideDanmaku::Keep),
+            (ScrollSpeedModel::ConstantSpeed, WideDanmaku::Keep),
+            (
+                ScrollSpeedModel::ConstantDuration,
+                WideDanmaku::CapSpeed(0.25),
+            ),
+        ] {
+            let param = scroll_param(scroll_speed, wide_danmaku);
+            let time = DanmakuTime::from_millis(0);
+            let x_at = |millis| {
+                position_at(
+                    DanmakuPosition::Scroll(0),
+                    width,
+                    time,
+                    DanmakuTime::from_millis(millis),
+                    &param,
+                )
+                .unwrap()
+                .0
+            };
+            assert_eq!(x_at(0), 1280.0);
+            let speed = (x_at(0) - x_at(1000)) as f64 / 1000.0;
+            let expected =
+                param
+                    .scroll_speed_model()
+                    .speed(param.screen_size.0, width, param.scroll_lifetime);
+            assert!((speed - expected).abs() < 1e-3, "{speed} != {expected}");
+        }
+    }
+
+    #[test]
+    fn test_easing() {
+        for easing in [Easing::Linear, Easing::EaseOut(0.5), Easing::EaseInOut(1.0)] {
+            assert_eq!(easing.apply(0.0), 0.0);
+            assert_eq!(easing.apply(1.0), 1.0);
+            // Never faster than max_speed, and never moving backwards
+            let steps = 1000;
+            for step in 0..steps {
+                let from = easing.apply(step as f32 / steps as f32);
+                let to = easing.apply((step + 1) as f32 / steps as f32);
+                assert!(to >= from);
+                assert!((to - from) * steps as f32 <= easing.max_speed() + 1e-3);
+            }
+        }
+        // Ahead of linear, slowing down near the end
+        let easing = Easing::EaseOut(1.0);
+        assert_eq!(easing.apply(0.5), 0.75);
+
+        let param = scroll_param(ScrollSpeedModel::ConstantDuration, WideDanmaku::Keep);
+        let time = DanmakuTime::from_millis(0);
+        let now = DanmakuTime::from_millis(4000);
+        let scroll = DanmakuPosition::Scroll(0);
+        let linear = position_at(scroll, 200, time, now, &param).unwrap();
+        let eased = eased_position_at(scroll, 200, time, now, &param, easing).unwrap();
+        assert_eq!(linear.1, eased.1);
+        assert!(eased.0 < linear.0);
+    }
+}
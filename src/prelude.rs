@@ -0,0 +1,43 @@
+// The types most players need, for `use danmaku_renderer::prelude::*`. The cosmic-text types for
+// building a DanmakuParam come from the version this crate is built with.
+pub use crate::{
+    clock::{PlaybackClock, SharedPlayback},
+    danmaku::{Danmaku, DanmakuColor, DanmakuSize, DanmakuTime, DanmakuType},
+    filter::{DanmakuFilter, MergeFilter, SimpleFilter},
+    layout::{LayoutMode, ScrollSpeedModel},
+    renderer::RendererParam,
+    shaper::{CosmicTextShaper, TextShaper},
+    sources::{
+        bilibili::{parse_proto, parse_xml_from_file},
+        filtered::FilteredDanmakuSource,
+        merged::MergedDanmakuSource,
+        offset::OffsetDanmakuSource,
+        DanmakuSource, VecDanmakuSource,
+    },
+    worker::{
+        DanmakuParam, LineHeight, ShadowKernel, TallDanmaku, WideDanmaku, WorkerError, WorkerEvent,
+        WorkerManager, WorkerStateBuilder,
+    },
+    Attrs, AttrsList, Family, FontSystem, Weight,
+};
+
+#[cfg(feature = "regex")]
+pub use crate::filter::RegexFilter;
+
+#[cfg(any(feature = "renderer-cairo", feature = "renderer-wgpu"))]
+pub use crate::pipeline::DanmakuPipelineBuilder;
+
+#[cfg(feature = "renderer-cairo")]
+pub use crate::{pipeline::CairoPipeline, renderer::cairo::CairoRenderer};
+
+#[cfg(feature = "renderer-wgpu")]
+pub use crate::{
+    pipeline::WgpuPipeline,
+    renderer::wgpu::{WgpuRenderCache, WgpuRenderer, WgpuVertexBuffer, WgpuWorkerManager},
+};
+
+#[cfg(feature = "gtk4")]
+pub use crate::renderer::gtk4::DanmakuArea;
+
+#[cfg(feature = "winit")]
+pub use crate::renderer::winit::WinitOverlay;
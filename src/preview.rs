@@ -0,0 +1,144 @@
+// A single frame of the danmaku at a time, e.g. for thumbnails in file managers or web services.
+// Only the chunks on the screen at the time are laid out, without a worker thread, so the layout
+// may differ from the one of a playback which reached the time from the start.
+use std::{error::Error, fmt::Display};
+
+use cairo::{BorrowError, Context, Format, ImageSurface};
+
+use crate::{
+    danmaku::DanmakuTime,
+    manager::{ChunkError, DanmakuTimeChunkProvider},
+    renderer::{
+        cairo::{CairoGlyphCache, CairoRenderer, StrideGlyphCache},
+        RendererParam, RgbaImage,
+    },
+    shaper::TextShaper,
+    sources::DanmakuSource,
+    worker::{DanmakuParam, DanmakuParamError, RenderCache},
+};
+
+#[derive(Debug)]
+pub enum PreviewError {
+    InvalidParam(DanmakuParamError),
+    Chunk(ChunkError),
+    Cairo(cairo::Error),
+    Surface(BorrowError),
+}
+
+impl Display for PreviewError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreviewError::InvalidParam(err) => write!(f, "Invalid danmaku param: {}", err),
+            PreviewError::Chunk(err) => write!(f, "Failed to lay out the danmaku: {}", err),
+            PreviewError::Cairo(err) => write!(f, "Failed to draw the danmaku: {}", err),
+            PreviewError::Surface(err) => write!(f, "Failed to read the image: {}", err),
+        }
+    }
+}
+
+impl Error for PreviewError {}
+
+impl From<DanmakuParamError> for PreviewError {
+    fn from(err: DanmakuParamError) -> Self {
+        PreviewError::InvalidParam(err)
+    }
+}
+
+impl From<ChunkError> for PreviewError {
+    fn from(err: ChunkError) -> Self {
+        PreviewError::Chunk(err)
+    }
+}
+
+impl From<cairo::Error> for PreviewError {
+    fn from(err: cairo::Error) -> Self {
+        PreviewError::Cairo(err)
+    }
+}
+
+impl From<BorrowError> for PreviewError {
+    fn from(err: BorrowError) -> Self {
+        PreviewError::Surface(err)
+    }
+}
+
+// Renders the danmaku on the screen at the time into an image of the output size of the
+// orientation, transparent where there are no danmaku. The shaper can be reused for previews of
+// other sources.
+pub fn preview(
+    source: Box<dyn DanmakuSource + Send>,
+    param: DanmakuParam,
+    renderer_param: &RendererParam,
+    shaper: &mut dyn TextShaper,
+    time: DanmakuTime,
+) -> Result<RgbaImage, PreviewError> {
+    param.validate()?;
+    let param = param.resolve_line_height(shaper);
+    let index = param.chunk_index(time);
+    let first = index.saturating_sub(param.lookback_chunks());
+    let mut provider = DanmakuTimeChunkProvider::new(param.clone(), source);
+
+    // The layout starts over at the first chunk of the window
+    let mut chunks = Vec::new();
+    let mut base_state_index = None;
+    for index in first..=index {
+        let chunk = provider.get_chunk(shaper, base_state_index, index)?;
+        base_state_index = Some(chunk.base_state_index);
+        chunks.push(chunk);
+    }
+    // Wide danmaku from before the window may still be on the screen
+    let first_visible = chunks.last().unwrap().first_visible();
+    if first_visible < first {
+        let mut older = Vec::new();
+        for index in first_visible..first {
+            older.push(provider.get_chunk(shaper, base_state_index, index)?);
+        }
+        chunks.splice(0..0, older);
+    }
+
+    let mut glyph_cache = StrideGlyphCache::new(param.clone());
+    for chunk in &chunks {
+        glyph_cache.prepare(shaper, chunk);
+    }
+    glyph_cache.flush();
+
+    let (width, height) = renderer_param.orientation.output_size(param.screen_size);
+    let surface = ImageSurface::create(Format::ARgb32, width as i32, height as i32)?;
+    let context = Context::new(&surface)?;
+    let renderer = CairoRenderer::new(renderer_param.clone());
+    let mut cairo_glyph_cache = CairoGlyphCache::default();
+    for chunk in &chunks {
+        renderer.draw_chunk(
+            &param,
+            chunk,
+            &glyph_cache,
+            &mut cairo_glyph_cache,
+            &context,
+            time,
+        )?;
+    }
+    drop(context);
+
+    let premultiplied = renderer_param.premultiplied_alpha;
+    let stride = surface.stride() as usize;
+    let mut data = Vec::with_capacity((width * height * 4) as usize);
+    surface.with_data(|surface_data| {
+        for row in surface_data.chunks(stride).take(height as usize) {
+            for pixel in row[..width as usize * 4].chunks_exact(4) {
+                data.extend(unpack_argb32(pixel, premultiplied));
+            }
+        }
+    })?;
+    Ok(RgbaImage::new(width, height, data))
+}
+
+// Pixels of cairo are premultiplied ARGB in native endian words
+fn unpack_argb32(pixel: &[u8], premultiplied: bool) -> [u8; 4] {
+    let argb = u32::from_ne_bytes(pixel.try_into().unwrap());
+    let [a, r, g, b] = argb.to_be_bytes();
+    if premultiplied || a == 0 {
+        return [r, g, b, a];
+    }
+    let unpremultiply = |channel: u8| ((channel as u32 * 255 + a as u32 / 2) / a as u32) as u8;
+    [unpremultiply(r), unpremultiply(g), unpremultiply(b), a]
+}
@@ -1,16 +1,38 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
-use cairo::{Context, Format, ImageSurface, Operator, SurfacePattern};
-use cosmic_text::{CacheKey, FontSystem, Placement, SwashCache, SwashContent};
+use cairo::{
+    Content, Context, FillRule, Format, ImageSurface, LinearGradient, Operator, RecordingSurface,
+    Rectangle, SurfacePattern,
+};
+use cosmic_text::{CacheKey, Placement, SwashContent};
 
 use crate::{
-    danmaku::DanmakuTime,
-    layout::DanmakuPosition,
+    danmaku::{DanmakuColor, DanmakuTime},
+    emote::EmoteImage,
     manager::DanmakuTimeChunk,
+    shaper::{GlyphBitmap, TextShaper},
     worker::{DanmakuParam, RenderCache},
 };
 
-use super::RendererParam;
+use super::{
+    disk_cache::{self, GlyphDiskCache},
+    BlendMode, RendererParam, ShadowColor, Transition,
+};
+
+// Offsets of the glyph copies making up the outline, in shadow sizes
+const STROKE_DIRECTIONS: [(f64, f64); 8] = [
+    (-1.0, -1.0),
+    (0.0, -1.0),
+    (1.0, -1.0),
+    (-1.0, 0.0),
+    (1.0, 0.0),
+    (-1.0, 1.0),
+    (0.0, 1.0),
+    (1.0, 1.0),
+];
 
 #[derive(Clone)]
 struct ImageData {
@@ -29,50 +51,61 @@ enum GlyphImage {
 
 pub struct StrideGlyphCache {
     images: HashMap<CacheKey, Option<(GlyphImage, Placement)>>,
-    swash_cache: SwashCache,
+    emotes: HashMap<String, ImageData>,
+    disk_cache: Option<GlyphDiskCache>,
     danmaku_param: DanmakuParam,
+    // Bumped when the glyph images are dropped, so the surfaces made from them are dropped too
+    generation: u64,
 }
 
 impl StrideGlyphCache {
     pub fn new(param: DanmakuParam) -> Self {
         Self {
             images: Default::default(),
-            swash_cache: SwashCache::new(),
+            emotes: Default::default(),
+            disk_cache: None,
             danmaku_param: param,
+            generation: 0,
         }
     }
+
+    // Loads the rasterized glyphs from the disk cache, and saves the new ones into it
+    pub fn with_disk_cache(mut self, disk_cache: GlyphDiskCache) -> Self {
+        self.disk_cache = Some(disk_cache);
+        self
+    }
 }
 
 impl RenderCache for StrideGlyphCache {
     fn new_param(&mut self, new_param: DanmakuParam) {
-        if (new_param.font_size != self.danmaku_param.font_size)
-            || (new_param.font_attrs != self.danmaku_param.font_attrs)
-        {
-            self.swash_cache.image_cache.clear();
-            self.swash_cache.outline_command_cache.clear();
+        if new_param.font_changed(&self.danmaku_param) {
+            self.images.clear();
+            self.generation += 1;
         }
         self.danmaku_param = new_param;
     }
 
-    fn prepare(&mut self, font_system: &mut FontSystem, chunk: &DanmakuTimeChunk) {
-        for glyph in chunk.glyph_ids() {
-            if !self.images.contains_key(glyph) {
-                self.images.insert(
-                    *glyph,
-                    Self::generate(&mut self.swash_cache, font_system, *glyph),
-                );
+    fn prepare(&mut self, shaper: &mut dyn TextShaper, chunk: &DanmakuTimeChunk) {
+        let missing: Vec<_> = chunk
+            .glyph_ids()
+            .filter(|glyph| !self.images.contains_key(glyph))
+            .copied()
+            .collect();
+        let images = disk_cache::get_images(self.disk_cache.as_mut(), shaper, &missing);
+        for (glyph, image) in missing.into_iter().zip(images) {
+            self.images.insert(glyph, image.map(Self::generate));
+        }
+        for (shortcode, image) in chunk.emotes() {
+            if !self.emotes.contains_key(shortcode) {
+                self.emotes
+                    .insert(shortcode.to_string(), Self::generate_emote(image));
             }
         }
     }
 }
 
 impl StrideGlyphCache {
-    fn generate(
-        swash_cache: &mut SwashCache,
-        font_system: &mut FontSystem,
-        glyph: CacheKey,
-    ) -> Option<(GlyphImage, Placement)> {
-        let image = swash_cache.get_image_uncached(font_system, glyph)?;
+    fn generate(image: GlyphBitmap) -> (GlyphImage, Placement) {
         let width = image.placement.width;
         let height = image.placement.height;
 
@@ -131,12 +164,47 @@ impl StrideGlyphCache {
                 })
             }
         };
-        Some((surface, image.placement))
+        (surface, image.placement)
+    }
+
+    fn generate_emote(image: &EmoteImage) -> ImageData {
+        let width = image.width();
+        let height = image.height();
+        let format = Format::ARgb32;
+        let stride = format.stride_for_width(width).unwrap() as u32;
+        let mut data: Vec<u8> = Vec::with_capacity((stride * height) as usize);
+
+        // ARgb32 stores premultiplied pixels as native endian u32
+        for row in image.data().chunks_exact((width * 4) as usize) {
+            let row_start = data.len();
+            for pixel in row.chunks_exact(4) {
+                let alpha = pixel[3] as u32;
+                let premultiply = |value: u8| (value as u32 * alpha + 127) / 255;
+                let argb = alpha << 24
+                    | premultiply(pixel[0]) << 16
+                    | premultiply(pixel[1]) << 8
+                    | premultiply(pixel[2]);
+                data.extend_from_slice(&argb.to_ne_bytes());
+            }
+            data.resize(row_start + stride as usize, 0);
+        }
+
+        ImageData {
+            format,
+            width,
+            height,
+            stride,
+            data,
+        }
     }
 
     fn get(&self, glyph: CacheKey) -> Option<&(GlyphImage, Placement)> {
         self.images.get(&glyph).and_then(|item| item.as_ref())
     }
+
+    fn get_emote(&self, shortcode: &str) -> Option<&ImageData> {
+        self.emotes.get(shortcode)
+    }
 }
 
 enum CairoGlyphImage {
@@ -174,9 +242,13 @@ impl TryFrom<GlyphImage> for CairoGlyphImage {
     }
 }
 
+// Surfaces for a cairo context made from the images of a StrideGlyphCache, dropped together with
+// the images when the font changes
 #[derive(Default)]
 pub struct CairoGlyphCache {
     surfaces: HashMap<CacheKey, Option<(CairoGlyphImage, Placement)>>,
+    emote_surfaces: HashMap<String, Option<ImageSurface>>,
+    generation: u64,
 }
 
 impl CairoGlyphCache {
@@ -185,6 +257,10 @@ impl CairoGlyphCache {
         cache: &StrideGlyphCache,
         glyph: CacheKey,
     ) -> Option<&(CairoGlyphImage, Placement)> {
+        if self.generation != cache.generation {
+            self.surfaces.clear();
+            self.generation = cache.generation;
+        }
         self.surfaces
             .entry(glyph)
             .or_insert_with(|| {
@@ -196,21 +272,129 @@ impl CairoGlyphCache {
             })
             .as_ref()
     }
+
+    fn get_emote(&mut self, cache: &StrideGlyphCache, shortcode: &str) -> Option<&ImageSurface> {
+        if !self.emote_surfaces.contains_key(shortcode) {
+            let surface = cache.get_emote(shortcode).and_then(|image| {
+                let image = image.clone();
+                ImageSurface::create_for_data(
+                    image.data,
+                    image.format,
+                    image.width as i32,
+                    image.height as i32,
+                    image.stride as i32,
+                )
+                .ok()
+            });
+            self.emote_surfaces.insert(shortcode.to_string(), surface);
+        }
+        self.emote_surfaces
+            .get(shortcode)
+            .and_then(|surface| surface.as_ref())
+    }
 }
 
+// Closed polygon in physical pixels of the screen
+pub type MaskPath = Vec<(f64, f64)>;
+
 pub struct CairoRenderer {
     renderer_param: RendererParam,
+    opacity: Transition,
+    mask: Vec<MaskPath>,
 }
 
 impl CairoRenderer {
     pub fn new(renderer_param: RendererParam) -> Self {
-        CairoRenderer { renderer_param }
+        let opacity = Transition::new(renderer_param.opacity);
+        CairoRenderer {
+            renderer_param,
+            opacity,
+            mask: Vec::new(),
+        }
+    }
+
+    // Hides the danmaku inside the paths, e.g. the people of the frame for Bilibili's smart mask.
+    // Set the paths of each video frame before drawing it, overlapping paths show the danmaku
+    // where they overlap. Empty shows the danmaku everywhere again.
+    pub fn set_mask(&mut self, mask: Vec<MaskPath>) {
+        self.mask = mask;
+    }
+
+    fn clip_mask(&self, context: &Context, param: &DanmakuParam) {
+        if self.mask.is_empty() {
+            return;
+        }
+        let (width, height) = param.screen_size;
+        context.rectangle(0.0, 0.0, width as f64, height as f64);
+        for path in &self.mask {
+            let Some(((x, y), rest)) = path.split_first() else {
+                continue;
+            };
+            context.move_to(*x, *y);
+            for (x, y) in rest {
+                context.line_to(*x, *y);
+            }
+            context.close_path();
+        }
+        // The paths are cut out of the screen
+        context.set_fill_rule(FillRule::EvenOdd);
+        context.clip();
+        context.set_fill_rule(FillRule::Winding);
     }
 
     pub fn update_renderer_param(&mut self, param: RendererParam) {
+        if param.opacity != self.opacity.target() {
+            let duration = Duration::from_millis(param.animation.transition_millis as u64);
+            self.opacity.set(param.opacity, duration, Instant::now());
+        }
         self.renderer_param = param;
     }
 
+    // Draw every frame until the opacity reaches the new one
+    pub fn is_transitioning(&self) -> bool {
+        !self.opacity.is_finished(Instant::now())
+    }
+
+    pub fn renderer_param(&self) -> &RendererParam {
+        &self.renderer_param
+    }
+
+    // An empty recording surface covering the output, draw_chunk records into a context of it
+    pub fn recording_surface(
+        &self,
+        param: &DanmakuParam,
+    ) -> Result<RecordingSurface, cairo::Error> {
+        let (width, height) = self
+            .renderer_param
+            .orientation
+            .output_size(param.screen_size);
+        let extents = Rectangle::new(0.0, 0.0, width as f64, height as f64);
+        RecordingSurface::create(Content::ColorAlpha, Some(extents))
+    }
+
+    // Records the draw operations of the chunk instead of painting them, for compositors which
+    // cache the danmaku layer and replay it with set_source_surface
+    pub fn record_chunk(
+        &self,
+        param: &DanmakuParam,
+        chunk: &DanmakuTimeChunk,
+        glyph_cache: &StrideGlyphCache,
+        cario_glyph_cache: &mut CairoGlyphCache,
+        now_time: DanmakuTime,
+    ) -> Result<RecordingSurface, cairo::Error> {
+        let surface = self.recording_surface(param)?;
+        let context = Context::new(&surface)?;
+        self.draw_chunk(
+            param,
+            chunk,
+            glyph_cache,
+            cario_glyph_cache,
+            &context,
+            now_time,
+        )?;
+        Ok(surface)
+    }
+
     pub fn draw_chunk(
         &self,
         param: &DanmakuParam,
@@ -222,38 +406,96 @@ impl CairoRenderer {
     ) -> Result<(), cairo::Error> {
         context.save()?;
 
-        context.set_operator(Operator::Source);
-        let opacity = self.renderer_param.opacity as f64;
+        let orientation = self.renderer_param.orientation;
+        let output_size = orientation.output_size(param.screen_size);
+        context.translate(output_size.0 as f64 / 2.0, output_size.1 as f64 / 2.0);
+        context.rotate((orientation.rotation.degrees() as f64).to_radians());
+        if orientation.mirrored {
+            context.scale(-1.0, 1.0);
+        }
+        context.translate(
+            -(param.screen_size.0 as f64) / 2.0,
+            -(param.screen_size.1 as f64) / 2.0,
+        );
+        self.clip_mask(context, param);
+
+        context.set_operator(match self.renderer_param.blend_mode {
+            BlendMode::Additive => Operator::Add,
+            BlendMode::Normal | BlendMode::Replace => Operator::Source,
+        });
+        let opacity = self.opacity.value_at(Instant::now()) as f64;
+        // Cairo draws no shadow, the contrast color is an outline of the glyphs instead
+        let stroke = (self.renderer_param.shadow_color == ShadowColor::Contrast
+            && param.shadow_size > 0)
+            .then(|| param.physical_shadow_size().max(1) as f64);
 
         for item in &chunk.items {
+            let easing = self.renderer_param.animation.scroll_easing;
+            let (x, y) = match item.eased_position_at(now_time, param, easing) {
+                Some(position) => position,
+                None => continue,
+            };
             let time = item.item.time;
-            if now_time < time || now_time - time >= param.lifetime {
+            let lifetime = param.item_lifetime(item.position, item.item.width());
+            let (scale, alpha) = item.item.animation_at(now_time);
+            if scale <= 0.0 || alpha <= 0.0 {
                 continue;
             }
 
+            let fade = self
+                .renderer_param
+                .animation
+                .fade(now_time - time, lifetime) as f64
+                * item.item.opacity as f64
+                * self.renderer_param.type_opacity.get(item.position) as f64
+                * alpha as f64;
+
             context.save()?;
-            match item.position {
-                DanmakuPosition::Scroll(pos) => {
-                    let progress = (now_time.as_millis() as f64 - time.as_millis() as f64)
-                        / param.lifetime.as_millis() as f64;
-                    let x = (param.screen_size.0 as f64)
-                        - (param.screen_size.0 as f64 + item.item.width() as f64) * progress;
-                    let y = (pos as f64 + 1.0) * param.line_height as f64;
-                    context.translate(x, y);
-                }
-                DanmakuPosition::Top(pos) | DanmakuPosition::Bottom(pos) => {
-                    let x = (param.screen_size.0 as f64 - item.item.width() as f64) / 2.0;
-                    let y = match item.position {
-                        DanmakuPosition::Top(_) => (pos as f64 + 1.0) * param.line_height as f64,
-                        DanmakuPosition::Bottom(_) => {
-                            param.screen_size.1 as f64 - pos as f64 * param.line_height as f64
-                        }
-                        _ => unreachable!(),
-                    };
-                    context.translate(x, y);
-                }
-            };
-            context.translate(0.0, -(item.item.layout_line.max_descent as f64));
+            context.translate(x as f64, y as f64);
+            context.translate(0.0, -(item.item.max_descent() as f64));
+            if scale != 1.0 {
+                // Around the center of the background
+                let (x, y, width, height) = item.item.background_rect();
+                let center_x = x as f64 + width as f64 / 2.0;
+                let center_y = y as f64 + height as f64 / 2.0 + item.item.max_descent() as f64;
+                context.translate(center_x, center_y);
+                context.scale(scale as f64, scale as f64);
+                context.translate(-center_x, -center_y);
+            }
+
+            if let Some(background) = item.item.background {
+                let (x, y, width, height) = item.item.background_rect();
+                let r = (background.r() as f64) / 255.0;
+                let g = (background.g() as f64) / 255.0;
+                let b = (background.b() as f64) / 255.0;
+                context.set_source_rgba(r, g, b, opacity * fade);
+                context.rectangle(
+                    x as f64,
+                    y as f64 + item.item.max_descent() as f64,
+                    width as f64,
+                    height as f64,
+                );
+                context.fill()?;
+            }
+
+            if item.item.bordered {
+                let border = item.item.border_width() as f64;
+                let (_, y, _, height) = item.item.background_rect();
+                let top = y as f64 + item.item.max_descent() as f64;
+                let bottom = top + height as f64;
+                let r = (DanmakuColor::BORDER.r() as f64) / 255.0;
+                let g = (DanmakuColor::BORDER.g() as f64) / 255.0;
+                let b = (DanmakuColor::BORDER.b() as f64) / 255.0;
+                context.set_source_rgba(r, g, b, opacity * fade);
+                context.set_line_width(border);
+                context.rectangle(
+                    -border * 1.5,
+                    top + border / 2.0,
+                    item.item.width() as f64 + border * 3.0,
+                    bottom - top - border,
+                );
+                context.stroke()?;
+            }
 
             for glyph in &item.item.physical_glyphs {
                 let image = cario_glyph_cache.get(glyph_cache, glyph.cache_key);
@@ -265,10 +507,42 @@ impl CairoRenderer {
 
                     match image {
                         CairoGlyphImage::Mask(mask) => {
+                            if let Some(stroke) = stroke {
+                                let color = ShadowColor::Contrast.color_for(item.item.color);
+                                context.set_source_rgba(
+                                    (color.r() as f64) / 255.0,
+                                    (color.g() as f64) / 255.0,
+                                    (color.b() as f64) / 255.0,
+                                    opacity * fade,
+                                );
+                                for (x, y) in STROKE_DIRECTIONS {
+                                    context.save()?;
+                                    context.translate(x * stroke, y * stroke);
+                                    context.mask(mask)?;
+                                    context.restore()?;
+                                }
+                            }
                             let r = (item.item.color.r() as f64) / 255.0;
                             let g = (item.item.color.g() as f64) / 255.0;
                             let b = (item.item.color.b() as f64) / 255.0;
-                            context.set_source_rgba(r, g, b, opacity);
+                            match item.item.gradient {
+                                Some(gradient) => {
+                                    // Across the whole line, not the glyph
+                                    let left = -(glyph.x as f64 + placement.left as f64);
+                                    let right = left + item.item.width() as f64;
+                                    let pattern = LinearGradient::new(left, 0.0, right, 0.0);
+                                    pattern.add_color_stop_rgba(0.0, r, g, b, opacity * fade);
+                                    pattern.add_color_stop_rgba(
+                                        1.0,
+                                        (gradient.r() as f64) / 255.0,
+                                        (gradient.g() as f64) / 255.0,
+                                        (gradient.b() as f64) / 255.0,
+                                        opacity * fade,
+                                    );
+                                    context.set_source(&pattern)?;
+                                }
+                                None => context.set_source_rgba(r, g, b, opacity * fade),
+                            }
                             context.rectangle(
                                 0.0,
                                 0.0,
@@ -279,7 +553,7 @@ impl CairoRenderer {
                         }
                         CairoGlyphImage::Color(color) => {
                             context.set_source_surface(color, 0.0, 0.0)?;
-                            context.paint()?;
+                            context.paint_with_alpha(fade)?;
                         }
                     }
 
@@ -287,6 +561,27 @@ impl CairoRenderer {
                 }
             }
 
+            for emote in &item.item.emotes {
+                let surface = match cario_glyph_cache.get_emote(glyph_cache, &emote.shortcode) {
+                    Some(surface) => surface,
+                    None => continue,
+                };
+                context.save()?;
+
+                context.translate(emote.x as f64, emote.y as f64);
+                context.scale(
+                    emote.width as f64 / surface.width() as f64,
+                    emote.height as f64 / surface.height() as f64,
+                );
+                context.rectangle(0.0, 0.0, surface.width() as f64, surface.height() as f64);
+                context.clip();
+                context.set_operator(Operator::Over);
+                context.set_source_surface(surface, 0.0, 0.0)?;
+                context.paint_with_alpha(opacity * fade)?;
+
+                context.restore()?;
+            }
+
             context.restore()?;
         }
 
@@ -294,3 +589,122 @@ impl CairoRenderer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use cairo::{Context, Format, ImageSurface};
+    use cosmic_text::FontSystem;
+
+    use crate::{
+        danmaku::{Danmaku, DanmakuTime, DanmakuType},
+        layout::LayoutMode,
+        manager::DanmakuTimeChunkProvider,
+        renderer::{BlendMode, RendererParam},
+        shaper::CosmicTextShaper,
+        sources::VecDanmakuSource,
+        worker::{test::test_param, DanmakuParam, RenderCache},
+    };
+
+    use super::{CairoGlyphCache, CairoRenderer, StrideGlyphCache};
+
+    #[test]
+    fn test_new_param_invalidation() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let param = DanmakuParam {
+            chunk_duration: Duration::from_secs(30),
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let source = VecDanmakuSource::new(vec![Danmaku::new(
+            DanmakuTime::from_millis(0),
+            DanmakuType::Scroll,
+            "cairo",
+        )]);
+        let mut provider = DanmakuTimeChunkProvider::new(param.clone(), Box::new(source));
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+        let glyph = *chunk.glyph_ids().next().unwrap();
+
+        let mut cache = StrideGlyphCache::new(param.clone());
+        cache.prepare(&mut shaper, &chunk);
+        let mut surfaces = CairoGlyphCache::default();
+        assert!(surfaces.get(&cache, glyph).is_some());
+
+        // The surfaces stay while the font is the same
+        cache.new_param(param.clone());
+        assert!(surfaces.get(&cache, glyph).is_some());
+
+        // Dropped with the images, instead of drawing the stale surface
+        cache.new_param(DanmakuParam {
+            font_size: 36.0,
+            ..param
+        });
+        assert!(surfaces.get(&cache, glyph).is_none());
+        assert_eq!(surfaces.generation, 1);
+    }
+
+    #[test]
+    fn test_record_and_mask() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let param = DanmakuParam {
+            chunk_duration: Duration::from_secs(30),
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let source = VecDanmakuSource::new(vec![Danmaku::new(
+            DanmakuTime::from_millis(0),
+            DanmakuType::Top,
+            "recorded",
+        )]);
+        let mut provider = DanmakuTimeChunkProvider::new(param.clone(), Box::new(source));
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+        let mut cache = StrideGlyphCache::new(param.clone());
+        cache.prepare(&mut shaper, &chunk);
+        let mut surfaces = CairoGlyphCache::default();
+        let mut renderer = CairoRenderer::new(RendererParam {
+            opacity: 1.0,
+            color_space: Default::default(),
+            premultiplied_alpha: false,
+            blend_mode: BlendMode::Normal,
+            orientation: Default::default(),
+            animation: Default::default(),
+            type_opacity: Default::default(),
+            shadow_color: Default::default(),
+        });
+
+        let mut record = |now| {
+            let now = DanmakuTime::from_millis(now);
+            renderer
+                .record_chunk(&param, &chunk, &cache, &mut surfaces, now)
+                .unwrap()
+        };
+        let recorded = record(1000);
+        let extents = recorded.extents().unwrap();
+        assert_eq!((extents.width(), extents.height()), (1280.0, 720.0));
+        // Only the top line is drawn on
+        let (x, y, width, height) = recorded.ink_extents();
+        assert!(x > 0.0 && x + width < 1280.0);
+        assert!(y >= 0.0 && y + height <= 32.0);
+        assert!(width > 0.0);
+        // Nothing after the lifetime
+        let (_, _, width, _) = record(6000).ink_extents();
+        assert_eq!(width, 0.0);
+
+        // Nor behind a mask over the top line
+        renderer.set_mask(vec![vec![
+            (0.0, 0.0),
+            (1280.0, 0.0),
+            (1280.0, 64.0),
+            (0.0, 64.0),
+        ]]);
+        let mut image = ImageSurface::create(Format::ARgb32, 1280, 720).unwrap();
+        let context = Context::new(&image).unwrap();
+        let now = DanmakuTime::from_millis(1000);
+        renderer
+            .draw_chunk(&param, &chunk, &cache, &mut surfaces, &context, now)
+            .unwrap();
+        drop(context);
+        assert!(image.data().unwrap().iter().all(|byte| *byte == 0));
+    }
+}
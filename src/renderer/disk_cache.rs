@@ -0,0 +1,391 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use cosmic_text::{CacheKey, CacheKeyFlags, Placement, SubpixelBin, SwashContent};
+use log::{info, warn};
+
+use crate::shaper::{GlyphBitmap, TextShaper};
+
+const MAGIC: &[u8; 4] = b"DMKG";
+const VERSION: u32 = 1;
+
+// Font IDs are only valid in one session, the glyph is identified by the rest of the cache key
+// inside a file of the font
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct GlyphKey {
+    glyph_id: u16,
+    x_bin: u8,
+    y_bin: u8,
+    flags: u32,
+}
+
+impl From<CacheKey> for GlyphKey {
+    fn from(key: CacheKey) -> Self {
+        GlyphKey {
+            glyph_id: key.glyph_id,
+            x_bin: bin_to_u8(key.x_bin),
+            y_bin: bin_to_u8(key.y_bin),
+            flags: key.flags.bits(),
+        }
+    }
+}
+
+fn bin_to_u8(bin: SubpixelBin) -> u8 {
+    match bin {
+        SubpixelBin::Zero => 0,
+        SubpixelBin::One => 1,
+        SubpixelBin::Two => 2,
+        SubpixelBin::Three => 3,
+    }
+}
+
+// FNV-1a, unlike the std hasher it is stable between builds
+pub(crate) fn fnv1a(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub(crate) const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+#[derive(Default)]
+struct GlyphFile {
+    glyphs: HashMap<GlyphKey, Option<GlyphBitmap>>,
+    dirty: bool,
+}
+
+// Keeps the rasterized glyphs on disk, so they don't have to be rasterized again in the next
+// session. There is a file for every font, size and shadow parameters.
+pub struct GlyphDiskCache {
+    dir: PathBuf,
+    shadow_width: u32,
+    shadow_weight: f32,
+    files: HashMap<u64, GlyphFile>,
+}
+
+impl GlyphDiskCache {
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(GlyphDiskCache {
+            dir,
+            shadow_width: 0,
+            shadow_weight: 0.0,
+            files: HashMap::new(),
+        })
+    }
+
+    pub fn set_shadow(&mut self, shadow_width: u32, shadow_weight: f32) {
+        self.shadow_width = shadow_width;
+        self.shadow_weight = shadow_weight;
+    }
+
+    // Returns the glyph from the disk, or rasterizes it and saves it at the next save
+    pub fn get_image(
+        &mut self,
+        shaper: &mut dyn TextShaper,
+        glyph: CacheKey,
+    ) -> Option<GlyphBitmap> {
+        self.get_images(shaper, &[glyph]).pop().unwrap()
+    }
+
+    // Rasterizes the glyphs missing from the files together with TextShaper::rasterize_all
+    pub fn get_images(
+        &mut self,
+        shaper: &mut dyn TextShaper,
+        glyphs: &[CacheKey],
+    ) -> Vec<Option<GlyphBitmap>> {
+        let mut images = vec![None; glyphs.len()];
+        // Index into glyphs and the file to save the bitmap into
+        let mut missing = Vec::new();
+        for (index, glyph) in glyphs.iter().enumerate() {
+            let file_key = self.file_key(shaper, *glyph);
+            if let Some(file_key) = file_key {
+                let dir = &self.dir;
+                let file = self
+                    .files
+                    .entry(file_key)
+                    .or_insert_with(|| load_file(dir, file_key));
+                if let Some(bitmap) = file.glyphs.get(&GlyphKey::from(*glyph)) {
+                    images[index] = bitmap.clone();
+                    continue;
+                }
+            }
+            missing.push((index, file_key));
+        }
+        if missing.is_empty() {
+            return images;
+        }
+
+        let missing_glyphs: Vec<_> = missing.iter().map(|(index, _)| glyphs[*index]).collect();
+        let bitmaps = shaper.rasterize_all(&missing_glyphs);
+        for ((index, file_key), bitmap) in missing.into_iter().zip(bitmaps) {
+            if let Some(file) = file_key.and_then(|file_key| self.files.get_mut(&file_key)) {
+                file.glyphs
+                    .insert(GlyphKey::from(glyphs[index]), bitmap.clone());
+                file.dirty = true;
+            }
+            images[index] = bitmap;
+        }
+        images
+    }
+
+    // Writes the glyphs rasterized since the last save
+    pub fn save(&mut self) -> io::Result<()> {
+        for (file_key, file) in &mut self.files {
+            if !file.dirty {
+                continue;
+            }
+            let path = self.dir.join(file_name(*file_key));
+            let temp_path = path.with_extension("tmp");
+            fs::write(&temp_path, encode(&file.glyphs))?;
+            fs::rename(&temp_path, &path)?;
+            file.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn file_key(&self, shaper: &mut dyn TextShaper, glyph: CacheKey) -> Option<u64> {
+        let font_key = shaper.font_key(glyph.font_id)?;
+        let mut hash = fnv1a(FNV_OFFSET, &font_key.to_le_bytes());
+        hash = fnv1a(hash, &glyph.font_size_bits.to_le_bytes());
+        hash = fnv1a(hash, &self.shadow_width.to_le_bytes());
+        hash = fnv1a(hash, &self.shadow_weight.to_bits().to_le_bytes());
+        Some(hash)
+    }
+}
+
+impl Drop for GlyphDiskCache {
+    fn drop(&mut self) {
+        if let Err(err) = self.save() {
+            warn!("Failed to save glyph cache: {}", err);
+        }
+    }
+}
+
+// Reads the glyphs from the disk cache if there is one
+#[cfg(any(feature = "renderer-wgpu", feature = "renderer-cairo"))]
+pub(crate) fn get_images(
+    disk_cache: Option<&mut GlyphDiskCache>,
+    shaper: &mut dyn TextShaper,
+    glyphs: &[CacheKey],
+) -> Vec<Option<GlyphBitmap>> {
+    match disk_cache {
+        Some(disk_cache) => disk_cache.get_images(shaper, glyphs),
+        None => shaper.rasterize_all(glyphs),
+    }
+}
+
+fn file_name(file_key: u64) -> String {
+    format!("{:016x}.glyphs", file_key)
+}
+
+fn load_file(dir: &Path, file_key: u64) -> GlyphFile {
+    let path = dir.join(file_name(file_key));
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return GlyphFile::default(),
+        Err(err) => {
+            warn!("Failed to read glyph cache {}: {}", path.display(), err);
+            return GlyphFile::default();
+        }
+    };
+    match decode(&data) {
+        Some(glyphs) => {
+            info!("Loaded {} glyphs from {}", glyphs.len(), path.display());
+            GlyphFile {
+                glyphs,
+                dirty: false,
+            }
+        }
+        None => {
+            warn!("Glyph cache {} is corrupted, ignoring", path.display());
+            GlyphFile::default()
+        }
+    }
+}
+
+fn content_to_u8(content: SwashContent) -> u8 {
+    match content {
+        SwashContent::Mask => 0,
+        SwashContent::SubpixelMask => 1,
+        SwashContent::Color => 2,
+    }
+}
+
+fn encode(glyphs: &HashMap<GlyphKey, Option<GlyphBitmap>>) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&(glyphs.len() as u32).to_le_bytes());
+    for (key, bitmap) in glyphs {
+        data.extend_from_slice(&key.glyph_id.to_le_bytes());
+        data.push(key.x_bin);
+        data.push(key.y_bin);
+        data.extend_from_slice(&key.flags.to_le_bytes());
+        let bitmap = match bitmap {
+            Some(bitmap) => bitmap,
+            None => {
+                data.push(0);
+                continue;
+            }
+        };
+        data.push(1);
+        data.push(content_to_u8(bitmap.content));
+        data.extend_from_slice(&bitmap.placement.left.to_le_bytes());
+        data.extend_from_slice(&bitmap.placement.top.to_le_bytes());
+        data.extend_from_slice(&bitmap.placement.width.to_le_bytes());
+        data.extend_from_slice(&bitmap.placement.height.to_le_bytes());
+        data.extend_from_slice(&(bitmap.data.len() as u32).to_le_bytes());
+        data.extend_from_slice(&bitmap.data);
+    }
+    data
+}
+
+// Little endian fields of the cache files, None past the end of the data
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.bytes(N).map(|bytes| bytes.try_into().unwrap())
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        self.array::<1>().map(|[byte]| byte)
+    }
+
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    pub(crate) fn i32(&mut self) -> Option<i32> {
+        self.array().map(i32::from_le_bytes)
+    }
+
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    pub(crate) fn i64(&mut self) -> Option<i64> {
+        self.array().map(i64::from_le_bytes)
+    }
+
+    pub(crate) fn f32(&mut self) -> Option<f32> {
+        self.array().map(f32::from_le_bytes)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+fn decode(data: &[u8]) -> Option<HashMap<GlyphKey, Option<GlyphBitmap>>> {
+    let mut reader = Reader::new(data);
+    if reader.bytes(MAGIC.len())? != MAGIC || reader.u32()? != VERSION {
+        return None;
+    }
+    let count = reader.u32()?;
+    let mut glyphs = HashMap::new();
+    for _ in 0..count {
+        let key = GlyphKey {
+            glyph_id: u16::from_le_bytes(reader.array()?),
+            x_bin: reader.u8()?,
+            y_bin: reader.u8()?,
+            flags: reader.u32()?,
+        };
+        CacheKeyFlags::from_bits(key.flags)?;
+        if reader.u8()? == 0 {
+            glyphs.insert(key, None);
+            continue;
+        }
+        let content = match reader.u8()? {
+            0 => SwashContent::Mask,
+            1 => SwashContent::SubpixelMask,
+            2 => SwashContent::Color,
+            _ => return None,
+        };
+        let placement = Placement {
+            left: reader.i32()?,
+            top: reader.i32()?,
+            width: reader.u32()?,
+            height: reader.u32()?,
+        };
+        let len = reader.u32()? as usize;
+        let data = reader.bytes(len)?.to_vec();
+        glyphs.insert(
+            key,
+            Some(GlyphBitmap {
+                content,
+                placement,
+                data,
+            }),
+        );
+    }
+    Some(glyphs)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use cosmic_text::{Placement, SwashContent};
+
+    use crate::shaper::GlyphBitmap;
+
+    use super::{decode, encode, GlyphKey};
+
+    #[test]
+    fn test_encode_glyphs() {
+        let key = |glyph_id| GlyphKey {
+            glyph_id,
+            x_bin: 1,
+            y_bin: 0,
+            flags: 0,
+        };
+        let mut glyphs = HashMap::new();
+        glyphs.insert(key(3), None);
+        glyphs.insert(
+            key(4),
+            Some(GlyphBitmap {
+                content: SwashContent::Mask,
+                placement: Placement {
+                    left: -1,
+                    top: 2,
+                    width: 2,
+                    height: 2,
+                },
+                data: vec![0, 64, 128, 255],
+            }),
+        );
+        let data = encode(&glyphs);
+        let decoded = decode(&data).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert!(decoded[&key(3)].is_none());
+        let bitmap = decoded[&key(4)].as_ref().unwrap();
+        assert_eq!(bitmap.content, SwashContent::Mask);
+        assert_eq!(bitmap.placement.left, -1);
+        assert_eq!(bitmap.placement.top, 2);
+        assert_eq!(bitmap.data, vec![0, 64, 128, 255]);
+
+        // Truncated files are ignored instead of being partially loaded
+        assert!(decode(&data[..data.len() - 1]).is_none());
+    }
+}
@@ -0,0 +1,236 @@
+// A DrawingArea drawing the danmaku with the cairo renderer. It owns the worker, follows its own
+// size, and redraws only when the danmaku change.
+use std::time::{Duration, Instant};
+
+use gtk4::{cairo, glib, prelude::*, subclass::prelude::*};
+use log::warn;
+
+use crate::{
+    danmaku::{Danmaku, DanmakuTime},
+    pipeline::{CairoPipeline, DanmakuPipelineBuilder},
+    sources::DanmakuSource,
+    worker::{DanmakuParam, WorkerError},
+};
+
+use super::RendererParam;
+
+// Bounds of the time between frames, the worker may have finished new chunks meanwhile
+const MIN_REDRAW_INTERVAL: Duration = Duration::from_millis(8);
+const MAX_REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+mod imp {
+    use std::{
+        cell::{Cell, RefCell},
+        time::Instant,
+    };
+
+    use gtk4::{glib, prelude::*, subclass::prelude::*};
+
+    use crate::{
+        clock::PlaybackClock, pipeline::CairoPipeline, renderer::RendererParam,
+        worker::DanmakuParam,
+    };
+
+    #[derive(Default)]
+    pub struct DanmakuArea {
+        // Set by DanmakuArea::new, the screen size follows the size of the widget
+        pub(super) param: RefCell<Option<DanmakuParam>>,
+        pub(super) renderer_param: RefCell<Option<RendererParam>>,
+        // None until a source is set
+        pub(super) pipeline: RefCell<Option<CairoPipeline>>,
+        pub(super) clock: RefCell<PlaybackClock>,
+        pub(super) redraw_at: Cell<Option<Instant>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for DanmakuArea {
+        const NAME: &'static str = "DanmakuArea";
+        type Type = super::DanmakuArea;
+        type ParentType = gtk4::DrawingArea;
+    }
+
+    impl ObjectImpl for DanmakuArea {
+        fn constructed(&self) {
+            self.parent_constructed();
+            self.obj().set_draw_func(|area, context, _, _| {
+                if let Some(area) = area.downcast_ref::<super::DanmakuArea>() {
+                    area.draw(context);
+                }
+            });
+        }
+
+        fn dispose(&self) {
+            // Stops the worker thread with the widget
+            self.pipeline.take();
+        }
+    }
+
+    impl WidgetImpl for DanmakuArea {}
+
+    impl DrawingAreaImpl for DanmakuArea {
+        fn resize(&self, width: i32, height: i32) {
+            self.parent_resize(width, height);
+            self.obj().resize(width, height);
+        }
+    }
+}
+
+glib::wrapper! {
+    pub struct DanmakuArea(ObjectSubclass<imp::DanmakuArea>)
+        @extends gtk4::DrawingArea, gtk4::Widget,
+        @implements gtk4::Accessible, gtk4::Buildable, gtk4::ConstraintTarget;
+}
+
+impl DanmakuArea {
+    // Draws nothing until a source is set. The playback clock starts paused at zero.
+    pub fn new(param: DanmakuParam, renderer_param: RendererParam) -> Self {
+        let area: Self = glib::Object::new();
+        let imp = area.imp();
+        imp.param.replace(Some(param));
+        imp.renderer_param.replace(Some(renderer_param));
+        imp.clock.borrow_mut().sync(DanmakuTime::from_millis(0));
+        area
+    }
+
+    // Replaces the danmaku with the ones of the source, starting a new worker
+    pub fn set_source(&self, source: Box<dyn DanmakuSource + Send>) -> Result<(), WorkerError> {
+        let imp = self.imp();
+        let param = imp.param.borrow().clone().unwrap();
+        let renderer_param = imp.renderer_param.borrow().clone().unwrap();
+        let pipeline = DanmakuPipelineBuilder::new(source, param, renderer_param).build_cairo()?;
+        self.set_pipeline(pipeline);
+        Ok(())
+    }
+
+    // For pipelines built with custom fonts, emotes or caches. The param of the pipeline is
+    // replaced by the one of the widget on the next resize.
+    pub fn set_pipeline(&self, pipeline: CairoPipeline) {
+        self.imp().pipeline.replace(Some(pipeline));
+        self.queue_draw();
+    }
+
+    // Position reported by the player, the widget advances from it while playing
+    pub fn set_time(&self, time: DanmakuTime) {
+        self.imp().clock.borrow_mut().sync(time);
+        self.queue_draw();
+    }
+
+    pub fn set_playing(&self, playing: bool) {
+        self.imp().clock.borrow_mut().set_playing(playing);
+        self.queue_draw();
+    }
+
+    pub fn set_rate(&self, rate: f64) {
+        self.imp().clock.borrow_mut().set_rate(rate);
+        self.queue_draw();
+    }
+
+    pub fn set_opacity(&self, opacity: f32) {
+        let mut renderer_param = self.renderer_param();
+        renderer_param.opacity = opacity;
+        self.set_renderer_param(renderer_param);
+    }
+
+    pub fn renderer_param(&self) -> RendererParam {
+        self.imp().renderer_param.borrow().clone().unwrap()
+    }
+
+    pub fn set_renderer_param(&self, renderer_param: RendererParam) {
+        let imp = self.imp();
+        if let Some(pipeline) = imp.pipeline.borrow_mut().as_mut() {
+            pipeline.set_renderer_param(renderer_param.clone());
+        }
+        imp.renderer_param.replace(Some(renderer_param));
+        self.queue_draw();
+    }
+
+    pub fn param(&self) -> DanmakuParam {
+        self.imp().param.borrow().clone().unwrap()
+    }
+
+    // The screen size is kept at the size of the widget
+    pub fn set_param(&self, param: DanmakuParam) -> Result<(), WorkerError> {
+        let imp = self.imp();
+        let param = DanmakuParam {
+            screen_size: self.param().screen_size,
+            ..param
+        };
+        if let Some(pipeline) = imp.pipeline.borrow_mut().as_mut() {
+            pipeline.set_param(param.clone())?;
+        }
+        imp.param.replace(Some(param));
+        self.queue_draw();
+        Ok(())
+    }
+
+    pub fn insert_local(&self, danmaku: Danmaku) -> Result<(), WorkerError> {
+        match self.imp().pipeline.borrow_mut().as_mut() {
+            Some(pipeline) => pipeline.insert_local(danmaku),
+            None => Ok(()),
+        }
+    }
+
+    fn resize(&self, width: i32, height: i32) {
+        if width <= 0 || height <= 0 {
+            return;
+        }
+        let mut param = self.param();
+        param.screen_size = (width as u32, height as u32);
+        if let Err(err) = self.set_param(param) {
+            warn!("Failed to resize the danmaku area: {}", err);
+        }
+    }
+
+    fn draw(&self, context: &cairo::Context) {
+        let imp = self.imp();
+        let mut pipeline = imp.pipeline.borrow_mut();
+        let Some(pipeline) = pipeline.as_mut() else {
+            return;
+        };
+        let (now, playing, rate) = {
+            let mut clock = imp.clock.borrow_mut();
+            let now = clock.now();
+            pipeline.worker().playback().update(now, &clock);
+            (now, clock.is_playing(), clock.rate())
+        };
+        if let Err(err) = pipeline.tick(now) {
+            warn!("Failed to request chunk: {}", err);
+        }
+        if let Err(err) = pipeline.render(context) {
+            warn!("Draw failed: {}", err);
+        }
+
+        // Paused danmaku only change when the missing chunk arrives
+        let delay = match pipeline.next_change() {
+            Some(delay) if delay.is_zero() => delay,
+            _ if !playing => return,
+            Some(delay) => delay.div_f64(rate.abs().max(0.01)),
+            None => MAX_REDRAW_INTERVAL,
+        };
+        self.schedule_redraw(delay.clamp(MIN_REDRAW_INTERVAL, MAX_REDRAW_INTERVAL));
+    }
+
+    // Draws again after the delay, unless an earlier redraw is pending
+    fn schedule_redraw(&self, delay: Duration) {
+        let imp = self.imp();
+        let deadline = Instant::now() + delay;
+        if imp
+            .redraw_at
+            .get()
+            .is_some_and(|pending| pending <= deadline)
+        {
+            return;
+        }
+        imp.redraw_at.set(Some(deadline));
+        let area = self.downgrade();
+        glib::timeout_add_local_once(delay, move || {
+            let Some(area) = area.upgrade() else {
+                return;
+            };
+            if area.imp().redraw_at.get() == Some(deadline) {
+                area.imp().redraw_at.set(None);
+            }
+            area.queue_draw();
+        });
+    }
+}
@@ -1,10 +1,349 @@
+use std::time::Duration;
+#[cfg(any(feature = "renderer-wgpu", feature = "renderer-cairo"))]
+use std::time::Instant;
+
+use crate::{danmaku::DanmakuColor, layout::DanmakuPosition, position::Easing};
+
 #[cfg(feature = "renderer-cairo")]
 pub mod cairo;
+pub mod disk_cache;
+#[cfg(feature = "gtk4")]
+pub mod gtk4;
 pub mod noop;
 #[cfg(feature = "renderer-wgpu")]
 pub mod wgpu;
+#[cfg(feature = "winit")]
+pub mod winit;
+
+// Captured danmaku layer, RGBA8 sRGB encoded. The alpha is premultiplied if the renderer
+// renders with premultiplied alpha.
+pub struct RgbaImage {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+impl RgbaImage {
+    pub fn new(width: u32, height: u32, data: Vec<u8>) -> Self {
+        assert_eq!(data.len(), (width * height * 4) as usize);
+        RgbaImage {
+            width,
+            height,
+            data,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let offset = ((y * self.width + x) * 4) as usize;
+        self.data[offset..offset + 4].try_into().unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ColorSpace {
+    // Detect from the target format
+    #[default]
+    Auto,
+    // Target stores gamma encoded values, shader writes sRGB values directly
+    Srgb,
+    // Target stores linear values (*Srgb formats, Rgba16Float / scRGB)
+    LinearSrgb,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Rotation {
+    #[default]
+    None,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+impl Rotation {
+    pub fn degrees(&self) -> u32 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Clockwise90 => 90,
+            Rotation::Clockwise180 => 180,
+            Rotation::Clockwise270 => 270,
+        }
+    }
+
+    pub fn is_transposed(&self) -> bool {
+        matches!(self, Rotation::Clockwise90 | Rotation::Clockwise270)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Orientation {
+    pub rotation: Rotation,
+    // Mirror horizontally before rotating
+    pub mirrored: bool,
+}
+
+impl Orientation {
+    // Column-major 2x2 matrix in a y-up coordinate system (e.g. clip space)
+    pub fn matrix(&self) -> [[f32; 2]; 2] {
+        let (sin, cos) = match self.rotation {
+            Rotation::None => (0.0, 1.0),
+            Rotation::Clockwise90 => (1.0, 0.0),
+            Rotation::Clockwise180 => (0.0, -1.0),
+            Rotation::Clockwise270 => (-1.0, 0.0),
+        };
+        let mirror = if self.mirrored { -1.0 } else { 1.0 };
+        [[cos * mirror, -sin * mirror], [sin, cos]]
+    }
+
+    pub fn output_size(&self, size: (u32, u32)) -> (u32, u32) {
+        if self.rotation.is_transposed() {
+            (size.1, size.0)
+        } else {
+            size
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AnimationParam {
+    pub fade_in_millis: u32,
+    pub fade_out_millis: u32,
+    // Changes of the opacity and the font size are animated over this duration
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub transition_millis: u32,
+    // Of the scroll danmaku across the screen, the static ones stay in place
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub scroll_easing: Easing,
+}
+
+impl AnimationParam {
+    pub fn fade(&self, elapsed: Duration, lifetime: Duration) -> f32 {
+        let elapsed = elapsed.as_millis() as f32;
+        let remaining = lifetime.as_millis() as f32 - elapsed;
+        let mut fade: f32 = 1.0;
+        if self.fade_in_millis > 0 {
+            fade = fade.min(elapsed / self.fade_in_millis as f32);
+        }
+        if self.fade_out_millis > 0 {
+            fade = fade.min(remaining / self.fade_out_millis as f32);
+        }
+        fade.clamp(0.0, 1.0)
+    }
+}
+
+// A value moving linearly to its target, for animating the changes of the parameters
+#[cfg(any(feature = "renderer-wgpu", feature = "renderer-cairo"))]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Transition {
+    from: f32,
+    to: f32,
+    start: Instant,
+    duration: Duration,
+}
+
+#[cfg(any(feature = "renderer-wgpu", feature = "renderer-cairo"))]
+impl Transition {
+    pub(crate) fn new(value: f32) -> Self {
+        Transition {
+            from: value,
+            to: value,
+            start: Instant::now(),
+            duration: Duration::ZERO,
+        }
+    }
+
+    // Starts from the current value, so changes during a transition don't jump
+    pub(crate) fn set(&mut self, to: f32, duration: Duration, now: Instant) {
+        self.from = self.value_at(now);
+        self.to = to;
+        self.start = now;
+        self.duration = duration;
+    }
+
+    pub(crate) fn target(&self) -> f32 {
+        self.to
+    }
+
+    pub(crate) fn value_at(&self, now: Instant) -> f32 {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.duration {
+            return self.to;
+        }
+        let progress = elapsed.as_secs_f32() / self.duration.as_secs_f32();
+        self.from + (self.to - self.from) * progress
+    }
+
+    pub(crate) fn is_finished(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.start) >= self.duration
+    }
+}
+
+// Opacity multiplier for each danmaku type, applied on top of RendererParam::opacity
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct TypeOpacity {
+    pub scroll: f32,
+    pub top: f32,
+    pub bottom: f32,
+}
+
+impl Default for TypeOpacity {
+    fn default() -> Self {
+        TypeOpacity {
+            scroll: 1.0,
+            top: 1.0,
+            bottom: 1.0,
+        }
+    }
+}
+
+impl TypeOpacity {
+    pub fn get(&self, position: DanmakuPosition) -> f32 {
+        match position {
+            DanmakuPosition::Scroll(_) => self.scroll,
+            DanmakuPosition::Top(_) => self.top,
+            DanmakuPosition::Bottom(_) => self.bottom,
+        }
+    }
+}
+
+// How the danmaku layer is composited over what is already in the target, usually the video
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BlendMode {
+    // Danmaku over the target
+    #[default]
+    Normal,
+    // Adds the danmaku color to the target, brightening dark scenes without covering them
+    Additive,
+    // Overwrites the target, for overlay surfaces that only hold the danmaku layer
+    Replace,
+}
+
+// Color of the shadow around the glyphs, see DanmakuParam::shadow_size
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ShadowColor {
+    #[default]
+    Black,
+    // Black around light text and white around dark text, readable on both dark and light scenes
+    Contrast,
+}
+
+impl ShadowColor {
+    pub fn color_for(&self, color: DanmakuColor) -> DanmakuColor {
+        match self {
+            ShadowColor::Black => DanmakuColor::from_code(0x000000),
+            // Relative luminance of WCAG, the threshold gives black and white the same contrast
+            ShadowColor::Contrast => {
+                let linear = |channel: u8| {
+                    let channel = channel as f32 / 255.0;
+                    if channel <= 0.04045 {
+                        channel / 12.92
+                    } else {
+                        ((channel + 0.055) / 1.055).powf(2.4)
+                    }
+                };
+                let luminance = 0.2126 * linear(color.r())
+                    + 0.7152 * linear(color.g())
+                    + 0.0722 * linear(color.b());
+                match luminance > 0.179 {
+                    true => DanmakuColor::from_code(0x000000),
+                    false => DanmakuColor::from_code(0xFFFFFF),
+                }
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RendererParam {
     pub opacity: f32,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub color_space: ColorSpace,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub premultiplied_alpha: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub blend_mode: BlendMode,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub orientation: Orientation,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub animation: AnimationParam,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub type_opacity: TypeOpacity,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub shadow_color: ShadowColor,
+}
+
+#[cfg(all(test, any(feature = "renderer-wgpu", feature = "renderer-cairo")))]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::danmaku::DanmakuColor;
+
+    use super::{ShadowColor, Transition};
+
+    #[test]
+    fn test_transition() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut transition = Transition::new(1.0);
+        assert!(transition.is_finished(start));
+        assert_eq!(transition.value_at(start), 1.0);
+
+        transition.set(0.0, Duration::from_millis(200), at(0));
+        assert_eq!(transition.value_at(at(50)), 0.75);
+        assert!(!transition.is_finished(at(50)));
+        assert_eq!(transition.value_at(at(200)), 0.0);
+        assert!(transition.is_finished(at(200)));
+
+        // Turning back halfway continues from the current value
+        transition.set(0.0, Duration::from_millis(200), at(0));
+        transition.set(1.0, Duration::from_millis(200), at(100));
+        assert_eq!(transition.value_at(at(100)), 0.5);
+        assert_eq!(transition.value_at(at(200)), 0.75);
+        assert_eq!(transition.target(), 1.0);
+
+        // Without a duration the value snaps
+        transition.set(0.25, Duration::ZERO, at(300));
+        assert_eq!(transition.value_at(at(300)), 0.25);
+    }
+
+    #[test]
+    fn test_contrast_shadow() {
+        let shadow = |code| {
+            ShadowColor::Contrast
+                .color_for(DanmakuColor::from_code(code))
+                .code()
+        };
+        assert_eq!(shadow(0xFFFFFF), 0x000000);
+        assert_eq!(shadow(0xFFFF00), 0x000000);
+        assert_eq!(shadow(0x66FF66), 0x000000);
+        assert_eq!(shadow(0x000000), 0xFFFFFF);
+        assert_eq!(shadow(0x0000FF), 0xFFFFFF);
+        assert_eq!(shadow(0x800000), 0xFFFFFF);
+        let black = ShadowColor::Black.color_for(DanmakuColor::from_code(0x000000));
+        assert_eq!(black.code(), 0x000000);
+    }
 }
@@ -8,7 +8,7 @@ impl RenderCache for NoopRenderCache {
 
     fn prepare<'a>(
         &mut self,
-        _font_system: &mut cosmic_text::FontSystem,
+        _shaper: &mut dyn crate::shaper::TextShaper,
         _chunk: &crate::manager::DanmakuTimeChunk,
     ) {
     }
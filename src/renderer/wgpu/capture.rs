@@ -0,0 +1,190 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use wgpu::{
+    Buffer, BufferAsyncError, BufferDescriptor, BufferUsages, CommandEncoderDescriptor, Device,
+    ImageCopyBuffer, ImageDataLayout, MapMode, Queue, Texture, TextureFormat,
+    COPY_BYTES_PER_ROW_ALIGNMENT,
+};
+
+use crate::renderer::RgbaImage;
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1F) as i32;
+    let mantissa = (bits & 0x3FF) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        0x1F if mantissa == 0.0 => f32::INFINITY,
+        0x1F => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let encoded = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0).round() as u8
+}
+
+fn is_supported(format: TextureFormat) -> bool {
+    matches!(
+        format,
+        TextureFormat::Rgba8Unorm
+            | TextureFormat::Rgba8UnormSrgb
+            | TextureFormat::Bgra8Unorm
+            | TextureFormat::Bgra8UnormSrgb
+            | TextureFormat::Rgba16Float
+    )
+}
+
+// One row of the target texture to RGBA8 sRGB
+fn convert_row(format: TextureFormat, row: &[u8], output: &mut Vec<u8>) {
+    match format {
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => output.extend_from_slice(row),
+        TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => {
+            for pixel in row.chunks_exact(4) {
+                output.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+            }
+        }
+        TextureFormat::Rgba16Float => {
+            for pixel in row.chunks_exact(8) {
+                let channel = |index: usize| {
+                    f16_to_f32(u16::from_le_bytes([pixel[index * 2], pixel[index * 2 + 1]]))
+                };
+                output.extend_from_slice(&[
+                    linear_to_srgb(channel(0)),
+                    linear_to_srgb(channel(1)),
+                    linear_to_srgb(channel(2)),
+                    (channel(3).clamp(0.0, 1.0) * 255.0).round() as u8,
+                ]);
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[derive(Default)]
+struct MapState {
+    result: Option<Result<(), BufferAsyncError>>,
+    waker: Option<Waker>,
+}
+
+pub(crate) struct ReadBack {
+    buffer: Buffer,
+    state: Arc<Mutex<MapState>>,
+    format: TextureFormat,
+    width: u32,
+    height: u32,
+    bytes_per_row: u32,
+}
+
+impl ReadBack {
+    pub(crate) fn new(device: &Device, queue: &Queue, texture: &Texture) -> Self {
+        let format = texture.format();
+        assert!(
+            is_supported(format),
+            "Reading back {:?} textures is not supported",
+            format
+        );
+        let size = texture.size();
+        let pixel_size = format.block_copy_size(None).unwrap();
+        let bytes_per_row = (size.width * pixel_size).div_ceil(COPY_BYTES_PER_ROW_ALIGNMENT)
+            * COPY_BYTES_PER_ROW_ALIGNMENT;
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Danmaku read back buffer"),
+            size: (bytes_per_row * size.height) as u64,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("Danmaku read back command encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            size,
+        );
+        queue.submit(Some(encoder.finish()));
+
+        let state = Arc::new(Mutex::new(MapState::default()));
+        let callback_state = state.clone();
+        buffer.slice(..).map_async(MapMode::Read, move |result| {
+            let mut state = callback_state.lock().unwrap();
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+
+        ReadBack {
+            buffer,
+            state,
+            format,
+            width: size.width,
+            height: size.height,
+            bytes_per_row,
+        }
+    }
+
+    fn image(&self) -> RgbaImage {
+        let mapped = self.buffer.slice(..).get_mapped_range();
+        let row_size = (self.width * self.format.block_copy_size(None).unwrap()) as usize;
+        let mut data = Vec::with_capacity((self.width * self.height * 4) as usize);
+        for row in mapped.chunks_exact(self.bytes_per_row as usize) {
+            convert_row(self.format, &row[..row_size], &mut data);
+        }
+        drop(mapped);
+        self.buffer.unmap();
+        RgbaImage::new(self.width, self.height, data)
+    }
+}
+
+impl Future for ReadBack {
+    type Output = Result<RgbaImage, BufferAsyncError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = {
+            let mut state = self.state.lock().unwrap();
+            match state.result.take() {
+                Some(result) => result,
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        };
+        Poll::Ready(result.map(|_| self.image()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{f16_to_f32, linear_to_srgb};
+
+    #[test]
+    fn test_convert_half() {
+        assert_eq!(f16_to_f32(0x3C00), 1.0);
+        assert_eq!(f16_to_f32(0xB800), -0.5);
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7C00), f32::INFINITY);
+        assert_eq!(linear_to_srgb(f16_to_f32(0x3800)), 188);
+        assert_eq!(linear_to_srgb(2.0), 255);
+    }
+}
@@ -0,0 +1,239 @@
+use std::{mem::size_of, num::NonZeroUsize};
+
+use bytemuck::{Pod, Zeroable};
+use wgpu::{
+    util::{BufferInitDescriptor, DeviceExt},
+    AddressMode, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+    BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, BlendState,
+    Buffer, BufferBindingType, BufferUsages, ColorTargetState, ColorWrites, Device, FilterMode,
+    FragmentState, FrontFace, MultisampleState, PipelineLayoutDescriptor, PolygonMode,
+    PrimitiveState, PrimitiveTopology, Queue, RenderPass, RenderPipeline, RenderPipelineDescriptor,
+    Sampler, SamplerBindingType, SamplerDescriptor, ShaderModuleDescriptor, ShaderSource,
+    ShaderStages, TextureFormat, TextureSampleType, TextureView, TextureViewDimension, VertexState,
+};
+
+use crate::renderer::{BlendMode, RendererParam};
+
+use super::copy::Viewport;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+pub struct CompositeConfigUniform {
+    pub transform: [[f32; 2]; 2],
+    pub opacity: f32,
+    pub additive: u32,
+    pub premultiplied: u32,
+    _padding: u32,
+}
+
+impl CompositeConfigUniform {
+    pub fn new(opacity: f32, renderer_param: &RendererParam) -> Self {
+        CompositeConfigUniform {
+            transform: renderer_param.orientation.matrix(),
+            opacity,
+            additive: (renderer_param.blend_mode == BlendMode::Additive) as u32,
+            premultiplied: renderer_param.premultiplied_alpha as u32,
+            _padding: 0,
+        }
+    }
+
+    pub fn prepare(&self, device: &Device) -> Buffer {
+        device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Composite config Buffer"),
+            contents: bytemuck::cast_slice(&[*self]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        })
+    }
+
+    pub fn update(&self, buffer: &Buffer, queue: &Queue) {
+        let size: NonZeroUsize = size_of::<CompositeConfigUniform>().try_into().unwrap();
+        let mut buffer = queue
+            .write_buffer_with(buffer, 0, size.try_into().unwrap())
+            .unwrap();
+        buffer.copy_from_slice(bytemuck::cast_slice(&[*self]));
+    }
+}
+
+// Draws the video frame with the target texture over it in one pass, replacing the pixels of the
+// pass. Replace blending is composited like the normal one, as the output replaces the video
+// anyway.
+pub(crate) struct VideoCompositor {
+    render_pipeline: RenderPipeline,
+    sampler: Sampler,
+    bind_group_layout: BindGroupLayout,
+    // None until the frame is set, and again after the target texture changed
+    bind_group: Option<BindGroup>,
+    config_buffer: Buffer,
+}
+
+impl VideoCompositor {
+    pub fn new(
+        device: &Device,
+        format: TextureFormat,
+        opacity: f32,
+        renderer_param: &RendererParam,
+    ) -> Self {
+        let config_buffer = CompositeConfigUniform::new(opacity, renderer_param).prepare(device);
+
+        // The video may have another size than the screen
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let texture_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                multisampled: false,
+                view_dimension: TextureViewDimension::D2,
+                sample_type: TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            entries: &[
+                texture_entry(0),
+                texture_entry(1),
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("Composite bind group layout"),
+        });
+
+        let shader = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("Composite shader"),
+            source: ShaderSource::Wgsl(include_str!("composite.wgsl").into()),
+        });
+        let render_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Composite pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let render_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("Composite render Pipeline"),
+            layout: Some(&render_pipeline_layout),
+            vertex: VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                compilation_options: Default::default(),
+                buffers: &[],
+            },
+            fragment: Some(FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                compilation_options: Default::default(),
+                targets: &[Some(ColorTargetState {
+                    format,
+                    blend: Some(BlendState::REPLACE),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                polygon_mode: PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+            cache: None,
+        });
+
+        Self {
+            render_pipeline,
+            sampler,
+            bind_group_layout,
+            bind_group: None,
+            config_buffer,
+        }
+    }
+
+    pub fn update_config(&self, queue: &Queue, opacity: f32, renderer_param: &RendererParam) {
+        CompositeConfigUniform::new(opacity, renderer_param).update(&self.config_buffer, queue);
+    }
+
+    pub fn set_textures(
+        &mut self,
+        device: &Device,
+        video_view: &TextureView,
+        danmaku_view: &TextureView,
+    ) {
+        self.bind_group = Some(device.create_bind_group(&BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(video_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(danmaku_view),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::Sampler(&self.sampler),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: self.config_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("Composite bind group"),
+        }));
+    }
+
+    pub fn clear_textures(&mut self) {
+        self.bind_group = None;
+    }
+
+    pub fn has_textures(&self) -> bool {
+        self.bind_group.is_some()
+    }
+
+    pub fn render(&self, render_pass: &mut RenderPass, viewport: Option<Viewport>) {
+        let Some(bind_group) = &self.bind_group else {
+            return;
+        };
+        if let Some(viewport) = viewport {
+            render_pass.set_viewport(
+                viewport.x,
+                viewport.y,
+                viewport.width,
+                viewport.height,
+                0.0,
+                1.0,
+            );
+        }
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
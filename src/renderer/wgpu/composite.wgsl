@@ -0,0 +1,55 @@
+struct CompositeConfigUniform {
+    // Columns of the orientation matrix of the danmaku, see CopyConfigUniform
+    transform: vec4f,
+    opacity: f32,
+    additive: u32,
+    // The target texture holds premultiplied colors
+    premultiplied: u32,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4f,
+    @location(0) video_coords: vec2f,
+    @location(1) danmaku_coords: vec2f,
+}
+
+@group(0) @binding(0)
+var video_texture: texture_2d<f32>;
+@group(0) @binding(1)
+var danmaku_texture: texture_2d<f32>;
+@group(0) @binding(2)
+var texture_sampler: sampler;
+@group(0) @binding(3)
+var<uniform> config: CompositeConfigUniform;
+
+fn tex_coords(position: vec2f) -> vec2f {
+    return vec2f(position.x, -position.y) * 0.5 + 0.5;
+}
+
+// One triangle covering the screen
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var out: VertexOutput;
+    let position = vec2f(f32((index << 1u) & 2u), f32(index & 2u)) * 2.0 - 1.0;
+    out.clip_position = vec4f(position, 0.0, 1.0);
+    out.video_coords = tex_coords(position);
+    // The orientation only rotates and mirrors, so its inverse is the transpose
+    let transform = mat2x2f(config.transform.xy, config.transform.zw);
+    out.danmaku_coords = tex_coords(transpose(transform) * position);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    let video = textureSample(video_texture, texture_sampler, in.video_coords);
+    let danmaku = textureSample(danmaku_texture, texture_sampler, in.danmaku_coords);
+    let alpha = danmaku.a * config.opacity;
+    var color = danmaku.rgb * config.opacity;
+    if config.premultiplied == 0u {
+        color = color * danmaku.a;
+    }
+    if config.additive != 0u {
+        return vec4(video.rgb + color, video.a);
+    }
+    return vec4(color + video.rgb * (1.0 - alpha), video.a);
+}
@@ -3,10 +3,26 @@ use std::{mem::size_of, num::NonZeroUsize};
 use bytemuck::{Pod, Zeroable};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
-    Buffer, BufferUsages, Device, Queue,
+    Buffer, BufferUsages, Device, Queue, TextureFormat,
 };
 
-use crate::worker::DanmakuParam;
+use crate::{
+    layout::ScrollSpeedModel,
+    position::Easing,
+    renderer::{ColorSpace, RendererParam, ShadowColor},
+    worker::DanmakuParam,
+};
+
+pub(crate) fn resolve_color_space(color_space: ColorSpace, format: TextureFormat) -> ColorSpace {
+    match color_space {
+        ColorSpace::Auto => match format {
+            TextureFormat::Rgba16Float | TextureFormat::Rgba32Float => ColorSpace::LinearSrgb,
+            format if format.is_srgb() => ColorSpace::LinearSrgb,
+            _ => ColorSpace::Srgb,
+        },
+        color_space => color_space,
+    }
+}
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
@@ -14,21 +30,79 @@ pub struct ConfigUniform {
     screen_width: u32,
     screen_height: u32,
     line_height: u32,
-    lifetime: u32,
+    scroll_lifetime: u32,
+    static_lifetime: u32,
+    linear_output: u32,
+    fade_in: u32,
+    fade_out: u32,
+    scroll_speed: u32,
+    // Width of ScrollSpeedModel::CappedDuration
+    scroll_speed_cap: u32,
+    scroll_speed_variation: f32,
+    scroll_opacity: f32,
+    top_opacity: f32,
+    bottom_opacity: f32,
+    // Of the quads around the danmaku centers, while the font size is in transition
+    scale: f32,
+    // Applied by the copier instead, unless the renderer draws into the pass of the host
+    opacity: f32,
+    // See Easing::apply
+    easing: u32,
+    easing_strength: f32,
+    contrast_shadow: u32,
 }
 
-impl From<DanmakuParam> for ConfigUniform {
-    fn from(value: DanmakuParam) -> Self {
+impl ConfigUniform {
+    pub fn new(
+        danmaku_param: &DanmakuParam,
+        renderer_param: &RendererParam,
+        format: TextureFormat,
+    ) -> Self {
+        let color_space = resolve_color_space(renderer_param.color_space, format);
+        let scroll_speed = danmaku_param.scroll_speed_model();
+        let (easing, easing_strength) = match renderer_param.animation.scroll_easing {
+            Easing::Linear => (0, 0.0),
+            Easing::EaseOut(strength) => (1, strength.clamp(0.0, 1.0)),
+            Easing::EaseInOut(strength) => (2, strength.clamp(0.0, 1.0)),
+        };
         ConfigUniform {
-            screen_width: value.screen_size.0,
-            screen_height: value.screen_size.1,
-            line_height: value.line_height,
-            lifetime: value.lifetime.as_millis() as u32,
+            screen_width: danmaku_param.screen_size.0,
+            screen_height: danmaku_param.screen_size.1,
+            line_height: danmaku_param.physical_line_height(),
+            scroll_lifetime: danmaku_param.scroll_lifetime.as_millis() as u32,
+            static_lifetime: danmaku_param.static_lifetime.as_millis() as u32,
+            linear_output: (color_space == ColorSpace::LinearSrgb) as u32,
+            fade_in: renderer_param.animation.fade_in_millis,
+            fade_out: renderer_param.animation.fade_out_millis,
+            scroll_speed: match scroll_speed {
+                ScrollSpeedModel::ConstantDuration => 0,
+                ScrollSpeedModel::ConstantSpeed => 1,
+                ScrollSpeedModel::CappedDuration(_) => 2,
+            },
+            scroll_speed_cap: match scroll_speed {
+                ScrollSpeedModel::CappedDuration(max_width) => max_width,
+                _ => 0,
+            },
+            scroll_speed_variation: danmaku_param.scroll_speed_variation,
+            scroll_opacity: renderer_param.type_opacity.scroll,
+            top_opacity: renderer_param.type_opacity.top,
+            bottom_opacity: renderer_param.type_opacity.bottom,
+            scale: 1.0,
+            opacity: 1.0,
+            easing,
+            easing_strength,
+            contrast_shadow: (renderer_param.shadow_color == ShadowColor::Contrast) as u32,
         }
     }
-}
 
-impl ConfigUniform {
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale;
+    }
+
+    pub fn set_opacity(&mut self, opacity: f32) {
+        self.opacity = opacity;
+    }
+
     pub fn prepare(&self, device: &Device) -> Buffer {
         let buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: Some("Config Buffer"),
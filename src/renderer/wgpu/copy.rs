@@ -14,13 +14,55 @@ use wgpu::{
     VertexBufferLayout, VertexFormat, VertexState, VertexStepMode,
 };
 
+use crate::renderer::{BlendMode, Orientation};
+
+// Blend state of the pipelines drawing into a target, the colors are multiplied by the alpha in
+// the shader with premultiplied alpha
+pub(crate) fn blend_state(blend_mode: BlendMode, premultiplied_alpha: bool) -> BlendState {
+    let src_factor = if premultiplied_alpha {
+        BlendFactor::One
+    } else {
+        BlendFactor::SrcAlpha
+    };
+    let over = BlendComponent {
+        src_factor,
+        dst_factor: BlendFactor::OneMinusSrcAlpha,
+        operation: BlendOperation::Add,
+    };
+    match blend_mode {
+        BlendMode::Normal => BlendState {
+            color: over,
+            alpha: over,
+        },
+        BlendMode::Additive => BlendState {
+            color: BlendComponent {
+                src_factor,
+                dst_factor: BlendFactor::One,
+                operation: BlendOperation::Add,
+            },
+            alpha: over,
+        },
+        BlendMode::Replace => BlendState::REPLACE,
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
 pub struct CopyConfigUniform {
+    pub transform: [[f32; 2]; 2],
     pub opacity: f32,
+    _padding: [u32; 3],
 }
 
 impl CopyConfigUniform {
+    pub fn new(opacity: f32, orientation: Orientation) -> Self {
+        CopyConfigUniform {
+            transform: orientation.matrix(),
+            opacity,
+            _padding: [0; 3],
+        }
+    }
+
     pub fn prepare(&self, device: &Device) -> Buffer {
         let buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: Some("Copy config Buffer"),
@@ -89,6 +131,14 @@ const VERTICES: &[Vertex] = &[
 
 const INDICES: &[u16] = &[0, 2, 1, 1, 2, 3];
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
 pub(crate) struct TextureCopier {
     render_pipeline: RenderPipeline,
     vertex_buffer: Buffer,
@@ -105,8 +155,11 @@ impl TextureCopier {
         texture: &Texture,
         texture_view: &TextureView,
         opacity: f32,
+        orientation: Orientation,
+        blend_mode: BlendMode,
+        premultiplied_alpha: bool,
     ) -> Self {
-        let config_uniform = CopyConfigUniform { opacity };
+        let config_uniform = CopyConfigUniform::new(opacity, orientation);
         let config_buffer = config_uniform.prepare(device);
 
         let sampler = device.create_sampler(&SamplerDescriptor {
@@ -139,7 +192,7 @@ impl TextureCopier {
                 },
                 BindGroupLayoutEntry {
                     binding: 2,
-                    visibility: ShaderStages::FRAGMENT,
+                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -181,6 +234,12 @@ impl TextureCopier {
             push_constant_ranges: &[],
         });
 
+        let blend = blend_state(blend_mode, premultiplied_alpha);
+        let fragment_entry_point = if premultiplied_alpha {
+            "fs_main_premultiplied"
+        } else {
+            "fs_main"
+        };
         let render_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
             label: Some("Copy render Pipeline"),
             layout: Some(&render_pipeline_layout),
@@ -192,22 +251,11 @@ impl TextureCopier {
             },
             fragment: Some(FragmentState {
                 module: &shader,
-                entry_point: "fs_main",
+                entry_point: fragment_entry_point,
                 compilation_options: Default::default(),
                 targets: &[Some(ColorTargetState {
-                    format: texture.format().add_srgb_suffix(),
-                    blend: Some(BlendState {
-                        color: BlendComponent {
-                            src_factor: BlendFactor::SrcAlpha,
-                            dst_factor: BlendFactor::OneMinusSrcAlpha,
-                            operation: BlendOperation::Add,
-                        },
-                        alpha: BlendComponent {
-                            src_factor: BlendFactor::SrcAlpha,
-                            dst_factor: BlendFactor::OneMinusSrcAlpha,
-                            operation: BlendOperation::Add,
-                        },
-                    }),
+                    format: texture.format(),
+                    blend: Some(blend),
                     write_mask: ColorWrites::ALL,
                 })],
             }),
@@ -252,8 +300,8 @@ impl TextureCopier {
         }
     }
 
-    pub fn update_opacity(&self, queue: &Queue, opacity: f32) {
-        let new_uniform = CopyConfigUniform { opacity };
+    pub fn update_config(&self, queue: &Queue, opacity: f32, orientation: Orientation) {
+        let new_uniform = CopyConfigUniform::new(opacity, orientation);
         new_uniform.update(&self.config_buffer, queue);
     }
 
@@ -278,7 +326,17 @@ impl TextureCopier {
         });
     }
 
-    pub fn render(&self, render_pass: &mut RenderPass) {
+    pub fn render(&self, render_pass: &mut RenderPass, viewport: Option<Viewport>) {
+        if let Some(viewport) = viewport {
+            render_pass.set_viewport(
+                viewport.x,
+                viewport.y,
+                viewport.width,
+                viewport.height,
+                0.0,
+                1.0,
+            );
+        }
         render_pass.set_pipeline(&self.render_pipeline);
         render_pass.set_bind_group(0, &self.bind_group, &[]);
         render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
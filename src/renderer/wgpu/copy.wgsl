@@ -1,4 +1,7 @@
 struct CopyConfigUniform {
+    // Columns of the orientation matrix. Not a mat2x2f, as some backends lay out its columns 16
+    // bytes apart in uniforms.
+    transform: vec4f,
     opacity: f32,
 };
 
@@ -18,7 +21,8 @@ fn vs_main(
 ) -> VertexOutput {
     var out: VertexOutput;
     out.tex_coords = model.tex_coords;
-    out.clip_position = vec4f(model.position, 1.0);
+    let transform = mat2x2f(config.transform.xy, config.transform.zw);
+    out.clip_position = vec4f(transform * model.position.xy, model.position.z, 1.0);
     return out;
 }
 
@@ -33,4 +37,10 @@ var<uniform> config: CopyConfigUniform;
 fn fs_main(in: VertexOutput) -> @location(0) vec4f {
     let sample = textureSample(t_diffuse, s_diffuse, in.tex_coords);
     return vec4(sample.rgb, sample.a * config.opacity);
-}
\ No newline at end of file
+}
+
+@fragment
+fn fs_main_premultiplied(in: VertexOutput) -> @location(0) vec4f {
+    let sample = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    return sample * config.opacity;
+}
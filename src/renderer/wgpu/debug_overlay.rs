@@ -0,0 +1,214 @@
+use std::mem::size_of;
+
+use bytemuck::{Pod, Zeroable};
+use wgpu::{
+    include_wgsl,
+    util::{BufferInitDescriptor, DeviceExt},
+    vertex_attr_array, BlendState, Buffer, BufferAddress, BufferUsages, ColorTargetState,
+    ColorWrites, Device, FragmentState, MultisampleState, PipelineLayoutDescriptor, PrimitiveState,
+    RenderPass, RenderPipeline, RenderPipelineDescriptor, TextureFormat, VertexBufferLayout,
+    VertexState, VertexStepMode,
+};
+
+use crate::{
+    danmaku::DanmakuTime, layout::DanmakuPosition, manager::chunk_index, worker::DanmakuParam,
+};
+
+use super::vertex_buffer::VertexBuffer;
+
+const TRACK_LINE_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 0.4];
+const SCROLL_COLOR: [f32; 4] = [0.2, 0.4, 1.0, 0.3];
+const TOP_COLOR: [f32; 4] = [0.2, 1.0, 0.4, 0.3];
+const BOTTOM_COLOR: [f32; 4] = [1.0, 0.4, 0.2, 0.3];
+// Alternates between chunks, so the chunk boundaries are visible
+const CHUNK_COLORS: [[f32; 4]; 2] = [[1.0, 1.0, 0.0, 0.8], [1.0, 0.0, 1.0, 0.8]];
+const CHUNK_BAR_HEIGHT: u32 = 4;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct OverlayVertex {
+    position: [f32; 2],
+    color: [f32; 4],
+}
+
+impl OverlayVertex {
+    const ATTRIBS: [wgpu::VertexAttribute; 2] = vertex_attr_array![0 => Float32x2, 1 => Float32x4];
+
+    fn desc<'a>() -> VertexBufferLayout<'a> {
+        VertexBufferLayout {
+            array_stride: size_of::<Self>() as BufferAddress,
+            step_mode: VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+struct RectBuilder {
+    screen_size: (u32, u32),
+    premultiplied_alpha: bool,
+    vertices: Vec<OverlayVertex>,
+}
+
+impl RectBuilder {
+    fn rect(&mut self, (x, y): (i32, i32), (width, height): (u32, u32), color: [f32; 4]) {
+        let [r, g, b, a] = color;
+        let color = if self.premultiplied_alpha {
+            [r * a, g * a, b * a, a]
+        } else {
+            color
+        };
+        let (screen_width, screen_height) = (self.screen_size.0 as f32, self.screen_size.1 as f32);
+        let vertex = |x: i32, y: i32| OverlayVertex {
+            position: [
+                x as f32 / screen_width * 2.0 - 1.0,
+                1.0 - y as f32 / screen_height * 2.0,
+            ],
+            color,
+        };
+        let (right, bottom) = (x + width as i32, y + height as i32);
+        self.vertices.extend([
+            vertex(x, y),
+            vertex(x, bottom),
+            vertex(right, y),
+            vertex(right, y),
+            vertex(x, bottom),
+            vertex(right, bottom),
+        ]);
+    }
+}
+
+pub(crate) struct DebugOverlay {
+    render_pipeline: RenderPipeline,
+    premultiplied_alpha: bool,
+    vertex_buffer: Option<Buffer>,
+    vertices: u32,
+}
+
+impl DebugOverlay {
+    pub(crate) fn new(device: &Device, format: TextureFormat, premultiplied_alpha: bool) -> Self {
+        let shader = device.create_shader_module(include_wgsl!("debug_overlay.wgsl"));
+        let layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Debug overlay pipeline layout"),
+            bind_group_layouts: &[],
+            push_constant_ranges: &[],
+        });
+        let blend = if premultiplied_alpha {
+            BlendState::PREMULTIPLIED_ALPHA_BLENDING
+        } else {
+            BlendState::ALPHA_BLENDING
+        };
+        let render_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("Debug overlay pipeline"),
+            layout: Some(&layout),
+            vertex: VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                compilation_options: Default::default(),
+                buffers: &[OverlayVertex::desc()],
+            },
+            fragment: Some(FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                compilation_options: Default::default(),
+                targets: &[Some(ColorTargetState {
+                    format,
+                    blend: Some(blend),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+        DebugOverlay {
+            render_pipeline,
+            premultiplied_alpha,
+            vertex_buffer: None,
+            vertices: 0,
+        }
+    }
+
+    pub(crate) fn premultiplied_alpha(&self) -> bool {
+        self.premultiplied_alpha
+    }
+
+    // Rebuilds the rectangles for the given time, called once per frame
+    pub(crate) fn prepare<'a>(
+        &mut self,
+        device: &Device,
+        param: &DanmakuParam,
+        timestamp: DanmakuTime,
+        chunks: impl Iterator<Item = &'a VertexBuffer>,
+    ) {
+        let (screen_width, screen_height) = param.screen_size;
+        let line_height = param.physical_line_height();
+        let mut builder = RectBuilder {
+            screen_size: param.screen_size,
+            premultiplied_alpha: self.premultiplied_alpha,
+            vertices: Vec::new(),
+        };
+
+        // Occupied area of every danmaku on the screen, drawn with the same math as the shader
+        for span in chunks.flat_map(|chunk| chunk.spans.iter()) {
+            let lifetime = param.item_lifetime(span.position, span.width);
+            let elapsed = timestamp.as_millis() - span.time.as_millis();
+            if elapsed < 0 || elapsed as u128 >= lifetime.as_millis() {
+                continue;
+            }
+            let progress = elapsed as f32 / lifetime.as_millis() as f32;
+            let centered = (screen_width as i32 - span.width as i32) / 2;
+            let (x, y, color) = match span.position {
+                DanmakuPosition::Scroll(track) => {
+                    let x = screen_width as f32 - (screen_width + span.width) as f32 * progress;
+                    (x as i32, line_height * track as u32, SCROLL_COLOR)
+                }
+                DanmakuPosition::Top(track) => (centered, line_height * track as u32, TOP_COLOR),
+                DanmakuPosition::Bottom(track) => (
+                    centered,
+                    screen_height.saturating_sub(line_height * (track as u32 + 1)),
+                    BOTTOM_COLOR,
+                ),
+            };
+            builder.rect((x, y as i32), (span.width, line_height * span.lines), color);
+        }
+
+        // Track boundaries
+        if line_height > 0 {
+            for y in (line_height..screen_height).step_by(line_height as usize) {
+                builder.rect((0, y as i32), (screen_width, 1), TRACK_LINE_COLOR);
+            }
+        }
+
+        // Progress through the current chunk
+        let chunk_millis = param.chunk_duration.as_millis() as i64;
+        if chunk_millis > 0 {
+            let index = chunk_index(timestamp, param.chunk_duration);
+            let progress = timestamp.as_millis().max(0) % chunk_millis;
+            let width = (screen_width as i64 * progress / chunk_millis) as u32;
+            builder.rect(
+                (0, 0),
+                (width, CHUNK_BAR_HEIGHT),
+                CHUNK_COLORS[index as usize % 2],
+            );
+        }
+
+        self.vertices = builder.vertices.len() as u32;
+        self.vertex_buffer = (self.vertices > 0).then(|| {
+            device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("Debug overlay vertex buffer"),
+                contents: bytemuck::cast_slice(&builder.vertices),
+                usage: BufferUsages::VERTEX,
+            })
+        });
+    }
+
+    pub(crate) fn render(&self, render_pass: &mut RenderPass) {
+        if let Some(vertex_buffer) = &self.vertex_buffer {
+            render_pass.set_pipeline(&self.render_pipeline);
+            render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+            render_pass.draw(0..self.vertices, 0..1);
+        }
+    }
+}
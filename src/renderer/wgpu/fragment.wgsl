@@ -1,14 +1,25 @@
 struct GlyphConfigUniform {
     texture_width: u32,
-    texture_height: u32
+    texture_height: u32,
+    emote_texture_width: u32,
+    emote_texture_height: u32
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4f,
     @location(0) color: vec3f,
     @location(1) tex_coords: vec2f,
+    @location(2) fade: f32,
+    @location(3) @interpolate(flat) kind: u32,
+    @location(4) shadow_color: vec3f,
+    @location(5) mask_coords: vec2f,
 };
 
+@group(0) @binding(2)
+var mask_texture: texture_2d<f32>;
+@group(0) @binding(3)
+var mask_sampler: sampler;
+
 @group(1) @binding(0)
 var texture: texture_2d<f32>;
 @group(1) @binding(1)
@@ -17,17 +28,65 @@ var shadow_texture: texture_2d<f32>;
 var texture_sampler: sampler;
 @group(1) @binding(3)
 var<uniform> config: GlyphConfigUniform;
+@group(1) @binding(4)
+var emote_texture: texture_2d<f32>;
 
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+fn srgb_to_linear(color: vec3f) -> vec3f {
+    let lower = color / 12.92;
+    let higher = pow((color + 0.055) / 1.055, vec3f(2.4));
+    return select(higher, lower, color <= vec3f(0.04045));
+}
+
+fn glyph_color(in: VertexOutput) -> vec4f {
+    // Sample everything up front, texture sampling must stay in uniform control flow
     let tex_coords = vec2f(
         in.tex_coords.x / f32(config.texture_width),
         in.tex_coords.y / f32(config.texture_height)
     );
+    let emote_tex_coords = vec2f(
+        in.tex_coords.x / f32(config.emote_texture_width),
+        in.tex_coords.y / f32(config.emote_texture_height)
+    );
     let sampled = textureSample(texture, texture_sampler, tex_coords);
     let shadow_sampled = textureSample(shadow_texture, texture_sampler, tex_coords);
-    let alpha = sampled.r;
-    let text = vec4(in.color * alpha, alpha);
-    let shadow = vec4(vec3(0.0), shadow_sampled.r);
-    return shadow + text * alpha;
+    let emote_sampled = textureSample(emote_texture, texture_sampler, emote_tex_coords);
+
+    switch in.kind {
+        case 1u, 3u: {
+            var color = emote_sampled.rgb;
+            if in.kind == 3u {
+                color = srgb_to_linear(color);
+            }
+            return vec4(color, emote_sampled.a * in.fade);
+        }
+        case 2u: {
+            return vec4(in.color, in.fade);
+        }
+        default: {
+            let alpha = sampled.r;
+            let text = vec4(in.color * alpha, alpha);
+            // The shadow color only shows where the glyph doesn't cover it
+            let shadow = vec4(in.shadow_color * (1.0 - alpha * alpha), shadow_sampled.r);
+            let color = shadow + text * alpha;
+            return vec4(color.rgb, color.a * in.fade);
+        }
+    }
+}
+
+// Transparent where the mask is set
+fn masked_color(in: VertexOutput) -> vec4f {
+    let mask = textureSample(mask_texture, mask_sampler, in.mask_coords).r;
+    let color = glyph_color(in);
+    return vec4(color.rgb, color.a * (1.0 - mask));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    return masked_color(in);
+}
+
+@fragment
+fn fs_main_premultiplied(in: VertexOutput) -> @location(0) vec4f {
+    let color = masked_color(in);
+    return vec4(color.rgb * color.a, color.a);
 }
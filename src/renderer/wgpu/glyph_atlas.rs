@@ -1,13 +1,17 @@
-use cosmic_text::{Placement, SwashImage};
+use cosmic_text::Placement;
 use etagere::{size2, Allocation, BucketedAtlasAllocator};
 use wgpu::{Extent3d, ImageCopyTexture, ImageDataLayout, Origin3d, Queue, Texture, TextureAspect};
 
+use crate::{renderer::RgbaImage, shaper::GlyphBitmap};
+
 // TODO: add some recycle
 #[allow(unused)]
 pub(crate) struct GlyphItem {
+    // Including the padding
     pub(crate) placement: Placement,
     pub(crate) tex_coords: (u32, u32),
     pub(crate) tex_size: (u32, u32),
+    pub(crate) padding: u32,
     allocation: Allocation,
 }
 
@@ -15,22 +19,23 @@ impl GlyphItem {
     fn new(
         texture: &Texture,
         queue: &Queue,
-        image: &SwashImage,
+        placement: Placement,
+        data: &[u8],
+        bytes_per_pixel: u32,
         allocation: Allocation,
-        shadow_width: u32,
+        padding: u32,
     ) -> Self {
-        let data = &image.data;
-        assert!(allocation.rectangle.width() as u32 >= image.placement.width);
-        assert!(allocation.rectangle.height() as u32 >= image.placement.height);
+        assert!(allocation.rectangle.width() as u32 >= placement.width);
+        assert!(allocation.rectangle.height() as u32 >= placement.height);
         let allocation_x = allocation.rectangle.min.x;
         let allocation_y = allocation.rectangle.min.y;
         let tex_coords = (allocation_x as u32, allocation_y as u32);
         let tex_size = (
-            image.placement.width + shadow_width * 2,
-            image.placement.height + shadow_width * 2,
+            placement.width + padding * 2,
+            placement.height + padding * 2,
         );
-        let width = image.placement.width;
-        let height = image.placement.height;
+        let width = placement.width;
+        let height = placement.height;
         let allocation_x = allocation.rectangle.min.x as u32;
         let allocation_y = allocation.rectangle.min.y as u32;
         let size = Extent3d {
@@ -43,8 +48,8 @@ impl GlyphItem {
                 texture,
                 mip_level: 0,
                 origin: Origin3d {
-                    x: allocation_x + shadow_width,
-                    y: allocation_y + shadow_width,
+                    x: allocation_x + padding,
+                    y: allocation_y + padding,
                     z: 0,
                 },
                 aspect: TextureAspect::All,
@@ -52,24 +57,39 @@ impl GlyphItem {
             data,
             ImageDataLayout {
                 offset: 0,
-                bytes_per_row: Some(image.placement.width),
-                rows_per_image: Some(image.placement.height),
+                bytes_per_row: Some(placement.width * bytes_per_pixel),
+                rows_per_image: Some(placement.height),
             },
             size,
         );
         let new_placement = Placement {
-            left: image.placement.left - shadow_width as i32,
-            top: image.placement.top + shadow_width as i32,
-            width: image.placement.width + shadow_width * 2,
-            height: image.placement.height + shadow_width * 2,
+            left: placement.left - padding as i32,
+            top: placement.top + padding as i32,
+            width: placement.width + padding * 2,
+            height: placement.height + padding * 2,
         };
         Self {
             placement: new_placement,
             tex_coords,
             tex_size,
+            padding,
             allocation,
         }
     }
+
+    // Placement, texture coordinates and size with only the given width of the padding
+    pub(crate) fn padded(&self, width: u32) -> (Placement, (u32, u32), (u32, u32)) {
+        let trim = self.padding.saturating_sub(width);
+        let placement = Placement {
+            left: self.placement.left + trim as i32,
+            top: self.placement.top - trim as i32,
+            width: self.placement.width - trim * 2,
+            height: self.placement.height - trim * 2,
+        };
+        let tex_coords = (self.tex_coords.0 + trim, self.tex_coords.1 + trim);
+        let tex_size = (self.tex_size.0 - trim * 2, self.tex_size.1 - trim * 2);
+        (placement, tex_coords, tex_size)
+    }
 }
 
 pub(crate) struct GlyphLayer {
@@ -99,15 +119,146 @@ impl GlyphLayer {
         &mut self,
         texture: &Texture,
         queue: &Queue,
-        image: &SwashImage,
+        image: &GlyphBitmap,
         shadow_width: u32,
+    ) -> Option<GlyphItem> {
+        self.new_image(
+            texture,
+            queue,
+            image.placement,
+            &image.data,
+            1,
+            shadow_width,
+        )
+    }
+
+    pub(crate) fn new_image(
+        &mut self,
+        texture: &Texture,
+        queue: &Queue,
+        placement: Placement,
+        data: &[u8],
+        bytes_per_pixel: u32,
+        padding: u32,
     ) -> Option<GlyphItem> {
         let size = size2(
-            (image.placement.width + shadow_width * 2) as i32,
-            (image.placement.height + shadow_width * 2) as i32,
+            (placement.width + padding * 2) as i32,
+            (placement.height + padding * 2) as i32,
         );
-        self.allocator
-            .allocate(size)
-            .map(|allocation| GlyphItem::new(texture, queue, image, allocation, shadow_width))
+        self.allocator.allocate(size).map(|allocation| {
+            GlyphItem::new(
+                texture,
+                queue,
+                placement,
+                data,
+                bytes_per_pixel,
+                allocation,
+                padding,
+            )
+        })
+    }
+}
+
+// Copy of the contents of an atlas texture in memory
+pub(crate) struct AtlasMirror {
+    size: (u32, u32),
+    bytes_per_pixel: u32,
+    data: Vec<u8>,
+}
+
+impl AtlasMirror {
+    pub(crate) fn new(size: (u32, u32), bytes_per_pixel: u32) -> Self {
+        Self {
+            size,
+            bytes_per_pixel,
+            data: vec![0; (size.0 * size.1 * bytes_per_pixel) as usize],
+        }
+    }
+
+    pub(crate) fn write(&mut self, origin: (u32, u32), placement: Placement, data: &[u8]) {
+        let row_size = (placement.width * self.bytes_per_pixel) as usize;
+        let stride = (self.size.0 * self.bytes_per_pixel) as usize;
+        for (y, row) in data
+            .chunks_exact(row_size)
+            .take(placement.height as usize)
+            .enumerate()
+        {
+            let start =
+                (origin.1 as usize + y) * stride + (origin.0 * self.bytes_per_pixel) as usize;
+            self.data[start..start + row_size].copy_from_slice(row);
+        }
+    }
+
+    // The existing contents stay at the top left corner, like with the allocator
+    pub(crate) fn grow(&mut self, new_size: (u32, u32)) {
+        let mut grown = AtlasMirror::new(new_size, self.bytes_per_pixel);
+        let placement = Placement {
+            left: 0,
+            top: 0,
+            width: self.size.0,
+            height: self.size.1,
+        };
+        grown.write((0, 0), placement, &self.data);
+        *self = grown;
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.data.fill(0);
+    }
+
+    pub(crate) fn upload(&self, queue: &Queue, texture: &Texture) {
+        queue.write_texture(
+            texture.as_image_copy(),
+            &self.data,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(self.size.0 * self.bytes_per_pixel),
+                rows_per_image: Some(self.size.1),
+            },
+            Extent3d {
+                width: self.size.0,
+                height: self.size.1,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    // Masks are white with the coverage as alpha
+    pub(crate) fn to_image(&self) -> RgbaImage {
+        let data = match self.bytes_per_pixel {
+            1 => self
+                .data
+                .iter()
+                .flat_map(|alpha| [255, 255, 255, *alpha])
+                .collect(),
+            _ => self.data.clone(),
+        };
+        RgbaImage::new(self.size.0, self.size.1, data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cosmic_text::Placement;
+
+    use super::AtlasMirror;
+
+    #[test]
+    fn test_atlas_mirror() {
+        let mut mirror = AtlasMirror::new((4, 2), 1);
+        let placement = Placement {
+            left: 0,
+            top: 0,
+            width: 2,
+            height: 2,
+        };
+        mirror.write((1, 0), placement, &[1, 2, 3, 4]);
+        mirror.grow((8, 4));
+        let image = mirror.to_image();
+        assert_eq!((image.width(), image.height()), (8, 4));
+        assert_eq!(image.pixel(1, 0), [255, 255, 255, 1]);
+        assert_eq!(image.pixel(2, 1), [255, 255, 255, 4]);
+        assert_eq!(image.pixel(0, 1), [255, 255, 255, 0]);
+        assert_eq!(image.pixel(1, 2), [255, 255, 255, 0]);
     }
 }
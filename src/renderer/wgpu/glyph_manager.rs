@@ -5,8 +5,8 @@ use std::{
 };
 
 use bytemuck::{Pod, Zeroable};
-use cosmic_text::{CacheKey, FontSystem, SwashCache, SwashImage};
-use log::info;
+use cosmic_text::{CacheKey, Placement};
+use log::{info, warn};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
     AddressMode, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
@@ -17,12 +17,20 @@ use wgpu::{
     TextureView, TextureViewDescriptor, TextureViewDimension,
 };
 
-use crate::manager::DanmakuTimeChunk;
+use crate::{
+    emote::EmoteImage,
+    manager::DanmakuTimeChunk,
+    renderer::{
+        disk_cache::{self, GlyphDiskCache},
+        RgbaImage,
+    },
+    shaper::{GlyphBitmap, TextShaper},
+    worker::ShadowKernel,
+};
 
 use super::{
-    glyph_atlas::{GlyphItem, GlyphLayer},
+    glyph_atlas::{AtlasMirror, GlyphItem, GlyphLayer},
     glyph_shadow::GlyphShadow,
-    index_buffer::IndexBuffer,
 };
 
 #[repr(C)]
@@ -30,6 +38,8 @@ use super::{
 pub struct GlyphConfigUniform {
     pub texture_width: u32,
     pub texture_height: u32,
+    pub emote_texture_width: u32,
+    pub emote_texture_height: u32,
 }
 
 impl GlyphConfigUniform {
@@ -51,20 +61,124 @@ impl GlyphConfigUniform {
     }
 }
 
+fn create_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    [texture_view, shadow_texture_view, emote_texture_view]: [&TextureView; 3],
+    sampler: &Sampler,
+    config_buffer: &Buffer,
+) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("Glyph texture bind group"),
+        layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(texture_view),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(shadow_texture_view),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::Sampler(sampler),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: config_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::TextureView(emote_texture_view),
+            },
+        ],
+    })
+}
+
+// Emotes are scaled by the sampler, keep a transparent border to avoid bleeding
+const EMOTE_PADDING: u32 = 1;
+
+// Physical pixels reserved around the glyphs even with smaller shadows, so the shadow can grow
+// without rasterizing the glyphs again. Costs atlas space, a 28x28 glyph takes 36x36.
+const MIN_PADDING: u32 = 4;
+
+fn create_glyph_textures(device: &Device, size: Extent3d) -> (Texture, Texture) {
+    let texture = device.create_texture(&TextureDescriptor {
+        label: Some("Glyph texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: TextureFormat::R8Unorm,
+        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST | TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+    let shadow_texture = device.create_texture(&TextureDescriptor {
+        label: Some("Shadow texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        // Written by the shadow compute shaders, R8Unorm can't be a storage texture
+        format: TextureFormat::Rgba8Unorm,
+        usage: TextureUsages::TEXTURE_BINDING
+            | TextureUsages::STORAGE_BINDING
+            | TextureUsages::COPY_DST
+            | TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+    (texture, shadow_texture)
+}
+
+fn create_emote_texture(device: &Device, size: Extent3d) -> Texture {
+    device.create_texture(&TextureDescriptor {
+        label: Some("Emote texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        // Emotes are sRGB encoded, the vertex shader decides whether to decode them
+        format: TextureFormat::Rgba8Unorm,
+        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST | TextureUsages::COPY_SRC,
+        view_formats: &[],
+    })
+}
+
+fn create_view(texture: &Texture) -> TextureView {
+    texture.create_view(&TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::D2),
+        ..Default::default()
+    })
+}
+
 pub struct GlyphTextureManager {
     texture_size: (u32, u32),
-    swash_cache: SwashCache,
+    disk_cache: Option<GlyphDiskCache>,
     pub(crate) texture: Texture,
     pub(crate) shadow_texture: Texture,
+    texture_view: TextureView,
+    shadow_texture_view: TextureView,
     sampler: Sampler,
     pub(crate) bind_group_layout: BindGroupLayout,
     pub(crate) bind_group: BindGroup,
     layer: GlyphLayer,
     glyphs: HashMap<CacheKey, Option<GlyphItem>>,
+    emote_texture_size: (u32, u32),
+    emote_texture: Texture,
+    emote_texture_view: TextureView,
+    emote_layer: GlyphLayer,
+    emotes: HashMap<String, Option<GlyphItem>>,
     config_uniform: GlyphConfigUniform,
     config_buffer: Buffer,
+    // As requested, the drawn shadow is limited to the padding
     shadow_width: u32,
+    // Space reserved around the glyphs when the atlas is created, so changing the shadow up to
+    // this width only redraws the shadow texture
+    padding: u32,
     shadow: GlyphShadow,
+    // Copies of the glyph and emote textures, see enable_mirror
+    mirror: Option<(AtlasMirror, AtlasMirror)>,
 }
 
 impl GlyphTextureManager {
@@ -73,10 +187,31 @@ impl GlyphTextureManager {
         device: &Device,
         shadow_width: u32,
         shadow_weight: f32,
+        shadow_kernel: ShadowKernel,
+    ) -> Self {
+        Self::with_sizes(
+            texture_size,
+            (256, 256),
+            device,
+            shadow_width,
+            shadow_weight,
+            shadow_kernel,
+        )
+    }
+
+    fn with_sizes(
+        texture_size: (u32, u32),
+        emote_texture_size: (u32, u32),
+        device: &Device,
+        shadow_width: u32,
+        shadow_weight: f32,
+        shadow_kernel: ShadowKernel,
     ) -> Self {
         let config_uniform = GlyphConfigUniform {
             texture_width: texture_size.0,
             texture_height: texture_size.1,
+            emote_texture_width: emote_texture_size.0,
+            emote_texture_height: emote_texture_size.1,
         };
         let config_buffer = config_uniform.prepare(device);
         let sampler = device.create_sampler(&SamplerDescriptor {
@@ -93,39 +228,18 @@ impl GlyphTextureManager {
             height: texture_size.1,
             depth_or_array_layers: 1,
         };
-        let texture = device.create_texture(&TextureDescriptor {
-            label: Some("Glyph texture"),
-            size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: TextureDimension::D2,
-            format: TextureFormat::R8Unorm,
-            usage: TextureUsages::TEXTURE_BINDING
-                | TextureUsages::COPY_DST
-                | TextureUsages::COPY_SRC,
-            view_formats: &[],
-        });
-        let shadow_texture = device.create_texture(&TextureDescriptor {
-            label: Some("Shadow texture"),
-            size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: TextureDimension::D2,
-            format: TextureFormat::R8Unorm,
-            usage: TextureUsages::TEXTURE_BINDING
-                | TextureUsages::RENDER_ATTACHMENT
-                | TextureUsages::COPY_DST
-                | TextureUsages::COPY_SRC,
-            view_formats: &[],
-        });
-        let texture_view = texture.create_view(&TextureViewDescriptor {
-            dimension: Some(TextureViewDimension::D2),
-            ..Default::default()
-        });
-        let shadow_texture_view = shadow_texture.create_view(&TextureViewDescriptor {
-            dimension: Some(TextureViewDimension::D2),
-            ..Default::default()
-        });
+        let (texture, shadow_texture) = create_glyph_textures(device, size);
+        let texture_view = create_view(&texture);
+        let shadow_texture_view = create_view(&shadow_texture);
+        let emote_texture = create_emote_texture(
+            device,
+            Extent3d {
+                width: emote_texture_size.0,
+                height: emote_texture_size.1,
+                depth_or_array_layers: 1,
+            },
+        );
+        let emote_texture_view = create_view(&emote_texture);
         let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("Glyph texture bind group layout"),
             entries: &[
@@ -165,150 +279,143 @@ impl GlyphTextureManager {
                     },
                     count: None,
                 },
-            ],
-        });
-        let bind_group = device.create_bind_group(&BindGroupDescriptor {
-            label: Some("Glyph texture bind group"),
-            layout: &bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: BindingResource::TextureView(&texture_view),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::TextureView(&shadow_texture_view),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: BindingResource::Sampler(&sampler),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: config_buffer.as_entire_binding(),
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
                 },
             ],
         });
+        let bind_group = create_bind_group(
+            device,
+            &bind_group_layout,
+            [&texture_view, &shadow_texture_view, &emote_texture_view],
+            &sampler,
+            &config_buffer,
+        );
 
         let layer = GlyphLayer::new(texture_size);
         let shadow = GlyphShadow::new(
             device,
-            &texture,
             &texture_view,
             texture_size,
             shadow_width,
             shadow_weight,
+            shadow_kernel,
         );
 
         Self {
             texture_size,
-            swash_cache: SwashCache::new(),
+            disk_cache: None,
             texture,
             shadow_texture,
+            texture_view,
+            shadow_texture_view,
             bind_group_layout,
             sampler,
             bind_group,
             layer,
             glyphs: Default::default(),
+            emote_texture_size,
+            emote_texture,
+            emote_texture_view,
+            emote_layer: GlyphLayer::new(emote_texture_size),
+            emotes: Default::default(),
             config_uniform,
             config_buffer,
             shadow_width,
+            padding: shadow_width.max(MIN_PADDING),
             shadow,
+            mirror: None,
         }
     }
 
-    // TODO: Copy the texture to memory if out of display memory
+    // Keeps a copy of the textures in memory. Growing uploads the copy instead of copying the
+    // old texture on the GPU, recreate keeps the glyphs, and the atlases can be dumped. Glyphs
+    // added before are dropped.
+    pub fn enable_mirror(&mut self) {
+        self.clear();
+        self.emotes.clear();
+        self.emote_layer.clear();
+        self.mirror = Some((
+            AtlasMirror::new(self.texture_size, 1),
+            AtlasMirror::new(self.emote_texture_size, 4),
+        ));
+    }
+
     fn copy_texture(
         &mut self,
         device: &Device,
+        queue: &Queue,
         new_size: Extent3d,
-    ) -> (CommandBuffer, TextureView) {
+    ) -> Option<CommandBuffer> {
         let old_size = Extent3d {
             width: self.texture_size.0,
             height: self.texture_size.1,
             depth_or_array_layers: 1,
         };
 
-        let new_texture = device.create_texture(&TextureDescriptor {
-            label: Some("Glyph texture"),
-            size: new_size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: TextureDimension::D2,
-            format: TextureFormat::R8Unorm,
-            usage: TextureUsages::TEXTURE_BINDING
-                | TextureUsages::COPY_DST
-                | TextureUsages::COPY_SRC,
-            view_formats: &[],
-        });
-        let new_shadow_texture = device.create_texture(&TextureDescriptor {
-            label: Some("Shadow texture"),
-            size: new_size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: TextureDimension::D2,
-            format: TextureFormat::R8Unorm,
-            usage: TextureUsages::TEXTURE_BINDING
-                | TextureUsages::RENDER_ATTACHMENT
-                | TextureUsages::COPY_DST
-                | TextureUsages::COPY_SRC,
-            view_formats: &[],
-        });
+        let (new_texture, new_shadow_texture) = create_glyph_textures(device, new_size);
 
-        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
-            label: Some("Texture growing commands"),
-        });
-        encoder.copy_texture_to_texture(
-            self.texture.as_image_copy(),
-            new_texture.as_image_copy(),
-            old_size,
-        );
-        encoder.copy_texture_to_texture(
-            self.shadow_texture.as_image_copy(),
-            new_shadow_texture.as_image_copy(),
-            old_size,
-        );
+        let buffer = match &mut self.mirror {
+            Some((mirror, _)) => {
+                mirror.grow((new_size.width, new_size.height));
+                mirror.upload(queue, &new_texture);
+                // The shadows are drawn again at the next flush
+                for item in self.glyphs.values().flatten() {
+                    self.shadow.new_glyph(item);
+                }
+                None
+            }
+            None => {
+                let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+                    label: Some("Texture growing commands"),
+                });
+                encoder.copy_texture_to_texture(
+                    self.texture.as_image_copy(),
+                    new_texture.as_image_copy(),
+                    old_size,
+                );
+                encoder.copy_texture_to_texture(
+                    self.shadow_texture.as_image_copy(),
+                    new_shadow_texture.as_image_copy(),
+                    old_size,
+                );
+                Some(encoder.finish())
+            }
+        };
 
-        let new_texture_view = new_texture.create_view(&TextureViewDescriptor {
-            dimension: Some(TextureViewDimension::D2),
-            ..Default::default()
-        });
-        let new_shadow_texture_view = new_shadow_texture.create_view(&TextureViewDescriptor {
-            dimension: Some(TextureViewDimension::D2),
-            ..Default::default()
-        });
-        let new_bind_group = device.create_bind_group(&BindGroupDescriptor {
-            label: Some("Glyph texture bind group layout"),
-            layout: &self.bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: BindingResource::TextureView(&new_texture_view),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::TextureView(&new_shadow_texture_view),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: BindingResource::Sampler(&self.sampler),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: self.config_buffer.as_entire_binding(),
-                },
+        let new_texture_view = create_view(&new_texture);
+        let new_shadow_texture_view = create_view(&new_shadow_texture);
+        let new_bind_group = create_bind_group(
+            device,
+            &self.bind_group_layout,
+            [
+                &new_texture_view,
+                &new_shadow_texture_view,
+                &self.emote_texture_view,
             ],
-        });
+            &self.sampler,
+            &self.config_buffer,
+        );
 
         self.texture = new_texture;
         self.shadow_texture = new_shadow_texture;
+        self.texture_view = new_texture_view;
+        self.shadow_texture_view = new_shadow_texture_view;
         self.bind_group = new_bind_group;
 
-        (encoder.finish(), new_texture_view)
+        buffer
     }
 
     #[must_use]
-    fn grow_texture(&mut self, device: &Device, queue: &Queue) -> CommandBuffer {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn grow_texture(&mut self, device: &Device, queue: &Queue) -> Option<CommandBuffer> {
         let new_size = (self.texture_size.0 * 2, self.texture_size.1 * 2);
         let new_texture_size = Extent3d {
             width: new_size.0,
@@ -321,11 +428,73 @@ impl GlyphTextureManager {
         self.config_uniform.texture_height = new_size.1;
         self.config_uniform.update(&self.config_buffer, queue);
 
-        let (buffer, texture_view) = self.copy_texture(device, new_texture_size);
+        let buffer = self.copy_texture(device, queue, new_texture_size);
         self.texture_size = new_size;
         self.layer.grow(new_size);
         self.shadow
-            .update_texture(device, queue, new_size, &texture_view);
+            .update_texture(device, queue, new_size, &self.texture_view);
+
+        buffer
+    }
+
+    #[must_use]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn grow_emote_texture(&mut self, device: &Device, queue: &Queue) -> Option<CommandBuffer> {
+        let old_size = Extent3d {
+            width: self.emote_texture_size.0,
+            height: self.emote_texture_size.1,
+            depth_or_array_layers: 1,
+        };
+        let new_size = (self.emote_texture_size.0 * 2, self.emote_texture_size.1 * 2);
+        info!("Grow emote texture to {}x{}", new_size.0, new_size.1);
+
+        self.config_uniform.emote_texture_width = new_size.0;
+        self.config_uniform.emote_texture_height = new_size.1;
+        self.config_uniform.update(&self.config_buffer, queue);
+
+        let new_texture = create_emote_texture(
+            device,
+            Extent3d {
+                width: new_size.0,
+                height: new_size.1,
+                depth_or_array_layers: 1,
+            },
+        );
+        let buffer = match &mut self.mirror {
+            Some((_, mirror)) => {
+                mirror.grow(new_size);
+                mirror.upload(queue, &new_texture);
+                None
+            }
+            None => {
+                let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+                    label: Some("Emote texture growing commands"),
+                });
+                encoder.copy_texture_to_texture(
+                    self.emote_texture.as_image_copy(),
+                    new_texture.as_image_copy(),
+                    old_size,
+                );
+                Some(encoder.finish())
+            }
+        };
+        let new_texture_view = create_view(&new_texture);
+        self.bind_group = create_bind_group(
+            device,
+            &self.bind_group_layout,
+            [
+                &self.texture_view,
+                &self.shadow_texture_view,
+                &new_texture_view,
+            ],
+            &self.sampler,
+            &self.config_buffer,
+        );
+
+        self.emote_texture = new_texture;
+        self.emote_texture_view = new_texture_view;
+        self.emote_texture_size = new_size;
+        self.emote_layer.grow(new_size);
 
         buffer
     }
@@ -334,79 +503,361 @@ impl GlyphTextureManager {
         self.glyphs.get(glyph).and_then(|item| item.as_ref())
     }
 
+    pub fn find_emote(&self, shortcode: &str) -> Option<&GlyphItem> {
+        self.emotes.get(shortcode).and_then(|item| item.as_ref())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn insert_emote(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        shortcode: &str,
+        image: &EmoteImage,
+        command_buffer: &mut Vec<CommandBuffer>,
+    ) {
+        let placement = Placement {
+            left: 0,
+            top: 0,
+            width: image.width(),
+            height: image.height(),
+        };
+        let max_size = device.limits().max_texture_dimension_2d;
+        loop {
+            let item = self.emote_layer.new_image(
+                &self.emote_texture,
+                queue,
+                placement,
+                image.data(),
+                4,
+                EMOTE_PADDING,
+            );
+            if let Some(item) = item {
+                if let Some((_, mirror)) = &mut self.mirror {
+                    let origin = (
+                        item.tex_coords.0 + EMOTE_PADDING,
+                        item.tex_coords.1 + EMOTE_PADDING,
+                    );
+                    mirror.write(origin, placement, image.data());
+                }
+                self.emotes.insert(shortcode.to_string(), Some(item));
+                return;
+            }
+            if self.emote_texture_size.0 * 2 > max_size {
+                warn!("Emote {} doesn't fit into the texture", shortcode);
+                self.emotes.insert(shortcode.to_string(), None);
+                return;
+            }
+            command_buffer.extend(self.grow_emote_texture(device, queue));
+            let pending_buffer = mem::take(command_buffer);
+            queue.submit(pending_buffer);
+        }
+    }
+
     fn exists(&self, glyph: &CacheKey) -> bool {
         self.glyphs.contains_key(glyph)
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     fn insert_glyph(
         &mut self,
         device: &Device,
         queue: &Queue,
         glyph: &CacheKey,
-        image: &SwashImage,
+        image: &GlyphBitmap,
         command_buffer: &mut Vec<CommandBuffer>,
     ) {
         if image.placement.width == 0 || image.placement.height == 0 {
             self.glyphs.insert(*glyph, None);
             return;
         }
-        if let Some(item) = self
-            .layer
-            .new_item(&self.texture, queue, image, self.shadow_width)
-        {
-            self.shadow.new_glyph(&item);
-            self.glyphs.insert(*glyph, Some(item));
-        } else {
-            command_buffer.push(self.grow_texture(device, queue));
+        let max_size = device.limits().max_texture_dimension_2d;
+        let fits = image.placement.width.max(image.placement.height) + self.padding * 2 <= max_size;
+        let item = loop {
+            if let Some(item) = self
+                .layer
+                .new_item(&self.texture, queue, image, self.padding)
+            {
+                break item;
+            }
+            // Huge emoji or fonts at large sizes are left out of the text instead of crashing
+            // the worker
+            if !fits || self.texture_size.0 * 2 > max_size || self.texture_size.1 * 2 > max_size {
+                warn!(
+                    "Glyph of {}x{} doesn't fit into the texture, skipping",
+                    image.placement.width, image.placement.height
+                );
+                self.glyphs.insert(*glyph, None);
+                return;
+            }
+            command_buffer.extend(self.grow_texture(device, queue));
             let pending_buffer = mem::take(command_buffer);
             queue.submit(pending_buffer);
-            command_buffer.clear();
-
-            let item = self
-                .layer
-                .new_item(&self.texture, queue, image, self.shadow_width)
-                .expect("Glyph too large");
-            self.shadow.new_glyph(&item);
-            self.glyphs.insert(*glyph, Some(item));
+        };
+        if let Some((mirror, _)) = &mut self.mirror {
+            let origin = (
+                item.tex_coords.0 + item.padding,
+                item.tex_coords.1 + item.padding,
+            );
+            mirror.write(origin, image.placement, &image.data);
         }
+        self.shadow.new_glyph(&item);
+        self.glyphs.insert(*glyph, Some(item));
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(index = chunk.index))
+    )]
     pub fn generate(
         &mut self,
         device: &Device,
         queue: &Queue,
-        font_system: &mut FontSystem,
+        shaper: &mut dyn TextShaper,
         chunk: &DanmakuTimeChunk,
         command_buffer: &mut Vec<CommandBuffer>,
     ) {
-        for glyph in chunk.glyph_ids() {
-            if self.exists(glyph) {
+        // Rasterized together, possibly on several threads, and uploaded one by one
+        let missing: Vec<_> = chunk
+            .glyph_ids()
+            .filter(|glyph| !self.exists(glyph))
+            .copied()
+            .collect();
+        let images = disk_cache::get_images(self.disk_cache.as_mut(), shaper, &missing);
+        for (glyph, image) in missing.iter().zip(images) {
+            let Some(image) = image else {
                 continue;
-            }
-            let image = self.swash_cache.get_image_uncached(font_system, *glyph);
-            let image = match image {
-                Some(image) => image,
-                None => continue,
             };
             self.insert_glyph(device, queue, glyph, &image, command_buffer);
         }
+        for (shortcode, image) in chunk.emotes() {
+            if self.emotes.contains_key(shortcode) {
+                continue;
+            }
+            self.insert_emote(device, queue, shortcode, image, command_buffer);
+        }
     }
 
-    pub fn flush(
+    pub fn flush(&mut self, device: &Device) -> Option<CommandBuffer> {
+        self.shadow.draw(device, &self.shadow_texture)
+    }
+
+    // Starts over on a new device, keeping the texture sizes and the disk cache. With the
+    // mirror the glyphs are uploaded again, otherwise they have to be rasterized again.
+    pub fn recreate(
         &mut self,
         device: &Device,
-        index_buffer: &mut IndexBuffer,
-    ) -> Option<CommandBuffer> {
-        self.shadow.draw(device, &self.shadow_texture, index_buffer)
+        queue: &Queue,
+        shadow_weight: f32,
+        shadow_kernel: ShadowKernel,
+    ) {
+        let manager = Self::with_sizes(
+            self.texture_size,
+            self.emote_texture_size,
+            device,
+            self.shadow_width,
+            shadow_weight,
+            shadow_kernel,
+        );
+        let old = mem::replace(self, manager);
+        self.disk_cache = old.disk_cache;
+        if let Some((mirror, emote_mirror)) = old.mirror {
+            // The allocations stay valid, only the contents are gone
+            mirror.upload(queue, &self.texture);
+            emote_mirror.upload(queue, &self.emote_texture);
+            self.layer = old.layer;
+            self.glyphs = old.glyphs;
+            self.emote_layer = old.emote_layer;
+            self.emotes = old.emotes;
+            self.padding = old.padding;
+            self.shadow
+                .new_param(queue, self.shadow_width(), shadow_weight, shadow_kernel);
+            for item in self.glyphs.values().flatten() {
+                self.shadow.new_glyph(item);
+            }
+            self.mirror = Some((mirror, emote_mirror));
+        }
+    }
+
+    // Contents of the glyph and emote textures, if the mirror is enabled
+    pub fn mirror_images(&self) -> Option<(RgbaImage, RgbaImage)> {
+        self.mirror
+            .as_ref()
+            .map(|(mirror, emote_mirror)| (mirror.to_image(), emote_mirror.to_image()))
+    }
+
+    pub fn set_disk_cache(&mut self, mut disk_cache: GlyphDiskCache, shadow_weight: f32) {
+        disk_cache.set_shadow(self.shadow_width(), shadow_weight);
+        self.disk_cache = Some(disk_cache);
+    }
+
+    // Width of the drawn shadow
+    pub fn shadow_width(&self) -> u32 {
+        self.shadow_width.min(self.padding)
+    }
+
+    // Drops the glyphs and emotes, and starts over with textures of the size instead of the grown
+    // ones. The chunks using them have to be prepared again.
+    pub fn reset(
+        &mut self,
+        device: &Device,
+        texture_size: (u32, u32),
+        shadow_weight: f32,
+        shadow_kernel: ShadowKernel,
+    ) {
+        let manager = Self::new(
+            texture_size,
+            device,
+            self.shadow_width,
+            shadow_weight,
+            shadow_kernel,
+        );
+        let old = mem::replace(self, manager);
+        self.disk_cache = old.disk_cache;
+        if old.mirror.is_some() {
+            self.enable_mirror();
+        }
+    }
+
+    // Bytes of the glyph and shadow textures, and of the emote texture
+    pub fn memory_usage(&self) -> (u64, u64) {
+        let (width, height) = self.texture_size;
+        // R8Unorm glyphs and Rgba8Unorm shadows
+        let glyphs = width as u64 * height as u64 * 5;
+        let (width, height) = self.emote_texture_size;
+        (glyphs, width as u64 * height as u64 * 4)
     }
 
     pub fn clear(&mut self) {
         self.glyphs.clear();
         self.shadow.clear();
         self.layer.clear();
+        if let Some((mirror, _)) = &mut self.mirror {
+            mirror.clear();
+        }
+    }
+
+    // Only redraws the shadow texture, the glyphs stay in the atlas. Shadows wider than the
+    // padding are cut to it until the atlas is created again, e.g. when the glyphs are evicted.
+    pub fn update_shadow(
+        &mut self,
+        queue: &Queue,
+        shadow_width: u32,
+        shadow_weight: f32,
+        shadow_kernel: ShadowKernel,
+    ) {
+        if shadow_width > self.padding {
+            warn!(
+                "Shadow of {} exceeds the glyph padding {}, limiting it",
+                shadow_width, self.padding
+            );
+        }
+        self.shadow_width = shadow_width;
+        let shadow_width = self.shadow_width();
+        self.shadow
+            .new_param(queue, shadow_width, shadow_weight, shadow_kernel);
+        if let Some(disk_cache) = &mut self.disk_cache {
+            disk_cache.set_shadow(shadow_width, shadow_weight);
+        }
+        // Shadows are drawn again at the next flush
+        for item in self.glyphs.values().flatten() {
+            self.shadow.new_glyph(item);
+        }
     }
+}
+
+#[cfg(test)]
+mod test {
+    use cosmic_text::{fontdb, CacheKey, CacheKeyFlags, Placement, SwashContent};
+
+    use crate::{
+        renderer::wgpu::testing::request_device, shaper::GlyphBitmap, worker::ShadowKernel,
+    };
+
+    use super::GlyphTextureManager;
+
+    fn bitmap(width: u32, height: u32) -> GlyphBitmap {
+        GlyphBitmap {
+            content: SwashContent::Mask,
+            placement: Placement {
+                left: 0,
+                top: height as i32,
+                width,
+                height,
+            },
+            data: vec![255; (width * height) as usize],
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a graphics adapter"]
+    fn test_oversized_glyphs() {
+        let (device, queue) = request_device().expect("No graphics adapter");
+        let mut manager =
+            GlyphTextureManager::new((64, 64), &device, 2, 1.0, ShadowKernel::Outline);
+        let key = |glyph_id| {
+            let (key, _, _) = CacheKey::new(
+                fontdb::ID::dummy(),
+                glyph_id,
+                14.0,
+                (0.0, 0.0),
+                CacheKeyFlags::empty(),
+            );
+            key
+        };
+        let mut command_buffer = Vec::new();
+
+        // Grows more than once
+        manager.insert_glyph(
+            &device,
+            &queue,
+            &key(1),
+            &bitmap(200, 100),
+            &mut command_buffer,
+        );
+        assert!(manager.find(&key(1)).is_some());
+        assert!(manager.texture_size.0 >= 256);
+
+        // Skipped without growing the texture
+        let max_size = device.limits().max_texture_dimension_2d;
+        let texture_size = manager.texture_size;
+        manager.insert_glyph(
+            &device,
+            &queue,
+            &key(2),
+            &bitmap(max_size, 1),
+            &mut command_buffer,
+        );
+        assert!(manager.find(&key(2)).is_none());
+        assert_eq!(manager.texture_size, texture_size);
+    }
+
+    #[test]
+    #[ignore = "needs a graphics adapter"]
+    fn test_update_shadow() {
+        let (device, queue) = request_device().expect("No graphics adapter");
+        let mut manager =
+            GlyphTextureManager::new((256, 256), &device, 0, 1.0, ShadowKernel::Outline);
+        let (key, _, _) = CacheKey::new(
+            fontdb::ID::dummy(),
+            1,
+            14.0,
+            (0.0, 0.0),
+            CacheKeyFlags::empty(),
+        );
+        let mut command_buffer = Vec::new();
+        manager.insert_glyph(&device, &queue, &key, &bitmap(10, 10), &mut command_buffer);
+
+        // Grows into the reserved padding and keeps the glyph
+        manager.update_shadow(&queue, 3, 1.0, ShadowKernel::Outline);
+        assert!(manager.find(&key).is_some());
+        assert_eq!(manager.shadow_width(), 3);
+        let (placement, _, tex_size) = manager.find(&key).unwrap().padded(3);
+        assert_eq!((placement.width, tex_size.0), (16, 16));
 
-    pub fn new_param(&mut self, queue: &Queue, shadow_width: u32, shadow_weight: f32) {
-        self.shadow.new_param(queue, shadow_width, shadow_weight);
+        // Cut to the padding
+        manager.update_shadow(&queue, 10, 1.0, ShadowKernel::Outline);
+        assert!(manager.find(&key).is_some());
+        assert_eq!(manager.shadow_width(), 4);
     }
 }
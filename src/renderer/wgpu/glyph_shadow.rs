@@ -3,18 +3,19 @@ use std::{mem::size_of, num::NonZeroUsize};
 use bytemuck::{Pod, Zeroable};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
-    vertex_attr_array, AddressMode, BindGroup, BindGroupDescriptor, BindGroupEntry,
-    BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType,
-    BlendState, Buffer, BufferAddress, BufferBindingType, BufferUsages, ColorTargetState,
-    ColorWrites, CommandBuffer, Device, Face, FilterMode, FragmentState, FrontFace, IndexFormat,
-    MultisampleState, PipelineLayoutDescriptor, PolygonMode, PrimitiveState, PrimitiveTopology,
-    Queue, RenderPipeline, RenderPipelineDescriptor, Sampler, SamplerBindingType,
-    SamplerDescriptor, ShaderModuleDescriptor, ShaderSource, ShaderStages, Texture,
-    TextureSampleType, TextureView, TextureViewDescriptor, TextureViewDimension,
-    VertexBufferLayout, VertexState, VertexStepMode,
+    BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+    BindGroupLayoutEntry, BindingResource, BindingType, Buffer, BufferBindingType, BufferUsages,
+    CommandBuffer, ComputePassDescriptor, ComputePipeline, ComputePipelineDescriptor, Device,
+    PipelineLayoutDescriptor, Queue, ShaderModuleDescriptor, ShaderSource, ShaderStages,
+    StorageTextureAccess, Texture, TextureFormat, TextureSampleType, TextureView,
+    TextureViewDescriptor, TextureViewDimension,
 };
 
-use super::{glyph_atlas::GlyphItem, index_buffer::IndexBuffer};
+use crate::worker::ShadowKernel;
+
+use super::glyph_atlas::GlyphItem;
+
+const WORKGROUP_SIZE: u32 = 8;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
@@ -23,8 +24,6 @@ pub struct ShadowConfigUniform {
     pub shadow_weight: f32,
     pub texture_width: u32,
     pub texture_height: u32,
-    pub pixel_width: f32,
-    pub pixel_height: f32,
 }
 
 impl ShadowConfigUniform {
@@ -46,104 +45,94 @@ impl ShadowConfigUniform {
     }
 }
 
+// One workgroup layer per glyph, padding included
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-struct Vertex {
+struct GlyphRect {
     position: [u32; 2],
+    size: [u32; 2],
+    // Start of the glyph in the blur buffer
+    offset: u32,
+    _padding: u32,
 }
 
-impl Vertex {
-    const ATTRIBS: [wgpu::VertexAttribute; 1] = vertex_attr_array![
-        0 => Uint32x2
-    ];
-
-    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
-        VertexBufferLayout {
-            array_stride: size_of::<Self>() as BufferAddress,
-            step_mode: VertexStepMode::Vertex,
-            attributes: &Self::ATTRIBS,
-        }
-    }
+fn compute_pipeline(
+    device: &Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    entry_point: &str,
+) -> ComputePipeline {
+    device.create_compute_pipeline(&ComputePipelineDescriptor {
+        label: Some("Shadow compute pipeline"),
+        layout: Some(layout),
+        module: shader,
+        entry_point,
+        compilation_options: Default::default(),
+        cache: None,
+    })
+}
 
-    fn new(tex_coords: (u32, u32), tex_size: (u32, u32)) -> [Vertex; 4] {
-        let top_left = Self {
-            position: [tex_coords.0, tex_coords.1],
-        };
-        let top_right = Self {
-            position: [tex_coords.0 + tex_size.0, tex_coords.1],
-        };
-        let bottom_left = Self {
-            position: [tex_coords.0, tex_coords.1 + tex_size.1],
-        };
-        let bottom_right = Self {
-            position: [tex_coords.0 + tex_size.0, tex_coords.1 + tex_size.1],
-        };
-        [top_left, top_right, bottom_left, bottom_right]
+fn storage_buffer_entry(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
     }
 }
 
+// Draws the shadows of the new glyphs into the shadow texture with compute shaders. The outline
+// kernel looks at every pixel in the shadow width, the gaussian kernel is separable and blurs
+// the rows first, so it stays cheap with large widths.
 pub(crate) struct GlyphShadow {
-    render_pipeline: RenderPipeline,
-    sampler: Sampler,
+    outline_pipeline: ComputePipeline,
+    blur_pipelines: (ComputePipeline, ComputePipeline),
+    kernel: ShadowKernel,
     bind_group_layout: BindGroupLayout,
+    target_bind_group_layout: BindGroupLayout,
     bind_group: BindGroup,
     config_uniform: ShadowConfigUniform,
     config_buffer: Buffer,
-    glyphs: u32,
-    vertexs: Vec<Vertex>,
+    rects: Vec<GlyphRect>,
 }
 
 impl GlyphShadow {
     pub fn new(
         device: &Device,
-        texture: &Texture,
         texture_view: &TextureView,
         texture_size: (u32, u32),
         shadow_width: u32,
         shadow_weight: f32,
+        kernel: ShadowKernel,
     ) -> Self {
         let config_uniform = ShadowConfigUniform {
             shadow_width,
             shadow_weight,
             texture_width: texture_size.0,
             texture_height: texture_size.1,
-            pixel_width: 1.0 / texture_size.0 as f32,
-            pixel_height: 1.0 / texture_size.1 as f32,
         };
         let config_buffer = config_uniform.prepare(device);
 
-        let sampler = device.create_sampler(&SamplerDescriptor {
-            address_mode_u: AddressMode::ClampToEdge,
-            address_mode_v: AddressMode::ClampToEdge,
-            address_mode_w: AddressMode::ClampToEdge,
-            mag_filter: FilterMode::Nearest,
-            min_filter: FilterMode::Nearest,
-            mipmap_filter: FilterMode::Nearest,
-            ..Default::default()
-        });
-
         let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("Shadow texture bind layout"),
             entries: &[
                 BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: ShaderStages::FRAGMENT,
+                    visibility: ShaderStages::COMPUTE,
                     ty: BindingType::Texture {
                         multisampled: false,
                         view_dimension: TextureViewDimension::D2,
-                        sample_type: TextureSampleType::Float { filterable: true },
+                        sample_type: TextureSampleType::Float { filterable: false },
                     },
                     count: None,
                 },
                 BindGroupLayoutEntry {
                     binding: 1,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: ShaderStages::FRAGMENT | ShaderStages::VERTEX,
+                    visibility: ShaderStages::COMPUTE,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -154,146 +143,172 @@ impl GlyphShadow {
             ],
         });
 
-        let bind_group = device.create_bind_group(&BindGroupDescriptor {
-            label: Some("Shadow bind group"),
-            layout: &bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: BindingResource::TextureView(texture_view),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::Sampler(&sampler),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: config_buffer.as_entire_binding(),
-                },
-            ],
-        });
+        let target_bind_group_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Shadow target bind layout"),
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::WriteOnly,
+                            format: TextureFormat::Rgba8Unorm,
+                            view_dimension: TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                    storage_buffer_entry(1, true),
+                    storage_buffer_entry(2, false),
+                ],
+            });
+
+        let bind_group =
+            Self::create_bind_group(device, &bind_group_layout, texture_view, &config_buffer);
 
         let shader = device.create_shader_module(ShaderModuleDescriptor {
             label: Some("Shadow shader"),
             source: ShaderSource::Wgsl(include_str!("shadow.wgsl").into()),
         });
 
-        let render_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
             label: Some("Shadow pipeline layout"),
-            bind_group_layouts: &[&bind_group_layout],
+            bind_group_layouts: &[&bind_group_layout, &target_bind_group_layout],
             push_constant_ranges: &[],
         });
 
-        let render_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
-            label: Some("Shadow render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                compilation_options: Default::default(),
-                buffers: &[Vertex::desc()],
-            },
-            fragment: Some(FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                compilation_options: Default::default(),
-                targets: &[Some(ColorTargetState {
-                    format: texture.format(),
-                    blend: Some(BlendState::REPLACE),
-                    write_mask: ColorWrites::ALL,
-                })],
-            }),
-            primitive: PrimitiveState {
-                topology: PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: FrontFace::Ccw,
-                cull_mode: Some(Face::Back),
-                polygon_mode: PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false,
-            },
-            depth_stencil: None,
-            multisample: MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-            cache: None,
-        });
-
         Self {
-            render_pipeline,
-            sampler,
+            outline_pipeline: compute_pipeline(device, &pipeline_layout, &shader, "cs_outline"),
+            blur_pipelines: (
+                compute_pipeline(device, &pipeline_layout, &shader, "cs_blur_horizontal"),
+                compute_pipeline(device, &pipeline_layout, &shader, "cs_blur_vertical"),
+            ),
+            kernel,
             bind_group,
             bind_group_layout,
+            target_bind_group_layout,
             config_uniform,
             config_buffer,
-            glyphs: 0,
-            vertexs: Vec::new(),
+            rects: Vec::new(),
         }
     }
 
+    fn create_bind_group(
+        device: &Device,
+        layout: &BindGroupLayout,
+        texture_view: &TextureView,
+        config_buffer: &Buffer,
+    ) -> BindGroup {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Shadow bind group"),
+            layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(texture_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: config_buffer.as_entire_binding(),
+                },
+            ],
+        })
+    }
+
     pub fn clear(&mut self) {
-        self.glyphs = 0;
-        self.vertexs.clear();
+        self.rects.clear();
     }
 
     pub fn new_glyph(&mut self, item: &GlyphItem) {
-        self.glyphs += 1;
-        self.vertexs.reserve(4);
-        Vertex::new(item.tex_coords, item.tex_size)
-            .into_iter()
-            .for_each(|item| self.vertexs.push(item))
+        self.rects.push(GlyphRect {
+            position: item.tex_coords.into(),
+            size: item.tex_size.into(),
+            offset: 0,
+            _padding: 0,
+        })
     }
 
-    pub fn draw(
-        &mut self,
-        device: &Device,
-        shadow_texture: &Texture,
-        index_buffer: &mut IndexBuffer,
-    ) -> Option<CommandBuffer> {
-        if self.glyphs == 0 {
+    pub fn draw(&mut self, device: &Device, shadow_texture: &Texture) -> Option<CommandBuffer> {
+        if self.rects.is_empty() {
             return None;
         }
-        index_buffer.ensure_size(device, self.glyphs);
 
         let shadow_texture_view = shadow_texture.create_view(&TextureViewDescriptor {
             dimension: Some(TextureViewDimension::D2),
             ..Default::default()
         });
 
-        let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
-            label: Some("Vertex for shadow"),
-            contents: bytemuck::cast_slice(&self.vertexs),
-            usage: BufferUsages::VERTEX,
-        });
-
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("Shadow render encoder"),
-        });
-        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: Some("Shadow render pass"),
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &shadow_texture_view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Load,
-                    store: wgpu::StoreOp::Store,
-                },
-            })],
-            depth_stencil_attachment: None,
-            occlusion_query_set: None,
-            timestamp_writes: None,
+            label: Some("Shadow compute encoder"),
         });
+        // A glyph is a layer of the dispatch, which has a limited number of them
+        let max_layers = device.limits().max_compute_workgroups_per_dimension as usize;
+        for rects in self.rects.chunks_mut(max_layers) {
+            let mut blur_len = 0;
+            for rect in rects.iter_mut() {
+                rect.offset = blur_len;
+                blur_len += rect.size[0] * rect.size[1];
+            }
+            let rect_buffer = device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("Glyph rects for shadow"),
+                contents: bytemuck::cast_slice(rects),
+                usage: BufferUsages::STORAGE,
+            });
+            let blur_buffer = match self.kernel {
+                ShadowKernel::Gaussian => blur_len.max(1),
+                ShadowKernel::Outline => 1,
+            };
+            let blur_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Shadow blur buffer"),
+                size: blur_buffer as u64 * size_of::<f32>() as u64,
+                usage: BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            });
+            let target_bind_group = device.create_bind_group(&BindGroupDescriptor {
+                label: Some("Shadow target bind group"),
+                layout: &self.target_bind_group_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(&shadow_texture_view),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: rect_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: blur_buffer.as_entire_binding(),
+                    },
+                ],
+            });
 
-        render_pass.set_pipeline(&self.render_pipeline);
-        render_pass.set_bind_group(0, &self.bind_group, &[]);
-        render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-        render_pass.set_index_buffer(index_buffer.buffer_slice(self.glyphs), IndexFormat::Uint32);
-        render_pass.draw_indexed(0..self.glyphs * 6, 0, 0..1);
+            let (width, height) = rects.iter().fold((0, 0), |(width, height), rect| {
+                (width.max(rect.size[0]), height.max(rect.size[1]))
+            });
+            let workgroups = (
+                width.div_ceil(WORKGROUP_SIZE),
+                height.div_ceil(WORKGROUP_SIZE),
+                rects.len() as u32,
+            );
+            // Without a width there is nothing to blur, the outline kernel clears the shadow
+            let pipelines = match self.kernel {
+                ShadowKernel::Gaussian if self.config_uniform.shadow_width > 0 => {
+                    vec![&self.blur_pipelines.0, &self.blur_pipelines.1]
+                }
+                _ => vec![&self.outline_pipeline],
+            };
 
-        drop(render_pass);
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Shadow compute pass"),
+                timestamp_writes: None,
+            });
+            compute_pass.set_bind_group(0, &self.bind_group, &[]);
+            compute_pass.set_bind_group(1, &target_bind_group, &[]);
+            for pipeline in pipelines {
+                compute_pass.set_pipeline(pipeline);
+                compute_pass.dispatch_workgroups(workgroups.0, workgroups.1, workgroups.2);
+            }
+        }
         self.clear();
 
         Some(encoder.finish())
@@ -308,33 +323,26 @@ impl GlyphShadow {
     ) {
         self.config_uniform.texture_width = texture_size.0;
         self.config_uniform.texture_height = texture_size.1;
-        self.config_uniform.pixel_width = 1.0 / texture_size.0 as f32;
-        self.config_uniform.pixel_height = 1.0 / texture_size.1 as f32;
         self.config_uniform.update(&self.config_buffer, queue);
 
-        self.bind_group = device.create_bind_group(&BindGroupDescriptor {
-            label: Some("Shadow bind group"),
-            layout: &self.bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: BindingResource::TextureView(texture_view),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::Sampler(&self.sampler),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: self.config_buffer.as_entire_binding(),
-                },
-            ],
-        });
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            texture_view,
+            &self.config_buffer,
+        );
     }
 
-    pub fn new_param(&mut self, queue: &Queue, shadow_width: u32, shadow_weight: f32) {
+    pub fn new_param(
+        &mut self,
+        queue: &Queue,
+        shadow_width: u32,
+        shadow_weight: f32,
+        kernel: ShadowKernel,
+    ) {
         self.config_uniform.shadow_width = shadow_width;
         self.config_uniform.shadow_weight = shadow_weight;
         self.config_uniform.update(&self.config_buffer, queue);
+        self.kernel = kernel;
     }
 }
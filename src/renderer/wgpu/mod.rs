@@ -1,15 +1,21 @@
+mod capture;
+mod composite;
 mod config;
 mod copy;
+#[cfg(feature = "debug-overlay")]
+mod debug_overlay;
 mod glyph_atlas;
 mod glyph_manager;
 mod glyph_shadow;
-mod index_buffer;
 mod render_cache;
 mod renderer;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod timestamp;
 mod vertex_buffer;
 
-pub use render_cache::WgpuRenderCache;
+pub use copy::Viewport;
+pub use render_cache::{GpuMemoryUsage, WgpuRenderCache};
 pub use renderer::WgpuRenderer;
 pub use vertex_buffer::VertexBuffer as WgpuVertexBuffer;
 pub use wgpu;
@@ -1,26 +1,46 @@
 use std::{mem, sync::Arc};
 
-use cosmic_text::FontSystem;
+use log::warn;
 use wgpu::{CommandBuffer, Device, Queue};
 
 use crate::{
     manager::DanmakuTimeChunk,
+    renderer::{disk_cache::GlyphDiskCache, RgbaImage},
+    shaper::TextShaper,
     worker::{DanmakuParam, RenderCache},
 };
 
-use super::{
-    glyph_manager::GlyphTextureManager, index_buffer::IndexBuffer,
-    vertex_buffer::VertexBufferManager,
-};
+use super::{glyph_manager::GlyphTextureManager, vertex_buffer::VertexBufferManager};
+
+// Bytes of the textures and buffers owned by the cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GpuMemoryUsage {
+    // Including the shadow texture
+    pub glyph_textures: u64,
+    pub emote_texture: u64,
+    // Of the cached chunks
+    pub vertex_buffers: u64,
+}
+
+impl GpuMemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.glyph_textures + self.emote_texture + self.vertex_buffers
+    }
+}
 
 pub struct WgpuRenderCache {
     pub(crate) device: Arc<Device>,
     pub(crate) queue: Arc<Queue>,
     pub(crate) glyph_texture_manager: GlyphTextureManager,
     pub(crate) vertex_buffer_manager: VertexBufferManager,
-    pub(crate) index_buffer: IndexBuffer,
     command_buffers: Vec<CommandBuffer>,
     danmaku_param: DanmakuParam,
+    texture_size: (u32, u32),
+    memory_budget: Option<u64>,
+    // Glyph texture bytes at the last eviction, the atlas isn't evicted again until it grows
+    // beyond them, or the glyphs of the screen would be rasterized on every chunk
+    evicted_glyph_textures: u64,
+    evict_glyphs: bool,
 }
 
 impl WgpuRenderCache {
@@ -33,57 +53,149 @@ impl WgpuRenderCache {
         let glyph_texture_manager = GlyphTextureManager::new(
             texture_size,
             &device,
-            danmaku_param.shadow_size,
+            danmaku_param.physical_shadow_size(),
             danmaku_param.shadow_weight,
+            danmaku_param.shadow_kernel,
         );
         WgpuRenderCache {
             device,
             queue,
             glyph_texture_manager,
             vertex_buffer_manager: Default::default(),
-            index_buffer: Default::default(),
             danmaku_param,
             command_buffers: Vec::new(),
+            texture_size,
+            memory_budget: None,
+            evicted_glyph_textures: 0,
+            evict_glyphs: false,
         }
     }
+
+    // Replaces the textures and buffers after the device was lost. Without the CPU mirror the
+    // glyphs are rasterized again (or loaded from the disk cache) as the worker prepares the
+    // chunks. Either way clear the chunks of the WorkerBuffer and refresh the worker afterwards.
+    pub fn recreate(&mut self, device: Arc<Device>, queue: Arc<Queue>) {
+        self.glyph_texture_manager.recreate(
+            &device,
+            &queue,
+            self.danmaku_param.shadow_weight,
+            self.danmaku_param.shadow_kernel,
+        );
+        self.vertex_buffer_manager.clear();
+        self.command_buffers.clear();
+        self.device = device;
+        self.queue = queue;
+    }
+
+    // Keeps a copy of the glyph atlas in memory, for device loss recovery and atlas_images
+    pub fn with_cpu_mirror(mut self) -> Self {
+        self.glyph_texture_manager.enable_mirror();
+        self
+    }
+
+    // The glyph and emote atlases for debugging, None without the CPU mirror
+    pub fn atlas_images(&self) -> Option<(RgbaImage, RgbaImage)> {
+        self.glyph_texture_manager.mirror_images()
+    }
+
+    // Bytes of textures and buffers to stay under. Exceeding it first shrinks the vertex buffer
+    // cache, then drops the glyph atlas before the next chunks are prepared, so it starts over at
+    // the initial size with only the glyphs on the screen. Checked as the chunks are prepared.
+    pub fn set_memory_budget(&mut self, budget: Option<u64>) {
+        self.memory_budget = budget;
+        self.evicted_glyph_textures = 0;
+    }
+
+    pub fn memory_usage(&self) -> GpuMemoryUsage {
+        let (glyph_textures, emote_texture) = self.glyph_texture_manager.memory_usage();
+        GpuMemoryUsage {
+            glyph_textures,
+            emote_texture,
+            vertex_buffers: self.vertex_buffer_manager.memory_usage(),
+        }
+    }
+
+    fn check_memory_budget(&mut self) {
+        let Some(budget) = self.memory_budget else {
+            return;
+        };
+        let usage = self.memory_usage();
+        if usage.total() <= budget {
+            return;
+        }
+        self.vertex_buffer_manager.shrink(usage.total() - budget);
+        let usage = self.memory_usage();
+        if usage.total() > budget && usage.glyph_textures > self.evicted_glyph_textures {
+            warn!(
+                "GPU memory {} exceeds the budget {}, evicting the glyphs",
+                usage.total(),
+                budget
+            );
+            self.evicted_glyph_textures = usage.glyph_textures;
+            self.evict_glyphs = true;
+        }
+    }
+
+    // Loads the rasterized glyphs from the disk cache, and saves the new ones into it
+    pub fn with_disk_cache(mut self, disk_cache: GlyphDiskCache) -> Self {
+        self.glyph_texture_manager
+            .set_disk_cache(disk_cache, self.danmaku_param.shadow_weight);
+        self
+    }
 }
 
 impl RenderCache for WgpuRenderCache {
     fn new_param(&mut self, new_param: DanmakuParam) {
         self.vertex_buffer_manager.clear();
-        if (new_param.font_size != self.danmaku_param.font_size)
-            || (new_param.font_attrs != self.danmaku_param.font_attrs)
-            || (new_param.shadow_size != self.danmaku_param.shadow_size)
-            || (new_param.shadow_weight != self.danmaku_param.shadow_weight)
-        {
-            self.glyph_texture_manager.clear();
-            self.glyph_texture_manager.new_param(
+        let shadow_changed = new_param.physical_shadow_size()
+            != self.danmaku_param.physical_shadow_size()
+            || new_param.shadow_weight != self.danmaku_param.shadow_weight
+            || new_param.shadow_kernel != self.danmaku_param.shadow_kernel;
+        // Changing the shadow only redraws the shadow texture
+        if shadow_changed {
+            self.glyph_texture_manager.update_shadow(
                 &self.queue,
-                new_param.shadow_size,
+                new_param.physical_shadow_size(),
                 new_param.shadow_weight,
+                new_param.shadow_kernel,
             );
         }
+        if new_param.font_changed(&self.danmaku_param) {
+            self.glyph_texture_manager.clear();
+        }
         self.danmaku_param = new_param;
     }
 
-    fn prepare(&mut self, font_system: &mut FontSystem, chunk: &DanmakuTimeChunk) {
+    fn prepare(&mut self, shaper: &mut dyn TextShaper, chunk: &DanmakuTimeChunk) {
+        // Every chunk of the WorkerBuffer is prepared again after a flush
+        if mem::take(&mut self.evict_glyphs) {
+            self.glyph_texture_manager.reset(
+                &self.device,
+                self.texture_size,
+                self.danmaku_param.shadow_weight,
+                self.danmaku_param.shadow_kernel,
+            );
+            self.vertex_buffer_manager.clear();
+        }
         self.glyph_texture_manager.generate(
             &self.device,
             &self.queue,
-            font_system,
+            shaper,
             chunk,
             &mut self.command_buffers,
         )
     }
 
+    fn invalidate(&mut self, from_index: u32) {
+        self.vertex_buffer_manager.invalidate(from_index);
+    }
+
     fn flush(&mut self) {
-        if let Some(buffer) = self
-            .glyph_texture_manager
-            .flush(&self.device, &mut self.index_buffer)
-        {
+        if let Some(buffer) = self.glyph_texture_manager.flush(&self.device) {
             self.command_buffers.push(buffer);
         }
         let buffers = mem::take(&mut self.command_buffers);
         self.queue.submit(buffers);
+        self.check_memory_budget();
     }
 }
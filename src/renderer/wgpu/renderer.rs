@@ -1,35 +1,200 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
 use log::info;
 use wgpu::{
-    include_wgsl, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayoutDescriptor,
-    BindGroupLayoutEntry, BindingType, BlendComponent, BlendFactor, BlendOperation, BlendState,
-    Buffer, BufferBindingType, Color, ColorTargetState, ColorWrites, CommandEncoderDescriptor,
-    Device, Extent3d, Face, FragmentState, FrontFace, IndexFormat, LoadOp, MultisampleState,
-    Operations, PipelineLayoutDescriptor, PolygonMode, PrimitiveState, PrimitiveTopology, Queue,
-    RenderPass, RenderPassColorAttachment, RenderPassDescriptor, RenderPipeline,
-    RenderPipelineDescriptor, ShaderStages, StoreOp, SurfaceConfiguration, Texture,
-    TextureDescriptor, TextureDimension, TextureFormat, TextureUsages, TextureView, VertexState,
+    include_wgsl, AddressMode, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+    BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, Buffer,
+    BufferAsyncError, BufferBindingType, Color, ColorTargetState, ColorWrites,
+    CommandEncoderDescriptor, Device, Extent3d, Face, FilterMode, FragmentState, FrontFace,
+    ImageCopyTexture, ImageDataLayout, LoadOp, MultisampleState, Operations, Origin3d,
+    PipelineLayout, PipelineLayoutDescriptor, PolygonMode, PrimitiveState, PrimitiveTopology,
+    Queue, RenderPass, RenderPassColorAttachment, RenderPassDescriptor, RenderPipeline,
+    RenderPipelineDescriptor, Sampler, SamplerBindingType, SamplerDescriptor, ShaderStages,
+    StoreOp, SurfaceConfiguration, Texture, TextureAspect, TextureDescriptor, TextureDimension,
+    TextureFormat, TextureSampleType, TextureUsages, TextureView, TextureViewDimension,
+    VertexState,
 };
 
-use crate::{danmaku::DanmakuTime, renderer::RendererParam, worker::DanmakuParam};
+use crate::{
+    danmaku::DanmakuTime,
+    renderer::{BlendMode, RendererParam, RgbaImage, Transition},
+    worker::DanmakuParam,
+};
 
+#[cfg(feature = "debug-overlay")]
+use super::debug_overlay::DebugOverlay;
 use super::{
-    config::ConfigUniform,
-    copy::TextureCopier,
-    index_buffer::IndexBuffer,
+    capture::ReadBack,
+    composite::VideoCompositor,
+    config::{resolve_color_space, ConfigUniform},
+    copy::{blend_state, TextureCopier, Viewport},
     timestamp::TimestampUniform,
-    vertex_buffer::{Vertex, VertexBuffer},
+    vertex_buffer::{Instance, VertexBuffer},
     WgpuRenderCache, WgpuWorkerBuffer,
 };
 
+fn transition_duration(renderer_param: &RendererParam) -> Duration {
+    Duration::from_millis(renderer_param.animation.transition_millis as u64)
+}
+
+fn create_render_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    format: TextureFormat,
+    blend_mode: BlendMode,
+    premultiplied_alpha: bool,
+) -> RenderPipeline {
+    let vertex_shader = device.create_shader_module(include_wgsl!("vertex.wgsl"));
+    let fragment_shader = device.create_shader_module(include_wgsl!("fragment.wgsl"));
+
+    // Replacing would let the transparent parts of the quads erase the danmaku below them
+    let blend = match blend_mode {
+        BlendMode::Replace => blend_state(BlendMode::Normal, premultiplied_alpha),
+        blend_mode => blend_state(blend_mode, premultiplied_alpha),
+    };
+    let fragment_entry_point = if premultiplied_alpha {
+        "fs_main_premultiplied"
+    } else {
+        "fs_main"
+    };
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: Some("Render Pipeline"),
+        layout: Some(layout),
+        vertex: VertexState {
+            module: &vertex_shader,
+            entry_point: "vs_main",
+            compilation_options: Default::default(),
+            buffers: &[Instance::desc()],
+        },
+        fragment: Some(FragmentState {
+            module: &fragment_shader,
+            entry_point: fragment_entry_point,
+            compilation_options: Default::default(),
+            targets: &[Some(ColorTargetState {
+                format,
+                blend: Some(blend),
+                write_mask: ColorWrites::ALL,
+            })],
+        }),
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::TriangleStrip,
+            strip_index_format: None,
+            front_face: FrontFace::Ccw,
+            cull_mode: Some(Face::Back),
+            polygon_mode: PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: None,
+        multisample: MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+        cache: None,
+    })
+}
+
+// The texture the danmaku are drawn into, and the copier compositing it into the pass of the host
+struct RenderTarget {
+    texture: Texture,
+    view: TextureView,
+    copier: TextureCopier,
+    // Used by render instead of the copier once a video frame is set
+    compositor: Option<VideoCompositor>,
+}
+
+impl RenderTarget {
+    fn update_config(&self, queue: &Queue, opacity: f32, renderer_param: &RendererParam) {
+        self.copier
+            .update_config(queue, opacity, renderer_param.orientation);
+        if let Some(compositor) = &self.compositor {
+            compositor.update_config(queue, opacity, renderer_param);
+        }
+    }
+}
+
+fn create_target_texture(
+    device: &Device,
+    danmaku_param: &DanmakuParam,
+    format: TextureFormat,
+    view_formats: &[TextureFormat],
+) -> (Texture, TextureView) {
+    let size = Extent3d {
+        width: danmaku_param.screen_size.0,
+        height: danmaku_param.screen_size.1,
+        depth_or_array_layers: 1,
+    };
+    info!("Target texture format: {:?}", format);
+    info!("Target texture size: {:?}", size);
+    let texture = device.create_texture(&TextureDescriptor {
+        label: Some("Danmaku render target texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format,
+        usage: TextureUsages::RENDER_ATTACHMENT
+            | TextureUsages::TEXTURE_BINDING
+            | TextureUsages::COPY_SRC,
+        view_formats,
+    });
+    let view = texture.create_view(&Default::default());
+    (texture, view)
+}
+
+// Single channel texture of the mask written by WgpuRenderer::write_mask
+fn create_mask_texture(device: &Device, size: (u32, u32)) -> (Texture, TextureView) {
+    let texture = device.create_texture(&TextureDescriptor {
+        label: Some("Danmaku mask texture"),
+        size: Extent3d {
+            width: size.0,
+            height: size.1,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: TextureFormat::R8Unorm,
+        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    let view = texture.create_view(&Default::default());
+    (texture, view)
+}
+
 pub struct WgpuRenderer {
     render_pipeline: RenderPipeline,
+    render_pipeline_layout: PipelineLayout,
+    bind_group_layout: BindGroupLayout,
     bind_group: BindGroup,
+    mask_sampler: Sampler,
+    // Zero everywhere, bound while there is no mask
+    empty_mask: TextureView,
+    // Of write_mask, kept while the size stays the same
+    mask_texture: Option<(Texture, TextureView)>,
     timestamp_buffer: Buffer,
+    config_uniform: ConfigUniform,
     config_buffer: Buffer,
-    target_texture: Texture,
-    target_texture_view: TextureView,
+    danmaku_param: DanmakuParam,
+    renderer_param: RendererParam,
+    format: TextureFormat,
     view_formats: Vec<TextureFormat>,
-    copier: TextureCopier,
+    // None for renderers drawing straight into the pass of the host, see new_direct
+    target: Option<RenderTarget>,
+    opacity: Transition,
+    // Physical, the danmaku are scaled by the current value over the target one
+    font_size: Transition,
+    transitioning: bool,
+    #[cfg(feature = "debug-overlay")]
+    timestamp: DanmakuTime,
+    #[cfg(feature = "debug-overlay")]
+    debug_overlay: Option<DebugOverlay>,
 }
 
 impl WgpuRenderer {
@@ -40,13 +205,59 @@ impl WgpuRenderer {
         renderer_param: RendererParam,
         cache: &WgpuRenderCache,
     ) -> Self {
-        let vertex_shader = device.create_shader_module(include_wgsl!("vertex.wgsl"));
-        let fragment_shader = device.create_shader_module(include_wgsl!("fragment.wgsl"));
+        Self::create(
+            device,
+            config.format,
+            config.view_formats.clone(),
+            danmaku_param,
+            renderer_param,
+            cache,
+            false,
+        )
+    }
+
+    // Draws with render_buffer_direct into a render pass of the host, in the format of the
+    // surface, without the target texture and the copy pass. The opacity is applied to every
+    // danmaku on its own, so overlapping ones show through each other, and the orientation is
+    // ignored.
+    pub fn new_direct(
+        config: &SurfaceConfiguration,
+        device: &Device,
+        danmaku_param: DanmakuParam,
+        renderer_param: RendererParam,
+        cache: &WgpuRenderCache,
+    ) -> Self {
+        Self::create(
+            device,
+            config.format,
+            config.view_formats.clone(),
+            danmaku_param,
+            renderer_param,
+            cache,
+            true,
+        )
+    }
 
+    fn create(
+        device: &Device,
+        format: TextureFormat,
+        view_formats: Vec<TextureFormat>,
+        danmaku_param: DanmakuParam,
+        renderer_param: RendererParam,
+        cache: &WgpuRenderCache,
+        direct: bool,
+    ) -> Self {
         let timestamp_uniform = TimestampUniform::default();
         let timestamp_buffer = timestamp_uniform.prepare(device);
 
-        let config_uniform: ConfigUniform = danmaku_param.clone().into();
+        info!(
+            "Target color space: {:?}",
+            resolve_color_space(renderer_param.color_space, format)
+        );
+        let mut config_uniform = ConfigUniform::new(&danmaku_param, &renderer_param, format);
+        if direct {
+            config_uniform.set_opacity(renderer_param.opacity);
+        }
         let config_buffer = config_uniform.prepare(device);
 
         let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
@@ -71,11 +282,119 @@ impl WgpuRenderer {
                     },
                     count: None,
                 },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: TextureViewDimension::D2,
+                        sample_type: TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
             ],
             label: Some("Renderer bind group layout"),
         });
-        let bind_group = device.create_bind_group(&BindGroupDescriptor {
-            layout: &bind_group_layout,
+        let mask_sampler = device.create_sampler(&SamplerDescriptor {
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Nearest,
+            ..Default::default()
+        });
+        // Textures start zeroed
+        let (_, empty_mask) = create_mask_texture(device, (1, 1));
+        let bind_group = Self::create_bind_group(
+            device,
+            &bind_group_layout,
+            &timestamp_buffer,
+            &config_buffer,
+            &empty_mask,
+            &mask_sampler,
+        );
+
+        let render_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Renderer pipeline layout"),
+            bind_group_layouts: &[
+                &bind_group_layout,
+                &cache.glyph_texture_manager.bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let render_pipeline = create_render_pipeline(
+            device,
+            &render_pipeline_layout,
+            format,
+            renderer_param.blend_mode,
+            renderer_param.premultiplied_alpha,
+        );
+
+        let target = (!direct).then(|| {
+            let (texture, view) =
+                create_target_texture(device, &danmaku_param, format, &view_formats);
+            let copier = TextureCopier::new(
+                device,
+                &texture,
+                &view,
+                renderer_param.opacity,
+                renderer_param.orientation,
+                renderer_param.blend_mode,
+                renderer_param.premultiplied_alpha,
+            );
+            RenderTarget {
+                texture,
+                view,
+                copier,
+                compositor: None,
+            }
+        });
+        let opacity = Transition::new(renderer_param.opacity);
+        let font_size = Transition::new(danmaku_param.physical_font_size());
+
+        Self {
+            render_pipeline,
+            render_pipeline_layout,
+            bind_group_layout,
+            bind_group,
+            mask_sampler,
+            empty_mask,
+            mask_texture: None,
+            timestamp_buffer,
+            config_uniform,
+            config_buffer,
+            danmaku_param,
+            renderer_param,
+            format,
+            view_formats,
+            target,
+            opacity,
+            font_size,
+            transitioning: false,
+            #[cfg(feature = "debug-overlay")]
+            timestamp: DanmakuTime::from_millis(0),
+            #[cfg(feature = "debug-overlay")]
+            debug_overlay: None,
+        }
+    }
+
+    fn create_bind_group(
+        device: &Device,
+        layout: &BindGroupLayout,
+        timestamp_buffer: &Buffer,
+        config_buffer: &Buffer,
+        mask: &TextureView,
+        mask_sampler: &Sampler,
+    ) -> BindGroup {
+        device.create_bind_group(&BindGroupDescriptor {
+            layout,
             entries: &[
                 BindGroupEntry {
                     binding: 0,
@@ -85,106 +404,175 @@ impl WgpuRenderer {
                     binding: 1,
                     resource: config_buffer.as_entire_binding(),
                 },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(mask),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::Sampler(mask_sampler),
+                },
             ],
             label: Some("Renderer bind group"),
-        });
+        })
+    }
 
-        let render_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
-            label: Some("Renderer pipeline layout"),
-            bind_group_layouts: &[
-                &bind_group_layout,
-                &cache.glyph_texture_manager.bind_group_layout,
-            ],
-            push_constant_ranges: &[],
-        });
-        let render_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: VertexState {
-                module: &vertex_shader,
-                entry_point: "vs_main",
-                compilation_options: Default::default(),
-                buffers: &[Vertex::desc()],
+    // Hides the danmaku where the red channel of the mask is set, e.g. behind the people of the
+    // frame for Bilibili's smart mask. The mask is stretched over the screen, set the one of each
+    // video frame before rendering it. None shows the danmaku everywhere again.
+    pub fn set_mask(&mut self, device: &Device, mask: Option<&TextureView>) {
+        self.mask_texture = None;
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.timestamp_buffer,
+            &self.config_buffer,
+            mask.unwrap_or(&self.empty_mask),
+            &self.mask_sampler,
+        );
+    }
+
+    // Uploads a mask of one byte per pixel, 255 where the danmaku are hidden, see set_mask
+    pub fn write_mask(&mut self, device: &Device, queue: &Queue, size: (u32, u32), data: &[u8]) {
+        assert_eq!(data.len(), (size.0 * size.1) as usize);
+        let reused = self
+            .mask_texture
+            .as_ref()
+            .is_some_and(|(texture, _)| (texture.width(), texture.height()) == size);
+        let (texture, view) = match self.mask_texture.take() {
+            Some(mask) if reused => mask,
+            _ => create_mask_texture(device, size),
+        };
+        queue.write_texture(
+            ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
             },
-            fragment: Some(FragmentState {
-                module: &fragment_shader,
-                entry_point: "fs_main",
-                compilation_options: Default::default(),
-                targets: &[Some(ColorTargetState {
-                    format: config.format,
-                    blend: Some(BlendState {
-                        color: BlendComponent {
-                            src_factor: BlendFactor::SrcAlpha,
-                            dst_factor: BlendFactor::OneMinusSrcAlpha,
-                            operation: BlendOperation::Add,
-                        },
-                        alpha: BlendComponent {
-                            src_factor: BlendFactor::SrcAlpha,
-                            dst_factor: BlendFactor::OneMinusSrcAlpha,
-                            operation: BlendOperation::Add,
-                        },
-                    }),
-                    write_mask: ColorWrites::ALL,
-                })],
-            }),
-            primitive: PrimitiveState {
-                topology: PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: FrontFace::Ccw,
-                cull_mode: Some(Face::Back),
-                polygon_mode: PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false,
+            data,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(size.0),
+                rows_per_image: None,
             },
-            depth_stencil: None,
-            multisample: MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
+            Extent3d {
+                width: size.0,
+                height: size.1,
+                depth_or_array_layers: 1,
             },
-            multiview: None,
-            cache: None,
-        });
+        );
+        if !reused {
+            self.set_mask(device, Some(&view));
+        }
+        self.mask_texture = Some((texture, view));
+    }
 
-        let size = Extent3d {
-            width: danmaku_param.screen_size.0,
-            height: danmaku_param.screen_size.1,
-            depth_or_array_layers: 1,
+    // Composites the danmaku over the video frame in render, replacing the pixels of the pass
+    // instead of blending into them, for players without a compositor of their own. Saves the
+    // pass drawing the video. The view must be filterable, e.g. RGBA, and is stretched over the
+    // screen. Set the one of each video frame after update_danmaku_param and recreate, None
+    // blends into the pass again. Ignored by direct renderers.
+    pub fn set_video_frame(&mut self, device: &Device, video: Option<&TextureView>) {
+        let Some(target) = &mut self.target else {
+            return;
         };
-        info!("Target texture format: {:?}", config.format);
-        info!("Target texture size: {:?}", size);
-        let target_texture = device.create_texture(&TextureDescriptor {
-            label: Some("Danmaku render target texture"),
-            size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: TextureDimension::D2,
-            format: config.format,
-            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
-            view_formats: &config.view_formats,
+        let Some(video) = video else {
+            target.compositor = None;
+            return;
+        };
+        let compositor = target.compositor.get_or_insert_with(|| {
+            VideoCompositor::new(
+                device,
+                self.format,
+                self.opacity.value_at(Instant::now()),
+                &self.renderer_param,
+            )
         });
-        let target_texture_view = target_texture.create_view(&Default::default());
-        let copier = TextureCopier::new(
+        compositor.set_textures(device, video, &target.view);
+    }
+
+    // Builds the pipelines and the target texture again on a new device after the old one was
+    // lost, with the cache already recreated by WgpuRenderCache::recreate. Set the mask and the
+    // video frame again.
+    pub fn recreate(&mut self, device: &Device, cache: &WgpuRenderCache) {
+        let renderer = Self::create(
             device,
-            &target_texture,
-            &target_texture_view,
-            renderer_param.opacity,
+            self.format,
+            self.view_formats.clone(),
+            self.danmaku_param.clone(),
+            self.renderer_param.clone(),
+            cache,
+            self.target.is_none(),
         );
+        #[cfg(feature = "debug-overlay")]
+        let debug_overlay = self.debug_overlay.is_some();
+        *self = renderer;
+        #[cfg(feature = "debug-overlay")]
+        self.set_debug_overlay(device, debug_overlay);
+    }
 
-        Self {
-            render_pipeline,
-            bind_group,
-            timestamp_buffer,
-            config_buffer,
-            target_texture,
-            target_texture_view,
-            view_formats: config.view_formats.clone(),
-            copier,
-        }
+    // Draws track boundaries, occupied areas and the progress through the current chunk on top
+    // of the danmaku, for tuning line_height and LayoutMode
+    #[cfg(feature = "debug-overlay")]
+    pub fn set_debug_overlay(&mut self, device: &Device, enabled: bool) {
+        self.debug_overlay = enabled.then(|| {
+            DebugOverlay::new(device, self.format, self.renderer_param.premultiplied_alpha)
+        });
     }
 
-    pub fn update_renderer_param(&mut self, queue: &Queue, renderer_param: RendererParam) {
-        self.copier.update_opacity(queue, renderer_param.opacity);
+    pub fn update_renderer_param(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        renderer_param: RendererParam,
+    ) {
+        let now = Instant::now();
+        if renderer_param.opacity != self.opacity.target() {
+            self.opacity.set(
+                renderer_param.opacity,
+                transition_duration(&renderer_param),
+                now,
+            );
+            self.transitioning = true;
+        }
+        let opacity = self.opacity.value_at(now);
+        if renderer_param.premultiplied_alpha != self.renderer_param.premultiplied_alpha
+            || renderer_param.blend_mode != self.renderer_param.blend_mode
+        {
+            self.render_pipeline = create_render_pipeline(
+                device,
+                &self.render_pipeline_layout,
+                self.format,
+                renderer_param.blend_mode,
+                renderer_param.premultiplied_alpha,
+            );
+            if let Some(target) = &mut self.target {
+                target.copier = TextureCopier::new(
+                    device,
+                    &target.texture,
+                    &target.view,
+                    opacity,
+                    renderer_param.orientation,
+                    renderer_param.blend_mode,
+                    renderer_param.premultiplied_alpha,
+                );
+            }
+        }
+        self.config_uniform = ConfigUniform::new(&self.danmaku_param, &renderer_param, self.format);
+        match &self.target {
+            Some(target) => target.update_config(queue, opacity, &renderer_param),
+            None => self.config_uniform.set_opacity(opacity),
+        }
+        self.config_uniform.update(&self.config_buffer, queue);
+        #[cfg(feature = "debug-overlay")]
+        if let Some(overlay) = &mut self.debug_overlay {
+            if overlay.premultiplied_alpha() != renderer_param.premultiplied_alpha {
+                *overlay =
+                    DebugOverlay::new(device, self.format, renderer_param.premultiplied_alpha);
+            }
+        }
+        self.renderer_param = renderer_param;
     }
 
     pub fn update_danmaku_param(
@@ -193,65 +581,117 @@ impl WgpuRenderer {
         queue: &Queue,
         danmaku_param: DanmakuParam,
     ) {
-        let config_uniform: ConfigUniform = danmaku_param.clone().into();
-        config_uniform.update(&self.config_buffer, queue);
+        let now = Instant::now();
+        let font_size = danmaku_param.physical_font_size();
+        if font_size != self.font_size.target() {
+            self.font_size
+                .set(font_size, transition_duration(&self.renderer_param), now);
+            self.transitioning = true;
+        }
+        self.config_uniform = ConfigUniform::new(&danmaku_param, &self.renderer_param, self.format);
+        self.config_uniform
+            .set_scale(self.font_size.value_at(now) / font_size);
+        if self.target.is_none() {
+            self.config_uniform.set_opacity(self.opacity.value_at(now));
+        }
+        self.config_uniform.update(&self.config_buffer, queue);
 
-        let size = Extent3d {
-            width: danmaku_param.screen_size.0,
-            height: danmaku_param.screen_size.1,
-            depth_or_array_layers: 1,
-        };
-        info!("Target texture format: {:?}", self.target_texture.format());
-        info!("Target texture size: {:?}", size);
-        let target_texture = device.create_texture(&TextureDescriptor {
-            label: Some("Danmaku render target texture"),
-            size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: TextureDimension::D2,
-            format: self.target_texture.format(),
-            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
-            view_formats: &self.view_formats,
-        });
-        let target_texture_view = target_texture.create_view(&Default::default());
-        self.copier.change_texture(device, &target_texture_view);
-        self.target_texture = target_texture;
-        self.target_texture_view = target_texture_view;
+        if let Some(target) = &mut self.target {
+            let (texture, view) =
+                create_target_texture(device, &danmaku_param, self.format, &self.view_formats);
+            target.copier.change_texture(device, &view);
+            if let Some(compositor) = &mut target.compositor {
+                compositor.clear_textures();
+            }
+            target.texture = texture;
+            target.view = view;
+        }
+        self.danmaku_param = danmaku_param;
     }
 
     pub fn update(&mut self, queue: &Queue, timestamp: DanmakuTime) {
         let timestamp_uniform: TimestampUniform = timestamp.into();
         timestamp_uniform.update(&self.timestamp_buffer, queue);
+        if self.transitioning {
+            self.update_transition(queue, Instant::now());
+        }
+        #[cfg(feature = "debug-overlay")]
+        {
+            self.timestamp = timestamp;
+        }
     }
 
-    fn render_vertex(
-        &self,
-        render_pass: &mut RenderPass,
-        vertex: &VertexBuffer,
-        index: &IndexBuffer,
-    ) {
+    fn update_transition(&mut self, queue: &Queue, now: Instant) {
+        let opacity = self.opacity.value_at(now);
+        match &self.target {
+            Some(target) => target.update_config(queue, opacity, &self.renderer_param),
+            None => self.config_uniform.set_opacity(opacity),
+        }
+        self.config_uniform
+            .set_scale(self.font_size.value_at(now) / self.font_size.target());
+        self.config_uniform.update(&self.config_buffer, queue);
+        self.transitioning = !self.opacity.is_finished(now) || !self.font_size.is_finished(now);
+    }
+
+    fn render_vertex(&self, render_pass: &mut RenderPass, vertex: &VertexBuffer) {
         let glyphs = vertex.glyphs();
-        if glyphs == 0 {
+        // Chunks shaped with the old font size are waiting to be replaced, and their glyphs may be
+        // gone from the atlas
+        if glyphs == 0 || vertex.font_size != self.font_size.target() {
             return;
         }
         render_pass.set_vertex_buffer(0, vertex.vertex_buffer.slice(..));
-        render_pass.set_index_buffer(index.buffer_slice(glyphs), IndexFormat::Uint32);
-        render_pass.draw_indexed(0..glyphs * 6, 0, 0..1);
+        // Each instance is a quad drawn as a strip of two triangles
+        render_pass.draw(0..4, 0..glyphs);
     }
 
+    #[cfg(feature = "debug-overlay")]
+    fn prepare_debug_overlay(&mut self, device: &Device, worker_buffer: &WgpuWorkerBuffer) {
+        if let Some(overlay) = &mut self.debug_overlay {
+            let chunks = worker_buffer.chunks().map(|chunk| chunk.as_ref());
+            overlay.prepare(device, &self.danmaku_param, self.timestamp, chunks);
+        }
+    }
+
+    fn draw_buffer(&self, render_pass: &mut RenderPass, worker_buffer: &WgpuWorkerBuffer) {
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_bind_group(
+            1,
+            &worker_buffer.cache.glyph_texture_manager.bind_group,
+            &[],
+        );
+
+        for chunk in worker_buffer.chunks() {
+            self.render_vertex(render_pass, chunk);
+        }
+        #[cfg(feature = "debug-overlay")]
+        if let Some(overlay) = &self.debug_overlay {
+            overlay.render(render_pass);
+        }
+    }
+
+    // Draws the danmaku into the target texture, composited by render. Direct renderers only
+    // prepare the debug overlay here.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     pub fn render_buffer(
         &mut self,
         device: &Device,
         queue: &Queue,
         worker_buffer: &WgpuWorkerBuffer,
     ) {
+        #[cfg(feature = "debug-overlay")]
+        self.prepare_debug_overlay(device, worker_buffer);
+        let Some(target) = &self.target else {
+            return;
+        };
         let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
             label: Some("Danmaku render command encoder"),
         });
         let target_render_pass_desc = RenderPassDescriptor {
             label: Some("Danmaku render pass"),
             color_attachments: &[Some(RenderPassColorAttachment {
-                view: &self.target_texture_view,
+                view: &target.view,
                 resolve_target: None,
                 ops: Operations {
                     load: LoadOp::Clear(Color::TRANSPARENT),
@@ -261,42 +701,78 @@ impl WgpuRenderer {
             ..Default::default()
         };
         let mut target_render_pass = encoder.begin_render_pass(&target_render_pass_desc);
+        self.draw_buffer(&mut target_render_pass, worker_buffer);
+        drop(target_render_pass);
 
-        target_render_pass.set_pipeline(&self.render_pipeline);
-        target_render_pass.set_bind_group(0, &self.bind_group, &[]);
-        target_render_pass.set_bind_group(
-            1,
-            &worker_buffer.cache.glyph_texture_manager.bind_group,
-            &[],
-        );
+        queue.submit(Some(encoder.finish()));
+    }
 
-        if let Some(previous) = &worker_buffer.previous {
-            self.render_vertex(
-                &mut target_render_pass,
-                previous,
-                &worker_buffer.cache.index_buffer,
-            );
-        }
-        if let Some(current) = &worker_buffer.current {
-            self.render_vertex(
-                &mut target_render_pass,
-                current,
-                &worker_buffer.cache.index_buffer,
-            );
-        }
-        if let Some(next) = &worker_buffer.next {
-            self.render_vertex(
-                &mut target_render_pass,
-                next,
-                &worker_buffer.cache.index_buffer,
+    // Draws the danmaku of a renderer from new_direct into a render pass begun by the host, after
+    // update and render_buffer with the time of the frame. The pass must target the format of
+    // the surface.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub fn render_buffer_direct(
+        &self,
+        render_pass: &mut RenderPass,
+        worker_buffer: &WgpuWorkerBuffer,
+        viewport: Option<Viewport>,
+    ) {
+        if let Some(viewport) = viewport {
+            render_pass.set_viewport(
+                viewport.x,
+                viewport.y,
+                viewport.width,
+                viewport.height,
+                0.0,
+                1.0,
             );
         }
-        drop(target_render_pass);
+        self.draw_buffer(render_pass, worker_buffer);
+    }
 
-        queue.submit(Some(encoder.finish()));
+    pub fn is_direct(&self) -> bool {
+        self.target.is_none()
     }
 
-    pub fn render(&self, render_pass: &mut RenderPass) {
-        self.copier.render(render_pass);
+    // None for direct renderers
+    pub fn target_texture(&self) -> Option<&Texture> {
+        self.target.as_ref().map(|target| &target.texture)
+    }
+
+    pub fn target_texture_view(&self) -> Option<&TextureView> {
+        self.target.as_ref().map(|target| &target.view)
+    }
+
+    // Copies the last rendered frame out of the target texture, None for direct renderers. The
+    // copy is only mapped when the device is polled, so keep calling Device::poll (or rendering)
+    // until the future is ready.
+    pub fn read_back(
+        &self,
+        device: &Device,
+        queue: &Queue,
+    ) -> Option<impl Future<Output = Result<RgbaImage, BufferAsyncError>>> {
+        let target = self.target.as_ref()?;
+        Some(ReadBack::new(device, queue, &target.texture))
+    }
+
+    pub fn is_composited(&self) -> bool {
+        self.target
+            .as_ref()
+            .and_then(|target| target.compositor.as_ref())
+            .is_some_and(|compositor| compositor.has_textures())
+    }
+
+    // Composites the target texture into the render pass, over the video frame when one is set.
+    // Direct renderers use render_buffer_direct instead.
+    pub fn render(&self, render_pass: &mut RenderPass, viewport: Option<Viewport>) {
+        let Some(target) = &self.target else {
+            return;
+        };
+        match &target.compositor {
+            Some(compositor) if compositor.has_textures() => {
+                compositor.render(render_pass, viewport)
+            }
+            _ => target.copier.render(render_pass, viewport),
+        }
     }
 }
@@ -2,43 +2,70 @@ struct ShadowConfigUniform {
     shadow_width: u32,
     shadow_weight: f32,
     texture_width: u32,
-    texture_height: u32,
-    pixel_width: f32,
-    pixel_height: f32
+    texture_height: u32
 };
 
-struct VertexInput {
-    @location(0) position: vec2u
-}
-
-struct VertexOutput {
-    @builtin(position) clip_position: vec4f,
-    @location(0) tex_coords: vec2f
+struct GlyphRect {
+    position: vec2u,
+    size: vec2u,
+    // Start of the glyph in the blur buffer
+    offset: u32
 }
 
 @group(0) @binding(0)
 var texture: texture_2d<f32>;
 @group(0) @binding(1)
-var texture_sampler: sampler;
-@group(0) @binding(2)
 var<uniform> config: ShadowConfigUniform;
 
-@vertex
-fn vs_main(
-    model: VertexInput,
-) -> VertexOutput {
-    var out: VertexOutput;
-    let tex_coords = vec2f(
-        f32(model.position.x) / f32(config.texture_width),
-        f32(model.position.y) / f32(config.texture_height)
-    );
-    out.tex_coords = tex_coords;
-    out.clip_position = vec4f((tex_coords.xy * 2.0 - 1.0) * vec2(1.0, -1.0), 0.0, 1.0);
-    return out;
-}
-
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) f32 {
+@group(1) @binding(0)
+var shadow_texture: texture_storage_2d<rgba8unorm, write>;
+@group(1) @binding(1)
+var<storage, read> rects: array<GlyphRect>;
+// Rows of the glyphs blurred horizontally
+@group(1) @binding(2)
+var<storage, read_write> blur: array<f32>;
+
+fn load_glyph(position: vec2i) -> f32 {
+    let size = vec2i(i32(config.texture_width), i32(config.texture_height));
+    if any(position < vec2i(0)) || any(position >= size) {
+        return 0.0;
+    }
+    return textureLoad(texture, position, 0).r;
+}
+
+fn gaussian(offset: i32) -> f32 {
+    // The kernel ends at two standard deviations
+    let sigma = f32(config.shadow_width) / 2.0;
+    let x = f32(offset) / sigma;
+    return exp(-0.5 * x * x);
+}
+
+fn gaussian_sum() -> f32 {
+    var sum: f32 = 0.0;
+    let shadow_width = i32(config.shadow_width);
+    for (var x: i32 = -shadow_width; x <= shadow_width; x++) {
+        sum += gaussian(x);
+    }
+    return sum;
+}
+
+fn store_shadow(position: vec2u, shadow: f32) {
+    let value = clamp(shadow * config.shadow_weight, 0.0, 1.0);
+    textureStore(shadow_texture, position, vec4f(value, 0.0, 0.0, 1.0));
+}
+
+// Cone around every pixel of the glyph, the outline keeps its shape
+@compute @workgroup_size(8, 8, 1)
+fn cs_outline(@builtin(global_invocation_id) id: vec3u) {
+    let rect = rects[id.z];
+    if any(id.xy >= rect.size) {
+        return;
+    }
+    let position = rect.position + id.xy;
+    if config.shadow_width == 0u {
+        store_shadow(position, 0.0);
+        return;
+    }
     var output: f32 = 0.0;
     let shadow_width = i32(config.shadow_width);
     let shadow_width_float = f32(config.shadow_width);
@@ -46,12 +73,44 @@ fn fs_main(in: VertexOutput) -> @location(0) f32 {
         for (var y: i32 = -shadow_width; y <= shadow_width; y++) {
             let distance = length(vec2f(f32(x), f32(y)));
             let weight = 1.0 - (distance / shadow_width_float);
-            let offset = vec2(f32(x) * config.pixel_width, f32(y) * config.pixel_height);
-            let sampled = textureSample(texture, texture_sampler, in.tex_coords + offset);
-            let shadow = sampled.r * weight;
-            let weighted_shadow = config.shadow_weight * shadow;
-            output = max(output, weighted_shadow);
+            let sampled = load_glyph(vec2i(position) + vec2i(x, y));
+            output = max(output, sampled * weight);
+        }
+    }
+    store_shadow(position, output);
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn cs_blur_horizontal(@builtin(global_invocation_id) id: vec3u) {
+    let rect = rects[id.z];
+    if any(id.xy >= rect.size) {
+        return;
+    }
+    let position = vec2i(rect.position + id.xy);
+    var output: f32 = 0.0;
+    let shadow_width = i32(config.shadow_width);
+    for (var x: i32 = -shadow_width; x <= shadow_width; x++) {
+        output += load_glyph(position + vec2i(x, 0)) * gaussian(x);
+    }
+    blur[rect.offset + id.y * rect.size.x + id.x] = output / gaussian_sum();
+}
+
+// The glyphs are padded by the shadow width, so the rows outside of the glyph are empty
+@compute @workgroup_size(8, 8, 1)
+fn cs_blur_vertical(@builtin(global_invocation_id) id: vec3u) {
+    let rect = rects[id.z];
+    if any(id.xy >= rect.size) {
+        return;
+    }
+    var output: f32 = 0.0;
+    let shadow_width = i32(config.shadow_width);
+    for (var y: i32 = -shadow_width; y <= shadow_width; y++) {
+        let row = i32(id.y) + y;
+        if row < 0 || row >= i32(rect.size.y) {
+            continue;
         }
+        output += blur[rect.offset + u32(row) * rect.size.x + id.x] * gaussian(y);
     }
-    return clamp(output, 0.0, 1.0);
-}
\ No newline at end of file
+    // Twice as strong as the plain blur, so the weight means about the same as for the outline
+    store_shadow(rect.position + id.xy, 2.0 * output / gaussian_sum());
+}
@@ -0,0 +1,789 @@
+use std::{
+    env,
+    fs::{self, File},
+    future::Future,
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+use wgpu::{
+    CompositeAlphaMode, Device, DeviceDescriptor, Instance, Maintain, PresentMode, Queue,
+    RequestAdapterOptions, SurfaceConfiguration, TextureFormat, TextureUsages,
+};
+
+use crate::{
+    danmaku::DanmakuTime,
+    renderer::{RendererParam, RgbaImage},
+    sources::DanmakuSource,
+    worker::{DanmakuParam, WorkerBuffer, WorkerManager, WorkerStateBuilder},
+};
+
+use super::{WgpuRenderCache, WgpuRenderer, WgpuWorkerBuffer, WgpuWorkerManager};
+
+// Set to write the rendered images as the new references instead of comparing against them
+pub const UPDATE_GOLDEN_ENV: &str = "DANMAKU_UPDATE_GOLDEN";
+
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+// Prefers the software fallback adapter, so the output doesn't depend on the GPU. None when no
+// adapter is available at all.
+pub fn request_device() -> Option<(Arc<Device>, Arc<Queue>)> {
+    let instance = Instance::default();
+    let adapter = block_on(instance.request_adapter(&RequestAdapterOptions {
+        force_fallback_adapter: true,
+        ..Default::default()
+    }))
+    .or_else(|| block_on(instance.request_adapter(&RequestAdapterOptions::default())))?;
+    let (device, queue) =
+        block_on(adapter.request_device(&DeviceDescriptor::default(), None)).ok()?;
+    Some((Arc::new(device), Arc::new(queue)))
+}
+
+// Renders single frames into an offscreen texture, waiting for the worker to finish the chunks
+pub struct HeadlessRenderer {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    renderer: WgpuRenderer,
+    buffer: Arc<Mutex<WgpuWorkerBuffer>>,
+    worker: WgpuWorkerManager,
+    param: DanmakuParam,
+}
+
+impl HeadlessRenderer {
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        param: DanmakuParam,
+        renderer_param: RendererParam,
+        state_builder: WorkerStateBuilder,
+        source: Box<dyn DanmakuSource + Send>,
+    ) -> Self {
+        let config = SurfaceConfiguration {
+            usage: TextureUsages::RENDER_ATTACHMENT,
+            format: TextureFormat::Rgba8UnormSrgb,
+            width: param.screen_size.0,
+            height: param.screen_size.1,
+            present_mode: PresentMode::Fifo,
+            desired_maximum_frame_latency: 2,
+            alpha_mode: CompositeAlphaMode::Auto,
+            view_formats: vec![],
+        };
+        let cache = WgpuRenderCache::new(device.clone(), queue.clone(), (256, 256), param.clone());
+        let mut renderer =
+            WgpuRenderer::new(&config, &device, param.clone(), renderer_param, &cache);
+        let buffer = Arc::new(Mutex::new(WorkerBuffer::new(cache)));
+        let state = state_builder.build(buffer.clone(), source);
+        let worker = WorkerManager::new(param, state);
+        // The line height may only be known once the fonts are loaded
+        let param = worker.param().clone();
+        renderer.update_danmaku_param(&device, &queue, param.clone());
+        HeadlessRenderer {
+            device,
+            queue,
+            renderer,
+            buffer,
+            worker,
+            param,
+        }
+    }
+
+    // Continues on another device, as after a device loss
+    pub fn recreate(&mut self, device: Arc<Device>, queue: Arc<Queue>) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.cache.recreate(device.clone(), queue.clone());
+        buffer.clear_chunks();
+        self.renderer.recreate(&device, &buffer.cache);
+        drop(buffer);
+        self.device = device;
+        self.queue = queue;
+        self.worker.refresh().unwrap();
+    }
+
+    pub fn render(&mut self, timestamp: DanmakuTime) -> RgbaImage {
+        let index = self.param.chunk_index(timestamp);
+        self.worker.request(None, index).unwrap();
+        let start = Instant::now();
+        while self.buffer.lock().unwrap().acquire_index(index).is_none() {
+            assert!(
+                start.elapsed() < CHUNK_TIMEOUT,
+                "Timed out waiting for chunk {}",
+                index
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        self.renderer.update(&self.queue, timestamp);
+        let buffer = self.buffer.lock().unwrap();
+        self.renderer
+            .render_buffer(&self.device, &self.queue, &buffer);
+        drop(buffer);
+        let image = self.renderer.read_back(&self.device, &self.queue).unwrap();
+        self.device.poll(Maintain::Wait);
+        block_on(image).unwrap()
+    }
+}
+
+// Number of pixels with any channel differing by more than the tolerance
+pub fn count_mismatches(actual: &RgbaImage, expected: &RgbaImage, tolerance: u8) -> usize {
+    assert_eq!(
+        (actual.width(), actual.height()),
+        (expected.width(), expected.height()),
+        "Image size mismatch"
+    );
+    actual
+        .data()
+        .chunks_exact(4)
+        .zip(expected.data().chunks_exact(4))
+        .filter(|(a, b)| {
+            a.iter()
+                .zip(b.iter())
+                .any(|(a, b)| a.abs_diff(*b) > tolerance)
+        })
+        .count()
+}
+
+// Compares against the reference PNG, allowing max_mismatch_ratio of the pixels to differ more
+// than the tolerance, as rasterizers and font hinting differ slightly. The actual image is saved
+// next to the reference on failure, and a missing reference fails unless the references are being
+// updated.
+pub fn assert_golden<P: AsRef<Path>>(
+    actual: &RgbaImage,
+    path: P,
+    tolerance: u8,
+    max_mismatch_ratio: f32,
+) {
+    let path = path.as_ref();
+    if env::var_os(UPDATE_GOLDEN_ENV).is_some() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        write_png(actual, path).unwrap();
+        return;
+    }
+    assert!(
+        path.exists(),
+        "Missing reference {}, set {} to create it",
+        path.display(),
+        UPDATE_GOLDEN_ENV
+    );
+    let expected = read_png(path).unwrap();
+    let mismatches = count_mismatches(actual, &expected, tolerance);
+    let allowed = (actual.width() * actual.height()) as f32 * max_mismatch_ratio;
+    if mismatches as f32 > allowed {
+        let mut actual_path = PathBuf::from(path);
+        actual_path.set_extension("actual.png");
+        write_png(actual, &actual_path).unwrap();
+        panic!(
+            "{} pixels differ from {}, saved the output to {}",
+            mismatches,
+            path.display(),
+            actual_path.display()
+        );
+    }
+}
+
+pub fn write_png<P: AsRef<Path>>(image: &RgbaImage, path: P) -> io::Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(writer, image.width(), image.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(image.data())?;
+    Ok(())
+}
+
+pub fn read_png<P: AsRef<Path>>(path: P) -> io::Result<RgbaImage> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    // Palette and low bit depth images come out as 8 bit samples
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+    if (info.color_type, info.bit_depth) != (png::ColorType::Rgba, png::BitDepth::Eight) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Only 8 bit RGBA PNG images are supported",
+        ));
+    }
+    pixels.truncate(info.buffer_size());
+    Ok(RgbaImage::new(info.width, info.height, pixels))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use cosmic_text::FontSystem;
+    use wgpu::{
+        Color, CompositeAlphaMode, Device, Extent3d, ImageCopyTexture, ImageDataLayout, LoadOp,
+        Maintain, Operations, Origin3d, PresentMode, Queue, RenderPassColorAttachment,
+        RenderPassDescriptor, StoreOp, SurfaceConfiguration, TextureAspect, TextureDescriptor,
+        TextureDimension, TextureFormat, TextureUsages,
+    };
+
+    use crate::{
+        danmaku::{Danmaku, DanmakuColor, DanmakuTime, DanmakuType},
+        layout::LayoutMode,
+        manager::DanmakuTimeChunkProvider,
+        pipeline::DanmakuPipelineBuilder,
+        position::Easing,
+        renderer::{BlendMode, RendererParam, RgbaImage, ShadowColor},
+        shaper::CosmicTextShaper,
+        sources::{bilibili::parse_xml_from_file, VecDanmakuSource},
+        worker::{test::test_param, DanmakuParam, LineHeight, WorkerStateBuilder},
+    };
+
+    use super::{
+        super::{capture::ReadBack, WgpuRenderer},
+        assert_golden, block_on, count_mismatches, read_png, request_device, write_png,
+        HeadlessRenderer, CHUNK_TIMEOUT,
+    };
+
+    // A small screen with a single font
+    fn small_param() -> DanmakuParam {
+        DanmakuParam {
+            screen_size: (320, 180),
+            font_size: 14.0,
+            line_height: LineHeight::Fixed(18),
+            layout_mode: LayoutMode::ShowAll,
+            shadow_size: 2,
+            shadow_weight: 1.0,
+            ..test_param()
+        }
+    }
+
+    fn test_renderer_param() -> RendererParam {
+        RendererParam {
+            opacity: 1.0,
+            color_space: Default::default(),
+            premultiplied_alpha: false,
+            blend_mode: BlendMode::Normal,
+            orientation: Default::default(),
+            animation: Default::default(),
+            type_opacity: Default::default(),
+            shadow_color: Default::default(),
+        }
+    }
+
+    // The danmaku of test/{name}.xml
+    fn golden_renderer(device: Arc<Device>, queue: Arc<Queue>, name: &str) -> HeadlessRenderer {
+        let source = parse_xml_from_file(format!("test/{}.xml", name)).unwrap();
+        let state_builder = WorkerStateBuilder::new().sans_serif_families(&["DejaVu Sans"]);
+        HeadlessRenderer::new(
+            device,
+            queue,
+            small_param(),
+            test_renderer_param(),
+            state_builder,
+            Box::new(source),
+        )
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        let data = (0..300 * 200 * 4).map(|i| (i * 7 % 251) as u8).collect();
+        let image = RgbaImage::new(300, 200, data);
+        let path = env::temp_dir().join("danmaku_renderer_png_round_trip.png");
+        write_png(&image, &path).unwrap();
+        assert_eq!(read_png(&path).unwrap().data(), image.data());
+    }
+
+    // The references were rendered with DejaVu Sans and no CJK font installed, so most glyphs are
+    // boxes. Regenerate them with DANMAKU_UPDATE_GOLDEN=1 when the fonts differ. Like the other
+    // tests needing a graphics adapter, it only runs with --ignored.
+    #[test]
+    #[ignore = "needs a graphics adapter"]
+    fn test_golden_images() {
+        let (device, queue) = request_device().expect("No graphics adapter");
+        // 747529524 only has danmaku at 12s and 84s
+        for (name, millis) in [("1176840", 30_000), ("747529524", 86_000)] {
+            let mut renderer = golden_renderer(device.clone(), queue.clone(), name);
+            let image = renderer.render(DanmakuTime::from_millis(millis));
+            assert!(image.data().chunks(4).any(|pixel| pixel[3] != 0));
+            assert_golden(&image, format!("test/golden/{}.png", name), 8, 0.01);
+
+            // The same frame after moving to a new device
+            let (device, queue) = request_device().unwrap();
+            renderer.recreate(device, queue);
+            let recreated = renderer.render(DanmakuTime::from_millis(millis));
+            assert_eq!(count_mismatches(&recreated, &image, 0), 0);
+        }
+    }
+    #[test]
+    #[ignore = "needs a graphics adapter"]
+    fn test_memory_budget() {
+        let (device, queue) = request_device().expect("No graphics adapter");
+        let mut renderer = golden_renderer(device.clone(), queue.clone(), "1176840");
+        let mut budgeted = golden_renderer(device, queue, "1176840");
+        budgeted
+            .buffer
+            .lock()
+            .unwrap()
+            .cache
+            .set_memory_budget(Some(1));
+        for millis in [30_000, 38_000] {
+            renderer.render(DanmakuTime::from_millis(millis));
+            budgeted.render(DanmakuTime::from_millis(millis));
+        }
+        let usage = renderer.buffer.lock().unwrap().cache.memory_usage();
+        assert!(usage.vertex_buffers > 0);
+
+        // The atlas started over, with only the glyphs of the chunks on the screen
+        let expected = renderer.render(DanmakuTime::from_millis(46_000));
+        let image = budgeted.render(DanmakuTime::from_millis(46_000));
+        assert_eq!(count_mismatches(&image, &expected, 0), 0);
+        let evicted = budgeted.buffer.lock().unwrap().cache.memory_usage();
+        let usage = renderer.buffer.lock().unwrap().cache.memory_usage();
+        assert!(evicted.glyph_textures <= usage.glyph_textures);
+        assert!(evicted.vertex_buffers < usage.vertex_buffers);
+    }
+
+    // The shader places the danmaku where the position module does, found by the edges of their
+    // solid backgrounds
+    #[test]
+    #[ignore = "needs a graphics adapter"]
+    fn test_shader_positions() {
+        let (device, queue) = request_device().expect("No graphics adapter");
+        let danmaku = |millis, r#type, color| Danmaku {
+            background: Some(DanmakuColor::from_code(color)),
+            ..Danmaku::new(DanmakuTime::from_millis(millis), r#type, "pos")
+        };
+        let items = vec![
+            danmaku(0, DanmakuType::Scroll, 0xFF0000),
+            danmaku(1000, DanmakuType::Top, 0x00FF00),
+            danmaku(1000, DanmakuType::Bottom, 0x0000FF),
+        ];
+        let param = DanmakuParam {
+            shadow_size: 0,
+            ..small_param()
+        };
+        let now = DanmakuTime::from_millis(2000);
+
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let mut provider = DanmakuTimeChunkProvider::new(
+            param.clone(),
+            Box::new(VecDanmakuSource::new(items.clone())),
+        );
+        let chunk = provider.get_chunk(&mut shaper, None, 0).unwrap();
+
+        // The shader eases the progress like the position module
+        for easing in [Easing::Linear, Easing::EaseOut(0.5)] {
+            let mut renderer_param = test_renderer_param();
+            renderer_param.animation.scroll_easing = easing;
+            let state_builder = WorkerStateBuilder::new()
+                .shaper(Box::new(CosmicTextShaper::new(FontSystem::new())));
+            let mut renderer = HeadlessRenderer::new(
+                device.clone(),
+                queue.clone(),
+                param.clone(),
+                renderer_param,
+                state_builder,
+                Box::new(VecDanmakuSource::new(items.clone())),
+            );
+            let image = renderer.render(now);
+
+            for item in &chunk.items {
+                let (x, y) = item.eased_position_at(now, &param, easing).unwrap();
+                let (bg_x, bg_y, bg_width, bg_height) = item.item.background_rect();
+                let background = item.item.background.unwrap();
+                let matches = |pixel: &[u8]| {
+                    pixel[..3]
+                        .iter()
+                        .zip([background.r(), background.g(), background.b()])
+                        .all(|(a, b)| a.abs_diff(b) < 48)
+                };
+                let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+                for (index, pixel) in image.data().chunks(4).enumerate() {
+                    if matches(pixel) {
+                        let (px, py) = (index as u32 % image.width(), index as u32 / image.width());
+                        left = left.min(px);
+                        top = top.min(py);
+                        right = right.max(px + 1);
+                        bottom = bottom.max(py + 1);
+                    }
+                }
+                let expected_left = x as i32 + bg_x;
+                let expected_top = y as i32 + bg_y;
+                assert!(
+                    (left as i32 - expected_left).abs() <= 1,
+                    "{:?}: left {} != {}",
+                    item.position,
+                    left,
+                    expected_left
+                );
+                assert!(
+                    (top as i32 - expected_top).abs() <= 1,
+                    "{:?}: top {} != {}",
+                    item.position,
+                    top,
+                    expected_top
+                );
+                assert!((right - left).abs_diff(bg_width) <= 1);
+                assert!((bottom - top).abs_diff(bg_height) <= 1);
+            }
+        }
+    }
+
+    // Dark text gets a light outline, which is missing with black shadows
+    #[test]
+    #[ignore = "needs a graphics adapter"]
+    fn test_contrast_shadow() {
+        let (device, queue) = request_device().expect("No graphics adapter");
+        let items = vec![Danmaku {
+            color: DanmakuColor::from_code(0x000080),
+            ..Danmaku::new(DanmakuTime::from_millis(0), DanmakuType::Top, "dark")
+        }];
+        let light_pixels = |shadow_color| {
+            let renderer_param = RendererParam {
+                shadow_color,
+                ..test_renderer_param()
+            };
+            let state_builder = WorkerStateBuilder::new()
+                .shaper(Box::new(CosmicTextShaper::new(FontSystem::new())));
+            let mut renderer = HeadlessRenderer::new(
+                device.clone(),
+                queue.clone(),
+                small_param(),
+                renderer_param,
+                state_builder,
+                Box::new(VecDanmakuSource::new(items.clone())),
+            );
+            let image = renderer.render(DanmakuTime::from_millis(1000));
+            image
+                .data()
+                .chunks(4)
+                .filter(|pixel| pixel[3] > 128 && pixel[..3].iter().all(|channel| *channel > 192))
+                .count()
+        };
+        assert_eq!(light_pixels(ShadowColor::Black), 0);
+        assert!(light_pixels(ShadowColor::Contrast) > 0);
+    }
+
+    #[test]
+    #[ignore = "needs a graphics adapter"]
+    fn test_mask() {
+        let (device, queue) = request_device().expect("No graphics adapter");
+        let items = vec![Danmaku::new(
+            DanmakuTime::from_millis(0),
+            DanmakuType::Top,
+            "masked masked",
+        )];
+        let state_builder =
+            WorkerStateBuilder::new().shaper(Box::new(CosmicTextShaper::new(FontSystem::new())));
+        let mut renderer = HeadlessRenderer::new(
+            device,
+            queue,
+            small_param(),
+            test_renderer_param(),
+            state_builder,
+            Box::new(VecDanmakuSource::new(items)),
+        );
+        let (width, height) = small_param().screen_size;
+        // Drawn pixels left and right of the center
+        let drawn = |image: &RgbaImage| {
+            let mut drawn = (0, 0);
+            for (index, pixel) in image.data().chunks(4).enumerate() {
+                let x = index as u32 % width;
+                if pixel[3] == 0 {
+                    continue;
+                }
+                if x < width / 2 - 2 {
+                    drawn.0 += 1;
+                } else if x >= width / 2 + 2 {
+                    drawn.1 += 1;
+                }
+            }
+            drawn
+        };
+        let now = DanmakuTime::from_millis(1000);
+        let (left, right) = drawn(&renderer.render(now));
+        assert!(left > 0 && right > 0);
+
+        // A mask of a quarter of the size over the left half
+        let mask: Vec<u8> = (0..width / 4 * height / 4)
+            .map(|index| {
+                if index % (width / 4) < width / 8 {
+                    255
+                } else {
+                    0
+                }
+            })
+            .collect();
+        renderer.renderer.write_mask(
+            &renderer.device,
+            &renderer.queue,
+            (width / 4, height / 4),
+            &mask,
+        );
+        assert_eq!(drawn(&renderer.render(now)), (0, right));
+
+        renderer.renderer.set_mask(&renderer.device, None);
+        assert_eq!(drawn(&renderer.render(now)), (left, right));
+    }
+
+    // The video shows where no danmaku is drawn, and the pass is covered everywhere
+    #[test]
+    #[ignore = "needs a graphics adapter"]
+    fn test_video_composite() {
+        let (device, queue) = request_device().expect("No graphics adapter");
+        let now = DanmakuTime::from_millis(30_000);
+        let mut renderer = golden_renderer(device.clone(), queue.clone(), "1176840");
+        let danmaku = renderer.render(now);
+        let (width, height) = renderer.param.screen_size;
+        let create_texture = |usage| {
+            device.create_texture(&TextureDescriptor {
+                label: None,
+                size: Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Rgba8UnormSrgb,
+                usage,
+                view_formats: &[],
+            })
+        };
+
+        // A blue video frame
+        let video = create_texture(TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST);
+        let pixels: Vec<u8> = [0, 0, 255, 255].repeat((width * height) as usize);
+        queue.write_texture(
+            ImageCopyTexture {
+                texture: &video,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            &pixels,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(width * 4),
+                rows_per_image: None,
+            },
+            video.size(),
+        );
+        renderer
+            .renderer
+            .set_video_frame(&device, Some(&video.create_view(&Default::default())));
+        assert!(renderer.renderer.is_composited());
+
+        let output = create_texture(TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC);
+        let view = output.create_view(&Default::default());
+        let render_output = |renderer: &WgpuRenderer| {
+            let mut encoder = device.create_command_encoder(&Default::default());
+            let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Color::TRANSPARENT),
+                        store: StoreOp::Store,
+                    },
+                })],
+                ..Default::default()
+            });
+            renderer.render(&mut render_pass, None);
+            drop(render_pass);
+            queue.submit(Some(encoder.finish()));
+            let image = ReadBack::new(&device, &queue, &output);
+            device.poll(Maintain::Wait);
+            block_on(image).unwrap()
+        };
+        let image = render_output(&renderer.renderer);
+
+        let mut covered = 0;
+        for (pixel, danmaku) in image.data().chunks(4).zip(danmaku.data().chunks(4)) {
+            assert_eq!(pixel[3], 255);
+            if danmaku[3] == 0 {
+                assert_eq!(pixel, [0, 0, 255, 255]);
+            } else if danmaku[3] == 255 {
+                assert!(pixel.iter().zip(danmaku).all(|(a, b)| a.abs_diff(*b) <= 1));
+                covered += 1;
+            }
+        }
+        assert!(covered > 0);
+
+        renderer.renderer.set_video_frame(&device, None);
+        assert!(!renderer.renderer.is_composited());
+
+        // The copier blends the same danmaku into the pass again
+        let image = render_output(&renderer.renderer);
+        for (pixel, danmaku) in image.data().chunks(4).zip(danmaku.data().chunks(4)) {
+            if danmaku[3] == 255 {
+                assert!(pixel.iter().zip(danmaku).all(|(a, b)| a.abs_diff(*b) <= 1));
+            }
+        }
+    }
+
+    // Both surfaces draw the same chunks, whatever their format
+    #[test]
+    #[ignore = "needs a graphics adapter"]
+    fn test_pipeline_surfaces() {
+        let (device, queue) = request_device().expect("No graphics adapter");
+        let config = |format| SurfaceConfiguration {
+            usage: TextureUsages::RENDER_ATTACHMENT,
+            format,
+            width: 320,
+            height: 180,
+            present_mode: PresentMode::Fifo,
+            desired_maximum_frame_latency: 2,
+            alpha_mode: CompositeAlphaMode::Auto,
+            view_formats: vec![],
+        };
+        let source = parse_xml_from_file("test/1176840.xml").unwrap();
+        let builder =
+            DanmakuPipelineBuilder::new(Box::new(source), small_param(), test_renderer_param())
+                .state_builder(WorkerStateBuilder::new().sans_serif_families(&["DejaVu Sans"]));
+        let mut pipeline = builder
+            .build_wgpu(
+                device.clone(),
+                queue.clone(),
+                &config(TextureFormat::Rgba8UnormSrgb),
+            )
+            .unwrap();
+        let surface = pipeline.add_surface(
+            &config(TextureFormat::Bgra8UnormSrgb),
+            test_renderer_param(),
+        );
+
+        let now = DanmakuTime::from_millis(30_000);
+        let start = Instant::now();
+        while pipeline.memory_usage().vertex_buffers == 0 {
+            assert!(start.elapsed() < CHUNK_TIMEOUT);
+            pipeline.tick(now).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        pipeline.tick(now).unwrap();
+
+        let read_back = |renderer: &WgpuRenderer| {
+            let image = renderer.read_back(&device, &queue).unwrap();
+            device.poll(Maintain::Wait);
+            block_on(image).unwrap()
+        };
+        let main = read_back(pipeline.renderer());
+        let other = read_back(pipeline.surface_renderer(surface).unwrap());
+        assert!(main.data().iter().any(|&value| value != 0));
+        assert_eq!(count_mismatches(&other, &main, 0), 0);
+
+        pipeline.remove_surface(surface);
+        assert!(pipeline.surface_renderer(surface).is_none());
+    }
+
+    // Drawing into the pass of the host covers the same pixels as the target texture
+    #[test]
+    #[ignore = "needs a graphics adapter"]
+    fn test_direct_render() {
+        let (device, queue) = request_device().expect("No graphics adapter");
+        let now = DanmakuTime::from_millis(30_000);
+        let mut renderer = golden_renderer(device.clone(), queue.clone(), "1176840");
+        let expected = renderer.render(now);
+
+        let (width, height) = renderer.param.screen_size;
+        let config = SurfaceConfiguration {
+            usage: TextureUsages::RENDER_ATTACHMENT,
+            format: TextureFormat::Rgba8UnormSrgb,
+            width,
+            height,
+            present_mode: PresentMode::Fifo,
+            desired_maximum_frame_latency: 2,
+            alpha_mode: CompositeAlphaMode::Auto,
+            view_formats: vec![],
+        };
+        let buffer = renderer.buffer.lock().unwrap();
+        let mut direct = WgpuRenderer::new_direct(
+            &config,
+            &device,
+            renderer.param.clone(),
+            test_renderer_param(),
+            &buffer.cache,
+        );
+        assert!(direct.is_direct());
+        assert!(direct.read_back(&device, &queue).is_none());
+
+        // The surface of the host
+        let texture = device.create_texture(&TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: config.format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&Default::default());
+        direct.update(&queue, now);
+        direct.render_buffer(&device, &queue, &buffer);
+        let mut encoder = device.create_command_encoder(&Default::default());
+        let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+            color_attachments: &[Some(RenderPassColorAttachment {
+                view: &view,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Clear(Color::TRANSPARENT),
+                    store: StoreOp::Store,
+                },
+            })],
+            ..Default::default()
+        });
+        direct.render_buffer_direct(&mut render_pass, &buffer, None);
+        drop(render_pass);
+        queue.submit(Some(encoder.finish()));
+        drop(buffer);
+
+        let image = ReadBack::new(&device, &queue, &texture);
+        device.poll(Maintain::Wait);
+        let image = block_on(image).unwrap();
+        let covered = |image: &RgbaImage| {
+            image
+                .data()
+                .chunks(4)
+                .map(|pixel| pixel[3] > 0)
+                .collect::<Vec<_>>()
+        };
+        let (actual, expected) = (covered(&image), covered(&expected));
+        assert!(expected.iter().any(|&covered| covered));
+        let mismatches = actual
+            .iter()
+            .zip(&expected)
+            .filter(|(actual, expected)| actual != expected)
+            .count();
+        assert!(mismatches * 100 < expected.len(), "{}", mismatches);
+    }
+}
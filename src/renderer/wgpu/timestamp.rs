@@ -16,8 +16,9 @@ pub struct TimestampUniform {
 
 impl From<DanmakuTime> for TimestampUniform {
     fn from(value: DanmakuTime) -> Self {
+        // Wraps around, the shader only uses the difference to the danmaku time
         Self {
-            time_millis: value.as_millis(),
+            time_millis: value.as_millis() as u32,
         }
     }
 }
@@ -6,23 +6,57 @@ struct ConfigUniform {
     screen_width: u32,
     screen_height: u32,
     line_height: u32,
-    lifetime: u32
+    scroll_lifetime: u32,
+    static_lifetime: u32,
+    linear_output: u32,
+    fade_in: u32,
+    fade_out: u32,
+    // 0: constant duration, 1: constant speed, 2: capped duration
+    scroll_speed: u32,
+    scroll_speed_cap: u32,
+    scroll_speed_variation: f32,
+    scroll_opacity: f32,
+    top_opacity: f32,
+    bottom_opacity: f32,
+    scale: f32,
+    opacity: f32,
+    // 0: linear, 1: ease out, 2: ease in and out
+    easing: u32,
+    easing_strength: f32,
+    // Shadows in the contrast color of the instances instead of black
+    contrast_shadow: u32,
 };
 
-struct VertexInput {
+struct InstanceInput {
     @location(0) time: u32,
-    @location(1) track_type: u32,
-    @location(2) track: u32,
-    @location(3) line_width: u32,
-    @location(4) offset: vec2i,
+    // Type (0: scroll, 1: top, 2: bottom) and index of the track
+    @location(1) track: vec2u,
+    @location(2) line_width: u32,
+    @location(3) offset: vec2i,
+    @location(4) size: vec2i,
     @location(5) tex_coords: vec2u,
-    @location(6) color: vec3f,
+    @location(6) tex_size: vec2u,
+    @location(7) color: vec4f,
+    @location(8) kind: u32,
+    @location(9) keyframe_times: vec4u,
+    @location(10) keyframe_scales: vec4f,
+    @location(11) keyframe_alphas: vec4f,
+    @location(12) animation: u32,
+    @location(13) center: vec2f,
+    @location(14) gradient: vec4f,
+    @location(15) contrast: vec3f,
 }
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4f,
     @location(0) color: vec3f,
     @location(1) tex_coords: vec2f,
+    @location(2) fade: f32,
+    // 0: glyph, 1: emote, 2: solid color, 3: emote which needs to be decoded to linear
+    @location(3) @interpolate(flat) kind: u32,
+    @location(4) shadow_color: vec3f,
+    // Position on the screen from 0 to 1
+    @location(5) mask_coords: vec2f,
 };
 
 @group(0) @binding(0)
@@ -37,39 +71,179 @@ fn coordinates_conv(screen: vec2i) -> vec2f {
     return vec2f(x, y);
 }
 
-@vertex
-fn vs_main(
-    model: VertexInput,
-) -> VertexOutput {
-    var out: VertexOutput;
-    let progress = f32(timestamp.time_millis - model.time) / f32(config.lifetime);
-
-    var offset_x: i32 = 0;
-    var offset_y: i32 = 0;
-    switch model.track_type {
-        case 0u, default: {
-            offset_x = i32(f32(config.screen_width) - f32(config.screen_width + model.line_width) * progress);
-            offset_y = i32(config.line_height * (model.track + 1));
-        }
+fn srgb_to_linear(color: vec3f) -> vec3f {
+    let lower = color / 12.92;
+    let higher = pow((color + 0.055) / 1.055, vec3f(2.4));
+    return select(higher, lower, color <= vec3f(0.04045));
+}
+
+// Same as track_speed_multiplier in layout.rs
+fn track_speed_multiplier(track: u32) -> f32 {
+    let fraction = f32(track * 2654435769u) / 4294967296.0;
+    return 1.0 + config.scroll_speed_variation * (fraction * 2.0 - 1.0);
+}
+
+// Same as DanmakuParam::item_lifetime in worker.rs
+fn item_lifetime(track_type: u32, track: u32, line_width: u32) -> f32 {
+    if track_type != 0u {
+        return f32(config.static_lifetime);
+    }
+    var lifetime = f32(config.scroll_lifetime) / track_speed_multiplier(track);
+    if config.scroll_speed == 1u {
+        lifetime *= f32(config.screen_width + line_width) / f32(config.screen_width);
+    } else if config.scroll_speed == 2u && line_width > config.scroll_speed_cap {
+        lifetime *= f32(config.screen_width + line_width)
+            / f32(config.screen_width + config.scroll_speed_cap);
+    }
+    return lifetime;
+}
+
+// Same as baseline in position.rs, truncated to whole pixels
+fn baseline(track_type: u32, track: u32, line_width: u32, progress: f32) -> vec2i {
+    let screen_width = f32(config.screen_width);
+    let top_y = i32(config.line_height * (track + 1u));
+    switch track_type {
         case 1u: {
-            offset_x = (i32(config.screen_width) - i32(model.line_width)) / 2;
-            offset_y = i32(config.line_height * (model.track + 1));
+            return vec2i(i32((screen_width - f32(line_width)) / 2.0), top_y);
         }
         case 2u: {
-            offset_x = (i32(config.screen_width) - i32(model.line_width)) / 2;
-            offset_y = i32(config.screen_height) - i32(config.line_height * model.track);
+            let bottom_y = i32(config.screen_height) - i32(config.line_height * track);
+            return vec2i(i32((screen_width - f32(line_width)) / 2.0), bottom_y);
+        }
+        default: {
+            return vec2i(i32(screen_width - (screen_width + f32(line_width)) * progress), top_y);
+        }
+    }
+}
+
+// Same as Easing::apply in position.rs
+fn ease(progress: f32) -> f32 {
+    var curve = progress;
+    if config.easing == 1u {
+        curve = 1.0 - (1.0 - progress) * (1.0 - progress);
+    } else if config.easing == 2u {
+        curve = progress * progress * (3.0 - 2.0 * progress);
+    }
+    return mix(progress, curve, config.easing_strength);
+}
+
+fn fade_factor(elapsed: f32, lifetime: f32) -> f32 {
+    var fade = 1.0;
+    if config.fade_in > 0u {
+        fade = min(fade, elapsed / f32(config.fade_in));
+    }
+    if config.fade_out > 0u {
+        fade = min(fade, (lifetime - elapsed) / f32(config.fade_out));
+    }
+    return clamp(fade, 0.0, 1.0);
+}
+
+// Same as DanmakuAnimation::evaluate in danmaku.rs, returns the scale and the alpha
+fn animation_at(
+    animation: u32,
+    times: vec4u,
+    scales: vec4f,
+    alphas: vec4f,
+    elapsed: f32,
+) -> vec2f {
+    let count = animation & 0x7FFFFFFFu;
+    if count == 0u {
+        return vec2f(1.0);
+    }
+    let looped = (animation >> 31u) != 0u;
+    let last_time = f32(times[count - 1u]);
+    var time = max(elapsed, 0.0);
+    if looped && last_time > 0.0 {
+        time = time % last_time;
+    }
+    var previous = vec3f(f32(times[0]), scales[0], alphas[0]);
+    for (var index = 0u; index < count; index++) {
+        let keyframe = vec3f(f32(times[index]), scales[index], alphas[index]);
+        if time < keyframe.x {
+            let span = keyframe.x - previous.x;
+            var progress = 0.0;
+            if span > 0.0 {
+                progress = max((time - previous.x) / span, 0.0);
+            }
+            return mix(previous.yz, keyframe.yz, progress);
         }
+        previous = keyframe;
     }
+    return previous.yz;
+}
 
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex_index: u32,
+    model: InstanceInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    // Triangle strip: top left, bottom left, top right, bottom right
+    let corner = vec2u(vertex_index / 2u, vertex_index % 2u);
+    var quad_offset = model.offset + model.size * vec2i(corner);
+    let quad_tex_coords = model.tex_coords + model.tex_size * corner;
+
+    let track_type = model.track.x;
+    let track = model.track.y;
+    let lifetime = item_lifetime(track_type, track, model.line_width);
+    // Times are wrapped to u32, reinterpret the difference as signed
+    let elapsed = f32(i32(timestamp.time_millis - model.time));
+
+    var color = model.color.rgb;
+    if model.gradient.a > 0.0 {
+        let progress = f32(quad_offset.x) / f32(max(model.line_width, 1u));
+        color = mix(color, model.gradient.rgb, clamp(progress, 0.0, 1.0));
+    }
+
+    let animation = animation_at(
+        model.animation,
+        model.keyframe_times,
+        model.keyframe_scales,
+        model.keyframe_alphas,
+        elapsed
+    );
+    let scale = animation.x * config.scale;
+    if scale != 1.0 {
+        quad_offset = vec2i(round(model.center + (vec2f(quad_offset) - model.center) * scale));
+    }
+    let progress = elapsed / lifetime;
+
+    var offset = baseline(track_type, track, model.line_width, ease(progress));
     if progress < 0.0 || progress >= 1.0 {
-        offset_y = -65536;
+        offset.y = -65536;
+    }
+    var type_opacity = config.scroll_opacity;
+    if track_type == 1u {
+        type_opacity = config.top_opacity;
+    } else if track_type == 2u {
+        type_opacity = config.bottom_opacity;
     }
 
-    let output_x = model.offset.x + offset_x;
-    let output_y = offset_y + model.offset.y;
+    let output_x = quad_offset.x + offset.x;
+    let output_y = offset.y + quad_offset.y;
 
-    out.color = model.color;
-    out.tex_coords = vec2f(model.tex_coords);
+    var shadow_color = vec3f(0.0);
+    if config.contrast_shadow != 0u {
+        shadow_color = model.contrast;
+    }
+    if config.linear_output != 0u {
+        out.color = srgb_to_linear(color);
+        out.shadow_color = srgb_to_linear(shadow_color);
+    } else {
+        out.color = color;
+        out.shadow_color = shadow_color;
+    }
+    out.kind = model.kind;
+    if model.kind == 1u && config.linear_output != 0u {
+        out.kind = 3u;
+    }
+    out.tex_coords = vec2f(quad_tex_coords);
+    out.fade = fade_factor(elapsed, lifetime) * model.color.a * type_opacity * animation.y
+        * config.opacity;
     out.clip_position = vec4f(coordinates_conv(vec2(output_x, output_y)), 0.0, 1.0);
+    out.mask_coords = vec2f(
+        f32(output_x) / f32(config.screen_width),
+        f32(output_y) / f32(config.screen_height)
+    );
     return out;
 }
@@ -9,49 +9,101 @@ use wgpu::{
     VertexStepMode,
 };
 
+use super::{glyph_atlas::GlyphItem, glyph_manager::GlyphTextureManager, WgpuRenderCache};
 use crate::{
-    danmaku::DanmakuColor,
+    danmaku::{DanmakuColor, DanmakuTime},
     layout::DanmakuPosition,
-    manager::{DanmakuTimeChunk, PositionedDanmakuItem},
-    worker::ChunkBuffer,
+    manager::{DanmakuTimeChunk, LayoutedEmote, PositionedDanmakuItem},
+    renderer::ShadowColor,
+    worker::{ChunkBuffer, DanmakuParam},
 };
 
-use super::{glyph_atlas::GlyphItem, glyph_manager::GlyphTextureManager, WgpuRenderCache};
-
-fn color_to_srgb(color: DanmakuColor) -> [f32; 3] {
-    let r = (color.r() as f32 / 255.0).powf(2.2);
-    let g = (color.g() as f32 / 255.0).powf(2.2);
-    let b = (color.b() as f32 / 255.0).powf(2.2);
+fn color_to_float(color: DanmakuColor) -> [f32; 3] {
+    let r = color.r() as f32 / 255.0;
+    let g = color.g() as f32 / 255.0;
+    let b = color.b() as f32 / 255.0;
     [r, g, b]
 }
 
+// Where a danmaku of a chunk stays on the screen, kept around for counting the visible danmaku
+// and drawing the occupancy
+pub(crate) struct TrackSpan {
+    pub(crate) position: DanmakuPosition,
+    pub(crate) time: DanmakuTime,
+    pub(crate) width: u32,
+    pub(crate) lines: u32,
+}
+
+impl TrackSpan {
+    fn new(item: &PositionedDanmakuItem) -> Self {
+        TrackSpan {
+            position: item.position,
+            time: item.item.time,
+            width: item.item.width(),
+            lines: item.item.tracks as u32,
+        }
+    }
+
+    fn is_visible(&self, now: DanmakuTime, param: &DanmakuParam) -> bool {
+        let lifetime = param.item_lifetime(self.position, self.width);
+        now >= self.time && now - self.time < lifetime
+    }
+}
+
+// One instance per quad, the corners are generated in the vertex shader
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
-pub(crate) struct Vertex {
+pub(crate) struct Instance {
     time: u32,
-    track_type: u32,
-    track: u32,
+    // Type and index of the track
+    track: [u32; 2],
     line_width: u32,
     offset: [i32; 2],
+    size: [i32; 2],
     tex_coords: [u32; 2],
-    color: [f32; 3],
+    tex_size: [u32; 2],
+    // Alpha is the opacity of the danmaku
+    color: [f32; 4],
+    // 0 for glyphs, 1 for emotes, 2 for solid rectangles
+    kind: u32,
+    // Keyframes of the animation, see DanmakuAnimation
+    keyframe_times: [u32; 4],
+    keyframe_scales: [f32; 4],
+    keyframe_alphas: [f32; 4],
+    // Number of keyframes with the highest bit set when looped, and the center of the item
+    // which is scaled around
+    animation: u32,
+    center: [f32; 2],
+    // Color at the right end of the line, alpha is 0 without a gradient
+    gradient: [f32; 4],
+    // Shadow color of ShadowColor::Contrast
+    contrast: [f32; 3],
 }
 
-impl Vertex {
-    const ATTRIBS: [wgpu::VertexAttribute; 7] = vertex_attr_array![
+impl Instance {
+    const ATTRIBS: [wgpu::VertexAttribute; 16] = vertex_attr_array![
         0 => Uint32,
-        1 => Uint32,
+        1 => Uint32x2,
         2 => Uint32,
-        3 => Uint32,
+        3 => Sint32x2,
         4 => Sint32x2,
         5 => Uint32x2,
-        6 => Float32x3
+        6 => Uint32x2,
+        7 => Float32x4,
+        8 => Uint32,
+        9 => Uint32x4,
+        10 => Float32x4,
+        11 => Float32x4,
+        12 => Uint32,
+        13 => Float32x2,
+        14 => Float32x4,
+        15 => Float32x3
     ];
 
     pub(crate) fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         VertexBufferLayout {
             array_stride: size_of::<Self>() as BufferAddress,
-            step_mode: VertexStepMode::Vertex,
+            step_mode: VertexStepMode::Instance,
             attributes: &Self::ATTRIBS,
         }
     }
@@ -60,81 +112,136 @@ impl Vertex {
         item: &PositionedDanmakuItem,
         glyph_item: &GlyphItem,
         glyph: &PhysicalGlyph,
-    ) -> [Self; 4] {
-        let (track_type, track) = match item.position {
-            DanmakuPosition::Scroll(track) => (0, track as u32),
-            DanmakuPosition::Top(track) => (1, track as u32),
-            DanmakuPosition::Bottom(track) => (2, track as u32),
-        };
-
-        let item_y = -item.item.layout_line.max_descent as i32;
-
-        let offset_x: i32 = glyph.x + glyph_item.placement.left;
-        let offset_y: i32 = glyph.y - glyph_item.placement.top + item_y;
-        let width: i32 = glyph_item.placement.width.try_into().unwrap();
-        let height: i32 = glyph_item.placement.height.try_into().unwrap();
-
-        let offset_top_left = [offset_x, offset_y];
-        let tex_coords_top_left = glyph_item.tex_coords;
+        shadow_width: u32,
+    ) -> Self {
+        let (placement, tex_coords, tex_size) = glyph_item.padded(shadow_width);
+        let (offset_x, offset_y, width, height) = item.item.glyph_rect(glyph, placement);
+        let width: i32 = width.try_into().unwrap();
+        let height: i32 = height.try_into().unwrap();
 
-        let offset_top_right = [offset_x + width, offset_y];
-        let tex_coords_top_right = (
-            glyph_item.tex_coords.0 + glyph_item.tex_size.0,
-            glyph_item.tex_coords.1,
+        let mut instance = Self::quad(
+            item,
+            [offset_x, offset_y],
+            [width, height],
+            tex_coords,
+            tex_size,
+            item.item.color,
+            0,
         );
+        if let Some(gradient) = item.item.gradient {
+            let [r, g, b] = color_to_float(gradient);
+            instance.gradient = [r, g, b, 1.0];
+        }
+        instance
+    }
 
-        let offset_bottom_left = [offset_x, offset_y + height];
-        let tex_coords_bottom_left = (
-            glyph_item.tex_coords.0,
-            glyph_item.tex_coords.1 + glyph_item.tex_size.1,
+    fn new_emote(
+        item: &PositionedDanmakuItem,
+        emote_item: &GlyphItem,
+        emote: &LayoutedEmote,
+    ) -> Self {
+        // Only sample the image itself, the padding is there for filtering
+        let padding = (emote_item.tex_size.0 - emote.image.width()) / 2;
+        let tex_coords = (
+            emote_item.tex_coords.0 + padding,
+            emote_item.tex_coords.1 + padding,
         );
+        let tex_size = (emote.image.width(), emote.image.height());
+        let (x, y, width, height) = item.item.emote_rect(emote);
+        let width: i32 = width.try_into().unwrap();
+        let height: i32 = height.try_into().unwrap();
 
-        let offset_bottom_right = [offset_x + width, offset_y + height];
-        let tex_coords_bottom_right = (
-            glyph_item.tex_coords.0 + glyph_item.tex_size.0,
-            glyph_item.tex_coords.1 + glyph_item.tex_size.1,
-        );
+        Self::quad(
+            item,
+            [x, y],
+            [width, height],
+            tex_coords,
+            tex_size,
+            item.item.color,
+            1,
+        )
+    }
 
-        let time = item.item.time.as_millis();
-        let line_width = item.item.width();
-        let color = color_to_srgb(item.item.color);
-        let top_left = Self {
-            time,
-            track_type,
-            track,
-            line_width,
-            offset: offset_top_left,
-            tex_coords: tex_coords_top_left.into(),
-            color,
-        };
-        let top_right = Self {
-            time,
-            track_type,
-            track,
-            line_width,
-            offset: offset_top_right,
-            tex_coords: tex_coords_top_right.into(),
-            color,
-        };
-        let bottom_left = Self {
-            time,
-            track_type,
-            track,
-            line_width,
-            offset: offset_bottom_left,
-            tex_coords: tex_coords_bottom_left.into(),
+    fn new_background(item: &PositionedDanmakuItem, color: DanmakuColor) -> Self {
+        let (x, y, width, height) = item.item.background_rect();
+        Self::quad(
+            item,
+            [x, y],
+            [width as i32, height as i32],
+            (0, 0),
+            (0, 0),
             color,
+            2,
+        )
+    }
+
+    fn new_border(item: &PositionedDanmakuItem) -> [Self; 4] {
+        let border: i32 = item.item.border_width().try_into().unwrap();
+        let (left, top, width, height) = item.item.background_rect();
+        let right = left + width as i32;
+        let bottom = top + height as i32;
+
+        let rectangle = |offset: [i32; 2], size: [i32; 2]| {
+            Self::quad(item, offset, size, (0, 0), (0, 0), DanmakuColor::BORDER, 2)
         };
-        let bottom_right = Self {
-            time,
-            track_type,
-            track,
-            line_width,
-            offset: offset_bottom_right,
-            tex_coords: tex_coords_bottom_right.into(),
-            color,
+        [
+            rectangle([left, top], [right - left, border]),
+            rectangle([left, bottom - border], [right - left, border]),
+            rectangle([left, top], [border, bottom - top]),
+            rectangle([right - border, top], [border, bottom - top]),
+        ]
+    }
+
+    fn quad(
+        item: &PositionedDanmakuItem,
+        offset: [i32; 2],
+        size: [i32; 2],
+        tex_coords: (u32, u32),
+        tex_size: (u32, u32),
+        color: DanmakuColor,
+        kind: u32,
+    ) -> Self {
+        let (track_type, track) = match item.position {
+            DanmakuPosition::Scroll(track) => (0, track as u32),
+            DanmakuPosition::Top(track) => (1, track as u32),
+            DanmakuPosition::Bottom(track) => (2, track as u32),
         };
-        [top_left, top_right, bottom_left, bottom_right]
+        let [r, g, b] = color_to_float(color);
+        let mut keyframe_times = [0; 4];
+        let mut keyframe_scales = [1.0; 4];
+        let mut keyframe_alphas = [1.0; 4];
+        let mut animation = 0;
+        if let Some(item_animation) = &item.item.animation {
+            let keyframes = item_animation.keyframes();
+            for (index, keyframe) in keyframes.iter().enumerate() {
+                keyframe_times[index] = keyframe.time;
+                keyframe_scales[index] = keyframe.scale;
+                keyframe_alphas[index] = keyframe.alpha;
+            }
+            animation = keyframes.len() as u32 | (item_animation.looped() as u32) << 31;
+        }
+        let (x, y, width, height) = item.item.background_rect();
+        Self {
+            time: item.item.time.as_millis() as u32,
+            track: [track_type, track],
+            line_width: item.item.width(),
+            offset,
+            size,
+            tex_coords: tex_coords.into(),
+            tex_size: tex_size.into(),
+            color: [r, g, b, item.item.opacity],
+            kind,
+            keyframe_times,
+            keyframe_scales,
+            keyframe_alphas,
+            animation,
+            center: [
+                x as f32 + width as f32 / 2.0,
+                y as f32 + height as f32 / 2.0,
+            ],
+            gradient: [0.0; 4],
+            contrast: color_to_float(ShadowColor::Contrast.color_for(color)),
+        }
     }
 }
 
@@ -142,7 +249,10 @@ pub struct VertexBuffer {
     index: u32,
     base_state_index: u32,
     glyphs: usize,
+    // Physical font size of the chunk
+    pub(crate) font_size: f32,
     pub(crate) vertex_buffer: Buffer,
+    pub(crate) spans: Vec<TrackSpan>,
 }
 
 impl VertexBuffer {
@@ -151,53 +261,70 @@ impl VertexBuffer {
         texture_manager: &GlyphTextureManager,
         device: &Device,
     ) -> Self {
-        let vertexs: Vec<Vertex> = chunk
+        let shadow_width = texture_manager.shadow_width();
+        let instances: Vec<Instance> = chunk
             .items
             .iter()
             .flat_map(|item| {
-                item.item
-                    .physical_glyphs
-                    .iter()
-                    .filter_map(|glyph| {
-                        let glyph_item = texture_manager.find(&glyph.cache_key)?;
-                        Some(Vertex::new(item, glyph_item, glyph))
-                    })
-                    .flatten()
+                // Drawn first, so it stays behind the text
+                let background = item
+                    .item
+                    .background
+                    .map(|color| Instance::new_background(item, color));
+                let glyphs = item.item.physical_glyphs.iter().filter_map(|glyph| {
+                    let glyph_item = texture_manager.find(&glyph.cache_key)?;
+                    Some(Instance::new(item, glyph_item, glyph, shadow_width))
+                });
+                let emotes = item.item.emotes.iter().filter_map(|emote| {
+                    let emote_item = texture_manager.find_emote(&emote.shortcode)?;
+                    Some(Instance::new_emote(item, emote_item, emote))
+                });
+                let border = item
+                    .item
+                    .bordered
+                    .then(|| Instance::new_border(item))
+                    .into_iter()
+                    .flatten();
+                background
+                    .into_iter()
+                    .chain(glyphs)
+                    .chain(emotes)
+                    .chain(border)
             })
             .collect();
-        assert_eq!(vertexs.len() % 4, 0);
-        let glyphs = vertexs.len() / 4;
+        let glyphs = instances.len();
         let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: Some(&format!(
-                "Vertex for chunk #{} (based on state ${})",
+                "Instances for chunk #{} (based on state ${})",
                 chunk.index, chunk.base_state_index
             )),
-            contents: bytemuck::cast_slice(&vertexs),
+            contents: bytemuck::cast_slice(&instances),
             usage: BufferUsages::VERTEX,
         });
         Self {
             index: chunk.index,
             glyphs,
             base_state_index: chunk.base_state_index,
+            font_size: chunk.font_size(),
             vertex_buffer,
+            spans: chunk.items.iter().map(TrackSpan::new).collect(),
         }
     }
 
     pub fn glyphs(&self) -> u32 {
         self.glyphs.try_into().unwrap()
     }
+
+    pub fn size(&self) -> u64 {
+        self.vertex_buffer.size()
+    }
 }
 
 impl ChunkBuffer<WgpuRenderCache> for VertexBuffer {
     fn new(chunk: &Arc<DanmakuTimeChunk>, cache: &mut WgpuRenderCache) -> Arc<Self> {
-        let vertex_buffer =
-            cache
-                .vertex_buffer_manager
-                .get(chunk, &cache.device, &mut cache.glyph_texture_manager);
         cache
-            .index_buffer
-            .ensure_size(&cache.device, vertex_buffer.glyphs());
-        vertex_buffer
+            .vertex_buffer_manager
+            .get(chunk, &cache.device, &mut cache.glyph_texture_manager)
     }
 
     fn index(&self) -> u32 {
@@ -207,8 +334,18 @@ impl ChunkBuffer<WgpuRenderCache> for VertexBuffer {
     fn base_state_index(&self) -> u32 {
         self.base_state_index
     }
+
+    fn visible_count(&self, now: DanmakuTime, param: &DanmakuParam) -> usize {
+        self.spans
+            .iter()
+            .filter(|span| span.is_visible(now, param))
+            .count()
+    }
 }
 
+// Previous, current and next chunks, and one being replaced
+const MIN_CACHED_BUFFERS: usize = 4;
+
 pub struct VertexBufferManager {
     buffer: LruCache<(u32, u32), Arc<VertexBuffer>>,
 }
@@ -226,6 +363,38 @@ impl VertexBufferManager {
         self.buffer.clear()
     }
 
+    pub fn memory_usage(&self) -> u64 {
+        self.buffer.iter().map(|(_, buffer)| buffer.size()).sum()
+    }
+
+    // Drops the least recently used buffers until the bytes are freed, and keeps the capacity at
+    // what is left. Buffers still used by the WorkerBuffer are only freed once it replaces them.
+    pub fn shrink(&mut self, bytes: u64) {
+        let mut freed = 0;
+        while freed < bytes && self.buffer.len() > MIN_CACHED_BUFFERS {
+            let Some((_, buffer)) = self.buffer.pop_lru() else {
+                break;
+            };
+            freed += buffer.size();
+        }
+        let capacity = self.buffer.len().max(MIN_CACHED_BUFFERS);
+        if capacity < self.buffer.cap().get() {
+            self.buffer.resize(NonZeroUsize::new(capacity).unwrap());
+        }
+    }
+
+    pub fn invalidate(&mut self, from_index: u32) {
+        let outdated: Vec<_> = self
+            .buffer
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|(_, index)| *index >= from_index)
+            .collect();
+        for key in outdated {
+            self.buffer.pop(&key);
+        }
+    }
+
     fn get(
         &mut self,
         chunk: &DanmakuTimeChunk,
@@ -0,0 +1,89 @@
+// Danmaku over the video of an existing winit and wgpu app. Call resize when the window is
+// resized or rescaled, tick with the playback time before every frame and draw in the render pass
+// of the video.
+use std::sync::Arc;
+
+use winit::window::Window;
+
+use crate::{
+    danmaku::{Danmaku, DanmakuTime},
+    pipeline::{DanmakuPipelineBuilder, WgpuPipeline},
+    worker::{DanmakuParam, WorkerError},
+};
+
+use super::{
+    wgpu::wgpu::{Device, Queue, RenderPass, SurfaceConfiguration},
+    RendererParam,
+};
+
+pub struct WinitOverlay {
+    pipeline: WgpuPipeline,
+    // As requested, with the screen of the window. The pipeline has the line height resolved.
+    param: DanmakuParam,
+}
+
+impl WinitOverlay {
+    // The screen of the param is taken from the window
+    pub fn new(
+        window: &Window,
+        builder: DanmakuPipelineBuilder,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        config: &SurfaceConfiguration,
+    ) -> Result<Self, WorkerError> {
+        let size = window.inner_size();
+        let builder = builder.screen((size.width, size.height), window.scale_factor() as f32);
+        let param = builder.param().clone();
+        let pipeline = builder.build_wgpu(device, queue, config)?;
+        Ok(WinitOverlay { pipeline, param })
+    }
+
+    // On WindowEvent::Resized and WindowEvent::ScaleFactorChanged, minimized windows are ignored
+    pub fn resize(&mut self, window: &Window) -> Result<(), WorkerError> {
+        let size = window.inner_size();
+        if size.width == 0 || size.height == 0 {
+            return Ok(());
+        }
+        let param = DanmakuParam {
+            screen_size: (size.width, size.height),
+            scale_factor: window.scale_factor() as f32,
+            ..self.param.clone()
+        };
+        self.set_param(param)
+    }
+
+    pub fn tick(&mut self, now: DanmakuTime) -> Result<(), WorkerError> {
+        self.pipeline.tick(now)
+    }
+
+    // Covers the whole render pass, use the pipeline for drawing into a part of it
+    pub fn draw(&self, render_pass: &mut RenderPass) {
+        self.pipeline.render(render_pass, None)
+    }
+
+    pub fn param(&self) -> &DanmakuParam {
+        &self.param
+    }
+
+    // Keep the screen of the current param, or call resize afterwards
+    pub fn set_param(&mut self, param: DanmakuParam) -> Result<(), WorkerError> {
+        if param == self.param {
+            return Ok(());
+        }
+        self.pipeline.set_param(param.clone())?;
+        self.param = param;
+        Ok(())
+    }
+
+    pub fn set_renderer_param(&mut self, renderer_param: RendererParam) {
+        self.pipeline.set_renderer_param(renderer_param)
+    }
+
+    pub fn insert_local(&mut self, danmaku: Danmaku) -> Result<(), WorkerError> {
+        self.pipeline.insert_local(danmaku)
+    }
+
+    pub fn pipeline(&mut self) -> &mut WgpuPipeline {
+        &mut self.pipeline
+    }
+}
@@ -0,0 +1,232 @@
+use std::{collections::HashMap, num::NonZeroUsize, thread};
+
+use cosmic_text::{
+    fontdb, AttrsList, CacheKey, Command, FontSystem, LayoutLine, Placement, ShapeBuffer,
+    ShapeLine, Shaping, SwashCache, SwashContent, Wrap,
+};
+
+use crate::renderer::disk_cache::{fnv1a, FNV_OFFSET};
+
+// Rasterized glyph, alpha mask or RGBA for color glyphs
+#[derive(Clone, Debug)]
+pub struct GlyphBitmap {
+    pub content: SwashContent,
+    pub placement: Placement,
+    pub data: Vec<u8>,
+}
+
+// Turns text into positioned glyphs and the glyphs into bitmaps. Glyphs are identified by
+// cosmic-text's CacheKey, shapers backed by other font stacks can register their faces in a
+// fontdb::Database to get font IDs.
+pub trait TextShaper: Send {
+    // Shapes a single line without wrapping, in logical pixels
+    fn shape_line(&mut self, text: &str, attrs: &AttrsList, font_size: f32) -> Option<LayoutLine>;
+
+    fn rasterize(&mut self, glyph: CacheKey) -> Option<GlyphBitmap>;
+
+    // Rasterizes the missing glyphs of a chunk, in the same order. Shapers which can share their
+    // fonts across threads may rasterize them in parallel.
+    fn rasterize_all(&mut self, glyphs: &[CacheKey]) -> Vec<Option<GlyphBitmap>> {
+        glyphs.iter().map(|glyph| self.rasterize(*glyph)).collect()
+    }
+
+    // Vector outline in physical pixels relative to the glyph origin, with y pointing up
+    fn outline(&mut self, _glyph: CacheKey) -> Option<Vec<Command>> {
+        None
+    }
+
+    // Identifies the font across sessions, glyphs of fonts without a key aren't cached on disk
+    fn font_key(&mut self, _font_id: fontdb::ID) -> Option<u64> {
+        None
+    }
+}
+
+// Glyphs for each thread, fewer are rasterized on the calling thread, as spawning the threads
+// costs more
+const PARALLEL_RASTERIZE_MIN: usize = 16;
+const MAX_RASTERIZE_THREADS: usize = 4;
+
+fn rasterize_with(
+    font_system: &mut FontSystem,
+    swash_cache: &mut SwashCache,
+    glyph: CacheKey,
+) -> Option<GlyphBitmap> {
+    swash_cache
+        .get_image_uncached(font_system, glyph)
+        .map(|image| GlyphBitmap {
+            content: image.content,
+            placement: image.placement,
+            data: image.data,
+        })
+}
+
+pub struct CosmicTextShaper {
+    font_system: FontSystem,
+    shape_buffer: ShapeBuffer,
+    swash_cache: SwashCache,
+    font_keys: HashMap<fontdb::ID, u64>,
+    // Font systems on copies of the database of the main one, which keep the font IDs, for the
+    // threads of rasterize_all. Each copy holds the faces of the database again, fonts loaded
+    // from memory included, so they are only created once a chunk has enough glyphs for them.
+    rasterizers: Vec<(FontSystem, SwashCache)>,
+}
+
+impl CosmicTextShaper {
+    pub fn new(font_system: FontSystem) -> Self {
+        CosmicTextShaper {
+            font_system,
+            shape_buffer: ShapeBuffer::default(),
+            swash_cache: SwashCache::new(),
+            font_keys: HashMap::new(),
+            rasterizers: Vec::new(),
+        }
+    }
+
+    // Fonts may be loaded into it, so the font systems of the rasterizing threads are created
+    // again
+    pub fn font_system(&mut self) -> &mut FontSystem {
+        self.rasterizers.clear();
+        &mut self.font_system
+    }
+}
+
+impl TextShaper for CosmicTextShaper {
+    fn shape_line(&mut self, text: &str, attrs: &AttrsList, font_size: f32) -> Option<LayoutLine> {
+        let shape_line = ShapeLine::new_in_buffer(
+            &mut self.shape_buffer,
+            &mut self.font_system,
+            text,
+            attrs,
+            Shaping::Advanced,
+            2,
+        );
+        let mut lines = Vec::new();
+        shape_line.layout_to_buffer(
+            &mut self.shape_buffer,
+            font_size,
+            None,
+            Wrap::None,
+            None,
+            &mut lines,
+            None,
+        );
+        lines.into_iter().next()
+    }
+
+    fn rasterize(&mut self, glyph: CacheKey) -> Option<GlyphBitmap> {
+        rasterize_with(&mut self.font_system, &mut self.swash_cache, glyph)
+    }
+
+    // Splits the glyphs between the calling thread and scoped threads with their own font systems
+    fn rasterize_all(&mut self, glyphs: &[CacheKey]) -> Vec<Option<GlyphBitmap>> {
+        let threads = thread::available_parallelism()
+            .map_or(1, NonZeroUsize::get)
+            .min(MAX_RASTERIZE_THREADS)
+            .min(glyphs.len() / PARALLEL_RASTERIZE_MIN);
+        if threads <= 1 {
+            return glyphs.iter().map(|glyph| self.rasterize(*glyph)).collect();
+        }
+        while self.rasterizers.len() + 1 < threads {
+            let font_system = FontSystem::new_with_locale_and_db(
+                self.font_system.locale().to_string(),
+                self.font_system.db().clone(),
+            );
+            self.rasterizers.push((font_system, SwashCache::new()));
+        }
+
+        let mut chunks = glyphs.chunks(glyphs.len().div_ceil(threads));
+        let own = chunks.next().unwrap();
+        let mut panicked = false;
+        let images = thread::scope(|scope| {
+            let handles: Vec<_> = chunks
+                .zip(&mut self.rasterizers)
+                .map(|(glyphs, (font_system, swash_cache))| {
+                    let handle = scope.spawn(move || {
+                        glyphs
+                            .iter()
+                            .map(|glyph| rasterize_with(font_system, swash_cache, *glyph))
+                            .collect::<Vec<_>>()
+                    });
+                    (glyphs, handle)
+                })
+                .collect();
+            let mut images: Vec<_> = own
+                .iter()
+                .map(|glyph| rasterize_with(&mut self.font_system, &mut self.swash_cache, *glyph))
+                .collect();
+            for (glyphs, handle) in handles {
+                match handle.join() {
+                    Ok(thread_images) => images.extend(thread_images),
+                    // The glyphs of a thread which panicked are rasterized here instead
+                    Err(_) => {
+                        panicked = true;
+                        images.extend(glyphs.iter().map(|glyph| {
+                            rasterize_with(&mut self.font_system, &mut self.swash_cache, *glyph)
+                        }));
+                    }
+                }
+            }
+            images
+        });
+        // The font system of the thread may be left half updated
+        if panicked {
+            self.rasterizers.clear();
+        }
+        images
+    }
+
+    fn outline(&mut self, glyph: CacheKey) -> Option<Vec<Command>> {
+        self.swash_cache
+            .get_outline_commands(&mut self.font_system, glyph)
+            .map(<[Command]>::to_vec)
+    }
+
+    // Hash of the font file
+    fn font_key(&mut self, font_id: fontdb::ID) -> Option<u64> {
+        if let Some(key) = self.font_keys.get(&font_id) {
+            return Some(*key);
+        }
+        let font = self.font_system.get_font(font_id)?;
+        let key = fnv1a(FNV_OFFSET, font.data());
+        self.font_keys.insert(font_id, key);
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cosmic_text::{Attrs, AttrsList, FontSystem};
+
+    use super::{CosmicTextShaper, GlyphBitmap, TextShaper, PARALLEL_RASTERIZE_MIN};
+
+    // The threads rasterize the same bitmaps as the calling thread, in order
+    #[test]
+    fn test_rasterize_all() {
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let line = shaper
+            .shape_line(
+                "The quick brown fox jumps over the lazy dog",
+                &AttrsList::new(Attrs::new()),
+                14.0,
+            )
+            .unwrap();
+        let glyphs: Vec<_> = line
+            .glyphs
+            .iter()
+            .map(|glyph| glyph.physical((0.0, 0.0), 1.0).cache_key)
+            .collect();
+        // Enough for two threads
+        assert!(glyphs.len() >= 2 * PARALLEL_RASTERIZE_MIN);
+
+        let images = shaper.rasterize_all(&glyphs);
+        assert_eq!(images.len(), glyphs.len());
+        for (glyph, image) in glyphs.iter().zip(images) {
+            let expected = shaper.rasterize(*glyph);
+            let bitmap = |image: GlyphBitmap| {
+                let placement = image.placement;
+                (placement.left, placement.top, placement.width, image.data)
+            };
+            assert_eq!(image.map(bitmap), expected.map(bitmap));
+        }
+    }
+}
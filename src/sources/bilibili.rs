@@ -1,18 +1,22 @@
 use core::str;
 use std::{
     cmp::Ordering,
+    collections::BTreeMap,
     error::Error,
     fmt::Display,
-    io::{BufRead, Cursor},
+    io::{self, BufRead, BufReader, Read, Write},
     num::{ParseFloatError, ParseIntError},
     path::Path,
     str::Utf8Error,
+    time::Duration,
 };
 
+use log::warn;
 use prost::Message;
 use quick_xml::{
-    events::{attributes::AttrError, Event},
+    events::{attributes::AttrError, BytesDecl, BytesEnd, BytesStart, BytesText, Event},
     reader::Reader,
+    writer::Writer,
 };
 
 use super::{DanmakuSource, VecDanmakuSource};
@@ -178,11 +182,12 @@ fn parse_xml<R: BufRead>(
                     let mut r#type: Option<DanmakuType> = None;
                     let mut size: Option<DanmakuSize> = None;
                     let mut color: Option<DanmakuColor> = None;
+                    let mut id: Option<u64> = None;
                     for (i, item) in attributes.split(',').enumerate() {
                         match i {
                             0 => {
                                 let seconds: f64 = item.parse()?;
-                                time = Some(DanmakuTime::from_millis((seconds * 1000.0) as u32));
+                                time = Some(DanmakuTime::from_millis((seconds * 1000.0) as i64));
                             }
                             1 => {
                                 let num: u32 = item.parse()?;
@@ -205,7 +210,13 @@ fn parse_xml<R: BufRead>(
                                 let code: u32 = item.parse()?;
                                 color = Some(DanmakuColor::from_code_cast(code));
                             }
-                            _ => break,
+                            // Send time, pool and user hash come before the dmid, zero is unknown
+                            7 => {
+                                let dmid: u64 = item.parse()?;
+                                id = Some(dmid).filter(|dmid| *dmid != 0);
+                            }
+                            8.. => break,
+                            _ => (),
                         }
                     }
                     let time = time.ok_or(BilibiliXmlParseError::BadAttribute)?;
@@ -218,6 +229,12 @@ fn parse_xml<R: BufRead>(
                         size,
                         r#type,
                         content: text,
+                        bordered: false,
+                        background: None,
+                        animation: None,
+                        gradient: None,
+                        id,
+                        group_id: None,
                     };
                     result.push(danmaku);
                 }
@@ -244,8 +261,46 @@ pub fn parse_xml_from_reader<R: BufRead>(
     parse_xml(reader)
 }
 
+// Writes the danmaku in the format of parse_xml. Fields which aren't kept in Danmaku (send
+// time, user hash...) and missing ids are written as zero, danmaku of unknown types are skipped.
+pub fn write_xml<S: DanmakuSource, W: Write>(
+    source: &mut S,
+    writer: W,
+) -> Result<(), quick_xml::Error> {
+    let mut writer = Writer::new(writer);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer.write_event(Event::Start(BytesStart::new("i")))?;
+    for danmaku in source.get_all() {
+        let mode = match danmaku.r#type {
+            DanmakuType::Scroll => 1,
+            DanmakuType::Bottom => 4,
+            DanmakuType::Top => 5,
+            DanmakuType::Unknown => continue,
+        };
+        let font_size = match danmaku.size {
+            DanmakuSize::Small => 18,
+            DanmakuSize::Regular => 25,
+            DanmakuSize::Large => 36,
+        };
+        let attributes = format!(
+            "{:.5},{},{},{},0,0,0,{}",
+            danmaku.time.as_millis() as f64 / 1000.0,
+            mode,
+            font_size,
+            danmaku.color.code(),
+            danmaku.id.unwrap_or(0)
+        );
+        writer
+            .create_element("d")
+            .with_attribute(("p", attributes.as_str()))
+            .write_text_content(BytesText::new(&danmaku.content))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("i")))?;
+    Ok(())
+}
+
 #[allow(clippy::all)]
-mod bilibili {
+pub(crate) mod bilibili {
     pub mod community {
         pub mod service {
             pub mod dm {
@@ -260,42 +315,313 @@ mod bilibili {
     }
 }
 
-use bilibili::community::service::dm::v1::DmSegMobileReply;
-
-pub fn parse_proto(buf: &[u8]) -> Result<impl DanmakuSource, Box<dyn Error>> {
-    let mut cursor = Cursor::new(buf);
-    let message = DmSegMobileReply::decode(&mut cursor)?;
-    let vec: Vec<Danmaku> = message
-        .elems
-        .into_iter()
-        .map(|item| Danmaku {
-            time: DanmakuTime::from_millis(item.progress.max(0) as u32),
-            r#type: match item.mode {
-                1..=3 => DanmakuType::Scroll,
-                4 => DanmakuType::Bottom,
-                5 => DanmakuType::Top,
-                _ => DanmakuType::Unknown,
-            },
-            size: match item.fontsize.cmp(&25) {
-                Ordering::Less => DanmakuSize::Small,
-                Ordering::Equal => DanmakuSize::Regular,
-                Ordering::Greater => DanmakuSize::Large,
-            },
-            color: DanmakuColor::from_code_cast(item.color),
-            content: item.content,
-        })
-        .collect();
-    Ok(VecDanmakuSource::new(vec))
+#[cfg(feature = "bilibili-client")]
+use bilibili::community::service::dm::v1::DmWebViewReply;
+use bilibili::community::service::dm::v1::{DanmakuElem, DmColorfulType};
+
+// The reply only links an image of the VIP gradient, these are the colors at its ends
+const VIP_GRADIENT: (u32, u32) = (0xFFAEE0, 0x8FD3FF);
+
+#[derive(Debug)]
+pub enum BilibiliProtoParseError {
+    ReadError(io::Error),
+    DecodeError(prost::DecodeError),
+    UnexpectedEof,
+    BadVarint,
+    BadWireType(u8),
+}
+
+impl Display for BilibiliProtoParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ReadError(err) => write!(f, "Failed to read protobuf: {}", err),
+            Self::DecodeError(err) => write!(f, "Failed to decode danmaku: {}", err),
+            Self::UnexpectedEof => write!(f, "Unexpected end of protobuf message"),
+            Self::BadVarint => write!(f, "Invalid varint"),
+            Self::BadWireType(wire_type) => write!(f, "Invalid wire type: {}", wire_type),
+        }
+    }
+}
+
+impl Error for BilibiliProtoParseError {}
+
+impl From<io::Error> for BilibiliProtoParseError {
+    fn from(value: io::Error) -> Self {
+        BilibiliProtoParseError::ReadError(value)
+    }
+}
+
+impl From<prost::DecodeError> for BilibiliProtoParseError {
+    fn from(value: prost::DecodeError) -> Self {
+        BilibiliProtoParseError::DecodeError(value)
+    }
+}
+
+// None at the end of the stream
+fn read_varint<R: Read>(reader: &mut R) -> Result<Option<u64>, BilibiliProtoParseError> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        let read = loop {
+            match reader.read(&mut byte) {
+                Ok(read) => break read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        };
+        match (read, shift) {
+            (0, 0) => return Ok(None),
+            (0, _) => return Err(BilibiliProtoParseError::UnexpectedEof),
+            _ => {}
+        }
+        let byte = byte[0];
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(BilibiliProtoParseError::BadVarint)
+}
+
+fn read_bytes<R: Read>(
+    reader: &mut R,
+    len: u64,
+    buf: &mut Vec<u8>,
+) -> Result<(), BilibiliProtoParseError> {
+    buf.clear();
+    if reader.take(len).read_to_end(buf)? as u64 != len {
+        return Err(BilibiliProtoParseError::UnexpectedEof);
+    }
+    Ok(())
+}
+
+// Decodes the fields of a DmSegMobileReply one by one, so only a single DanmakuElem is kept in
+// memory at a time
+fn read_segment<R: Read>(
+    reader: &mut R,
+    result: &mut Vec<Danmaku>,
+) -> Result<(), BilibiliProtoParseError> {
+    let mut buf = Vec::new();
+    while let Some(key) = read_varint(reader)? {
+        let field = key >> 3;
+        let len = match key & 0x7 {
+            0 => {
+                read_varint(reader)?.ok_or(BilibiliProtoParseError::UnexpectedEof)?;
+                continue;
+            }
+            1 => 8,
+            2 => read_varint(reader)?.ok_or(BilibiliProtoParseError::UnexpectedEof)?,
+            5 => 4,
+            wire_type => return Err(BilibiliProtoParseError::BadWireType(wire_type as u8)),
+        };
+        read_bytes(reader, len, &mut buf)?;
+        // Field 1 is the repeated elems, other fields are metadata
+        if field == 1 && key & 0x7 == 2 {
+            let item = DanmakuElem::decode(buf.as_slice())?;
+            result.push(convert_elem(item));
+        }
+    }
+    Ok(())
+}
+
+fn convert_elem(item: DanmakuElem) -> Danmaku {
+    let vip_gradient = item.colorful() == DmColorfulType::VipGradualColor;
+    let color = match vip_gradient {
+        true => DanmakuColor::from_code(VIP_GRADIENT.0),
+        false => DanmakuColor::from_code_cast(item.color),
+    };
+    Danmaku {
+        time: DanmakuTime::from_millis(item.progress.into()),
+        r#type: match item.mode {
+            1..=3 => DanmakuType::Scroll,
+            4 => DanmakuType::Bottom,
+            5 => DanmakuType::Top,
+            _ => DanmakuType::Unknown,
+        },
+        size: match item.fontsize.cmp(&25) {
+            Ordering::Less => DanmakuSize::Small,
+            Ordering::Equal => DanmakuSize::Regular,
+            Ordering::Greater => DanmakuSize::Large,
+        },
+        color,
+        content: item.content,
+        bordered: false,
+        background: None,
+        animation: None,
+        gradient: vip_gradient.then(|| DanmakuColor::from_code(VIP_GRADIENT.1)),
+        id: u64::try_from(item.id).ok().filter(|id| *id != 0),
+        group_id: None,
+    }
+}
+
+pub fn parse_proto(buf: &[u8]) -> Result<impl DanmakuSource, BilibiliProtoParseError> {
+    parse_proto_segment(buf)
+}
+
+pub(crate) fn parse_proto_segment(
+    mut buf: &[u8],
+) -> Result<VecDanmakuSource, BilibiliProtoParseError> {
+    let mut result = Vec::new();
+    read_segment(&mut buf, &mut result)?;
+    Ok(VecDanmakuSource::new(result))
+}
+
+// Number of protobuf segments in a DmWebViewReply, None when the reply doesn't tell
+#[cfg(feature = "bilibili-client")]
+pub(crate) fn parse_segment_count(buf: &[u8]) -> Result<Option<u32>, BilibiliProtoParseError> {
+    let reply = DmWebViewReply::decode(buf)?;
+    Ok(reply
+        .dm_sge
+        .and_then(|config| u32::try_from(config.total).ok())
+        .filter(|total| *total > 0))
+}
+
+// Reads a single DmSegMobileReply, e.g. the body of a network response
+pub fn parse_proto_from_reader<R: Read>(
+    reader: R,
+) -> Result<impl DanmakuSource, BilibiliProtoParseError> {
+    let mut reader = BufReader::new(reader);
+    let mut result = Vec::new();
+    read_segment(&mut reader, &mut result)?;
+    Ok(VecDanmakuSource::new(result))
+}
+
+// Reads DmSegMobileReply messages which are each prefixed with their length as a varint, e.g.
+// several segments saved into one file
+pub fn parse_proto_delimited_from_reader<R: Read>(
+    reader: R,
+) -> Result<impl DanmakuSource, BilibiliProtoParseError> {
+    let mut reader = BufReader::new(reader);
+    let mut result = Vec::new();
+    while let Some(len) = read_varint(&mut reader)? {
+        let mut message = (&mut reader).take(len);
+        read_segment(&mut message, &mut result)?;
+        if message.limit() != 0 {
+            return Err(BilibiliProtoParseError::UnexpectedEof);
+        }
+    }
+    // The same segment may have been saved more than once
+    Ok(VecDanmakuSource::new(result).dedup_by_id())
+}
+
+// Length of a protobuf segment served by Bilibili
+pub const SEGMENT_DURATION: Duration = Duration::from_secs(360);
+
+// Fetches the protobuf segments lazily when get_range reaches them, so only the watched part of
+// the video is downloaded. Segment indices start from 1, like the segment_index parameter of
+// the Bilibili API. The callback returns an empty buffer for segments without danmaku.
+pub struct SegmentedDanmakuSource<Fetch>
+where
+    Fetch: FnMut(u32) -> Vec<u8>,
+{
+    fetch: Fetch,
+    segment_duration: Duration,
+    segments: BTreeMap<u32, VecDanmakuSource>,
+}
+
+impl<Fetch> SegmentedDanmakuSource<Fetch>
+where
+    Fetch: FnMut(u32) -> Vec<u8>,
+{
+    pub fn new(fetch: Fetch) -> Self {
+        SegmentedDanmakuSource {
+            fetch,
+            segment_duration: SEGMENT_DURATION,
+            segments: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_segment_duration(mut self, segment_duration: Duration) -> Self {
+        assert!(!segment_duration.is_zero());
+        self.segment_duration = segment_duration;
+        self
+    }
+
+    fn segment_index(&self, time: DanmakuTime) -> u32 {
+        let index = time.as_millis().max(0) / self.segment_duration.as_millis() as i64;
+        u32::try_from(index + 1).unwrap_or(u32::MAX)
+    }
+
+    fn load_segment(&mut self, index: u32) {
+        if self.segments.contains_key(&index) {
+            return;
+        }
+        let buf = (self.fetch)(index);
+        let segment = if buf.is_empty() {
+            VecDanmakuSource::new(Vec::new())
+        } else {
+            parse_proto_segment(&buf).unwrap_or_else(|err| {
+                warn!("Failed to decode danmaku segment #{}: {}", index, err);
+                VecDanmakuSource::new(Vec::new())
+            })
+        };
+        self.segments.insert(index, segment);
+    }
+
+    pub fn loaded_segments(&self) -> impl Iterator<Item = u32> + '_ {
+        self.segments.keys().copied()
+    }
+}
+
+impl<Fetch> DanmakuSource for SegmentedDanmakuSource<Fetch>
+where
+    Fetch: FnMut(u32) -> Vec<u8>,
+{
+    fn get_range(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        if end_excluded <= start_included || end_excluded <= DanmakuTime::from_millis(0) {
+            return Box::new(std::iter::empty());
+        }
+        let first = self.segment_index(start_included);
+        let last = self.segment_index(DanmakuTime::from_millis(end_excluded.as_millis() - 1));
+        for index in first..=last {
+            self.load_segment(index);
+        }
+        Box::new(
+            self.segments
+                .range(first..=last)
+                .flat_map(move |(_, segment)| segment.range(start_included, end_excluded)),
+        )
+    }
+
+    // Only includes the segments loaded so far
+    fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        Box::new(
+            self.segments
+                .values_mut()
+                .flat_map(|segment| segment.get_all()),
+        )
+    }
+
+    fn into_all(self) -> Box<dyn Iterator<Item = Danmaku>> {
+        Box::new(
+            self.segments
+                .into_values()
+                .flat_map(|segment| segment.into_all()),
+        )
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::{fs::File, io::Read, path::Path};
 
+    use prost::Message;
+
     use crate::{
         danmaku::{DanmakuColor, DanmakuSize, DanmakuTime, DanmakuType},
         sources::{
-            bilibili::{parse_proto, parse_xml_from_file},
+            bilibili::{
+                bilibili::community::service::dm::v1::{
+                    DanmakuElem, DmColorfulType, DmSegMobileReply,
+                },
+                parse_proto, parse_proto_delimited_from_reader, parse_proto_from_reader,
+                parse_xml_from_file, parse_xml_from_reader, write_xml, SegmentedDanmakuSource,
+                VIP_GRADIENT,
+            },
             DanmakuSource,
         },
     };
@@ -313,18 +639,42 @@ mod test {
         assert_eq!(item.size, DanmakuSize::Regular);
         assert_eq!(item.color, DanmakuColor::from_code(0xFFFFFF));
         assert_eq!(item.content, "kksk");
+        assert_eq!(item.id, Some(1076032696272197632));
 
         let item = iter.next().unwrap();
         assert_eq!(item.time, DanmakuTime::from_millis(83679));
+        assert_eq!(item.id, Some(1086321520068004096));
         assert_eq!(item.r#type, DanmakuType::Scroll);
         assert_eq!(item.size, DanmakuSize::Regular);
         assert_eq!(item.color, DanmakuColor::from_code(0xFFFFFF));
         assert_eq!(item.content, "喜欢这段的吉他");
+        assert_eq!(item.id, Some(1086321520068004096));
 
         let item = iter.next();
         assert!(item.is_none());
     }
 
+    #[test]
+    fn test_write_xml() {
+        let path = Path::new("test/747529524.xml");
+        let mut source = parse_xml_from_file(path).unwrap();
+        let mut buf = Vec::new();
+        write_xml(&mut source, &mut buf).unwrap();
+
+        let mut written = parse_xml_from_reader(buf.as_slice()).unwrap();
+        let original: Vec<_> = source.get_all().collect();
+        let written: Vec<_> = written.get_all().collect();
+        assert_eq!(original.len(), written.len());
+        for (original, written) in original.iter().zip(written) {
+            assert_eq!(original.time, written.time);
+            assert_eq!(original.r#type, written.r#type);
+            assert_eq!(original.size, written.size);
+            assert_eq!(original.color, written.color);
+            assert_eq!(original.content, written.content);
+            assert_eq!(original.id, written.id);
+        }
+    }
+
     #[test]
     fn test_read_large_xml() {
         let path = Path::new("test/1176840.xml");
@@ -357,6 +707,91 @@ mod test {
         assert!(item.is_none());
     }
 
+    #[test]
+    fn test_gradient_protobuf() {
+        let elem = |colorful| DanmakuElem {
+            mode: 1,
+            fontsize: 25,
+            color: 0xFFFFFF,
+            colorful,
+            ..Default::default()
+        };
+        let reply = DmSegMobileReply {
+            elems: vec![elem(DmColorfulType::VipGradualColor as i32), elem(0)],
+            ..Default::default()
+        };
+        let content = reply.encode_to_vec();
+        let mut source = parse_proto(&content).unwrap();
+        let items: Vec<_> = source.get_all().collect();
+        assert_eq!(items[0].color, DanmakuColor::from_code(VIP_GRADIENT.0));
+        assert_eq!(
+            items[0].gradient,
+            Some(DanmakuColor::from_code(VIP_GRADIENT.1))
+        );
+        assert_eq!(items[1].color, DanmakuColor::from_code(0xFFFFFF));
+        assert_eq!(items[1].gradient, None);
+    }
+
+    #[test]
+    fn test_segmented_protobuf() {
+        let mut file = File::open("test/747529524.bin").unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        let mut fetched = Vec::new();
+        let mut source = SegmentedDanmakuSource::new(|index| {
+            fetched.push(index);
+            if index == 1 {
+                content.clone()
+            } else {
+                Vec::new()
+            }
+        });
+
+        let range = |source: &mut SegmentedDanmakuSource<_>, start, end| {
+            source
+                .get_range(
+                    DanmakuTime::from_millis(start),
+                    DanmakuTime::from_millis(end),
+                )
+                .count()
+        };
+        assert_eq!(range(&mut source, 0, 60_000), 0);
+        assert_eq!(range(&mut source, 80_000, 90_000), 1);
+        assert_eq!(range(&mut source, 300_000, 400_000), 0);
+        assert_eq!(source.loaded_segments().collect::<Vec<_>>(), vec![1, 2]);
+        drop(source);
+        assert_eq!(fetched, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_read_protobuf_from_reader() {
+        let mut content = Vec::new();
+        File::open("test/1176840.bin")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        let expected = parse_proto(&content).unwrap().get_all().count();
+
+        let file = File::open("test/1176840.bin").unwrap();
+        let mut source = parse_proto_from_reader(file).unwrap();
+        assert_eq!(source.get_all().count(), expected);
+
+        let mut delimited = Vec::new();
+        for _ in 0..2 {
+            prost::encoding::encode_varint(content.len() as u64, &mut delimited);
+            delimited.extend_from_slice(&content);
+        }
+        let count = parse_proto_delimited_from_reader(delimited.as_slice())
+            .unwrap()
+            .get_all()
+            .count();
+        // Danmaku of segments saved twice are only kept once
+        assert_eq!(count, expected);
+
+        delimited.pop();
+        assert!(parse_proto_delimited_from_reader(delimited.as_slice()).is_err());
+    }
+
     #[test]
     fn test_read_large_protobuf() {
         let mut file = File::open("test/1176840.bin").unwrap();
@@ -0,0 +1,292 @@
+// Fetches the danmaku of a video from the Bilibili API. The HTTP requests are made by the client
+// of the app, so this works with any async runtime.
+use std::{error::Error, fmt::Display, future::Future, io::Cursor, sync::Arc};
+
+use futures::executor::block_on;
+use log::warn;
+
+use super::{
+    bilibili::{
+        parse_proto_segment, parse_segment_count, parse_xml_from_reader, BilibiliProtoParseError,
+        BilibiliXmlParseError, SegmentedDanmakuSource,
+    },
+    DanmakuSource, VecDanmakuSource,
+};
+
+pub const API_BASE_URL: &str = "https://api.bilibili.com";
+
+pub type HttpError = Box<dyn Error + Send + Sync>;
+
+// The HTTP client of the app, e.g. reqwest. Non-success statuses are errors, and the body must
+// have its Content-Encoding decoded, as the XML API deflates every response.
+pub trait HttpGet: Send + Sync {
+    fn get(&self, url: &str) -> impl Future<Output = Result<Vec<u8>, HttpError>> + Send;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BilibiliVideo {
+    pub aid: u64,
+    pub cid: u64,
+}
+
+#[derive(Debug)]
+pub enum BilibiliClientError {
+    Http(HttpError),
+    Xml(BilibiliXmlParseError),
+    Proto(BilibiliProtoParseError),
+}
+
+impl Display for BilibiliClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BilibiliClientError::Http(err) => write!(f, "Request failed: {}", err),
+            BilibiliClientError::Xml(err) => write!(f, "Failed to parse the XML danmaku: {}", err),
+            BilibiliClientError::Proto(err) => {
+                write!(f, "Failed to parse the protobuf danmaku: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for BilibiliClientError {}
+
+impl From<BilibiliXmlParseError> for BilibiliClientError {
+    fn from(err: BilibiliXmlParseError) -> Self {
+        BilibiliClientError::Xml(err)
+    }
+}
+
+impl From<BilibiliProtoParseError> for BilibiliClientError {
+    fn from(err: BilibiliProtoParseError) -> Self {
+        BilibiliClientError::Proto(err)
+    }
+}
+
+pub struct BilibiliClient<Http: HttpGet> {
+    http: Arc<Http>,
+    base_url: String,
+}
+
+impl<Http: HttpGet + 'static> BilibiliClient<Http> {
+    pub fn new(http: Http) -> Self {
+        BilibiliClient {
+            http: Arc::new(http),
+            base_url: API_BASE_URL.to_string(),
+        }
+    }
+
+    // E.g. for a proxy, without the trailing slash
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    async fn get(&self, url: &str) -> Result<Vec<u8>, BilibiliClientError> {
+        self.http.get(url).await.map_err(BilibiliClientError::Http)
+    }
+
+    // The XML API only has a sample of the danmaku of long videos, fetch the segments for all
+    pub async fn fetch_xml(&self, cid: u64) -> Result<impl DanmakuSource, BilibiliClientError> {
+        let body = self
+            .get(&format!("{}/x/v1/dm/list.so?oid={}", self.base_url, cid))
+            .await?;
+        Ok(parse_xml_from_reader(Cursor::new(body))?)
+    }
+
+    // None when the video doesn't tell, e.g. before its danmaku were segmented
+    pub async fn segment_count(
+        &self,
+        video: BilibiliVideo,
+    ) -> Result<Option<u32>, BilibiliClientError> {
+        let url = format!(
+            "{}/x/v2/dm/web/view?type=1&oid={}&pid={}",
+            self.base_url, video.cid, video.aid
+        );
+        Ok(parse_segment_count(&self.get(&url).await?)?)
+    }
+
+    // Segment indices start from 1, see SegmentedDanmakuSource
+    pub async fn fetch_segment(
+        &self,
+        video: BilibiliVideo,
+        index: u32,
+    ) -> Result<impl DanmakuSource, BilibiliClientError> {
+        let body = self.get(&segment_url(&self.base_url, video, index)).await?;
+        Ok(parse_proto_segment(&body)?)
+    }
+
+    // Every segment one after another. Without a segment count, segments are fetched until an
+    // empty one.
+    pub async fn fetch_all(
+        &self,
+        video: BilibiliVideo,
+    ) -> Result<impl DanmakuSource, BilibiliClientError> {
+        let count = self.segment_count(video).await?;
+        let mut danmaku = Vec::new();
+        for index in 1..=count.unwrap_or(u32::MAX) {
+            let body = self.get(&segment_url(&self.base_url, video, index)).await?;
+            if count.is_none() && body.is_empty() {
+                break;
+            }
+            danmaku.extend(parse_proto_segment(&body)?.into_all());
+        }
+        Ok(VecDanmakuSource::new(danmaku).dedup_by_id())
+    }
+
+    // Fetches the segments when the worker reaches them, blocking the worker thread meanwhile.
+    // Failed requests are logged and the segment is left empty.
+    pub fn segmented(
+        &self,
+        video: BilibiliVideo,
+    ) -> SegmentedDanmakuSource<impl FnMut(u32) -> Vec<u8> + Send> {
+        let http = self.http.clone();
+        let base_url = self.base_url.clone();
+        SegmentedDanmakuSource::new(move |index| {
+            block_on(http.get(&segment_url(&base_url, video, index))).unwrap_or_else(|err| {
+                warn!("Failed to fetch danmaku segment #{}: {}", index, err);
+                Vec::new()
+            })
+        })
+    }
+}
+
+fn segment_url(base_url: &str, video: BilibiliVideo, index: u32) -> String {
+    format!(
+        "{}/x/v2/dm/web/seg.so?type=1&oid={}&pid={}&segment_index={}",
+        base_url, video.cid, video.aid, index
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        future::{ready, Future},
+        sync::Mutex,
+    };
+
+    use futures::executor::block_on;
+    use prost::Message;
+
+    use crate::{
+        danmaku::DanmakuTime,
+        sources::{
+            bilibili::bilibili::community::service::dm::v1::{
+                DanmakuElem, DmSegConfig, DmSegMobileReply, DmWebViewReply,
+            },
+            DanmakuSource,
+        },
+    };
+
+    use super::{BilibiliClient, BilibiliClientError, BilibiliVideo, HttpError, HttpGet};
+
+    // Serves fixed bodies by URL and records the requests
+    #[derive(Default)]
+    struct FakeHttp {
+        bodies: HashMap<String, Vec<u8>>,
+        requests: Mutex<Vec<String>>,
+    }
+
+    impl HttpGet for FakeHttp {
+        fn get(&self, url: &str) -> impl Future<Output = Result<Vec<u8>, HttpError>> + Send {
+            self.requests.lock().unwrap().push(url.to_string());
+            ready(match self.bodies.get(url) {
+                Some(body) => Ok(body.clone()),
+                None => Err(format!("404 {}", url).into()),
+            })
+        }
+    }
+
+    const VIDEO: BilibiliVideo = BilibiliVideo { aid: 2, cid: 1 };
+
+    fn segment(elems: &[(i64, i32)]) -> Vec<u8> {
+        let elems = elems
+            .iter()
+            .map(|(id, progress)| DanmakuElem {
+                id: *id,
+                progress: *progress,
+                mode: 1,
+                fontsize: 25,
+                content: format!("#{}", id),
+                ..Default::default()
+            })
+            .collect();
+        DmSegMobileReply {
+            elems,
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+
+    fn fake_http(total: Option<i64>) -> FakeHttp {
+        let view = DmWebViewReply {
+            dm_sge: total.map(|total| DmSegConfig {
+                page_size: 360_000,
+                total,
+            }),
+            ..Default::default()
+        };
+        let seg = |index| {
+            format!("http://test/x/v2/dm/web/seg.so?type=1&oid=1&pid=2&segment_index={index}")
+        };
+        let mut http = FakeHttp::default();
+        http.bodies.insert(
+            "http://test/x/v2/dm/web/view?type=1&oid=1&pid=2".to_string(),
+            view.encode_to_vec(),
+        );
+        http.bodies.insert(seg(1), segment(&[(1, 1000), (2, 2000)]));
+        // The segments may overlap
+        http.bodies
+            .insert(seg(2), segment(&[(2, 2000), (3, 400_000)]));
+        http.bodies.insert(seg(3), Vec::new());
+        http
+    }
+
+    fn ids(source: &mut impl DanmakuSource) -> Vec<u64> {
+        source
+            .get_all()
+            .map(|danmaku| danmaku.id.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_fetch_all() {
+        let client = BilibiliClient::new(fake_http(Some(2))).with_base_url("http://test");
+        let mut source = block_on(client.fetch_all(VIDEO)).unwrap();
+        assert_eq!(ids(&mut source), vec![1, 2, 3]);
+        assert_eq!(client.http.requests.lock().unwrap().len(), 3);
+
+        // Without a count, up to the first empty segment
+        let client = BilibiliClient::new(fake_http(None)).with_base_url("http://test");
+        let mut source = block_on(client.fetch_all(VIDEO)).unwrap();
+        assert_eq!(ids(&mut source), vec![1, 2, 3]);
+        assert_eq!(client.http.requests.lock().unwrap().len(), 4);
+
+        let client = BilibiliClient::new(FakeHttp::default()).with_base_url("http://test");
+        assert!(matches!(
+            block_on(client.fetch_all(VIDEO)),
+            Err(BilibiliClientError::Http(_))
+        ));
+    }
+
+    #[test]
+    fn test_segmented() {
+        let mut http = fake_http(Some(2));
+        http.bodies.insert(
+            "http://test/x/v1/dm/list.so?oid=1".to_string(),
+            br#"<i><d p="1.5,1,25,16777215,0,0,0,7">xml</d></i>"#.to_vec(),
+        );
+        let client = BilibiliClient::new(http).with_base_url("http://test");
+        let mut source = block_on(client.fetch_xml(VIDEO.cid)).unwrap();
+        assert_eq!(ids(&mut source), vec![7]);
+
+        let mut source = client.segmented(VIDEO);
+        let range = source.get_range(DanmakuTime::from_millis(0), DanmakuTime::from_millis(5000));
+        assert_eq!(range.count(), 2);
+        assert_eq!(source.loaded_segments().collect::<Vec<_>>(), vec![1]);
+        // Missing segments are empty
+        let start = DanmakuTime::from_millis(360_000 * 5);
+        let end = DanmakuTime::from_millis(360_000 * 6);
+        assert_eq!(source.get_range(start, end).count(), 0);
+    }
+}
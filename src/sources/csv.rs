@@ -0,0 +1,328 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    io::{self, Read, Write},
+};
+
+use super::{DanmakuSource, VecDanmakuSource};
+
+use crate::danmaku::{Danmaku, DanmakuColor, DanmakuSize, DanmakuTime, DanmakuType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsvTimeUnit {
+    // Fractions are allowed, e.g. 12.5
+    Seconds,
+    Millis,
+}
+
+// Where the fields of the danmaku are. Columns are zero based, missing type and color columns
+// default to white scrolling danmaku.
+#[derive(Clone, Debug)]
+pub struct CsvFormat {
+    pub delimiter: char,
+    pub has_header: bool,
+    pub time_column: usize,
+    pub time_unit: CsvTimeUnit,
+    pub type_column: Option<usize>,
+    pub color_column: Option<usize>,
+    pub content_column: usize,
+}
+
+impl CsvFormat {
+    // time,type,color,content in seconds, with a header
+    pub fn csv() -> Self {
+        CsvFormat {
+            delimiter: ',',
+            has_header: true,
+            time_column: 0,
+            time_unit: CsvTimeUnit::Seconds,
+            type_column: Some(1),
+            color_column: Some(2),
+            content_column: 3,
+        }
+    }
+
+    pub fn tsv() -> Self {
+        CsvFormat {
+            delimiter: '\t',
+            ..Self::csv()
+        }
+    }
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        Self::csv()
+    }
+}
+
+#[derive(Debug)]
+pub enum CsvParseError {
+    IoError(io::Error),
+    // The input ends inside a quoted field
+    UnterminatedQuote,
+    // Index of the malformed record, not counting the header
+    BadRecord(usize),
+}
+
+impl Display for CsvParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(err) => write!(f, "Failed to read CSV: {}", err),
+            Self::UnterminatedQuote => write!(f, "Unterminated quoted field"),
+            Self::BadRecord(index) => write!(f, "Bad danmaku record #{}", index),
+        }
+    }
+}
+
+impl Error for CsvParseError {}
+
+impl From<io::Error> for CsvParseError {
+    fn from(value: io::Error) -> Self {
+        CsvParseError::IoError(value)
+    }
+}
+
+// RFC 4180 records: quoted fields may contain the delimiter, line breaks and doubled quotes
+fn split_records(text: &str, delimiter: char) -> Result<Vec<Vec<String>>, CsvParseError> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(char) = chars.next() {
+        if quoted {
+            match char {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                char => field.push(char),
+            }
+            continue;
+        }
+        match char {
+            '"' if field.is_empty() => quoted = true,
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            char if char == delimiter => record.push(std::mem::take(&mut field)),
+            char => field.push(char),
+        }
+    }
+    if quoted {
+        return Err(CsvParseError::UnterminatedQuote);
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    // Blank lines carry no danmaku
+    records.retain(|record| !(record.len() == 1 && record[0].is_empty()));
+    Ok(records)
+}
+
+// Bilibili modes or names
+fn parse_type(value: &str) -> Option<DanmakuType> {
+    let value = value.trim();
+    if let Ok(mode) = value.parse::<u32>() {
+        return Some(match mode {
+            1..=3 => DanmakuType::Scroll,
+            4 => DanmakuType::Bottom,
+            5 => DanmakuType::Top,
+            _ => DanmakuType::Unknown,
+        });
+    }
+    match value.to_ascii_lowercase().as_str() {
+        "scroll" => Some(DanmakuType::Scroll),
+        "top" => Some(DanmakuType::Top),
+        "bottom" => Some(DanmakuType::Bottom),
+        _ => None,
+    }
+}
+
+// Decimal or #RRGGBB
+fn parse_color(value: &str) -> Option<DanmakuColor> {
+    let value = value.trim();
+    let code = match value.strip_prefix('#') {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => value.parse().ok()?,
+    };
+    Some(DanmakuColor::from_code_cast(code))
+}
+
+fn parse_record(record: &[String], format: &CsvFormat) -> Option<Danmaku> {
+    let time = record.get(format.time_column)?.trim();
+    let time = match format.time_unit {
+        CsvTimeUnit::Seconds => (time.parse::<f64>().ok()? * 1000.0).round() as i64,
+        CsvTimeUnit::Millis => time.parse().ok()?,
+    };
+    let r#type = match format.type_column {
+        Some(column) => parse_type(record.get(column)?)?,
+        None => DanmakuType::Scroll,
+    };
+    let color = match format.color_column {
+        Some(column) => parse_color(record.get(column)?)?,
+        None => DanmakuColor::from_code(0xFFFFFF),
+    };
+    Some(Danmaku {
+        time: DanmakuTime::from_millis(time),
+        r#type,
+        size: DanmakuSize::Regular,
+        color,
+        content: record.get(format.content_column)?.clone(),
+        bordered: false,
+        background: None,
+        animation: None,
+        gradient: None,
+        id: None,
+        group_id: None,
+    })
+}
+
+pub fn parse_csv(text: &str, format: &CsvFormat) -> Result<impl DanmakuSource, CsvParseError> {
+    let records = split_records(text, format.delimiter)?;
+    let skip = if format.has_header { 1 } else { 0 };
+    let vec = records
+        .iter()
+        .skip(skip)
+        .enumerate()
+        .map(|(index, record)| parse_record(record, format).ok_or(CsvParseError::BadRecord(index)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(VecDanmakuSource::new(vec))
+}
+
+pub fn parse_csv_from_reader<R: Read>(
+    mut reader: R,
+    format: &CsvFormat,
+) -> Result<impl DanmakuSource, CsvParseError> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+    parse_csv(&text, format)
+}
+
+fn write_field<W: Write>(writer: &mut W, field: &str, delimiter: char) -> io::Result<()> {
+    let needs_quote = field.contains([delimiter, '"', '\n', '\r']);
+    if needs_quote {
+        write!(writer, "\"{}\"", field.replace('"', "\"\""))
+    } else {
+        write!(writer, "{}", field)
+    }
+}
+
+// Writes the columns of the format, so parse_csv reads the danmaku back. Types are written as
+// Bilibili modes, danmaku of unknown types are skipped.
+pub fn write_csv<S: DanmakuSource, W: Write>(
+    source: &mut S,
+    mut writer: W,
+    format: &CsvFormat,
+) -> io::Result<()> {
+    let columns = [
+        Some(format.time_column),
+        format.type_column,
+        format.color_column,
+        Some(format.content_column),
+    ]
+    .into_iter()
+    .flatten()
+    .max()
+    .unwrap_or(0)
+        + 1;
+    let mut record = vec![String::new(); columns];
+    let write_record = |writer: &mut W, record: &[String]| {
+        for (index, field) in record.iter().enumerate() {
+            if index > 0 {
+                write!(writer, "{}", format.delimiter)?;
+            }
+            write_field(writer, field, format.delimiter)?;
+        }
+        writer.write_all(b"\r\n")
+    };
+
+    if format.has_header {
+        record[format.time_column] = "time".to_string();
+        if let Some(column) = format.type_column {
+            record[column] = "type".to_string();
+        }
+        if let Some(column) = format.color_column {
+            record[column] = "color".to_string();
+        }
+        record[format.content_column] = "content".to_string();
+        write_record(&mut writer, &record)?;
+    }
+    for danmaku in source.get_all() {
+        let mode = match danmaku.r#type {
+            DanmakuType::Scroll => 1,
+            DanmakuType::Bottom => 4,
+            DanmakuType::Top => 5,
+            DanmakuType::Unknown => continue,
+        };
+        record.iter_mut().for_each(String::clear);
+        let millis = danmaku.time.as_millis();
+        record[format.time_column] = match format.time_unit {
+            CsvTimeUnit::Seconds => format!("{}", millis as f64 / 1000.0),
+            CsvTimeUnit::Millis => millis.to_string(),
+        };
+        if let Some(column) = format.type_column {
+            record[column] = mode.to_string();
+        }
+        if let Some(column) = format.color_column {
+            record[column] = danmaku.color.code().to_string();
+        }
+        record[format.content_column] = danmaku.content.clone();
+        write_record(&mut writer, &record)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        danmaku::{DanmakuColor, DanmakuTime, DanmakuType},
+        sources::DanmakuSource,
+    };
+
+    use super::{parse_csv, write_csv, CsvFormat, CsvTimeUnit};
+
+    #[test]
+    fn test_read_write_csv() {
+        let content = "time,type,color,content\n\
+            12.5,1,16777215,kksk\r\n\
+            3.25,top,#0000FF,\"a, \"\"quoted\"\"\nline\"\n";
+        let mut source = parse_csv(content, &CsvFormat::csv()).unwrap();
+        let mut buf = Vec::new();
+        write_csv(&mut source, &mut buf, &CsvFormat::tsv()).unwrap();
+        let written = String::from_utf8(buf).unwrap();
+        let mut written = parse_csv(&written, &CsvFormat::tsv()).unwrap();
+
+        for source in [&mut source as &mut dyn DanmakuSource, &mut written] {
+            let items: Vec<_> = source.get_all().collect();
+            assert_eq!(items.len(), 2);
+            assert_eq!(items[0].time, DanmakuTime::from_millis(3250));
+            assert_eq!(items[0].r#type, DanmakuType::Top);
+            assert_eq!(items[0].color, DanmakuColor::from_code(0x0000FF));
+            assert_eq!(items[0].content, "a, \"quoted\"\nline");
+            assert_eq!(items[1].content, "kksk");
+        }
+
+        // Only time and content, in milliseconds
+        let format = CsvFormat {
+            has_header: false,
+            time_column: 1,
+            time_unit: CsvTimeUnit::Millis,
+            type_column: None,
+            color_column: None,
+            content_column: 0,
+            ..CsvFormat::csv()
+        };
+        let mut source = parse_csv("hello,1500\n", &format).unwrap();
+        let items: Vec<_> = source.get_all().collect();
+        assert_eq!(items[0].time, DanmakuTime::from_millis(1500));
+        assert_eq!(items[0].r#type, DanmakuType::Scroll);
+        assert!(parse_csv("hello\n", &format).is_err());
+        assert!(parse_csv("\"hello,1500\n", &format).is_err());
+    }
+}
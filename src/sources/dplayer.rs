@@ -0,0 +1,155 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    io::{Read, Write},
+};
+
+use serde_json::{json, Value};
+
+use super::{DanmakuSource, VecDanmakuSource};
+
+use crate::danmaku::{Danmaku, DanmakuColor, DanmakuSize, DanmakuTime, DanmakuType};
+
+#[derive(Debug)]
+pub enum DplayerParseError {
+    JsonError(serde_json::Error),
+    // Neither an array nor an object with a data array
+    BadRoot,
+    // Index of the malformed item
+    BadItem(usize),
+}
+
+impl Display for DplayerParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::JsonError(err) => write!(f, "Failed to parse JSON: {}", err),
+            Self::BadRoot => write!(f, "Missing danmaku data array"),
+            Self::BadItem(index) => write!(f, "Bad danmaku item #{}", index),
+        }
+    }
+}
+
+impl Error for DplayerParseError {}
+
+impl From<serde_json::Error> for DplayerParseError {
+    fn from(value: serde_json::Error) -> Self {
+        DplayerParseError::JsonError(value)
+    }
+}
+
+fn parse_item(item: &Value) -> Option<Danmaku> {
+    let [time, r#type, color, _author, text] = item.as_array()?.as_slice() else {
+        return None;
+    };
+    let seconds = time.as_f64()?;
+    let r#type = match r#type.as_u64()? {
+        0 => DanmakuType::Scroll,
+        1 => DanmakuType::Top,
+        2 => DanmakuType::Bottom,
+        _ => DanmakuType::Unknown,
+    };
+    // Some servers store the color as a string
+    let color = match color {
+        Value::String(color) => color.parse().ok()?,
+        color => color.as_u64()?.try_into().ok()?,
+    };
+    Some(Danmaku {
+        time: DanmakuTime::from_millis((seconds * 1000.0).round() as i64),
+        r#type,
+        size: DanmakuSize::Regular,
+        color: DanmakuColor::from_code_cast(color),
+        content: text.as_str()?.to_string(),
+        bordered: false,
+        background: None,
+        animation: None,
+        gradient: None,
+        id: None,
+        group_id: None,
+    })
+}
+
+// Accepts the response of the v3 API ({"code": 0, "data": [...]}) or the bare data array, whose
+// items are [time, type, color, author, text]
+pub fn parse_json(value: &Value) -> Result<impl DanmakuSource, DplayerParseError> {
+    let items = match value {
+        Value::Array(items) => items,
+        Value::Object(object) => object
+            .get("data")
+            .and_then(Value::as_array)
+            .ok_or(DplayerParseError::BadRoot)?,
+        _ => return Err(DplayerParseError::BadRoot),
+    };
+    let vec = items
+        .iter()
+        .enumerate()
+        .map(|(index, item)| parse_item(item).ok_or(DplayerParseError::BadItem(index)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(VecDanmakuSource::new(vec))
+}
+
+pub fn parse_json_from_reader<R: Read>(reader: R) -> Result<impl DanmakuSource, DplayerParseError> {
+    let value: Value = serde_json::from_reader(reader)?;
+    parse_json(&value)
+}
+
+pub fn parse_json_from_slice(buf: &[u8]) -> Result<impl DanmakuSource, DplayerParseError> {
+    let value: Value = serde_json::from_slice(buf)?;
+    parse_json(&value)
+}
+
+// Writes a v3 API response. The author isn't kept in Danmaku, so it is left empty, and danmaku
+// of unknown types are skipped.
+pub fn write_json<S: DanmakuSource, W: Write>(
+    source: &mut S,
+    writer: W,
+) -> Result<(), serde_json::Error> {
+    let data: Vec<Value> = source
+        .get_all()
+        .filter_map(|danmaku| {
+            let r#type = match danmaku.r#type {
+                DanmakuType::Scroll => 0,
+                DanmakuType::Top => 1,
+                DanmakuType::Bottom => 2,
+                DanmakuType::Unknown => return None,
+            };
+            Some(json!([
+                danmaku.time.as_millis() as f64 / 1000.0,
+                r#type,
+                danmaku.color.code(),
+                "",
+                danmaku.content
+            ]))
+        })
+        .collect();
+    serde_json::to_writer(writer, &json!({ "code": 0, "data": data }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        danmaku::{DanmakuColor, DanmakuTime, DanmakuType},
+        sources::DanmakuSource,
+    };
+
+    use super::{parse_json_from_slice, write_json};
+
+    #[test]
+    fn test_read_write_json() {
+        let content =
+            br#"{"code":0,"data":[[12.5,0,16777215,"a","kksk"],[3.25,1,"255","b","top"]]}"#;
+        let mut source = parse_json_from_slice(content).unwrap();
+        let mut buf = Vec::new();
+        write_json(&mut source, &mut buf).unwrap();
+        let mut written = parse_json_from_slice(&buf).unwrap();
+
+        for source in [&mut source as &mut dyn DanmakuSource, &mut written] {
+            let items: Vec<_> = source.get_all().collect();
+            assert_eq!(items.len(), 2);
+            assert_eq!(items[0].time, DanmakuTime::from_millis(3250));
+            assert_eq!(items[0].r#type, DanmakuType::Top);
+            assert_eq!(items[0].color, DanmakuColor::from_code(0x0000FF));
+            assert_eq!(items[1].content, "kksk");
+        }
+        assert!(parse_json_from_slice(br#"{"code":0,"data":[[1.0,0]]}"#).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use crate::danmaku::{Danmaku, DanmakuTime};
+
+use super::DanmakuSource;
+
+// A part of the original video which is kept, playing from playback_start in the edited one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EditSegment {
+    pub source_start: DanmakuTime,
+    // Excluded
+    pub source_end: DanmakuTime,
+    pub playback_start: DanmakuTime,
+}
+
+impl EditSegment {
+    fn contains(&self, time: DanmakuTime) -> bool {
+        time >= self.source_start && time < self.source_end
+    }
+
+    fn map(&self, time: DanmakuTime) -> DanmakuTime {
+        let offset = time.as_millis() - self.source_start.as_millis();
+        DanmakuTime::from_millis(self.playback_start.as_millis().saturating_add(offset))
+    }
+
+    fn playback_end(&self) -> DanmakuTime {
+        self.map(self.source_end)
+    }
+}
+
+// What happens to the danmaku of the parts which were cut
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CutDanmaku {
+    #[default]
+    Skip,
+    // Shown where the video continues, i.e. the start of the next kept segment. Danmaku after
+    // the last segment are skipped.
+    Merge,
+}
+
+// Moves the danmaku of the original video to the times of an edited one, e.g. after sponsor
+// segments were removed or the parts were reordered. Times outside of the segments were cut.
+pub struct EditListSource<Source: DanmakuSource> {
+    source: Source,
+    // Sorted by source_start, not overlapping
+    segments: Vec<EditSegment>,
+    cut: CutDanmaku,
+    buffer: Vec<Danmaku>,
+}
+
+impl<Source: DanmakuSource> EditListSource<Source> {
+    pub fn new(source: Source, mut segments: Vec<EditSegment>, cut: CutDanmaku) -> Self {
+        segments.sort_by_key(|segment| segment.source_start);
+        for segment in &segments {
+            assert!(segment.source_start < segment.source_end);
+        }
+        for pair in segments.windows(2) {
+            assert!(pair[0].source_end <= pair[1].source_start);
+        }
+        EditListSource {
+            source,
+            segments,
+            cut,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn segments(&self) -> &[EditSegment] {
+        &self.segments
+    }
+
+    // Playback time of a danmaku at the time of the original video, None if it is skipped
+    pub fn map(&self, time: DanmakuTime) -> Option<DanmakuTime> {
+        map_time(&self.segments, self.cut, time)
+    }
+
+    fn map_all<'a>(
+        segments: &'a [EditSegment],
+        cut: CutDanmaku,
+        iterator: impl Iterator<Item = Danmaku> + 'a,
+    ) -> impl Iterator<Item = Danmaku> + 'a {
+        iterator.filter_map(move |danmaku| {
+            let time = map_time(segments, cut, danmaku.time)?;
+            Some(Danmaku { time, ..danmaku })
+        })
+    }
+}
+
+fn map_time(segments: &[EditSegment], cut: CutDanmaku, time: DanmakuTime) -> Option<DanmakuTime> {
+    let index = segments.partition_point(|segment| segment.source_end <= time);
+    let segment = segments.get(index)?;
+    if segment.contains(time) {
+        Some(segment.map(time))
+    } else {
+        (cut == CutDanmaku::Merge).then_some(segment.playback_start)
+    }
+}
+
+impl<Source: DanmakuSource> DanmakuSource for EditListSource<Source> {
+    fn get_range(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        self.buffer.clear();
+        let mut cut_start = DanmakuTime::MIN;
+        for segment in &self.segments {
+            // The part of the segment which plays in the range, back in the original times
+            let playback_start = segment.playback_start.max(start_included);
+            let playback_end = segment.playback_end().min(end_excluded);
+            if playback_start < playback_end {
+                let offset = segment.source_start.as_millis() - segment.playback_start.as_millis();
+                let inner_start = DanmakuTime::from_millis(playback_start.as_millis() + offset);
+                let inner_end = DanmakuTime::from_millis(playback_end.as_millis() + offset);
+                self.buffer.extend(
+                    self.source
+                        .get_range(inner_start, inner_end)
+                        .map(|danmaku| Danmaku {
+                            time: segment.map(danmaku.time),
+                            ..danmaku.clone()
+                        }),
+                );
+            }
+            let merged =
+                segment.playback_start >= start_included && segment.playback_start < end_excluded;
+            if self.cut == CutDanmaku::Merge && merged && cut_start < segment.source_start {
+                self.buffer
+                    .extend(self.source.get_range(cut_start, segment.source_start).map(
+                        |danmaku| Danmaku {
+                            time: segment.playback_start,
+                            ..danmaku.clone()
+                        },
+                    ));
+            }
+            cut_start = segment.source_end;
+        }
+        Box::new(self.buffer.iter())
+    }
+
+    fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        self.buffer.clear();
+        let danmaku = self.source.get_all().cloned();
+        self.buffer
+            .extend(Self::map_all(&self.segments, self.cut, danmaku));
+        self.buffer.sort_by_key(Danmaku::order_key);
+        Box::new(self.buffer.iter())
+    }
+
+    fn into_all(self) -> Box<dyn Iterator<Item = Danmaku>> {
+        let mut danmaku: Vec<_> =
+            Self::map_all(&self.segments, self.cut, self.source.into_all()).collect();
+        danmaku.sort_by_key(Danmaku::order_key);
+        Box::new(danmaku.into_iter())
+    }
+
+    // The segments after the changed time may be played in any order, so the earliest of their
+    // playback times is changed
+    fn take_changed(&mut self) -> Option<DanmakuTime> {
+        let time = self.source.take_changed()?;
+        self.segments
+            .iter()
+            .filter(|segment| segment.source_end > time)
+            .map(|segment| segment.map(time.max(segment.source_start)))
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        danmaku::{Danmaku, DanmakuTime, DanmakuType},
+        sources::{DanmakuSource, VecDanmakuSource},
+    };
+
+    use super::{CutDanmaku, EditListSource, EditSegment};
+
+    #[test]
+    fn test_edit_list() {
+        let danmaku: Vec<_> = [5, 15, 25, 35, 45, 55]
+            .into_iter()
+            .map(|seconds| {
+                Danmaku::new(
+                    DanmakuTime::from_millis(seconds * 1000),
+                    DanmakuType::Scroll,
+                    seconds.to_string(),
+                )
+            })
+            .collect();
+        let segment = |source_start: i64, source_end: i64, playback_start: i64| EditSegment {
+            source_start: DanmakuTime::from_millis(source_start * 1000),
+            source_end: DanmakuTime::from_millis(source_end * 1000),
+            playback_start: DanmakuTime::from_millis(playback_start * 1000),
+        };
+        // From 10s to 20s and from 50s on were cut
+        let segments = vec![segment(20, 50, 10), segment(0, 10, 0)];
+        let range = |source: &mut dyn DanmakuSource, start: i64, end: i64| {
+            let mut range: Vec<_> = source
+                .get_range(
+                    DanmakuTime::from_millis(start * 1000),
+                    DanmakuTime::from_millis(end * 1000),
+                )
+                .map(|danmaku| (danmaku.time.as_millis() / 1000, danmaku.content.clone()))
+                .collect();
+            range.sort();
+            range
+        };
+        let item = |seconds: i64, content: &str| (seconds, content.to_string());
+
+        let mut skipped = EditListSource::new(
+            VecDanmakuSource::new(danmaku.clone()),
+            segments.clone(),
+            CutDanmaku::Skip,
+        );
+        assert_eq!(skipped.map(DanmakuTime::from_millis(15_000)), None);
+        assert_eq!(
+            range(&mut skipped, 0, 60),
+            vec![item(5, "5"), item(15, "25"), item(25, "35"), item(35, "45")]
+        );
+        assert_eq!(range(&mut skipped, 10, 20), vec![item(15, "25")]);
+
+        let mut merged =
+            EditListSource::new(VecDanmakuSource::new(danmaku), segments, CutDanmaku::Merge);
+        // Where the video continues after the cut
+        assert_eq!(
+            merged.map(DanmakuTime::from_millis(15_000)),
+            Some(DanmakuTime::from_millis(10_000))
+        );
+        assert_eq!(merged.map(DanmakuTime::from_millis(55_000)), None);
+        assert_eq!(
+            range(&mut merged, 10, 20),
+            vec![item(10, "15"), item(15, "25")]
+        );
+        assert_eq!(range(&mut merged, 11, 20), vec![item(15, "25")]);
+        let all: Vec<_> = merged.get_all().map(|danmaku| danmaku.time).collect();
+        assert!(all.is_sorted());
+        assert_eq!(all.len(), 5);
+
+        // Parts may be played in another order
+        let segments = vec![segment(20, 30, 0), segment(0, 10, 10)];
+        let source = VecDanmakuSource::new(Vec::new());
+        let reordered = EditListSource::new(source, segments, CutDanmaku::Skip);
+        assert_eq!(
+            reordered.map(DanmakuTime::from_millis(25_000)),
+            Some(DanmakuTime::from_millis(5_000))
+        );
+        assert_eq!(
+            reordered.map(DanmakuTime::from_millis(5_000)),
+            Some(DanmakuTime::from_millis(15_000))
+        );
+    }
+}
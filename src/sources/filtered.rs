@@ -3,6 +3,7 @@ use std::{borrow::Borrow, marker::PhantomData};
 use crate::{
     danmaku::{Danmaku, DanmakuTime},
     filter::DanmakuFilter,
+    style::DanmakuStyle,
 };
 
 use super::DanmakuSource;
@@ -97,6 +98,19 @@ where
         Box::new(iter)
     }
 
+    fn get_range_styled(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = (&'_ Danmaku, Option<DanmakuStyle>)> + '_> {
+        let filter = &self.filter;
+        let iter = self
+            .source
+            .get_range_styled(start_included, end_excluded)
+            .filter(move |(danmaku, _)| !filter.is_filtered(&danmaku.content));
+        Box::new(iter)
+    }
+
     fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
         let iter = self.source.get_all();
         let iter = FilteredDanmakuSourceIterator::<&'_ Danmaku, &'_ Filter, Filter>::new(
@@ -111,4 +125,8 @@ where
         let iter = FilteredDanmakuSourceIterator::<Danmaku, Filter, Filter>::new(iter, self.filter);
         Box::new(iter)
     }
+
+    fn take_changed(&mut self) -> Option<DanmakuTime> {
+        self.source.take_changed()
+    }
 }
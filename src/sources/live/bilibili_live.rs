@@ -0,0 +1,361 @@
+// Packets of the Bilibili live danmaku WebSocket. The host opens the WebSocket, sends
+// auth_packet and then heartbeat_packet every 30 seconds, and passes the binary messages it
+// receives to BilibiliLiveFeed.
+use std::{
+    cmp::Ordering,
+    error::Error,
+    fmt::Display,
+    io::{self, Read},
+};
+
+use flate2::read::ZlibDecoder;
+use serde_json::{json, Value};
+
+use super::LiveDanmakuSender;
+
+use crate::danmaku::{Danmaku, DanmakuColor, DanmakuSize, DanmakuTime, DanmakuType};
+
+const HEADER_LEN: usize = 16;
+
+const OP_HEARTBEAT: u32 = 2;
+const OP_HEARTBEAT_REPLY: u32 = 3;
+const OP_COMMAND: u32 = 5;
+const OP_AUTH: u32 = 7;
+const OP_AUTH_REPLY: u32 = 8;
+
+// Protocol versions of the packet bodies
+pub const PROTOVER_JSON: u16 = 0;
+pub const PROTOVER_INT: u16 = 1;
+pub const PROTOVER_ZLIB: u16 = 2;
+pub const PROTOVER_BROTLI: u16 = 3;
+
+// The server sends plain packets in its batches, deeper ones are refused rather than decompressed
+// without end
+const MAX_BATCH_DEPTH: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LiveCompression {
+    Zlib,
+    Brotli,
+}
+
+// The body of a compressed packet is a batch of packets. Replaces the built-in zlib and brotli
+// decompression, e.g. with the implementations the host already has.
+pub type LiveDecompressor = Box<dyn FnMut(LiveCompression, &[u8]) -> io::Result<Vec<u8>> + Send>;
+
+#[derive(Debug)]
+pub enum LivePacket {
+    AuthReply(Value),
+    // Popularity of the room
+    HeartbeatReply(u32),
+    Command(Value),
+}
+
+#[derive(Debug)]
+pub enum LiveDecodeError {
+    // The packet is longer than the message
+    Truncated,
+    BadHeader,
+    JsonError(serde_json::Error),
+    DecompressError(io::Error),
+    // Compressed batch inside more than MAX_BATCH_DEPTH batches
+    NestedTooDeep,
+}
+
+impl Display for LiveDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "Truncated packet"),
+            Self::BadHeader => write!(f, "Bad packet header"),
+            Self::JsonError(err) => write!(f, "Failed to parse command: {}", err),
+            Self::DecompressError(err) => write!(f, "Failed to decompress packet: {}", err),
+            Self::NestedTooDeep => write!(f, "Compressed packets nested too deep"),
+        }
+    }
+}
+
+impl Error for LiveDecodeError {}
+
+impl From<serde_json::Error> for LiveDecodeError {
+    fn from(value: serde_json::Error) -> Self {
+        LiveDecodeError::JsonError(value)
+    }
+}
+
+pub fn encode_packet(protover: u16, op: u32, body: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_LEN + body.len());
+    packet.extend_from_slice(&((HEADER_LEN + body.len()) as u32).to_be_bytes());
+    packet.extend_from_slice(&(HEADER_LEN as u16).to_be_bytes());
+    packet.extend_from_slice(&protover.to_be_bytes());
+    packet.extend_from_slice(&op.to_be_bytes());
+    packet.extend_from_slice(&1u32.to_be_bytes());
+    packet.extend_from_slice(body);
+    packet
+}
+
+// The protocol version asks the server to compress the commands, PROTOVER_BROTLI is what the
+// web player asks for
+pub fn auth_packet(room_id: u64, uid: u64, key: &str, protover: u16) -> Vec<u8> {
+    let body = json!({
+        "uid": uid,
+        "roomid": room_id,
+        "protover": protover,
+        "platform": "web",
+        "type": 2,
+        "key": key,
+    });
+    encode_packet(PROTOVER_INT, OP_AUTH, body.to_string().as_bytes())
+}
+
+pub fn heartbeat_packet() -> Vec<u8> {
+    encode_packet(PROTOVER_INT, OP_HEARTBEAT, &[])
+}
+
+fn decompress(compression: LiveCompression, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut batch = Vec::new();
+    match compression {
+        LiveCompression::Zlib => ZlibDecoder::new(data).read_to_end(&mut batch)?,
+        LiveCompression::Brotli => brotli::Decompressor::new(data, 4096).read_to_end(&mut batch)?,
+    };
+    Ok(batch)
+}
+
+#[derive(Default)]
+pub struct LivePacketDecoder {
+    decompressor: Option<LiveDecompressor>,
+}
+
+impl LivePacketDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_decompressor(mut self, decompressor: LiveDecompressor) -> Self {
+        self.decompressor = Some(decompressor);
+        self
+    }
+
+    // Decodes a WebSocket message, which may hold several packets
+    pub fn decode(&mut self, message: &[u8]) -> Result<Vec<LivePacket>, LiveDecodeError> {
+        let mut packets = Vec::new();
+        self.decode_into(message, &mut packets, 0)?;
+        Ok(packets)
+    }
+
+    fn decode_into(
+        &mut self,
+        mut data: &[u8],
+        packets: &mut Vec<LivePacket>,
+        depth: usize,
+    ) -> Result<(), LiveDecodeError> {
+        while !data.is_empty() {
+            if data.len() < HEADER_LEN {
+                return Err(LiveDecodeError::Truncated);
+            }
+            let packet_len = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+            let header_len = u16::from_be_bytes(data[4..6].try_into().unwrap()) as usize;
+            let protover = u16::from_be_bytes(data[6..8].try_into().unwrap());
+            let op = u32::from_be_bytes(data[8..12].try_into().unwrap());
+            if header_len < HEADER_LEN || packet_len < header_len {
+                return Err(LiveDecodeError::BadHeader);
+            }
+            if data.len() < packet_len {
+                return Err(LiveDecodeError::Truncated);
+            }
+            let body = &data[header_len..packet_len];
+            data = &data[packet_len..];
+
+            let compression = match protover {
+                PROTOVER_ZLIB => Some(LiveCompression::Zlib),
+                PROTOVER_BROTLI => Some(LiveCompression::Brotli),
+                _ => None,
+            };
+            if let Some(compression) = compression {
+                if depth >= MAX_BATCH_DEPTH {
+                    return Err(LiveDecodeError::NestedTooDeep);
+                }
+                let batch = match &mut self.decompressor {
+                    Some(decompressor) => decompressor(compression, body),
+                    None => decompress(compression, body),
+                }
+                .map_err(LiveDecodeError::DecompressError)?;
+                self.decode_into(&batch, packets, depth + 1)?;
+                continue;
+            }
+            match op {
+                OP_HEARTBEAT_REPLY => {
+                    let popularity = body
+                        .get(0..4)
+                        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+                        .unwrap_or(0);
+                    packets.push(LivePacket::HeartbeatReply(popularity));
+                }
+                OP_COMMAND => packets.push(LivePacket::Command(serde_json::from_slice(body)?)),
+                OP_AUTH_REPLY => packets.push(LivePacket::AuthReply(serde_json::from_slice(body)?)),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Danmaku of a DANMU_MSG command, shown at the given time. Other commands return None.
+pub fn parse_danmu_msg(command: &Value, time: DanmakuTime) -> Option<Danmaku> {
+    let cmd = command.get("cmd")?.as_str()?;
+    // Newer servers append parameters, e.g. DANMU_MSG:4:0:2:2:2:0
+    if cmd.split(':').next() != Some("DANMU_MSG") {
+        return None;
+    }
+    let info = command.get("info")?.as_array()?;
+    let attributes = info.first()?.as_array()?;
+    let r#type = match attributes.get(1)?.as_u64()? {
+        1..=3 => DanmakuType::Scroll,
+        4 => DanmakuType::Bottom,
+        5 => DanmakuType::Top,
+        _ => DanmakuType::Unknown,
+    };
+    let size = match attributes.get(2)?.as_u64()?.cmp(&25) {
+        Ordering::Less => DanmakuSize::Small,
+        Ordering::Equal => DanmakuSize::Regular,
+        Ordering::Greater => DanmakuSize::Large,
+    };
+    let color = attributes.get(3)?.as_u64()?;
+    Some(Danmaku {
+        time,
+        r#type,
+        size,
+        color: DanmakuColor::from_code_cast(color as u32),
+        content: info.get(1)?.as_str()?.to_string(),
+        bordered: false,
+        background: None,
+        animation: None,
+        gradient: None,
+        id: None,
+        group_id: None,
+    })
+}
+
+// Appends the danmaku of the received messages to a LiveDanmakuSource
+pub struct BilibiliLiveFeed {
+    decoder: LivePacketDecoder,
+    sender: LiveDanmakuSender,
+}
+
+impl BilibiliLiveFeed {
+    pub fn new(decoder: LivePacketDecoder, sender: LiveDanmakuSender) -> Self {
+        BilibiliLiveFeed { decoder, sender }
+    }
+
+    // Returns the number of danmaku appended, at the playback time the message is received
+    pub fn feed(&mut self, message: &[u8], now: DanmakuTime) -> Result<usize, LiveDecodeError> {
+        let mut count = 0;
+        for packet in self.decoder.decode(message)? {
+            if let LivePacket::Command(command) = packet {
+                if let Some(danmaku) = parse_danmu_msg(&command, now) {
+                    self.sender.push(danmaku);
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::{write::ZlibEncoder, Compression};
+    use serde_json::json;
+
+    use crate::{
+        danmaku::{DanmakuColor, DanmakuTime, DanmakuType},
+        sources::{live::LiveDanmakuSource, DanmakuSource},
+    };
+
+    use super::{
+        encode_packet, BilibiliLiveFeed, LiveCompression, LiveDecodeError, LivePacket,
+        LivePacketDecoder, OP_COMMAND, OP_HEARTBEAT_REPLY, PROTOVER_BROTLI, PROTOVER_INT,
+        PROTOVER_JSON, PROTOVER_ZLIB,
+    };
+
+    fn zlib_packet(batch: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(batch).unwrap();
+        encode_packet(PROTOVER_ZLIB, OP_COMMAND, &encoder.finish().unwrap())
+    }
+
+    fn brotli_packet(batch: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+        writer.write_all(batch).unwrap();
+        drop(writer);
+        encode_packet(PROTOVER_BROTLI, OP_COMMAND, &compressed)
+    }
+
+    #[test]
+    fn test_live_feed() {
+        let command = json!({
+            "cmd": "DANMU_MSG:4:0:2:2:2:0",
+            "info": [[0, 5, 25, 16711680], "live", [1, "user"]],
+        });
+        let mut batch = encode_packet(PROTOVER_JSON, OP_COMMAND, command.to_string().as_bytes());
+        let other = json!({ "cmd": "INTERACT_WORD", "data": {} });
+        batch.extend(encode_packet(
+            PROTOVER_JSON,
+            OP_COMMAND,
+            other.to_string().as_bytes(),
+        ));
+        let mut message = encode_packet(PROTOVER_INT, OP_HEARTBEAT_REPLY, &42u32.to_be_bytes());
+        message.extend(zlib_packet(&batch));
+
+        let packets = LivePacketDecoder::new().decode(&message[..24]);
+        assert!(matches!(packets, Err(LiveDecodeError::Truncated)));
+        let packets = LivePacketDecoder::new().decode(&message).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert!(matches!(packets[0], LivePacket::HeartbeatReply(42)));
+        assert!(matches!(packets[1], LivePacket::Command(_)));
+
+        let mut source = LiveDanmakuSource::new();
+        let mut feed = BilibiliLiveFeed::new(LivePacketDecoder::new(), source.sender());
+        assert_eq!(
+            feed.feed(&message, DanmakuTime::from_millis(5000)).unwrap(),
+            1
+        );
+        assert_eq!(
+            feed.feed(&brotli_packet(&batch), DanmakuTime::from_millis(6000))
+                .unwrap(),
+            1
+        );
+
+        assert_eq!(source.take_changed(), Some(DanmakuTime::from_millis(5000)));
+        assert_eq!(source.take_changed(), None);
+        let items: Vec<_> = source.get_all().collect();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].r#type, DanmakuType::Top);
+        assert_eq!(items[0].color, DanmakuColor::from_code(0xFF0000));
+        assert_eq!(items[0].content, "live");
+        assert_eq!(items[1].time, DanmakuTime::from_millis(6000));
+
+        // The decompressor of the host replaces the built-in ones
+        let mut decoder =
+            LivePacketDecoder::new().with_decompressor(Box::new(|compression, data| {
+                assert_eq!(compression, LiveCompression::Zlib);
+                Ok(data.to_vec())
+            }));
+        let stored = encode_packet(PROTOVER_ZLIB, OP_COMMAND, &batch);
+        assert_eq!(decoder.decode(&stored).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_nested_batches() {
+        let command = json!({ "cmd": "DANMU_MSG", "info": [[0, 1, 25, 0], "nested"] });
+        let batch = encode_packet(PROTOVER_JSON, OP_COMMAND, command.to_string().as_bytes());
+        let nested = zlib_packet(&brotli_packet(&batch));
+        let packets = LivePacketDecoder::new().decode(&nested).unwrap();
+        assert_eq!(packets.len(), 1);
+
+        let packets = LivePacketDecoder::new().decode(&zlib_packet(&nested));
+        assert!(matches!(packets, Err(LiveDecodeError::NestedTooDeep)));
+    }
+}
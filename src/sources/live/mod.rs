@@ -0,0 +1,85 @@
+#[cfg(feature = "source-bilibili-live")]
+pub mod bilibili_live;
+
+use std::sync::{Arc, Mutex};
+
+use crate::danmaku::{Danmaku, DanmakuTime};
+
+use super::DanmakuSource;
+
+#[derive(Default)]
+struct LiveState {
+    // Sorted by time
+    danmaku: Vec<Danmaku>,
+    changed: Option<DanmakuTime>,
+}
+
+// Danmaku received while playing, appended through a LiveDanmakuSender from any thread. After
+// appending, WorkerManager::refresh shows them in the chunks which are already generated.
+#[derive(Default)]
+pub struct LiveDanmakuSource {
+    state: Arc<Mutex<LiveState>>,
+    buffer: Vec<Danmaku>,
+}
+
+#[derive(Clone)]
+pub struct LiveDanmakuSender(Arc<Mutex<LiveState>>);
+
+impl LiveDanmakuSender {
+    pub fn push(&self, danmaku: Danmaku) {
+        let mut state = self.0.lock().unwrap();
+        let time = danmaku.time;
+        let position = state.danmaku.partition_point(|item| item.time <= time);
+        state.danmaku.insert(position, danmaku);
+        state.changed = Some(state.changed.map_or(time, |changed| changed.min(time)));
+    }
+}
+
+impl LiveDanmakuSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sender(&self) -> LiveDanmakuSender {
+        LiveDanmakuSender(self.state.clone())
+    }
+
+    // Copies the danmaku out, so the sender isn't blocked while the chunk is generated
+    fn fill_buffer(&mut self, start_included: DanmakuTime, end_excluded: DanmakuTime) {
+        let state = self.state.lock().unwrap();
+        let start = state
+            .danmaku
+            .partition_point(|item| item.time < start_included);
+        let end = state
+            .danmaku
+            .partition_point(|item| item.time < end_excluded)
+            .max(start);
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&state.danmaku[start..end]);
+    }
+}
+
+impl DanmakuSource for LiveDanmakuSource {
+    fn get_range(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        self.fill_buffer(start_included, end_excluded);
+        Box::new(self.buffer.iter())
+    }
+
+    fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        self.fill_buffer(DanmakuTime::MIN, DanmakuTime::from_millis(i64::MAX));
+        Box::new(self.buffer.iter())
+    }
+
+    fn into_all(mut self) -> Box<dyn Iterator<Item = Danmaku>> {
+        self.fill_buffer(DanmakuTime::MIN, DanmakuTime::from_millis(i64::MAX));
+        Box::new(self.buffer.into_iter())
+    }
+
+    fn take_changed(&mut self) -> Option<DanmakuTime> {
+        self.state.lock().unwrap().changed.take()
+    }
+}
@@ -0,0 +1,231 @@
+use std::{borrow::Borrow, iter::Peekable};
+
+use crate::{
+    danmaku::{Danmaku, DanmakuTime},
+    style::DanmakuStyle,
+};
+
+use super::DanmakuSource;
+
+struct MergedSourceEntry {
+    source: Box<dyn DanmakuSource + Send>,
+    enabled: bool,
+    // Tint and opacity of every danmaku from this source
+    style: Option<DanmakuStyle>,
+}
+
+// Combines several sources (e.g. official, local and friend-sourced comment files) ordered by
+// time. Changing the enable flags only affects chunks generated afterwards.
+#[derive(Default)]
+pub struct MergedDanmakuSource {
+    sources: Vec<MergedSourceEntry>,
+}
+
+impl MergedDanmakuSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_source(
+        mut self,
+        source: Box<dyn DanmakuSource + Send>,
+        style: Option<DanmakuStyle>,
+    ) -> Self {
+        self.push(source, style);
+        self
+    }
+
+    // Returns the index of the added source
+    pub fn push(
+        &mut self,
+        source: Box<dyn DanmakuSource + Send>,
+        style: Option<DanmakuStyle>,
+    ) -> usize {
+        self.sources.push(MergedSourceEntry {
+            source,
+            enabled: true,
+            style,
+        });
+        self.sources.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    pub fn is_enabled(&self, index: usize) -> bool {
+        self.sources[index].enabled
+    }
+
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) {
+        self.sources[index].enabled = enabled;
+    }
+
+    pub fn style(&self, index: usize) -> Option<DanmakuStyle> {
+        self.sources[index].style
+    }
+
+    pub fn set_style(&mut self, index: usize, style: Option<DanmakuStyle>) {
+        self.sources[index].style = style;
+    }
+
+    fn merge<'a, Item: Borrow<Danmaku> + 'a>(
+        &'a mut self,
+        get_iterator: impl Fn(
+            &'a mut Box<dyn DanmakuSource + Send>,
+        ) -> Box<dyn Iterator<Item = Item> + 'a>,
+    ) -> MergedDanmakuSourceIterator<'a, (Item, Option<DanmakuStyle>)> {
+        let iterators = self
+            .sources
+            .iter_mut()
+            .filter(|entry| entry.enabled)
+            .map(|entry| {
+                let style = entry.style;
+                let iterator: Box<dyn Iterator<Item = (Item, Option<DanmakuStyle>)> + 'a> =
+                    Box::new(get_iterator(&mut entry.source).map(move |item| (item, style)));
+                iterator.peekable()
+            })
+            .collect();
+        MergedDanmakuSourceIterator { iterators }
+    }
+}
+
+// K-way merge, ties are resolved in the order of the sources
+struct MergedDanmakuSourceIterator<'a, Item> {
+    iterators: Vec<Peekable<Box<dyn Iterator<Item = Item> + 'a>>>,
+}
+
+impl<'a, Item: Borrow<Danmaku>, Style> Iterator for MergedDanmakuSourceIterator<'a, (Item, Style)> {
+    type Item = (Item, Style);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut earliest: Option<(usize, DanmakuTime)> = None;
+        for (index, iterator) in self.iterators.iter_mut().enumerate() {
+            if let Some((item, _)) = iterator.peek() {
+                let time = item.borrow().time;
+                if earliest.is_none_or(|(_, earliest_time)| time < earliest_time) {
+                    earliest = Some((index, time));
+                }
+            }
+        }
+        let (index, _) = earliest?;
+        self.iterators[index].next()
+    }
+}
+
+impl DanmakuSource for MergedDanmakuSource {
+    fn get_range(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        Box::new(
+            self.get_range_styled(start_included, end_excluded)
+                .map(|(danmaku, _)| danmaku),
+        )
+    }
+
+    fn get_range_styled(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = (&'_ Danmaku, Option<DanmakuStyle>)> + '_> {
+        Box::new(self.merge(move |source| source.get_range(start_included, end_excluded)))
+    }
+
+    fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        Box::new(
+            self.merge(|source| source.get_all())
+                .map(|(danmaku, _)| danmaku),
+        )
+    }
+
+    // Includes the disabled sources, without their styles
+    fn into_all(self) -> Box<dyn Iterator<Item = Danmaku>> {
+        let iterators = self
+            .sources
+            .into_iter()
+            .map(|mut entry| {
+                // Boxed sources can't be consumed, so their danmaku are cloned
+                let danmaku: Vec<Danmaku> = entry.source.get_all().cloned().collect();
+                let iterator: Box<dyn Iterator<Item = (Danmaku, ())>> =
+                    Box::new(danmaku.into_iter().map(|danmaku| (danmaku, ())));
+                iterator.peekable()
+            })
+            .collect();
+        Box::new(MergedDanmakuSourceIterator { iterators }.map(|(danmaku, _)| danmaku))
+    }
+
+    // Disabled sources are polled too, so their changes aren't reported once they are enabled
+    fn take_changed(&mut self) -> Option<DanmakuTime> {
+        self.sources
+            .iter_mut()
+            .filter_map(|entry| entry.source.take_changed())
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        danmaku::{Danmaku, DanmakuTime, DanmakuType},
+        sources::{DanmakuSource, VecDanmakuSource},
+        style::DanmakuStyle,
+    };
+
+    use super::MergedDanmakuSource;
+
+    fn source(times: &[i64]) -> Box<VecDanmakuSource> {
+        let danmaku = times
+            .iter()
+            .map(|time| {
+                Danmaku::new(
+                    DanmakuTime::from_millis(*time),
+                    DanmakuType::Scroll,
+                    time.to_string(),
+                )
+            })
+            .collect();
+        Box::new(VecDanmakuSource::new(danmaku))
+    }
+
+    #[test]
+    fn test_merged_source() {
+        let style = DanmakuStyle {
+            opacity: 0.5,
+            ..Default::default()
+        };
+        let mut merged = MergedDanmakuSource::new()
+            .with_source(source(&[100, 300, 500, 700]), None)
+            .with_source(source(&[200, 300, 600, 900]), Some(style))
+            .with_source(source(&[400, 1000]), None);
+
+        let range: Vec<_> = merged
+            .get_range_styled(DanmakuTime::from_millis(200), DanmakuTime::from_millis(650))
+            .map(|(danmaku, style)| (danmaku.time.as_millis(), style.is_some()))
+            .collect();
+        assert_eq!(
+            range,
+            vec![
+                (200, true),
+                (300, false),
+                (300, true),
+                (400, false),
+                (500, false),
+                (600, true)
+            ]
+        );
+
+        merged.set_enabled(1, false);
+        let all: Vec<_> = merged
+            .get_all()
+            .map(|danmaku| danmaku.time.as_millis())
+            .collect();
+        assert_eq!(all, vec![100, 300, 400, 500, 700, 1000]);
+        assert_eq!(merged.into_all().count(), 10);
+    }
+}
@@ -1,60 +1,96 @@
 pub mod bilibili;
+#[cfg(feature = "bilibili-client")]
+pub mod bilibili_client;
+#[cfg(feature = "source-csv")]
+pub mod csv;
+#[cfg(feature = "source-dplayer")]
+pub mod dplayer;
+pub mod edit_list;
 pub mod filtered;
+pub mod live;
+pub mod merged;
+pub mod normalized;
+pub mod offset;
+pub mod rate_limited;
+pub mod shared;
 
-use crate::danmaku::{Danmaku, DanmakuTime};
+use std::collections::HashSet;
+
+use crate::{
+    danmaku::{Danmaku, DanmakuTime},
+    filter::DanmakuFilter,
+    style::DanmakuStyle,
+};
 
 pub trait DanmakuSource {
+    // The range doesn't have to be sorted, DanmakuTimeChunkProvider sorts it by time
     fn get_range<'a>(
         &'a mut self,
         start_included: DanmakuTime,
         end_excluded: DanmakuTime,
     ) -> Box<dyn Iterator<Item = &'a Danmaku> + 'a>;
 
+    // Like get_range, with the style the source attaches to each danmaku (e.g. the tint of a
+    // merged source). It is applied on top of the style from the DanmakuStyler.
+    fn get_range_styled<'a>(
+        &'a mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = (&'a Danmaku, Option<DanmakuStyle>)> + 'a> {
+        Box::new(
+            self.get_range(start_included, end_excluded)
+                .map(|danmaku| (danmaku, None)),
+        )
+    }
+
     fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_>;
 
     fn into_all(self) -> Box<dyn Iterator<Item = Danmaku>>;
+
+    // Earliest time of the danmaku added since the last call, for sources which grow while
+    // playing (e.g. live streams). Chunks from that time on are generated again.
+    fn take_changed(&mut self) -> Option<DanmakuTime> {
+        None
+    }
+}
+
+// Danmaku whose content matches the query in time order, e.g. a SimpleFilter for a keyword or a
+// RegexFilter for a pattern
+pub fn search<'a, S: DanmakuSource + ?Sized>(
+    source: &'a mut S,
+    query: &'a dyn DanmakuFilter,
+) -> impl Iterator<Item = &'a Danmaku> + 'a {
+    source
+        .get_all()
+        .filter(move |item| query.is_filtered(&item.content))
 }
 
 pub struct VecDanmakuSource(Vec<Danmaku>);
 
 impl VecDanmakuSource {
     pub fn new(mut vec: Vec<Danmaku>) -> Self {
-        vec.sort_unstable_by(|a, b| a.time.cmp(&b.time));
+        vec.sort_by_key(Danmaku::order_key);
         VecDanmakuSource(vec)
     }
 
-    fn find_index(&self, time: DanmakuTime) -> Result<usize, usize> {
-        self.0.binary_search_by(|item| item.time.cmp(&time))
+    // Keeps the first of the danmaku with the same id, e.g. when segments were fetched twice.
+    // Danmaku without an id are kept.
+    pub fn dedup_by_id(mut self) -> Self {
+        let mut ids = HashSet::new();
+        self.0
+            .retain(|danmaku| danmaku.id.is_none_or(|id| ids.insert(id)));
+        self
     }
-}
-
-struct VecDanmakuSourceIterator<'a> {
-    source: &'a [Danmaku],
-    index: usize,
-    end_excluded: DanmakuTime,
-}
 
-impl<'a> VecDanmakuSourceIterator<'a> {
-    fn new(source: &'a [Danmaku], index: usize, end_excluded: DanmakuTime) -> Self {
-        VecDanmakuSourceIterator {
-            source,
-            index,
-            end_excluded,
-        }
+    // First index whose time is not earlier than the given time
+    fn find_index(&self, time: DanmakuTime) -> usize {
+        self.0.partition_point(|item| item.time < time)
     }
-}
 
-impl<'a> Iterator for VecDanmakuSourceIterator<'a> {
-    type Item = &'a Danmaku;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let item = &self.source[self.index];
-        if item.time >= self.end_excluded {
-            None
-        } else {
-            self.index += 1;
-            Some(item)
-        }
+    pub fn range(&self, start_included: DanmakuTime, end_excluded: DanmakuTime) -> &[Danmaku] {
+        let start = self.find_index(start_included);
+        let end = self.find_index(end_excluded).max(start);
+        &self.0[start..end]
     }
 }
 
@@ -64,11 +100,7 @@ impl DanmakuSource for VecDanmakuSource {
         start_included: DanmakuTime,
         end_excluded: DanmakuTime,
     ) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
-        let start = self
-            .find_index(start_included)
-            .unwrap_or_else(|index| index);
-
-        Box::new(VecDanmakuSourceIterator::new(&self.0, start, end_excluded))
+        Box::new(self.range(start_included, end_excluded).iter())
     }
 
     fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
@@ -82,5 +114,76 @@ impl DanmakuSource for VecDanmakuSource {
 
 #[cfg(test)]
 mod test {
-    // TODO
+    use crate::{
+        danmaku::{Danmaku, DanmakuTime, DanmakuType},
+        filter::SimpleFilter,
+    };
+
+    use super::{search, DanmakuSource, VecDanmakuSource};
+
+    #[test]
+    fn test_vec_source_range() {
+        let danmaku = [300, 100, 200, 200, 200]
+            .into_iter()
+            .map(|time| {
+                Danmaku::new(
+                    DanmakuTime::from_millis(time),
+                    DanmakuType::Scroll,
+                    time.to_string(),
+                )
+            })
+            .collect();
+        let mut source = VecDanmakuSource::new(danmaku);
+        let range = |source: &mut VecDanmakuSource, start, end| {
+            let iter = source.get_range(
+                DanmakuTime::from_millis(start),
+                DanmakuTime::from_millis(end),
+            );
+            assert_eq!(iter.size_hint().0, iter.size_hint().1.unwrap());
+            iter.map(|danmaku| danmaku.time.as_millis())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(range(&mut source, 200, 250), vec![200, 200, 200]);
+        assert_eq!(range(&mut source, 150, 1000), vec![200, 200, 200, 300]);
+        assert_eq!(range(&mut source, 400, 1000), Vec::<i64>::new());
+        assert_eq!(range(&mut source, 300, 100), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn test_search() {
+        let danmaku = [("hello world", 300), ("goodbye", 100), ("hello", 200)]
+            .into_iter()
+            .map(|(content, time)| {
+                Danmaku::new(DanmakuTime::from_millis(time), DanmakuType::Scroll, content)
+            })
+            .collect();
+        let mut source = VecDanmakuSource::new(danmaku);
+        let query = SimpleFilter::new("hello".to_string());
+        let times: Vec<_> = search(&mut source, &query)
+            .map(|item| item.time.as_millis())
+            .collect();
+        assert_eq!(times, vec![200, 300]);
+    }
+
+    #[test]
+    fn test_vec_source_ids() {
+        let danmaku = [(200, Some(3)), (200, Some(1)), (100, None), (200, Some(3))]
+            .into_iter()
+            .map(|(time, id)| Danmaku {
+                id,
+                ..Danmaku::new(
+                    DanmakuTime::from_millis(time),
+                    DanmakuType::Scroll,
+                    time.to_string(),
+                )
+            })
+            .collect();
+        let mut source = VecDanmakuSource::new(danmaku);
+        let ids: Vec<_> = source.get_all().map(|danmaku| danmaku.id).collect();
+        assert_eq!(ids, vec![None, Some(1), Some(3), Some(3)]);
+
+        let mut source = source.dedup_by_id();
+        let ids: Vec<_> = source.get_all().map(|danmaku| danmaku.id).collect();
+        assert_eq!(ids, vec![None, Some(1), Some(3)]);
+    }
 }
@@ -0,0 +1,218 @@
+use crate::{
+    danmaku::{Danmaku, DanmakuTime},
+    style::DanmakuStyle,
+};
+
+use super::DanmakuSource;
+
+const ZERO_WIDTH: [char; 5] = ['\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}'];
+
+// The generic combining blocks used for "zalgo" text. Marks of scripts like Thai or Devanagari
+// are left alone, they stack legitimately.
+fn is_combining_mark(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0300}'..='\u{036F}'
+            | '\u{0483}'..='\u{0489}'
+            | '\u{1AB0}'..='\u{1AFF}'
+            | '\u{1DC0}'..='\u{1DFF}'
+            | '\u{20D0}'..='\u{20FF}'
+            | '\u{FE20}'..='\u{FE2F}'
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NormalizeRules {
+    // Removes control characters, including line breaks
+    pub strip_control: bool,
+    // Runs of the same character longer than this are cut and end with "…"
+    pub collapse_repeats: Option<usize>,
+    // "１２３" to "123"
+    pub half_width_digits: bool,
+    pub strip_zero_width: bool,
+    // Combining marks after the same base character beyond this are removed, stacked marks are
+    // slow to shape and draw far outside of the line
+    pub max_combining_marks: Option<usize>,
+}
+
+impl Default for NormalizeRules {
+    fn default() -> Self {
+        NormalizeRules {
+            strip_control: true,
+            collapse_repeats: Some(3),
+            half_width_digits: true,
+            strip_zero_width: true,
+            max_combining_marks: Some(2),
+        }
+    }
+}
+
+impl NormalizeRules {
+    pub fn normalize(&self, content: &str) -> String {
+        let mut result = String::with_capacity(content.len());
+        let mut last = None;
+        let mut repeats = 0;
+        let mut marks = 0;
+        for c in content.chars() {
+            if self.strip_control && c.is_control() {
+                continue;
+            }
+            if self.strip_zero_width && ZERO_WIDTH.contains(&c) {
+                continue;
+            }
+            if is_combining_mark(c) {
+                marks += 1;
+                if self.max_combining_marks.is_some_and(|max| marks > max) {
+                    continue;
+                }
+            } else {
+                marks = 0;
+            }
+            let c = match c {
+                '０'..='９' if self.half_width_digits => {
+                    char::from_u32(c as u32 - '０' as u32 + '0' as u32).unwrap()
+                }
+                c => c,
+            };
+            if last == Some(c) {
+                repeats += 1;
+            } else {
+                last = Some(c);
+                repeats = 1;
+            }
+            match self.collapse_repeats {
+                Some(max) if repeats == max + 1 => result.push('…'),
+                Some(max) if repeats > max => {}
+                _ => result.push(c),
+            }
+        }
+        result
+    }
+}
+
+// Applies the rules to the content of the inner source before layout, danmaku left empty are
+// dropped
+pub struct NormalizedDanmakuSource<Source: DanmakuSource> {
+    source: Source,
+    rules: NormalizeRules,
+    buffer: Vec<(Danmaku, Option<DanmakuStyle>)>,
+}
+
+impl<Source: DanmakuSource> NormalizedDanmakuSource<Source> {
+    pub fn new(source: Source, rules: NormalizeRules) -> Self {
+        NormalizedDanmakuSource {
+            source,
+            rules,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn fill_buffer<'a>(
+        buffer: &mut Vec<(Danmaku, Option<DanmakuStyle>)>,
+        rules: &NormalizeRules,
+        iterator: impl Iterator<Item = (&'a Danmaku, Option<DanmakuStyle>)>,
+    ) {
+        buffer.clear();
+        buffer.extend(iterator.filter_map(|(danmaku, style)| {
+            let content = rules.normalize(&danmaku.content);
+            (!content.is_empty()).then(|| {
+                let danmaku = Danmaku {
+                    content,
+                    ..danmaku.clone()
+                };
+                (danmaku, style)
+            })
+        }));
+    }
+}
+
+impl<Source: DanmakuSource> DanmakuSource for NormalizedDanmakuSource<Source> {
+    fn get_range(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        Box::new(
+            self.get_range_styled(start_included, end_excluded)
+                .map(|(danmaku, _)| danmaku),
+        )
+    }
+
+    fn get_range_styled(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = (&'_ Danmaku, Option<DanmakuStyle>)> + '_> {
+        Self::fill_buffer(
+            &mut self.buffer,
+            &self.rules,
+            self.source.get_range_styled(start_included, end_excluded),
+        );
+        Box::new(self.buffer.iter().map(|(danmaku, style)| (danmaku, *style)))
+    }
+
+    fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        Self::fill_buffer(
+            &mut self.buffer,
+            &self.rules,
+            self.source.get_all().map(|danmaku| (danmaku, None)),
+        );
+        Box::new(self.buffer.iter().map(|(danmaku, _)| danmaku))
+    }
+
+    fn into_all(self) -> Box<dyn Iterator<Item = Danmaku>> {
+        let rules = self.rules;
+        Box::new(self.source.into_all().filter_map(move |danmaku| {
+            let content = rules.normalize(&danmaku.content);
+            (!content.is_empty()).then_some(Danmaku { content, ..danmaku })
+        }))
+    }
+
+    fn take_changed(&mut self) -> Option<DanmakuTime> {
+        self.source.take_changed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NormalizeRules;
+
+    #[test]
+    fn test_normalize() {
+        let rules = NormalizeRules::default();
+        assert_eq!(rules.normalize("hhhhhh"), "hhh…");
+        assert_eq!(rules.normalize("hhh\u{200B}h"), "hhh…");
+        assert_eq!(rules.normalize("第１２３集\n"), "第123集");
+        assert_eq!(rules.normalize("\u{7}"), "");
+
+        let rules = NormalizeRules {
+            collapse_repeats: None,
+            half_width_digits: false,
+            ..Default::default()
+        };
+        assert_eq!(rules.normalize("hhhhhh１"), "hhhhhh１");
+    }
+
+    #[test]
+    fn test_combining_marks() {
+        let rules = NormalizeRules::default();
+        let zalgo = "h\u{0310}\u{0352}\u{0338}\u{0327}\u{0316}i\u{0346}\u{0489}\u{1DC4}";
+        assert_eq!(rules.normalize(zalgo), "h\u{0310}\u{0352}i\u{0346}\u{0489}");
+        // Decomposed Vietnamese keeps both of its marks
+        assert_eq!(rules.normalize("e\u{0302}\u{0301}"), "e\u{0302}\u{0301}");
+        // Repeated marks are cut before they count as repeats
+        let repeated = "a\u{0301}\u{0301}\u{0301}\u{0301}\u{0301}";
+        assert_eq!(rules.normalize(repeated), "a\u{0301}\u{0301}");
+        // Thai marks are not in the zalgo blocks
+        assert_eq!(rules.normalize("กิ่"), "กิ่");
+
+        let rules = NormalizeRules {
+            max_combining_marks: None,
+            ..Default::default()
+        };
+        assert_eq!(
+            rules.normalize("o\u{0308}\u{0308}\u{0308}"),
+            "o\u{0308}\u{0308}\u{0308}"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::sync::{
+    atomic::{AtomicI64, AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::danmaku::{Danmaku, DanmakuTime};
+
+use super::DanmakuSource;
+
+struct OffsetState {
+    offset_millis: AtomicI64,
+    // Bits of a f64
+    scale: AtomicU64,
+}
+
+// Adjusts the offset of an OffsetDanmakuSource which is already owned by a worker. Chunks
+// generated before the change keep the old times until the worker is restarted (e.g. by
+// WorkerManager::change_param).
+#[derive(Clone)]
+pub struct OffsetHandle(Arc<OffsetState>);
+
+impl OffsetHandle {
+    pub fn offset(&self) -> i64 {
+        self.0.offset_millis.load(Ordering::Relaxed)
+    }
+
+    pub fn set_offset(&self, offset_millis: i64) {
+        self.0.offset_millis.store(offset_millis, Ordering::Relaxed);
+    }
+
+    pub fn scale(&self) -> f64 {
+        f64::from_bits(self.0.scale.load(Ordering::Relaxed))
+    }
+
+    pub fn set_scale(&self, scale: f64) {
+        assert!(scale.is_finite() && scale > 0.0);
+        self.0.scale.store(scale.to_bits(), Ordering::Relaxed);
+    }
+
+    fn map(&self, time: DanmakuTime) -> DanmakuTime {
+        let millis = (time.as_millis() as f64 * self.scale()).round() as i64;
+        DanmakuTime::from_millis(millis.saturating_add(self.offset()))
+    }
+
+    fn unmap(&self, time: DanmakuTime) -> f64 {
+        time.as_millis().saturating_sub(self.offset()) as f64 / self.scale()
+    }
+}
+
+// Shifts the times of the inner source by offset_millis, after multiplying them by scale
+// (e.g. 25.0 / 23.976 for a frame-rate converted video)
+pub struct OffsetDanmakuSource<Source: DanmakuSource> {
+    source: Source,
+    handle: OffsetHandle,
+    buffer: Vec<Danmaku>,
+}
+
+impl<Source: DanmakuSource> OffsetDanmakuSource<Source> {
+    pub fn new(source: Source, offset_millis: i64, scale: f64) -> Self {
+        let handle = OffsetHandle(Arc::new(OffsetState {
+            offset_millis: AtomicI64::new(offset_millis),
+            scale: AtomicU64::new(1.0f64.to_bits()),
+        }));
+        handle.set_scale(scale);
+        OffsetDanmakuSource {
+            source,
+            handle,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn handle(&self) -> OffsetHandle {
+        self.handle.clone()
+    }
+
+    fn fill_buffer<'a>(
+        buffer: &mut Vec<Danmaku>,
+        handle: &OffsetHandle,
+        iterator: impl Iterator<Item = &'a Danmaku>,
+    ) {
+        buffer.clear();
+        buffer.extend(iterator.map(|danmaku| Danmaku {
+            time: handle.map(danmaku.time),
+            ..danmaku.clone()
+        }));
+    }
+}
+
+impl<Source: DanmakuSource> DanmakuSource for OffsetDanmakuSource<Source> {
+    fn get_range(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        // Widened by a millisecond to cover rounding, the result is filtered again below
+        let inner_start =
+            DanmakuTime::from_millis(self.handle.unmap(start_included).floor() as i64 - 1);
+        let inner_end = DanmakuTime::from_millis(self.handle.unmap(end_excluded).ceil() as i64 + 1);
+        Self::fill_buffer(
+            &mut self.buffer,
+            &self.handle,
+            self.source.get_range(inner_start, inner_end),
+        );
+        Box::new(
+            self.buffer.iter().filter(move |danmaku| {
+                danmaku.time >= start_included && danmaku.time < end_excluded
+            }),
+        )
+    }
+
+    fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        Self::fill_buffer(&mut self.buffer, &self.handle, self.source.get_all());
+        Box::new(self.buffer.iter())
+    }
+
+    fn into_all(self) -> Box<dyn Iterator<Item = Danmaku>> {
+        let handle = self.handle;
+        Box::new(self.source.into_all().map(move |danmaku| Danmaku {
+            time: handle.map(danmaku.time),
+            ..danmaku
+        }))
+    }
+
+    fn take_changed(&mut self) -> Option<DanmakuTime> {
+        self.source.take_changed().map(|time| self.handle.map(time))
+    }
+}
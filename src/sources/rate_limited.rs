@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use crate::{
+    danmaku::{Danmaku, DanmakuTime},
+    style::DanmakuStyle,
+};
+
+use super::DanmakuSource;
+
+const WINDOW_MILLIS: i64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    // Danmaku kept per second in the long run
+    pub per_second: f32,
+    // Danmaku kept at once after a quiet period
+    pub burst: f32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            per_second: 20.0,
+            burst: 40.0,
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f32,
+    // Time up to which the tokens are refilled
+    last_millis: i64,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: &RateLimit, until_millis: i64) {
+        let elapsed = (until_millis - self.last_millis).max(0) as f32 / 1000.0;
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.last_millis = until_millis;
+    }
+
+    // Keeps the danmaku the tokens allow in every second. Messages repeated in the same second
+    // go last, then longer ones.
+    fn select<T>(
+        &mut self,
+        limit: &RateLimit,
+        items: &mut Vec<T>,
+        end_millis: i64,
+        danmaku: impl Fn(&T) -> &Danmaku,
+    ) {
+        items.sort_by_key(|item| danmaku(item).time);
+        let mut keep = vec![false; items.len()];
+        let mut start = 0;
+        while start < items.len() {
+            let window = danmaku(&items[start])
+                .time
+                .as_millis()
+                .div_euclid(WINDOW_MILLIS);
+            let end = start
+                + items[start..]
+                    .iter()
+                    .take_while(|item| {
+                        danmaku(item).time.as_millis().div_euclid(WINDOW_MILLIS) == window
+                    })
+                    .count();
+            self.refill(limit, ((window + 1) * WINDOW_MILLIS).min(end_millis));
+
+            let mut seen = HashMap::new();
+            let mut ranks: Vec<_> = (start..end)
+                .map(|index| {
+                    let content = &danmaku(&items[index]).content;
+                    let repeats = seen.entry(content.as_str()).or_insert(0);
+                    *repeats += 1;
+                    (*repeats - 1, content.chars().count(), index)
+                })
+                .collect();
+            ranks.sort_unstable();
+            let count = (self.tokens.max(0.0) as usize).min(ranks.len());
+            for (_, _, index) in &ranks[..count] {
+                keep[*index] = true;
+            }
+            self.tokens -= count as f32;
+            start = end;
+        }
+        let mut keep = keep.into_iter();
+        items.retain(|_| keep.next().unwrap());
+    }
+}
+
+// Bounds the danmaku per second of the inner source with a token bucket, so a chat storm of a
+// live stream can't overload the layout. The tokens left at the end of a range carry over to the
+// range right after it, ranges after a seek start with a full bucket.
+pub struct RateLimitedDanmakuSource<Source: DanmakuSource> {
+    source: Source,
+    limit: RateLimit,
+    // End of the last range and the tokens left there
+    carry: Option<(DanmakuTime, f32)>,
+    buffer: Vec<(Danmaku, Option<DanmakuStyle>)>,
+}
+
+impl<Source: DanmakuSource> RateLimitedDanmakuSource<Source> {
+    pub fn new(source: Source, limit: RateLimit) -> Self {
+        RateLimitedDanmakuSource {
+            source,
+            limit,
+            carry: None,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn bucket(&self, start_included: DanmakuTime) -> TokenBucket {
+        let tokens = match self.carry {
+            Some((end, tokens)) if end == start_included => tokens,
+            _ => self.limit.burst,
+        };
+        TokenBucket {
+            tokens,
+            last_millis: start_included.as_millis(),
+        }
+    }
+}
+
+impl<Source: DanmakuSource> DanmakuSource for RateLimitedDanmakuSource<Source> {
+    fn get_range(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        Box::new(
+            self.get_range_styled(start_included, end_excluded)
+                .map(|(danmaku, _)| danmaku),
+        )
+    }
+
+    fn get_range_styled(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = (&'_ Danmaku, Option<DanmakuStyle>)> + '_> {
+        let mut bucket = self.bucket(start_included);
+        self.buffer.clear();
+        self.buffer.extend(
+            self.source
+                .get_range_styled(start_included, end_excluded)
+                .map(|(danmaku, style)| (danmaku.clone(), style)),
+        );
+        let end_millis = end_excluded.as_millis();
+        bucket.select(&self.limit, &mut self.buffer, end_millis, |(danmaku, _)| {
+            danmaku
+        });
+        bucket.refill(&self.limit, end_millis);
+        self.carry = Some((end_excluded, bucket.tokens));
+        Box::new(self.buffer.iter().map(|(danmaku, style)| (danmaku, *style)))
+    }
+
+    fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        let mut items: Vec<_> = self.source.get_all().collect();
+        if let Some(first) = items.iter().map(|danmaku| danmaku.time).min() {
+            let mut bucket = TokenBucket {
+                tokens: self.limit.burst,
+                last_millis: first.as_millis(),
+            };
+            bucket.select(&self.limit, &mut items, i64::MAX, |danmaku| danmaku);
+        }
+        Box::new(items.into_iter())
+    }
+
+    fn into_all(self) -> Box<dyn Iterator<Item = Danmaku>> {
+        let mut items: Vec<_> = self.source.into_all().collect();
+        if let Some(first) = items.iter().map(|danmaku| danmaku.time).min() {
+            let mut bucket = TokenBucket {
+                tokens: self.limit.burst,
+                last_millis: first.as_millis(),
+            };
+            bucket.select(&self.limit, &mut items, i64::MAX, |danmaku| danmaku);
+        }
+        Box::new(items.into_iter())
+    }
+
+    fn take_changed(&mut self) -> Option<DanmakuTime> {
+        let changed = self.source.take_changed();
+        if changed.is_some() {
+            // The ranges from the change on are requested again
+            self.carry = None;
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        danmaku::{Danmaku, DanmakuTime, DanmakuType},
+        sources::{DanmakuSource, VecDanmakuSource},
+    };
+
+    use super::{RateLimit, RateLimitedDanmakuSource};
+
+    #[test]
+    fn test_rate_limit() {
+        let danmaku = [
+            (100, "long message"),
+            (200, "hi"),
+            (300, "hi"),
+            (400, "ok"),
+            (500, "hello"),
+            (1100, "a"),
+            (1200, "b"),
+            (1300, "c"),
+            (2100, "d"),
+        ]
+        .into_iter()
+        .map(|(time, content)| {
+            Danmaku::new(DanmakuTime::from_millis(time), DanmakuType::Scroll, content)
+        })
+        .collect();
+        let limit = RateLimit {
+            per_second: 2.0,
+            burst: 3.0,
+        };
+        let mut source = RateLimitedDanmakuSource::new(VecDanmakuSource::new(danmaku), limit);
+        let mut range = |start, end| {
+            source
+                .get_range(
+                    DanmakuTime::from_millis(start),
+                    DanmakuTime::from_millis(end),
+                )
+                .map(|danmaku| danmaku.content.clone())
+                .collect::<Vec<_>>()
+        };
+        // The repeated "hi" and the longest message are dropped, in time order
+        assert_eq!(range(0, 1000), vec!["hi", "ok", "hello"]);
+        // The bucket continues from the last range
+        assert_eq!(range(1000, 2000), vec!["a", "b"]);
+        assert_eq!(range(2000, 3000), vec!["d"]);
+        // After a seek it starts full
+        assert_eq!(range(1000, 2000), vec!["a", "b", "c"]);
+    }
+}
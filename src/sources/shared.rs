@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use crate::danmaku::{Danmaku, DanmakuTime};
+
+use super::DanmakuSource;
+
+// Parsed danmaku shared between several workers, e.g. a fullscreen view and a
+// picture-in-picture view with different screen sizes. Each worker keeps its own
+// layout state and shapes the text with its own font system.
+#[derive(Clone)]
+pub struct SharedDanmakuSource(Arc<[Danmaku]>);
+
+impl SharedDanmakuSource {
+    pub fn new<Source: DanmakuSource>(source: Source) -> Self {
+        let mut vec: Vec<Danmaku> = source.into_all().collect();
+        vec.sort_by_key(Danmaku::order_key);
+        SharedDanmakuSource(vec.into())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl DanmakuSource for SharedDanmakuSource {
+    fn get_range(
+        &mut self,
+        start_included: DanmakuTime,
+        end_excluded: DanmakuTime,
+    ) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        let start = self
+            .0
+            .partition_point(|danmaku| danmaku.time < start_included);
+        let end = self
+            .0
+            .partition_point(|danmaku| danmaku.time < end_excluded);
+        Box::new(self.0[start..end.max(start)].iter())
+    }
+
+    fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+        Box::new(self.0.iter())
+    }
+
+    fn into_all(self) -> Box<dyn Iterator<Item = Danmaku>> {
+        let danmaku = self.0;
+        Box::new((0..danmaku.len()).map(move |index| danmaku[index].clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, io::Read, thread};
+
+    use crate::{
+        danmaku::DanmakuTime,
+        sources::{bilibili::parse_proto, DanmakuSource},
+    };
+
+    use super::SharedDanmakuSource;
+
+    #[test]
+    fn test_shared_source() {
+        let mut file = File::open("test/1176840.bin").unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        let mut source = SharedDanmakuSource::new(parse_proto(&content).unwrap());
+
+        let start = DanmakuTime::from_millis(10_000);
+        let end = DanmakuTime::from_millis(20_000);
+        let count = move |mut source: SharedDanmakuSource| source.get_range(start, end).count();
+
+        let other = source.clone();
+        let other_count = thread::spawn(move || count(other)).join().unwrap();
+        let expected = source
+            .get_all()
+            .filter(|danmaku| danmaku.time >= start && danmaku.time < end)
+            .count();
+        assert_eq!(count(source), expected);
+        assert_eq!(other_count, expected);
+    }
+}
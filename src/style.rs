@@ -0,0 +1,43 @@
+use crate::danmaku::{Danmaku, DanmakuColor};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DanmakuStyle {
+    pub color: Option<DanmakuColor>,
+    pub size_multiplier: f32,
+    pub opacity: f32,
+}
+
+impl Default for DanmakuStyle {
+    fn default() -> Self {
+        DanmakuStyle {
+            color: None,
+            size_multiplier: 1.0,
+            opacity: 1.0,
+        }
+    }
+}
+
+impl DanmakuStyle {
+    // Color of other takes precedence, multipliers are multiplied together
+    pub fn combine(&self, other: &DanmakuStyle) -> DanmakuStyle {
+        DanmakuStyle {
+            color: other.color.or(self.color),
+            size_multiplier: self.size_multiplier * other.size_multiplier,
+            opacity: self.opacity * other.opacity,
+        }
+    }
+}
+
+// Applied to every danmaku when its chunk is generated
+pub trait DanmakuStyler: Send + Sync {
+    fn style(&self, danmaku: &Danmaku) -> DanmakuStyle;
+}
+
+impl<F> DanmakuStyler for F
+where
+    F: Fn(&Danmaku) -> DanmakuStyle + Send + Sync,
+{
+    fn style(&self, danmaku: &Danmaku) -> DanmakuStyle {
+        self(danmaku)
+    }
+}
@@ -2,33 +2,59 @@ use std::{
     any::Any,
     error::Error,
     fmt::Display,
+    io,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    path::Path,
     sync::{
-        mpsc::{channel, Receiver, SendError, Sender},
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, RecvError, SendError, Sender, TryRecvError},
         Arc, Mutex,
     },
     thread::{spawn, JoinHandle},
     time::{Duration, Instant},
 };
 
-use cosmic_text::{AttrsList, FontSystem, ShapeBuffer};
-use log::{debug, log_enabled, warn, Level::Debug};
+use cosmic_text::{fontdb::Database, AttrsList, FontSystem};
+use log::{debug, warn};
 
 use crate::{
-    layout::LayoutMode,
-    manager::{DanmakuTimeChunk, DanmakuTimeChunkProvider},
+    cache::{layout_key, CachedLayout},
+    clock::SharedPlayback,
+    danmaku::{Danmaku, DanmakuTime, DanmakuType},
+    emote::EmoteProvider,
+    layout::{
+        track_lifetime, DanmakuPosition, LayoutMode, Rect, ScrollSpeedModel, StaticLimit,
+        TimedRegion,
+    },
+    manager::{
+        chunk_index, measure_line_height, ChunkError, DanmakuTimeChunk, DanmakuTimeChunkProvider,
+    },
+    shaper::{CosmicTextShaper, TextShaper},
     sources::DanmakuSource,
+    style::DanmakuStyler,
 };
 
+// Resources shared by the chunks of a backend, e.g. a glyph atlas. The worker calls prepare for
+// every chunk it lays out, with the shaper the glyphs have to be rasterized with, before building
+// the ChunkBuffer of the chunk.
 pub trait RenderCache: Sync + Send {
+    // The glyphs of a changed font (see DanmakuParam::font_changed) have to be dropped
     fn new_param(&mut self, new_param: DanmakuParam);
-    fn prepare(&mut self, font_system: &mut FontSystem, chunk: &DanmakuTimeChunk);
+    fn prepare(&mut self, shaper: &mut dyn TextShaper, chunk: &DanmakuTimeChunk);
     fn flush(&mut self) {}
+    // Buffers of chunks starting from the index are outdated
+    fn invalidate(&mut self, _from_index: u32) {}
 }
 
+// What a backend draws one chunk from, built on the worker thread after RenderCache::prepare and
+// put into the WorkerBuffer. DanmakuTimeChunk itself is one for backends drawing straight from
+// the items.
 pub trait ChunkBuffer<Cache: RenderCache>: Sync + Send {
     fn new(chunk: &Arc<DanmakuTimeChunk>, cache: &mut Cache) -> Arc<Self>;
     fn index(&self) -> u32;
     fn base_state_index(&self) -> u32;
+    // Number of danmaku of the chunk on the screen at the time
+    fn visible_count(&self, now: DanmakuTime, param: &DanmakuParam) -> usize;
 }
 
 impl<Cache: RenderCache> ChunkBuffer<Cache> for DanmakuTimeChunk {
@@ -43,11 +69,20 @@ impl<Cache: RenderCache> ChunkBuffer<Cache> for DanmakuTimeChunk {
     fn base_state_index(&self) -> u32 {
         self.base_state_index
     }
+
+    fn visible_count(&self, now: DanmakuTime, param: &DanmakuParam) -> usize {
+        DanmakuTimeChunk::visible_count(self, now, param)
+    }
 }
 
 #[derive(Debug)]
 enum WorkerRequest {
     Chunk(Option<u32>, u32),
+    InsertLocal(Box<Danmaku>),
+    BlockRegions(Vec<Rect>),
+    TimedRegions(Vec<TimedRegion>),
+    Indexing(bool),
+    Refresh,
     Stop,
 }
 
@@ -57,9 +92,14 @@ where
     Chunk: ChunkBuffer<Cache>,
 {
     pub cache: Cache,
+    // Chunks before previous, used when the chunk duration is shorter than the lifetime, or
+    // wide danmaku of older chunks are still on the screen
+    pub history: Vec<Arc<Chunk>>,
     pub previous: Option<Arc<Chunk>>,
     pub current: Option<Arc<Chunk>>,
     pub next: Option<Arc<Chunk>>,
+    // Index of the last chunk requested from the worker
+    requested_index: Option<u32>,
 }
 
 impl<Cache, Chunk> Default for WorkerBuffer<Cache, Chunk>
@@ -70,9 +110,11 @@ where
     fn default() -> Self {
         Self {
             cache: Default::default(),
+            history: Vec::new(),
             previous: None,
             current: None,
             next: None,
+            requested_index: None,
         }
     }
 }
@@ -85,12 +127,23 @@ where
     pub fn new(cache: Cache) -> Self {
         WorkerBuffer {
             cache,
+            history: Vec::new(),
             previous: None,
             current: None,
             next: None,
+            requested_index: None,
         }
     }
 
+    // Drops the buffers of the chunks, e.g. when they belong to a lost device. The worker
+    // builds them again on WorkerManager::refresh.
+    pub fn clear_chunks(&mut self) {
+        self.history.clear();
+        self.previous = None;
+        self.current = None;
+        self.next = None;
+    }
+
     pub fn should_request_worker(&self, index: u32) -> bool {
         if let Some((_, current)) = self.previous.as_ref().zip(self.current.as_ref()) {
             return current.index() != index;
@@ -103,6 +156,32 @@ where
         true
     }
 
+    // The worker hasn't served the last requested chunk yet, so the host is rendering outdated
+    // chunks (or nothing)
+    pub fn is_stale(&self) -> bool {
+        match self.requested_index {
+            Some(index) => self.acquire_index(index).is_none(),
+            None => false,
+        }
+    }
+
+    // The buffered chunks from the oldest to the newest, the order backends draw them in
+    pub fn chunks(&self) -> impl Iterator<Item = &Arc<Chunk>> {
+        self.history
+            .iter()
+            .chain(&self.previous)
+            .chain(&self.current)
+            .chain(&self.next)
+    }
+
+    // Number of danmaku on the screen at the time over all buffered chunks, e.g. for showing
+    // statistics or tuning the density
+    pub fn visible_count(&self, now: DanmakuTime, param: &DanmakuParam) -> usize {
+        self.chunks()
+            .map(|chunk| chunk.visible_count(now, param))
+            .sum()
+    }
+
     pub fn acquire_index(&self, index: u32) -> Option<(&Chunk, &Chunk)> {
         if let Some((previous, current)) = self.previous.as_ref().zip(self.current.as_ref()) {
             if current.index() == index {
@@ -127,150 +206,899 @@ where
     Chunk: ChunkBuffer<Cache>,
 {
     pub buffer: Arc<Mutex<WorkerBuffer<Cache, Chunk>>>,
-    pub font_system: FontSystem,
-    pub shape_buffer: ShapeBuffer,
+    pub shaper: Box<dyn TextShaper>,
     pub source: Box<dyn DanmakuSource + Send>,
+    pub emote_provider: Option<Arc<dyn EmoteProvider>>,
+    pub styler: Option<Arc<dyn DanmakuStyler>>,
+    // Danmaku sent by the user, kept across parameter changes
+    pub local_danmaku: Vec<Danmaku>,
+    // See WorkerManager::set_timed_regions, kept across parameter changes
+    pub timed_regions: Vec<TimedRegion>,
+    // See WorkerStateBuilder::cached_layouts
+    pub cached_layouts: Vec<CachedLayout>,
+}
+
+pub struct WorkerStateBuilder {
+    locale: String,
+    database: Database,
+    shaper: Option<Box<dyn TextShaper>>,
+    emote_provider: Option<Arc<dyn EmoteProvider>>,
+    styler: Option<Arc<dyn DanmakuStyler>>,
+    cached_layouts: Vec<CachedLayout>,
+}
+
+impl Default for WorkerStateBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-#[derive(Clone, Debug)]
+impl WorkerStateBuilder {
+    pub fn new() -> Self {
+        let (locale, database) = FontSystem::new().into_locale_and_db();
+        WorkerStateBuilder {
+            locale,
+            database,
+            shaper: None,
+            emote_provider: None,
+            styler: None,
+            cached_layouts: Vec::new(),
+        }
+    }
+
+    pub fn locale(mut self, locale: impl Into<String>) -> Self {
+        self.locale = locale.into();
+        self
+    }
+
+    pub fn load_font_file<P: AsRef<Path>>(mut self, path: P) -> Result<Self, io::Error> {
+        self.database.load_font_file(path)?;
+        Ok(self)
+    }
+
+    pub fn load_font_data(mut self, data: Vec<u8>) -> Self {
+        self.database.load_font_data(data);
+        self
+    }
+
+    pub fn load_fonts_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.database.load_fonts_dir(dir);
+        self
+    }
+
+    fn first_available<'a>(&self, families: &[&'a str]) -> Option<&'a str> {
+        families.iter().copied().find(|family| {
+            self.database
+                .faces()
+                .any(|face| face.families.iter().any(|(name, _)| name == family))
+        })
+    }
+
+    // The first installed family of the chain is used
+    pub fn serif_families(mut self, families: &[&str]) -> Self {
+        if let Some(family) = self.first_available(families) {
+            self.database.set_serif_family(family);
+        }
+        self
+    }
+
+    pub fn sans_serif_families(mut self, families: &[&str]) -> Self {
+        if let Some(family) = self.first_available(families) {
+            self.database.set_sans_serif_family(family);
+        }
+        self
+    }
+
+    pub fn monospace_families(mut self, families: &[&str]) -> Self {
+        if let Some(family) = self.first_available(families) {
+            self.database.set_monospace_family(family);
+        }
+        self
+    }
+
+    // Replaces the cosmic-text shaper, the fonts of the builder are unused then
+    pub fn shaper(mut self, shaper: Box<dyn TextShaper>) -> Self {
+        self.shaper = Some(shaper);
+        self
+    }
+
+    pub fn emote_provider(mut self, emote_provider: Arc<dyn EmoteProvider>) -> Self {
+        self.emote_provider = Some(emote_provider);
+        self
+    }
+
+    pub fn styler(mut self, styler: Arc<dyn DanmakuStyler>) -> Self {
+        self.styler = Some(styler);
+        self
+    }
+
+    // Layouts of a replay file, see ReplayFile::layouts. The one recorded with the param of the
+    // worker places the danmaku instead of the tracks.
+    pub fn cached_layouts(mut self, layouts: Vec<CachedLayout>) -> Self {
+        self.cached_layouts = layouts;
+        self
+    }
+
+    pub fn build<Cache, Chunk>(
+        self,
+        buffer: Arc<Mutex<WorkerBuffer<Cache, Chunk>>>,
+        source: Box<dyn DanmakuSource + Send>,
+    ) -> WorkerState<Cache, Chunk>
+    where
+        Cache: RenderCache,
+        Chunk: ChunkBuffer<Cache>,
+    {
+        let shaper = self.shaper.unwrap_or_else(|| {
+            let font_system = FontSystem::new_with_locale_and_db(self.locale, self.database);
+            Box::new(CosmicTextShaper::new(font_system))
+        });
+        WorkerState {
+            buffer,
+            shaper,
+            source,
+            emote_provider: self.emote_provider,
+            styler: self.styler,
+            local_danmaku: Vec::new(),
+            timed_regions: Vec::new(),
+            cached_layouts: self.cached_layouts,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LineHeight {
+    // In logical pixels
+    Fixed(u32),
+    // Multiple of the ascent + descent of the font, see DanmakuParam::resolve_line_height
+    Auto(f32),
+}
+
+// Handling of danmaku wider than a fraction of the screen width, long comments otherwise race
+// across the screen under ScrollSpeedModel::ConstantDuration
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WideDanmaku {
+    #[default]
+    Keep,
+    // Cut at the width, ending with an ellipsis
+    Ellipsize(f32),
+    // Broken into lines of the width, which take adjacent tracks
+    Wrap(f32),
+    // Scroll no faster than a danmaku of the width, see ScrollSpeedModel::CappedDuration
+    CapSpeed(f32),
+}
+
+impl WideDanmaku {
+    fn fraction(&self) -> Option<f32> {
+        match self {
+            WideDanmaku::Keep => None,
+            WideDanmaku::Ellipsize(fraction)
+            | WideDanmaku::Wrap(fraction)
+            | WideDanmaku::CapSpeed(fraction) => Some(*fraction),
+        }
+    }
+}
+
+// Handling of danmaku taller than the line height, e.g. large emoji or scripts with tall glyphs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TallDanmaku {
+    #[default]
+    Overflow,
+    // Take as many adjacent tracks as the glyphs cover
+    Expand,
+}
+
+// How the shadow spreads around the glyphs, up to the shadow size
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ShadowKernel {
+    // Fades out linearly with the distance to the glyph, a sharp outline
+    #[default]
+    Outline,
+    // Gaussian blur, softer and cheaper for large sizes
+    Gaussian,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DanmakuParam {
     pub screen_size: (u32, u32),
-    pub lifetime: Duration,
+    #[cfg_attr(feature = "serde", serde(with = "duration_millis"))]
+    pub scroll_lifetime: Duration,
+    #[cfg_attr(feature = "serde", serde(with = "duration_millis"))]
+    pub static_lifetime: Duration,
+    // Granularity of layout and prefetching, independent of the lifetimes
+    #[cfg_attr(feature = "serde", serde(with = "duration_millis"))]
+    pub chunk_duration: Duration,
     pub font_size: f32,
-    pub line_height: u32,
+    pub line_height: LineHeight,
+    // Not serialized, set the font attributes after loading
+    #[cfg_attr(feature = "serde", serde(skip, default = "default_font_attrs"))]
     pub font_attrs: AttrsList,
     pub layout_mode: LayoutMode,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub scroll_speed: ScrollSpeedModel,
+    // Minimum horizontal gap between scroll danmaku in the same track, in logical pixels
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub scroll_gap: u32,
+    // Scroll tracks move up to this fraction faster or slower than each other, e.g. 0.05
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub scroll_speed_variation: f32,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub wide_danmaku: WideDanmaku,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tall_danmaku: TallDanmaku,
+    // Screen areas kept free of danmaku, e.g. a banner or the subtitles, in physical pixels
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub blocked_regions: Vec<Rect>,
+    // Share of the top and bottom tracks the danmaku of a time window may take, against floods
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub static_limit: Option<StaticLimit>,
     pub shadow_size: u32,
     pub shadow_weight: f32,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub shadow_kernel: ShadowKernel,
+    pub scale_factor: f32,
+}
+
+#[cfg(feature = "serde")]
+fn default_font_attrs() -> AttrsList {
+    AttrsList::new(cosmic_text::Attrs::new())
+}
+
+// Lifetimes are written as milliseconds in config files
+#[cfg(feature = "serde")]
+mod duration_millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DanmakuParamError {
+    ZeroScreenSize,
+    ZeroLifetime,
+    ZeroChunkDuration,
+    ZeroLineHeight,
+    LineHeightTooLarge,
+    BadFontSize(f32),
+    BadLineHeightMultiplier(f32),
+    BadScaleFactor(f32),
+    BadShadowWeight(f32),
+    BadOverlapPercent(u32),
+    BadSpeedVariation(f32),
+    BadWideFraction(f32),
+    BadStaticRatio(f32),
+}
+
+impl Display for DanmakuParamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DanmakuParamError::ZeroScreenSize => write!(f, "Screen size must not be zero"),
+            DanmakuParamError::ZeroLifetime => write!(f, "Danmaku lifetime must not be zero"),
+            DanmakuParamError::ZeroChunkDuration => write!(f, "Chunk duration must not be zero"),
+            DanmakuParamError::ZeroLineHeight => write!(f, "Line height must not be zero"),
+            DanmakuParamError::LineHeightTooLarge => {
+                write!(f, "Line height is larger than the screen height")
+            }
+            DanmakuParamError::BadFontSize(size) => {
+                write!(f, "Font size must be a positive number, got {}", size)
+            }
+            DanmakuParamError::BadLineHeightMultiplier(multiplier) => {
+                write!(
+                    f,
+                    "Line height multiplier must be a positive number, got {}",
+                    multiplier
+                )
+            }
+            DanmakuParamError::BadScaleFactor(factor) => {
+                write!(f, "Scale factor must be a positive number, got {}", factor)
+            }
+            DanmakuParamError::BadShadowWeight(weight) => {
+                write!(f, "Shadow weight must not be negative, got {}", weight)
+            }
+            DanmakuParamError::BadOverlapPercent(percent) => {
+                write!(
+                    f,
+                    "Track percent must be between 0 and 100, got {}",
+                    percent
+                )
+            }
+            DanmakuParamError::BadSpeedVariation(variation) => {
+                write!(
+                    f,
+                    "Speed variation must be between 0 and 1, got {}",
+                    variation
+                )
+            }
+            DanmakuParamError::BadWideFraction(fraction) => {
+                write!(
+                    f,
+                    "Width of wide danmaku must be a positive fraction of the screen, got {}",
+                    fraction
+                )
+            }
+            DanmakuParamError::BadStaticRatio(ratio) => {
+                write!(
+                    f,
+                    "Ratio of static tracks must be between 0 and 1, got {}",
+                    ratio
+                )
+            }
+        }
+    }
+}
+
+impl Error for DanmakuParamError {}
+
+impl DanmakuParam {
+    pub fn validate(&self) -> Result<(), DanmakuParamError> {
+        if self.screen_size.0 == 0 || self.screen_size.1 == 0 {
+            return Err(DanmakuParamError::ZeroScreenSize);
+        }
+        if self.scroll_lifetime.as_millis() == 0 || self.static_lifetime.as_millis() == 0 {
+            return Err(DanmakuParamError::ZeroLifetime);
+        }
+        if self.chunk_duration.as_millis() == 0 {
+            return Err(DanmakuParamError::ZeroChunkDuration);
+        }
+        if !(self.font_size.is_finite() && self.font_size > 0.0) {
+            return Err(DanmakuParamError::BadFontSize(self.font_size));
+        }
+        if !(self.scale_factor.is_finite() && self.scale_factor > 0.0) {
+            return Err(DanmakuParamError::BadScaleFactor(self.scale_factor));
+        }
+        if let LineHeight::Auto(multiplier) = self.line_height {
+            if !(multiplier.is_finite() && multiplier > 0.0) {
+                return Err(DanmakuParamError::BadLineHeightMultiplier(multiplier));
+            }
+        }
+        if self.physical_line_height() == 0 {
+            return Err(DanmakuParamError::ZeroLineHeight);
+        }
+        if self.logical_line_height() > self.screen_size.1 {
+            return Err(DanmakuParamError::LineHeightTooLarge);
+        }
+        if !(self.shadow_weight.is_finite() && self.shadow_weight >= 0.0) {
+            return Err(DanmakuParamError::BadShadowWeight(self.shadow_weight));
+        }
+        if let LayoutMode::NoOverlap(percent) = self.layout_mode {
+            if percent > 100 {
+                return Err(DanmakuParamError::BadOverlapPercent(percent));
+            }
+        }
+        if !(0.0..1.0).contains(&self.scroll_speed_variation) {
+            return Err(DanmakuParamError::BadSpeedVariation(
+                self.scroll_speed_variation,
+            ));
+        }
+        if let Some(fraction) = self.wide_danmaku.fraction() {
+            if !(fraction.is_finite() && fraction > 0.0) {
+                return Err(DanmakuParamError::BadWideFraction(fraction));
+            }
+        }
+        if let Some(limit) = self.static_limit {
+            if !(0.0..=1.0).contains(&limit.max_ratio) {
+                return Err(DanmakuParamError::BadStaticRatio(limit.max_ratio));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn lifetime(&self, r#type: DanmakuType) -> Duration {
+        match r#type {
+            DanmakuType::Scroll => self.scroll_lifetime,
+            _ => self.static_lifetime,
+        }
+    }
+
+    // Time a danmaku of the given physical width stays on screen
+    pub fn item_lifetime(&self, position: DanmakuPosition, width: u32) -> Duration {
+        match position {
+            DanmakuPosition::Scroll(track) => {
+                let lifetime =
+                    track_lifetime(self.scroll_lifetime, track, self.scroll_speed_variation);
+                self.scroll_speed_model()
+                    .duration(self.screen_size.0, width, lifetime)
+            }
+            _ => self.static_lifetime,
+        }
+    }
+
+    pub fn font_changed(&self, other: &DanmakuParam) -> bool {
+        self.font_size != other.font_size
+            || self.font_attrs != other.font_attrs
+            || self.scale_factor != other.scale_factor
+    }
+
+    // Fields only the render caches use, the layout stays valid when they change
+    pub fn render_changed(&self, other: &DanmakuParam) -> bool {
+        self.shadow_size != other.shadow_size
+            || self.shadow_weight != other.shadow_weight
+            || self.shadow_kernel != other.shadow_kernel
+    }
+
+    // Whether the danmaku have to be laid out again by a new worker. The blocked regions are
+    // updated by the running worker.
+    pub fn layout_changed(&self, other: &DanmakuParam) -> bool {
+        let other = DanmakuParam {
+            blocked_regions: self.blocked_regions.clone(),
+            shadow_size: self.shadow_size,
+            shadow_weight: self.shadow_weight,
+            shadow_kernel: self.shadow_kernel,
+            ..other.clone()
+        };
+        *self != other
+    }
+
+    pub fn chunk_index(&self, time: DanmakuTime) -> u32 {
+        chunk_index(time, self.chunk_duration)
+    }
+
+    // How many chunks before the current one may still have danmaku on screen
+    pub fn lookback_chunks(&self) -> u32 {
+        // Under constant speed, danmaku up to the screen width are covered, wider ones are found
+        // with DanmakuTimeChunk::first_visible
+        let slowest = self
+            .scroll_lifetime
+            .div_f64(1.0 - self.scroll_speed_variation as f64);
+        let scroll_lifetime =
+            self.scroll_speed_model()
+                .duration(self.screen_size.0, self.screen_size.0, slowest);
+        let lifetime = scroll_lifetime.max(self.static_lifetime).as_millis();
+        let chunk_duration = self.chunk_duration.as_millis().max(1);
+        (lifetime.div_ceil(chunk_duration) as u32).max(1)
+    }
+
+    // Replaces LineHeight::Auto with the fixed height measured from the font
+    pub fn resolve_line_height(&self, shaper: &mut dyn TextShaper) -> DanmakuParam {
+        let line_height = match self.line_height {
+            LineHeight::Fixed(height) => height,
+            LineHeight::Auto(multiplier) => {
+                let height = measure_line_height(shaper, &self.font_attrs, self.font_size);
+                (height * multiplier).ceil() as u32
+            }
+        };
+        DanmakuParam {
+            line_height: LineHeight::Fixed(line_height),
+            ..self.clone()
+        }
+    }
+
+    // An unresolved LineHeight::Auto is estimated from the font size
+    pub fn logical_line_height(&self) -> u32 {
+        match self.line_height {
+            LineHeight::Fixed(height) => height,
+            LineHeight::Auto(multiplier) => (self.font_size * multiplier).ceil() as u32,
+        }
+    }
+
+    pub fn physical_font_size(&self) -> f32 {
+        self.font_size * self.scale_factor
+    }
+
+    pub fn physical_line_height(&self) -> u32 {
+        (self.logical_line_height() as f32 * self.scale_factor).round() as u32
+    }
+
+    pub fn physical_scroll_gap(&self) -> u32 {
+        (self.scroll_gap as f32 * self.scale_factor).round() as u32
+    }
+
+    // The scroll speed with WideDanmaku::CapSpeed applied
+    pub fn scroll_speed_model(&self) -> ScrollSpeedModel {
+        match (self.scroll_speed, self.wide_danmaku) {
+            (ScrollSpeedModel::ConstantDuration, WideDanmaku::CapSpeed(fraction)) => {
+                let max_width = (self.screen_size.0 as f32 * fraction).round() as u32;
+                ScrollSpeedModel::CappedDuration(max_width)
+            }
+            (model, _) => model,
+        }
+    }
+
+    // Logical width after which WideDanmaku::Ellipsize and Wrap break the lines
+    pub fn max_line_width(&self) -> Option<f32> {
+        match self.wide_danmaku {
+            WideDanmaku::Ellipsize(fraction) | WideDanmaku::Wrap(fraction) => {
+                Some(self.screen_size.0 as f32 * fraction / self.scale_factor)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn physical_shadow_size(&self) -> u32 {
+        (self.shadow_size as f32 * self.scale_factor).round() as u32
+    }
+}
+
+type WorkerCallback<Cache, Chunk> = (
+    Receiver<WorkerRequest>,
+    WorkerState<Cache, Chunk>,
+    Option<WorkerPanic>,
+);
+
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(index = now)))]
+fn generate_chunks<Cache, Chunk>(
+    provider: &mut DanmakuTimeChunkProvider,
+    shaper: &mut dyn TextShaper,
+    buffer: &Mutex<WorkerBuffer<Cache, Chunk>>,
+    lookback: u32,
+    start: Option<u32>,
+    now: u32,
+) -> Result<Duration, ChunkError>
+where
+    Cache: RenderCache,
+    Chunk: ChunkBuffer<Cache>,
+{
+    let start_time = Instant::now();
+    let mut start = start;
+    let mut history = Vec::new();
+    for index in now.saturating_sub(lookback)..now.saturating_sub(1) {
+        let chunk = provider.get_chunk(shaper, start, index)?;
+        start = Some(chunk.base_state_index);
+        debug!("Generated chunk #{}", index);
+        history.push(chunk);
+    }
+    let previous = if now > 0 {
+        let chunk = provider.get_chunk(shaper, start, now - 1)?;
+        start = Some(chunk.base_state_index);
+        debug!("Generated chunk #{}", now - 1);
+        Some(chunk)
+    } else {
+        None
+    };
+
+    let current = provider.get_chunk(shaper, start, now)?;
+    debug!("Generated chunk #{}", now);
+    // Wide danmaku from before the lookback may still be on the screen
+    let first_history = now.saturating_sub(lookback);
+    if current.first_visible() < first_history {
+        let mut older = Vec::new();
+        for index in current.first_visible()..first_history {
+            older.push(provider.get_chunk(shaper, start, index)?);
+        }
+        history.splice(0..0, older);
+    }
+    let next = provider.get_chunk(shaper, start, now + 1)?;
+    debug!("Generated chunk #{}", now + 1);
+
+    let mut buffer = buffer.lock().unwrap();
+    // The host locks the buffer every frame, a panic while preparing must not poison it
+    let prepared = catch_unwind(AssertUnwindSafe(|| {
+        for chunk in &history {
+            buffer.cache.prepare(shaper, chunk);
+        }
+        if let Some(previous) = &previous {
+            buffer.cache.prepare(shaper, previous)
+        }
+        buffer.cache.prepare(shaper, &current);
+        buffer.cache.prepare(shaper, &next);
+        buffer.cache.flush();
+        buffer.history = history
+            .iter()
+            .map(|chunk| Chunk::new(chunk, &mut buffer.cache))
+            .collect();
+        buffer.previous = previous
+            .as_ref()
+            .map(|previous| Chunk::new(previous, &mut buffer.cache));
+        buffer.current = Some(Chunk::new(&current, &mut buffer.cache));
+        buffer.next = Some(Chunk::new(&next, &mut buffer.cache));
+    }));
+    if let Err(panic) = prepared {
+        buffer.cache.invalidate(0);
+        drop(buffer);
+        resume_unwind(panic);
+    }
+    drop(buffer);
+    let generate_time = start_time.elapsed();
+    debug!("Generated chunk #{}, time: {:?}", now, generate_time);
+    Ok(generate_time)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IndexingProgress {
+    // Chunks laid out from the start of the source
+    pub indexed: u32,
+    pub total: u32,
+}
+
+impl IndexingProgress {
+    pub fn is_finished(&self) -> bool {
+        self.indexed >= self.total
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WorkerStatus {
+    // Requests sent to the worker which it hasn't started working on
+    pub queue_depth: usize,
+    // Time spent on generating the chunks of the last request
+    pub last_generation_time: Option<Duration>,
+    // See WorkerBuffer::is_stale
+    pub stale: bool,
+    // None unless WorkerManager::start_indexing was called, kept after finishing
+    pub indexing: Option<IndexingProgress>,
+}
+
+#[derive(Default)]
+struct WorkerStats {
+    queue_depth: AtomicUsize,
+    last_generation_time: Mutex<Option<Duration>>,
+    last_error: Mutex<Option<ChunkError>>,
+    indexing: Mutex<Option<IndexingProgress>>,
+    events: Mutex<Vec<WorkerEvent>>,
+}
+
+// Restarts in a row after panics of the worker before it is left stopped, so a chunk which
+// panics every time doesn't restart it forever
+const MAX_RESTARTS: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WorkerEvent {
+    // The worker panicked and was restarted with the same source, the message is the one of the
+    // panic. The chunks are laid out again.
+    Restarted(String),
+    // The worker panicked too many times in a row and stays stopped, see WorkerManager::is_alive
+    Stopped(String),
+}
+
+struct WorkerPanic {
+    message: String,
+    // A chunk was generated before the panic
+    served: bool,
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    match panic.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => "Unknown panic".to_string(),
+    }
+}
+
+// Next chunk to lay out in the background, while the worker has no requests
+struct IndexingCursor {
+    next: u32,
+    last: u32,
+}
+
+impl IndexingCursor {
+    fn start(provider: &mut DanmakuTimeChunkProvider) -> Self {
+        IndexingCursor {
+            next: 0,
+            last: provider.last_index(),
+        }
+    }
+
+    // Chunks from the index on were dropped by the provider
+    fn rewind(&mut self, index: u32) {
+        self.next = self.next.min(index);
+    }
+
+    fn progress(&self) -> IndexingProgress {
+        IndexingProgress {
+            indexed: self.next,
+            total: self.last.saturating_add(1),
+        }
+    }
 }
 
-type WorkerCallback<Cache, Chunk> = (Receiver<WorkerRequest>, WorkerState<Cache, Chunk>);
+// The chunk the playback reaches after the buffered ones, None while paused. Chunks before the
+// lookback are needed first when playing backwards.
+fn prefetch_index(
+    playback: &SharedPlayback,
+    chunk_duration: Duration,
+    lookback: u32,
+) -> Option<u32> {
+    if playback.is_paused() {
+        return None;
+    }
+    let now = chunk_index(playback.time(), chunk_duration);
+    if playback.speed() < 0.0 {
+        now.checked_sub(lookback + 1)
+    } else {
+        now.checked_add(2)
+    }
+}
 
 fn worker_thread<Cache, Chunk>(
     rx: Receiver<WorkerRequest>,
     param: DanmakuParam,
     mut state: WorkerState<Cache, Chunk>,
+    stats: Arc<WorkerStats>,
+    playback: SharedPlayback,
 ) -> WorkerCallback<Cache, Chunk>
 where
     Cache: RenderCache,
     Chunk: ChunkBuffer<Cache>,
 {
-    let mut provider = DanmakuTimeChunkProvider::new(
-        param.lifetime,
-        param.font_size,
-        param.font_attrs,
-        param.screen_size,
-        param.line_height,
-        param.layout_mode,
-        state.source,
-    );
-    loop {
-        let request = rx.recv();
-        if let Ok(request) = &request {
-            debug!("Worker request: {:?}", request);
-        }
-        match request {
-            Ok(WorkerRequest::Chunk(start, now)) => {
-                let start_time = if log_enabled!(Debug) {
-                    Some(Instant::now())
-                } else {
-                    None
-                };
-                let mut start = start;
-                let previous = if now > 0 {
-                    let chunk = provider.get_chunk(
-                        &mut state.font_system,
-                        &mut state.shape_buffer,
-                        start,
-                        now - 1,
-                    );
-                    let chunk = match chunk {
-                        Ok(chunk) => chunk,
-                        Err(err) => {
-                            warn!("Fetch chunk failed: {:?}", err);
+    let lookback = param.lookback_chunks();
+    let chunk_duration = param.chunk_duration;
+    let key = layout_key(&param);
+    let cached_layout = state
+        .cached_layouts
+        .iter()
+        .find(|layout| layout.key == key)
+        .cloned();
+    let mut provider = DanmakuTimeChunkProvider::new(param, state.source)
+        .with_emote_provider(state.emote_provider.clone())
+        .with_styler(state.styler.clone())
+        .with_local_danmaku(state.local_danmaku)
+        .with_timed_regions(state.timed_regions.clone())
+        .with_cached_layout(cached_layout);
+    let mut last_request = None;
+    let mut indexing: Option<IndexingCursor> = None;
+    // Laid out once the queue is empty, so the chunk is ready when the playback gets there
+    let mut prefetch: Option<u32> = None;
+    // Whether a chunk was generated since the start, see WorkerManager::restart_if_panicked
+    let mut served = false;
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        loop {
+            let request = if indexing.is_some() || prefetch.is_some() {
+                // Requests go first, the background layout continues whenever the queue is empty
+                match rx.try_recv() {
+                    Ok(request) => Ok(request),
+                    Err(TryRecvError::Empty) => {
+                        if let Some(index) = prefetch.take() {
+                            match provider.get_chunk(state.shaper.as_mut(), None, index) {
+                                Ok(_) => debug!("Prefetched chunk #{}", index),
+                                Err(err) => warn!("Prefetch chunk failed: {}", err),
+                            }
                             continue;
                         }
-                    };
-                    start = Some(chunk.base_state_index);
-                    debug!("Generated chunk #{}", now - 1);
-                    Some(chunk)
-                } else {
-                    None
-                };
-
-                let current =
-                    provider.get_chunk(&mut state.font_system, &mut state.shape_buffer, start, now);
-                let current = match current {
-                    Ok(current) => {
-                        debug!("Generated chunk #{}", now);
-                        current
-                    }
-                    Err(err) => {
-                        warn!("Fetch chunk failed: {:?}", err);
+                        let Some(cursor) = &mut indexing else {
+                            continue;
+                        };
+                        let result = provider.get_chunk(state.shaper.as_mut(), None, cursor.next);
+                        match result {
+                            Ok(_) => {
+                                debug!("Indexed chunk #{}", cursor.next);
+                                cursor.next += 1;
+                                *stats.indexing.lock().unwrap() = Some(cursor.progress());
+                                if cursor.next > cursor.last {
+                                    indexing = None;
+                                }
+                            }
+                            Err(err) => {
+                                warn!("Index chunk failed: {}", err);
+                                *stats.last_error.lock().unwrap() = Some(err);
+                                indexing = None;
+                            }
+                        }
                         continue;
                     }
-                };
-
-                let next = provider.get_chunk(
-                    &mut state.font_system,
-                    &mut state.shape_buffer,
+                    Err(TryRecvError::Disconnected) => Err(RecvError),
+                }
+            } else {
+                rx.recv()
+            };
+            if let Ok(request) = &request {
+                debug!("Worker request: {:?}", request);
+                stats.queue_depth.fetch_sub(1, Ordering::Relaxed);
+            }
+            let regenerate = match request {
+                Ok(WorkerRequest::Chunk(start, now)) => {
+                    provider.set_current(now);
+                    last_request = Some((start, now));
+                    last_request
+                }
+                Ok(WorkerRequest::InsertLocal(danmaku)) => {
+                    let index = provider.insert_local(*danmaku);
+                    state.buffer.lock().unwrap().cache.invalidate(index);
+                    if let Some(cursor) = &mut indexing {
+                        cursor.rewind(index);
+                    }
+                    last_request
+                }
+                Ok(WorkerRequest::BlockRegions(regions)) => {
+                    // Lays out the current chunk again, earlier danmaku finish their way across the
+                    // screen. The glyphs stay in the cache.
+                    let index = last_request.map_or(0, |(_, now)| now);
+                    provider.set_blocked_regions(regions, index);
+                    state.buffer.lock().unwrap().cache.invalidate(index);
+                    if let Some(cursor) = &mut indexing {
+                        cursor.rewind(index);
+                    }
+                    last_request
+                }
+                Ok(WorkerRequest::TimedRegions(regions)) => {
+                    let current = last_request.map_or(0, |(_, now)| now);
+                    state.timed_regions = regions.clone();
+                    if let Some(index) = provider.set_timed_regions(regions, current) {
+                        state.buffer.lock().unwrap().cache.invalidate(index);
+                        if let Some(cursor) = &mut indexing {
+                            cursor.rewind(index);
+                        }
+                    }
+                    last_request
+                }
+                Ok(WorkerRequest::Indexing(enabled)) => {
+                    indexing = enabled.then(|| IndexingCursor::start(&mut provider));
+                    *stats.indexing.lock().unwrap() =
+                        indexing.as_ref().map(IndexingCursor::progress);
+                    None
+                }
+                Ok(WorkerRequest::Refresh) => last_request,
+                Ok(WorkerRequest::Stop) => break,
+                Err(_) => {
+                    warn!("Receive message from main thread failed, is main thread dead?");
+                    break;
+                }
+            };
+            if let Some(index) = provider.take_source_changes() {
+                state.buffer.lock().unwrap().cache.invalidate(index);
+                // The source may have grown past the last chunk
+                if let Some(cursor) = &mut indexing {
+                    cursor.rewind(index);
+                    cursor.last = provider.last_index();
+                }
+            }
+            if let Some((start, now)) = regenerate {
+                let generation_time = generate_chunks(
+                    &mut provider,
+                    state.shaper.as_mut(),
+                    &state.buffer,
+                    lookback,
                     start,
-                    now + 1,
+                    now,
                 );
-                let next = match next {
-                    Ok(next) => {
-                        debug!("Generated chunk #{}", now + 1);
-                        next
+                match generation_time {
+                    Ok(generation_time) => {
+                        *stats.last_generation_time.lock().unwrap() = Some(generation_time);
+                        served = true;
+                        prefetch = prefetch_index(&playback, chunk_duration, lookback);
                     }
                     Err(err) => {
-                        warn!("Fetch chunk failed: {:?}", err);
-                        continue;
+                        warn!("Fetch chunk failed: {}", err);
+                        *stats.last_error.lock().unwrap() = Some(err);
                     }
-                };
-
-                let mut buffer = state.buffer.lock().unwrap();
-                if let Some(previous) = &previous {
-                    buffer.cache.prepare(&mut state.font_system, previous)
-                }
-                buffer.cache.prepare(&mut state.font_system, &current);
-                buffer.cache.prepare(&mut state.font_system, &next);
-                buffer.cache.flush();
-                buffer.previous = previous.map(|previous| Chunk::new(&previous, &mut buffer.cache));
-                buffer.current = Some(Chunk::new(&current, &mut buffer.cache));
-                buffer.next = Some(Chunk::new(&next, &mut buffer.cache));
-                drop(buffer);
-                if let Some(start_time) = start_time {
-                    let generate_time = start_time.elapsed();
-                    debug!("Generated chunk #{}, time: {:?}", now, generate_time);
                 }
             }
-            Ok(WorkerRequest::Stop) => break,
-            Err(_) => {
-                warn!("Receive message from main thread failed, is main thread dead?");
-                break;
-            }
         }
-    }
+    }));
+    let panic = result.err().map(|panic| {
+        let message = panic_message(panic.as_ref());
+        warn!("Worker thread panicked: {}", message);
+        WorkerPanic { message, served }
+    });
+    let (source, local_danmaku) = provider.into_parts();
     (
         rx,
         WorkerState {
-            source: provider.source(),
+            source,
+            local_danmaku,
             ..state
         },
+        panic,
     )
 }
 
 #[derive(Debug)]
 pub enum WorkerError {
     JoinError,
-    SendError,
+    // The worker thread has exited, see WorkerManager::is_alive
+    ChannelClosed,
+    InvalidParam(DanmakuParamError),
 }
 
 impl Display for WorkerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             WorkerError::JoinError => write!(f, "Worker thread panicked"),
-            WorkerError::SendError => write!(f, "Failed to send message to worker"),
+            WorkerError::ChannelClosed => write!(f, "Worker thread has exited"),
+            WorkerError::InvalidParam(err) => write!(f, "Invalid danmaku param: {}", err),
         }
     }
 }
@@ -283,9 +1111,15 @@ impl From<Box<dyn Any + Send + 'static>> for WorkerError {
     }
 }
 
+impl From<DanmakuParamError> for WorkerError {
+    fn from(err: DanmakuParamError) -> Self {
+        WorkerError::InvalidParam(err)
+    }
+}
+
 impl From<SendError<WorkerRequest>> for WorkerError {
     fn from(_: SendError<WorkerRequest>) -> Self {
-        WorkerError::SendError
+        WorkerError::ChannelClosed
     }
 }
 
@@ -297,6 +1131,15 @@ where
     sender: Sender<WorkerRequest>,
     thread_handle: Mutex<Option<JoinHandle<WorkerCallback<Cache, Chunk>>>>,
     last_request: Option<(Option<u32>, u32)>,
+    buffer: Arc<Mutex<WorkerBuffer<Cache, Chunk>>>,
+    stats: Arc<WorkerStats>,
+    playback: SharedPlayback,
+    param: DanmakuParam,
+    // Line height of the param before resolving it
+    requested_line_height: LineHeight,
+    indexing: bool,
+    // Restarts since the worker last generated a chunk
+    restarts: u32,
 }
 
 impl<Cache, Chunk> WorkerManager<Cache, Chunk>
@@ -304,32 +1147,203 @@ where
     Cache: RenderCache + 'static,
     Chunk: ChunkBuffer<Cache> + 'static,
 {
-    pub fn new(param: DanmakuParam, state: WorkerState<Cache, Chunk>) -> Self {
+    pub fn new(param: DanmakuParam, mut state: WorkerState<Cache, Chunk>) -> Self {
+        let requested_line_height = param.line_height;
+        let resolved = param.resolve_line_height(state.shaper.as_mut());
+        if resolved.line_height != param.line_height {
+            state
+                .buffer
+                .lock()
+                .unwrap()
+                .cache
+                .new_param(resolved.clone());
+        }
+        let param = resolved;
+        let thread_param = param.clone();
         let (sender, receiver) = channel();
-        let thread_handle = spawn(move || worker_thread(receiver, param, state));
+        let buffer = state.buffer.clone();
+        let stats = Arc::new(WorkerStats::default());
+        let thread_stats = stats.clone();
+        let playback = SharedPlayback::new();
+        let thread_playback = playback.clone();
+        let thread_handle = spawn(move || {
+            worker_thread(receiver, thread_param, state, thread_stats, thread_playback)
+        });
         WorkerManager {
             sender,
             thread_handle: Mutex::new(Some(thread_handle)),
             last_request: None,
+            buffer,
+            stats,
+            playback,
+            param,
+            requested_line_height,
+            indexing: false,
+            restarts: 0,
+        }
+    }
+
+    // The param used by the worker, with the line height resolved. Pass this one to the
+    // renderers so that they agree with the layout.
+    pub fn param(&self) -> &DanmakuParam {
+        &self.param
+    }
+
+    // Shared with the worker, which prefetches in the direction of the playback. The time is
+    // stored by tick, set the speed and the pause state as the player changes them, e.g. with
+    // SharedPlayback::update.
+    pub fn playback(&self) -> &SharedPlayback {
+        &self.playback
+    }
+
+    fn send(&self, request: WorkerRequest) -> Result<(), SendError<WorkerRequest>> {
+        self.stats.queue_depth.fetch_add(1, Ordering::Relaxed);
+        self.sender.send(request).inspect_err(|_| {
+            self.stats.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    pub fn status(&self) -> WorkerStatus {
+        WorkerStatus {
+            queue_depth: self.stats.queue_depth.load(Ordering::Relaxed),
+            last_generation_time: *self.stats.last_generation_time.lock().unwrap(),
+            stale: self.buffer.lock().unwrap().is_stale(),
+            indexing: *self.stats.indexing.lock().unwrap(),
+        }
+    }
+
+    // False once the worker thread has exited, e.g. after a panic. Panicked workers are restarted
+    // by the next tick, unless they panicked too many times in a row. Requests fail with
+    // WorkerError::ChannelClosed then.
+    pub fn is_alive(&self) -> bool {
+        self.thread_handle
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    // The last error of the worker since the previous call, the worker keeps serving later
+    // requests after a failure
+    pub fn take_error(&self) -> Option<ChunkError> {
+        self.stats.last_error.lock().unwrap().take()
+    }
+
+    // Restarts and stops of the worker since the previous call, in order
+    pub fn take_events(&self) -> Vec<WorkerEvent> {
+        std::mem::take(&mut self.stats.events.lock().unwrap())
+    }
+
+    // Starts the worker again with the source, local danmaku and render cache of the panicked
+    // one. The requests queued meanwhile are served by the new worker.
+    fn restart_if_panicked(&mut self) -> Result<(), WorkerError> {
+        let mut thread_handle = self.thread_handle.lock().unwrap();
+        if !thread_handle.as_ref().is_some_and(JoinHandle::is_finished) {
+            return Ok(());
+        }
+        let (receiver, state, panic) = thread_handle.take().unwrap().join()?;
+        let Some(panic) = panic else {
+            return Ok(());
+        };
+        self.restarts = if panic.served { 1 } else { self.restarts + 1 };
+        let mut events = self.stats.events.lock().unwrap();
+        if self.restarts > MAX_RESTARTS {
+            warn!("Worker panicked {} times in a row, stopping", self.restarts);
+            events.push(WorkerEvent::Stopped(panic.message));
+            return Ok(());
+        }
+        events.push(WorkerEvent::Restarted(panic.message));
+        drop(events);
+        let param = self.param.clone();
+        let stats = self.stats.clone();
+        let playback = self.playback.clone();
+        *thread_handle = Some(spawn(move || {
+            worker_thread(receiver, param, state, stats, playback)
+        }));
+        drop(thread_handle);
+        // The request which panicked is sent again by the tick
+        self.last_request = None;
+        if self.indexing {
+            self.send(WorkerRequest::Indexing(true))?;
         }
+        Ok(())
     }
 
     pub fn request(
         &mut self,
         state_begin_index: Option<u32>,
         index: u32,
-    ) -> Result<(), impl Error> {
+    ) -> Result<(), WorkerError> {
         if Some((state_begin_index, index)) == self.last_request {
             return Ok(());
         }
+        self.buffer.lock().unwrap().requested_index = Some(index);
         let request = WorkerRequest::Chunk(state_begin_index, index);
-        self.sender.send(request)?;
+        self.send(request)?;
         self.last_request = Some((state_begin_index, index));
-        Ok::<(), SendError<_>>(())
+        Ok(())
+    }
+
+    // Requests the chunk of the playback time unless the buffer already holds it, continuing from
+    // the layout state of the chunks on screen. Call it every frame with the current time.
+    pub fn tick(&mut self, now: DanmakuTime) -> Result<(), WorkerError> {
+        self.restart_if_panicked()?;
+        self.playback.set_time(now);
+        let index = self.param.chunk_index(now);
+        let buffer = self.buffer.lock().unwrap();
+        if !buffer.should_request_worker(index) {
+            return Ok(());
+        }
+        let base_state_index = buffer
+            .acquire_index(index)
+            .map(|(previous, _)| previous.base_state_index());
+        drop(buffer);
+        self.request(base_state_index, index)
+    }
+
+    // Shows a danmaku sent by the user right away, without waiting for the source to be reloaded
+    pub fn insert_local(&mut self, danmaku: Danmaku) -> Result<(), WorkerError> {
+        self.send(WorkerRequest::InsertLocal(Box::new(danmaku)))?;
+        Ok(())
+    }
+
+    // Replaces the regions static danmaku avoid during their time ranges, e.g. the subtitles
+    // found by OCR. The chunks placing danmaku shown with a changed region are laid out again.
+    pub fn set_timed_regions(&mut self, regions: Vec<TimedRegion>) -> Result<(), WorkerError> {
+        self.send(WorkerRequest::TimedRegions(regions))?;
+        Ok(())
+    }
+
+    // Lays out the chunks of the whole source one by one while no chunks are requested, so seeking
+    // anywhere later is instant. The layouts are kept by the worker, the render cache only
+    // prepares the chunks requested for the screen. See WorkerStatus::indexing for the progress.
+    pub fn start_indexing(&mut self) -> Result<(), WorkerError> {
+        self.send(WorkerRequest::Indexing(true))?;
+        self.indexing = true;
+        Ok(())
+    }
+
+    // The chunks indexed so far stay laid out
+    pub fn cancel_indexing(&mut self) -> Result<(), WorkerError> {
+        self.send(WorkerRequest::Indexing(false))?;
+        self.indexing = false;
+        Ok(())
+    }
+
+    // Generates the requested chunks again if the source changed, e.g. after a live source
+    // received new danmaku
+    pub fn refresh(&mut self) -> Result<(), WorkerError> {
+        self.send(WorkerRequest::Refresh)?;
+        Ok(())
     }
 
     pub fn change_param(&mut self, new_param: DanmakuParam) -> Result<(), WorkerError> {
-        self.sender.send(WorkerRequest::Stop)?;
+        new_param.validate()?;
+        self.restart_if_panicked()?;
+        if self.update_without_restart(&new_param)? {
+            return Ok(());
+        }
+        self.send(WorkerRequest::Stop)?;
         let mut thread_handle = self.thread_handle.lock().unwrap();
         let handle = thread_handle.take();
         let handle = match handle {
@@ -339,25 +1353,71 @@ where
                 return Ok(());
             }
         };
-        let (receiver, state) = handle.join()?;
+        let (receiver, mut state, _) = handle.join()?;
+        self.requested_line_height = new_param.line_height;
+        let new_param = new_param.resolve_line_height(state.shaper.as_mut());
 
         let mut state_lock = state.buffer.lock().unwrap();
         state_lock.cache.new_param(new_param.clone());
         drop(state_lock);
 
-        let new_thread_handle = spawn(move || worker_thread(receiver, new_param, state));
+        self.param = new_param.clone();
+        let stats = self.stats.clone();
+        let playback = self.playback.clone();
+        let new_thread_handle =
+            spawn(move || worker_thread(receiver, new_param, state, stats, playback));
         *thread_handle = Some(new_thread_handle);
         if let Some(last_request) = self.last_request {
-            self.sender
-                .send(WorkerRequest::Chunk(last_request.0, last_request.1))?;
+            self.send(WorkerRequest::Chunk(last_request.0, last_request.1))?;
+        }
+        // The layouts of the old param are gone, index again with the new one
+        if self.indexing {
+            self.send(WorkerRequest::Indexing(true))?;
         }
         Ok(())
     }
 
+    // Blocked regions go to the running worker and render-only fields to the cache, returns false
+    // when the layout changed and the worker has to be restarted
+    fn update_without_restart(&mut self, new_param: &DanmakuParam) -> Result<bool, WorkerError> {
+        // The param of the manager has the line height resolved
+        let line_height = if new_param.line_height == self.requested_line_height {
+            self.param.line_height
+        } else {
+            new_param.line_height
+        };
+        let new_param = DanmakuParam {
+            line_height,
+            ..new_param.clone()
+        };
+        if new_param.layout_changed(&self.param) {
+            return Ok(false);
+        }
+        let regions_changed = new_param.blocked_regions != self.param.blocked_regions;
+        if new_param.render_changed(&self.param) {
+            self.buffer
+                .lock()
+                .unwrap()
+                .cache
+                .new_param(new_param.clone());
+            // The chunks on the screen are prepared again with the cached layouts
+            if !regions_changed {
+                self.send(WorkerRequest::Refresh)?;
+            }
+        }
+        if regions_changed {
+            self.send(WorkerRequest::BlockRegions(
+                new_param.blocked_regions.clone(),
+            ))?;
+        }
+        self.param = new_param;
+        Ok(true)
+    }
+
     pub fn into_state(self) -> Result<WorkerState<Cache, Chunk>, WorkerError> {
-        self.sender.send(WorkerRequest::Stop)?;
+        self.send(WorkerRequest::Stop)?;
         let mut thread_handle = self.thread_handle.lock().unwrap();
-        let (_, state) = thread_handle.take().unwrap().join()?;
+        let (_, state, _) = thread_handle.take().unwrap().join()?;
         Ok(state)
     }
 }
@@ -375,3 +1435,390 @@ where
         let _result = self.sender.send(WorkerRequest::Stop);
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use cosmic_text::{Attrs, AttrsList, FontSystem};
+
+    use crate::{
+        clock::SharedPlayback,
+        danmaku::{Danmaku, DanmakuTime},
+        layout::{LayoutMode, ScrollSpeedModel},
+        manager::DanmakuTimeChunk,
+        renderer::noop::NoopRenderCache,
+        shaper::CosmicTextShaper,
+        sources::{bilibili::parse_xml_from_file, DanmakuSource},
+    };
+
+    use super::{
+        prefetch_index, DanmakuParam, DanmakuParamError, LineHeight, ShadowKernel, TallDanmaku,
+        WideDanmaku, WorkerBuffer, WorkerError, WorkerEvent, WorkerManager, WorkerState,
+    };
+
+    #[test]
+    fn test_validate_param() {
+        let param = DanmakuParam {
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        assert_eq!(param.validate(), Ok(()));
+
+        let zero_line_height = DanmakuParam {
+            line_height: LineHeight::Fixed(0),
+            ..param.clone()
+        };
+        assert_eq!(
+            zero_line_height.validate(),
+            Err(DanmakuParamError::ZeroLineHeight)
+        );
+
+        let zero_lifetime = DanmakuParam {
+            static_lifetime: Duration::ZERO,
+            ..param.clone()
+        };
+        assert_eq!(
+            zero_lifetime.validate(),
+            Err(DanmakuParamError::ZeroLifetime)
+        );
+
+        let bad_multiplier = DanmakuParam {
+            line_height: LineHeight::Auto(-1.0),
+            ..param.clone()
+        };
+        assert_eq!(
+            bad_multiplier.validate(),
+            Err(DanmakuParamError::BadLineHeightMultiplier(-1.0))
+        );
+
+        let bad_mode = DanmakuParam {
+            layout_mode: LayoutMode::NoOverlap(150),
+            ..param
+        };
+        assert_eq!(
+            bad_mode.validate(),
+            Err(DanmakuParamError::BadOverlapPercent(150))
+        );
+    }
+
+    #[test]
+    fn test_resolve_line_height() {
+        let param = DanmakuParam {
+            line_height: LineHeight::Auto(1.0),
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let mut shaper = CosmicTextShaper::new(FontSystem::new());
+        let single = param.resolve_line_height(&mut shaper);
+        let height = match single.line_height {
+            LineHeight::Fixed(height) => height,
+            LineHeight::Auto(_) => panic!("Line height is not resolved"),
+        };
+        assert!(height >= 28, "{}", height);
+
+        let double = DanmakuParam {
+            line_height: LineHeight::Auto(2.0),
+            ..param
+        };
+        let double = double.resolve_line_height(&mut shaper);
+        assert!(double.logical_line_height() >= height * 2 - 1);
+        assert_eq!(
+            single.resolve_line_height(&mut shaper).line_height,
+            single.line_height
+        );
+    }
+
+    #[test]
+    fn test_tick() {
+        let param = DanmakuParam {
+            layout_mode: LayoutMode::ShowAll,
+            ..test_param()
+        };
+        let buffer = Arc::new(Mutex::new(
+            WorkerBuffer::<NoopRenderCache, DanmakuTimeChunk>::new(NoopRenderCache),
+        ));
+        let state = WorkerState {
+            buffer: buffer.clone(),
+            shaper: Box::new(CosmicTextShaper::new(FontSystem::new())),
+            source: Box::new(parse_xml_from_file("test/1176840.xml").unwrap()),
+            emote_provider: None,
+            styler: None,
+            local_danmaku: Vec::new(),
+            timed_regions: Vec::new(),
+            cached_layouts: Vec::new(),
+        };
+        let mut worker = WorkerManager::new(param.clone(), state);
+        let wait_for = |index: u32| {
+            let start = Instant::now();
+            while buffer.lock().unwrap().acquire_index(index).is_none() {
+                assert!(start.elapsed() < Duration::from_secs(30));
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        worker.tick(DanmakuTime::from_millis(1_000)).unwrap();
+        assert_eq!(worker.playback().time(), DanmakuTime::from_millis(1_000));
+        wait_for(0);
+        // Nothing to request while the chunk is buffered
+        worker.tick(DanmakuTime::from_millis(2_000)).unwrap();
+        assert_eq!(worker.status().queue_depth, 0);
+
+        // The next chunk continues from the layout of the buffered ones
+        worker.tick(DanmakuTime::from_millis(9_000)).unwrap();
+        wait_for(1);
+        let buffer = buffer.lock().unwrap();
+        let (previous, current) = buffer.acquire_index(1).unwrap();
+        assert_eq!(current.index, 1);
+        assert_eq!(current.base_state_index, 0);
+
+        let now = DanmakuTime::from_millis(9_000);
+        let visible = previous.visible_count(now, &param) + current.visible_count(now, &param);
+        assert!(visible > 0);
+        assert_eq!(buffer.visible_count(now, &param), visible);
+    }
+
+    #[test]
+    fn test_prefetch_index() {
+        let playback = SharedPlayback::new();
+        let host = playback.clone();
+        let chunk_duration = Duration::from_secs(8);
+        host.set_time(DanmakuTime::from_millis(40_000));
+        // Chunks 3 to 6 are buffered
+        assert_eq!(prefetch_index(&playback, chunk_duration, 2), Some(7));
+        host.set_speed(-2.0);
+        assert_eq!(prefetch_index(&playback, chunk_duration, 2), Some(2));
+        host.set_time(DanmakuTime::from_millis(8_000));
+        assert_eq!(prefetch_index(&playback, chunk_duration, 2), None);
+        host.set_speed(1.0);
+        host.set_paused(true);
+        assert_eq!(prefetch_index(&playback, chunk_duration, 2), None);
+    }
+
+    // The param of the tests, which override the fields they depend on
+    pub(crate) fn test_param() -> DanmakuParam {
+        DanmakuParam {
+            screen_size: (1280, 720),
+            scroll_lifetime: Duration::from_secs(8),
+            static_lifetime: Duration::from_secs(5),
+            chunk_duration: Duration::from_secs(8),
+            font_size: 28.0,
+            line_height: LineHeight::Fixed(32),
+            font_attrs: AttrsList::new(Attrs::new()),
+            layout_mode: LayoutMode::NoOverlap(10),
+            scroll_speed: ScrollSpeedModel::ConstantDuration,
+            scroll_gap: 0,
+            scroll_speed_variation: 0.0,
+            wide_danmaku: WideDanmaku::Keep,
+            tall_danmaku: TallDanmaku::Overflow,
+            blocked_regions: Vec::new(),
+            static_limit: None,
+            shadow_size: 0,
+            shadow_weight: 0.0,
+            shadow_kernel: ShadowKernel::Outline,
+            scale_factor: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_indexing() {
+        let param = test_param();
+        let buffer = Arc::new(Mutex::new(
+            WorkerBuffer::<NoopRenderCache, DanmakuTimeChunk>::new(NoopRenderCache),
+        ));
+        let state = WorkerState {
+            buffer: buffer.clone(),
+            shaper: Box::new(CosmicTextShaper::new(FontSystem::new())),
+            source: Box::new(parse_xml_from_file("test/1176840.xml").unwrap()),
+            emote_provider: None,
+            styler: None,
+            local_danmaku: Vec::new(),
+            timed_regions: Vec::new(),
+            cached_layouts: Vec::new(),
+        };
+        let mut worker = WorkerManager::new(param.clone(), state);
+        assert_eq!(worker.status().indexing, None);
+
+        worker.start_indexing().unwrap();
+        let start = Instant::now();
+        let progress = loop {
+            match worker.status().indexing {
+                Some(progress) if progress.is_finished() => break progress,
+                _ => {
+                    assert!(start.elapsed() < Duration::from_secs(60));
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        };
+        assert!(progress.total > 2);
+
+        // Seeking to the end continues the layout from the start instead of an empty screen
+        let last = progress.total - 1;
+        worker
+            .tick(DanmakuTime::from_millis(last as i64 * 8_000))
+            .unwrap();
+        let start = Instant::now();
+        while buffer.lock().unwrap().acquire_index(last).is_none() {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let buffer_lock = buffer.lock().unwrap();
+        let (_, current) = buffer_lock.acquire_index(last).unwrap();
+        assert_eq!(current.base_state_index, 0);
+        drop(buffer_lock);
+
+        // Restarts from the first chunk, then stops
+        worker.start_indexing().unwrap();
+        worker.cancel_indexing().unwrap();
+        let start = Instant::now();
+        while worker.status().indexing.is_some() {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Panics on the given number of chunks, then has no danmaku
+    struct PanickingSource {
+        panics: u32,
+    }
+
+    impl DanmakuSource for PanickingSource {
+        fn get_range<'a>(
+            &'a mut self,
+            _start_included: DanmakuTime,
+            _end_excluded: DanmakuTime,
+        ) -> Box<dyn Iterator<Item = &'a Danmaku> + 'a> {
+            if self.panics > 0 {
+                self.panics -= 1;
+                panic!("Source failed")
+            }
+            Box::new(std::iter::empty())
+        }
+
+        fn get_all(&mut self) -> Box<dyn Iterator<Item = &'_ Danmaku> + '_> {
+            Box::new(std::iter::empty())
+        }
+
+        fn into_all(self) -> Box<dyn Iterator<Item = Danmaku>> {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    type TestBuffer = Arc<Mutex<WorkerBuffer<NoopRenderCache, DanmakuTimeChunk>>>;
+
+    fn panicking_worker(
+        panics: u32,
+    ) -> (TestBuffer, WorkerManager<NoopRenderCache, DanmakuTimeChunk>) {
+        let buffer = Arc::new(Mutex::new(
+            WorkerBuffer::<NoopRenderCache, DanmakuTimeChunk>::new(NoopRenderCache),
+        ));
+        let state = WorkerState {
+            buffer: buffer.clone(),
+            shaper: Box::new(CosmicTextShaper::new(FontSystem::new())),
+            source: Box::new(PanickingSource { panics }),
+            emote_provider: None,
+            styler: None,
+            local_danmaku: Vec::new(),
+            timed_regions: Vec::new(),
+            cached_layouts: Vec::new(),
+        };
+        (buffer, WorkerManager::new(test_param(), state))
+    }
+
+    #[test]
+    fn test_restart_after_panic() {
+        let (buffer, mut worker) = panicking_worker(1);
+        let start = Instant::now();
+        while buffer.lock().unwrap().acquire_index(0).is_none() {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            worker.tick(DanmakuTime::from_millis(1_000)).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The restarted worker kept the source, which doesn't panic anymore
+        assert_eq!(
+            worker.take_events(),
+            vec![WorkerEvent::Restarted("Source failed".to_string())]
+        );
+        assert!(worker.is_alive());
+    }
+
+    #[test]
+    fn test_dead_worker() {
+        let (_, mut worker) = panicking_worker(u32::MAX);
+        assert!(worker.is_alive());
+
+        let mut events = Vec::new();
+        let start = Instant::now();
+        while !matches!(events.last(), Some(WorkerEvent::Stopped(_))) {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            if let Err(err) = worker.tick(DanmakuTime::from_millis(1_000)) {
+                assert!(matches!(err, WorkerError::ChannelClosed));
+            }
+            events.extend(worker.take_events());
+            thread::sleep(Duration::from_millis(10));
+        }
+        let restarted = WorkerEvent::Restarted("Source failed".to_string());
+        assert_eq!(
+            events[..3],
+            [restarted.clone(), restarted.clone(), restarted]
+        );
+        assert_eq!(events.len(), 4);
+        assert!(!worker.is_alive());
+        assert!(matches!(
+            worker.request(None, 1),
+            Err(WorkerError::ChannelClosed)
+        ));
+        assert!(matches!(worker.refresh(), Err(WorkerError::ChannelClosed)));
+    }
+
+    #[test]
+    fn test_render_only_change() {
+        let param = test_param();
+        let buffer = Arc::new(Mutex::new(
+            WorkerBuffer::<NoopRenderCache, DanmakuTimeChunk>::new(NoopRenderCache),
+        ));
+        let state = WorkerState {
+            buffer: buffer.clone(),
+            shaper: Box::new(CosmicTextShaper::new(FontSystem::new())),
+            source: Box::new(parse_xml_from_file("test/1176840.xml").unwrap()),
+            emote_provider: None,
+            styler: None,
+            local_danmaku: Vec::new(),
+            timed_regions: Vec::new(),
+            cached_layouts: Vec::new(),
+        };
+        let mut worker = WorkerManager::new(param.clone(), state);
+        worker.tick(DanmakuTime::from_millis(1_000)).unwrap();
+        let start = Instant::now();
+        while buffer.lock().unwrap().acquire_index(0).is_none() {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let chunk = buffer.lock().unwrap().current.clone().unwrap();
+
+        let shadow = DanmakuParam {
+            shadow_size: 2,
+            shadow_weight: 1.0,
+            ..param.clone()
+        };
+        assert!(shadow.render_changed(&param));
+        assert!(!shadow.layout_changed(&param));
+        worker.change_param(shadow).unwrap();
+        assert_eq!(worker.param().shadow_size, 2);
+
+        // The worker kept its layouts and prepared the same chunk again
+        worker.into_state().unwrap();
+        let current = buffer.lock().unwrap().current.clone().unwrap();
+        assert!(Arc::ptr_eq(&chunk, &current));
+
+        let larger = DanmakuParam {
+            font_size: 32.0,
+            ..param.clone()
+        };
+        assert!(larger.layout_changed(&param));
+    }
+}
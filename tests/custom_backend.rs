@@ -0,0 +1,187 @@
+// A backend outside the crate, drawing the coverage of the glyphs into an alpha mask on the CPU,
+// with only the public API. Other backends follow the same steps: rasterize the glyphs in
+// RenderCache::prepare, keep what the chunk is drawn from in a ChunkBuffer, and draw the chunks
+// of the WorkerBuffer at the positions of their items.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use danmaku_renderer::{
+    cosmic_text::CacheKey,
+    danmaku::{Danmaku, DanmakuTime, DanmakuType},
+    layout::{LayoutMode, ScrollSpeedModel},
+    manager::DanmakuTimeChunk,
+    shaper::{GlyphBitmap, TextShaper},
+    sources::VecDanmakuSource,
+    worker::{
+        ChunkBuffer, DanmakuParam, LineHeight, RenderCache, ShadowKernel, TallDanmaku, WideDanmaku,
+        WorkerBuffer, WorkerManager, WorkerStateBuilder,
+    },
+    Attrs, AttrsList,
+};
+
+#[derive(Default)]
+struct CoverageCache {
+    glyphs: HashMap<CacheKey, Option<GlyphBitmap>>,
+}
+
+impl RenderCache for CoverageCache {
+    fn new_param(&mut self, _new_param: DanmakuParam) {
+        self.glyphs.clear();
+    }
+
+    fn prepare(&mut self, shaper: &mut dyn TextShaper, chunk: &DanmakuTimeChunk) {
+        let missing: Vec<_> = chunk
+            .glyph_ids()
+            .filter(|glyph| !self.glyphs.contains_key(glyph))
+            .copied()
+            .collect();
+        let images = shaper.rasterize_all(&missing);
+        self.glyphs.extend(missing.into_iter().zip(images));
+    }
+}
+
+// The chunk with its glyphs, so drawing doesn't need the cache
+struct CoverageChunk {
+    chunk: Arc<DanmakuTimeChunk>,
+    glyphs: HashMap<CacheKey, GlyphBitmap>,
+}
+
+impl ChunkBuffer<CoverageCache> for CoverageChunk {
+    fn new(chunk: &Arc<DanmakuTimeChunk>, cache: &mut CoverageCache) -> Arc<Self> {
+        let glyphs = chunk
+            .glyph_ids()
+            .filter_map(|glyph| Some((*glyph, cache.glyphs.get(glyph)?.clone()?)))
+            .collect();
+        Arc::new(CoverageChunk {
+            chunk: chunk.clone(),
+            glyphs,
+        })
+    }
+
+    fn index(&self) -> u32 {
+        self.chunk.index
+    }
+
+    fn base_state_index(&self) -> u32 {
+        self.chunk.base_state_index
+    }
+
+    fn visible_count(&self, now: DanmakuTime, param: &DanmakuParam) -> usize {
+        self.chunk.visible_count(now, param)
+    }
+}
+
+impl CoverageChunk {
+    fn draw(&self, mask: &mut [u8], now: DanmakuTime, param: &DanmakuParam) {
+        let (width, height) = param.screen_size;
+        for (x, y, item) in self.chunk.positions_at(now, param) {
+            for glyph in &item.physical_glyphs {
+                let Some(bitmap) = self.glyphs.get(&glyph.cache_key) else {
+                    continue;
+                };
+                if bitmap.data.is_empty() {
+                    continue;
+                }
+                let (left, top, glyph_width, _) = item.glyph_rect(glyph, bitmap.placement);
+                // Color glyphs are RGBA, take their alpha
+                let stride =
+                    bitmap.data.len() / (bitmap.placement.width * bitmap.placement.height) as usize;
+                for (index, pixel) in bitmap.data.chunks(stride).enumerate() {
+                    let px = x as i32 + left + (index as u32 % glyph_width) as i32;
+                    let py = y as i32 + top + (index as u32 / glyph_width) as i32;
+                    if px < 0 || py < 0 || px >= width as i32 || py >= height as i32 {
+                        continue;
+                    }
+                    let target = &mut mask[(py as u32 * width + px as u32) as usize];
+                    *target = (*target).max(pixel[stride - 1]);
+                }
+            }
+        }
+    }
+}
+
+fn danmaku(millis: i64, r#type: DanmakuType, content: &str) -> Danmaku {
+    Danmaku::new(DanmakuTime::from_millis(millis), r#type, content)
+}
+
+#[test]
+fn test_custom_backend() {
+    let param = DanmakuParam {
+        screen_size: (320, 180),
+        scroll_lifetime: Duration::from_secs(8),
+        static_lifetime: Duration::from_secs(5),
+        chunk_duration: Duration::from_secs(8),
+        font_size: 14.0,
+        line_height: LineHeight::Fixed(18),
+        font_attrs: AttrsList::new(Attrs::new()),
+        layout_mode: LayoutMode::ShowAll,
+        scroll_speed: ScrollSpeedModel::ConstantDuration,
+        scroll_gap: 0,
+        scroll_speed_variation: 0.0,
+        wide_danmaku: WideDanmaku::Keep,
+        tall_danmaku: TallDanmaku::Overflow,
+        blocked_regions: Vec::new(),
+        static_limit: None,
+        shadow_size: 0,
+        shadow_weight: 1.0,
+        shadow_kernel: ShadowKernel::Outline,
+        scale_factor: 1.0,
+    };
+    let items = vec![
+        danmaku(0, DanmakuType::Top, "top"),
+        danmaku(500, DanmakuType::Scroll, "scroll"),
+        danmaku(1000, DanmakuType::Bottom, "bottom"),
+    ];
+    let buffer = Arc::new(Mutex::new(
+        WorkerBuffer::<CoverageCache, CoverageChunk>::new(CoverageCache::default()),
+    ));
+    let state = WorkerStateBuilder::new()
+        .sans_serif_families(&["DejaVu Sans"])
+        .build(buffer.clone(), Box::new(VecDanmakuSource::new(items)));
+    let mut worker = WorkerManager::new(param, state);
+    let param = worker.param().clone();
+
+    let now = DanmakuTime::from_millis(2000);
+    let index = param.chunk_index(now);
+    worker.request(None, index).unwrap();
+    let start = Instant::now();
+    while buffer.lock().unwrap().acquire_index(index).is_none() {
+        assert!(start.elapsed() < Duration::from_secs(30));
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let (width, height) = param.screen_size;
+    let mut mask = vec![0; (width * height) as usize];
+    let buffer = buffer.lock().unwrap();
+    for chunk in buffer.chunks() {
+        chunk.draw(&mut mask, now, &param);
+    }
+    assert_eq!(buffer.visible_count(now, &param), 3);
+
+    // The glyphs cover something inside the backgrounds of the items, and nothing outside but
+    // antialiasing
+    let inside = |px: i32, py: i32| {
+        buffer.chunks().any(|chunk| {
+            chunk.chunk.positions_at(now, &param).any(|(x, y, item)| {
+                let (left, top, width, height) = item.background_rect();
+                let (left, top) = (x as i32 + left - 2, y as i32 + top - 2);
+                let (right, bottom) = (left + width as i32 + 4, top + height as i32 + 4);
+                (left..right).contains(&px) && (top..bottom).contains(&py)
+            })
+        })
+    };
+    let mut covered = 0;
+    for (index, alpha) in mask.iter().enumerate() {
+        if *alpha == 0 {
+            continue;
+        }
+        let (px, py) = (index as u32 % width, index as u32 / width);
+        assert!(inside(px as i32, py as i32), "({}, {}) is outside", px, py);
+        covered += 1;
+    }
+    assert!(covered > 0);
+}